@@ -0,0 +1,489 @@
+//! Writing a download as a tar or zip archive instead of a directory tree.
+//!
+//! Archives are written front to back, so pieces must arrive in order: [`ArchiveSink`] holds the
+//! pieces arriving early until the gap before them is filled. This keeps the output streamable,
+//! e.g. to a pipe.
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::{self, Display},
+    io::Write,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    storage::{layout, plain_name, StorageSink},
+    torrent::Torrent,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    /// Stored (uncompressed) entries, since pieces are already-compressed media more often than
+    /// not.
+    Zip,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = UnknownFormat;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "tar" => Ok(ArchiveFormat::Tar),
+            "zip" => Ok(ArchiveFormat::Zip),
+            _ => Err(UnknownFormat(format.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownFormat(pub String);
+
+impl Display for UnknownFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown archive format {:?}, expected tar or zip",
+            self.0
+        )
+    }
+}
+
+impl Error for UnknownFormat {}
+
+/// A file of the archive.
+#[derive(Debug, Clone)]
+struct Entry {
+    name: String,
+    length: u64,
+}
+
+/// What a zip's central directory records of an entry written before.
+#[derive(Debug, Clone)]
+struct ZipRecord {
+    name: String,
+    length: u32,
+    crc: u32,
+    offset: u32,
+}
+
+/// Writes the files of a torrent, given as the concatenation of their content, as an archive.
+pub struct ArchiveWriter<W: Write> {
+    format: ArchiveFormat,
+    writer: W,
+    /// Bytes written to `writer` so far.
+    position: u64,
+    entries: Vec<Entry>,
+    /// The entry being written, `entries.len()` once every entry is.
+    current: usize,
+    /// Bytes of the current entry still to be written, `None` before its header is written.
+    left: Option<u64>,
+    crc: Crc32,
+    records: Vec<ZipRecord>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// An archive of the files of `torrent`, under a directory named after it for multi-file
+    /// torrents, which must have a plain name that no entry could be extracted out of.
+    pub fn new(format: ArchiveFormat, torrent: &Torrent, writer: W) -> anyhow::Result<Self> {
+        if plain_name(&torrent.info.name).is_none() {
+            bail!(
+                "the torrent's name {:?} isn't a plain file name",
+                torrent.info.name
+            );
+        }
+        let entries = layout(torrent, Path::new(&torrent.info.name))?
+            .into_iter()
+            .map(|span| {
+                let name = span
+                    .path
+                    .iter()
+                    .map(|component| component.to_str().expect("torrent paths are strings"))
+                    .collect::<Vec<_>>()
+                    .join("/");
+                Entry {
+                    name,
+                    length: span.length,
+                }
+            })
+            .collect::<Vec<_>>();
+        if format == ArchiveFormat::Zip {
+            if entries.len() >= u16::MAX as usize {
+                bail!("zip archives hold fewer than 65535 files, the torrent has more");
+            }
+            let total: u64 = entries.iter().map(|entry| entry.length).sum();
+            // leaving room for headers, zip64 isn't supported
+            if total >= u64::from(u32::MAX) - (entries.len() as u64) * 1024 {
+                bail!("zip archives are limited to 4 GiB, use tar instead");
+            }
+        }
+        Ok(Self {
+            format,
+            writer,
+            position: 0,
+            entries,
+            current: 0,
+            left: None,
+            crc: Crc32::new(),
+            records: Vec::new(),
+        })
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.writer
+            .write_all(bytes)
+            .context("writing the archive")?;
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Write the next `data` of the concatenated files.
+    pub fn write(&mut self, mut data: &[u8]) -> anyhow::Result<()> {
+        while !data.is_empty() {
+            let left = self.advance()?;
+            let Some(left) = left else {
+                bail!("more data than the torrent's files hold");
+            };
+            let chunk = &data[..data.len().min(left as usize)];
+            self.emit(chunk)?;
+            self.crc.update(chunk);
+            self.left = Some(left - chunk.len() as u64);
+            data = &data[chunk.len()..];
+        }
+        Ok(())
+    }
+
+    /// Close every completed entry and open the next one, returning how much of it is left, or
+    /// `None` once every entry is written.
+    fn advance(&mut self) -> anyhow::Result<Option<u64>> {
+        loop {
+            match self.left {
+                Some(0) => {
+                    self.close_entry()?;
+                    self.current += 1;
+                    self.left = None;
+                }
+                Some(left) => return Ok(Some(left)),
+                None if self.current == self.entries.len() => return Ok(None),
+                None => {
+                    self.open_entry()?;
+                    self.left = Some(self.entries[self.current].length);
+                }
+            }
+        }
+    }
+
+    fn open_entry(&mut self) -> anyhow::Result<()> {
+        let entry = self.entries[self.current].clone();
+        self.crc = Crc32::new();
+        match self.format {
+            ArchiveFormat::Tar => {
+                if entry.name.len() > 100 || entry.length >= 1 << 33 {
+                    let pax = pax_records(&entry);
+                    let name = format!("PaxHeaders/{}", self.current);
+                    self.emit(&tar_header(&name, pax.len() as u64, b'x'))?;
+                    self.emit(&pax)?;
+                    self.emit(&vec![0; tar_padding(pax.len() as u64)])?;
+                }
+                self.emit(&tar_header(&entry.name, entry.length, b'0'))
+            }
+            ArchiveFormat::Zip => {
+                self.records.push(ZipRecord {
+                    name: entry.name.clone(),
+                    length: entry.length as u32,
+                    crc: 0,
+                    offset: self.position as u32,
+                });
+                let mut header = Vec::with_capacity(30 + entry.name.len());
+                header.extend(0x04034b50u32.to_le_bytes());
+                header.extend(ZIP_VERSION.to_le_bytes());
+                header.extend(ZIP_FLAGS.to_le_bytes());
+                header.extend(0u16.to_le_bytes()); // stored
+                header.extend(ZIP_TIME.to_le_bytes());
+                header.extend(ZIP_DATE.to_le_bytes());
+                header.extend(0u32.to_le_bytes()); // crc, in the data descriptor
+                header.extend((entry.length as u32).to_le_bytes());
+                header.extend((entry.length as u32).to_le_bytes());
+                header.extend((entry.name.len() as u16).to_le_bytes());
+                header.extend(0u16.to_le_bytes());
+                header.extend(entry.name.as_bytes());
+                self.emit(&header)
+            }
+        }
+    }
+
+    fn close_entry(&mut self) -> anyhow::Result<()> {
+        let entry = &self.entries[self.current];
+        match self.format {
+            ArchiveFormat::Tar => self.emit(&vec![0; tar_padding(entry.length)]),
+            ArchiveFormat::Zip => {
+                let length = entry.length as u32;
+                let crc = self.crc.finish();
+                self.records.last_mut().expect("the entry was opened").crc = crc;
+                let mut descriptor = Vec::with_capacity(16);
+                descriptor.extend(0x08074b50u32.to_le_bytes());
+                descriptor.extend(crc.to_le_bytes());
+                descriptor.extend(length.to_le_bytes());
+                descriptor.extend(length.to_le_bytes());
+                self.emit(&descriptor)
+            }
+        }
+    }
+
+    /// Write what closes the archive, failing if any file wasn't written entirely.
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        if self.advance()?.is_some() {
+            bail!(
+                "{} isn't complete, the archive can't be closed",
+                self.entries[self.current].name
+            );
+        }
+        match self.format {
+            ArchiveFormat::Tar => self.emit(&[0; 1024])?,
+            ArchiveFormat::Zip => {
+                let start = self.position;
+                for record in std::mem::take(&mut self.records) {
+                    let mut header = Vec::with_capacity(46 + record.name.len());
+                    header.extend(0x02014b50u32.to_le_bytes());
+                    header.extend(ZIP_VERSION.to_le_bytes()); // made by
+                    header.extend(ZIP_VERSION.to_le_bytes()); // needed
+                    header.extend(ZIP_FLAGS.to_le_bytes());
+                    header.extend(0u16.to_le_bytes());
+                    header.extend(ZIP_TIME.to_le_bytes());
+                    header.extend(ZIP_DATE.to_le_bytes());
+                    header.extend(record.crc.to_le_bytes());
+                    header.extend(record.length.to_le_bytes());
+                    header.extend(record.length.to_le_bytes());
+                    header.extend((record.name.len() as u16).to_le_bytes());
+                    header.extend([0; 12]); // extra, comment, disk, attributes
+                    header.extend(record.offset.to_le_bytes());
+                    header.extend(record.name.as_bytes());
+                    self.emit(&header)?;
+                }
+                let count = self.entries.len() as u16;
+                let mut end = Vec::with_capacity(22);
+                end.extend(0x06054b50u32.to_le_bytes());
+                end.extend([0; 4]); // disks
+                end.extend(count.to_le_bytes());
+                end.extend(count.to_le_bytes());
+                end.extend(((self.position - start) as u32).to_le_bytes());
+                end.extend((start as u32).to_le_bytes());
+                end.extend(0u16.to_le_bytes());
+                self.emit(&end)?;
+            }
+        }
+        self.writer.flush().context("writing the archive")
+    }
+}
+
+/// Version 2.0, the first to support data descriptors.
+const ZIP_VERSION: u16 = 20;
+/// Sizes and CRC in a data descriptor after the data, UTF-8 names.
+const ZIP_FLAGS: u16 = 1 << 3 | 1 << 11;
+/// Midnight, January 1st 1980, the earliest DOS time: torrents don't record modification times.
+const ZIP_TIME: u16 = 0;
+const ZIP_DATE: u16 = 1 << 5 | 1;
+
+fn tar_padding(length: u64) -> usize {
+    (512 - (length % 512) as usize) % 512
+}
+
+/// A ustar header, with the name and size truncated when they don't fit: a PAX header before it
+/// then holds them.
+fn tar_header(name: &str, size: u64, kind: u8) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    let name = &name.as_bytes()[..name.len().min(100)];
+    header[..name.len()].copy_from_slice(name);
+    let octal = |field: &mut [u8], value: u64| {
+        let digits = format!("{value:0width$o}", width = field.len() - 1);
+        let digits = &digits.as_bytes()[digits.len() - (field.len() - 1)..];
+        field[..digits.len()].copy_from_slice(digits);
+    };
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size.min((1 << 33) - 1));
+    octal(&mut header[136..148], 0);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    octal(&mut header[148..155], u64::from(checksum));
+    header
+}
+
+/// The records of a PAX extended header carrying the name and size of `entry`.
+fn pax_records(entry: &Entry) -> Vec<u8> {
+    let record = |key: &str, value: &str| {
+        // the length prefix counts itself
+        let rest = format!(" {key}={value}\n");
+        let mut length = rest.len() + 1;
+        while length.to_string().len() + rest.len() != length {
+            length += 1;
+        }
+        format!("{length}{rest}")
+    };
+    let mut records = record("path", &entry.name);
+    records.push_str(&record("size", &entry.length.to_string()));
+    records.into_bytes()
+}
+
+/// CRC-32 as used by zip (IEEE 802.3, reflected).
+#[derive(Debug, Clone)]
+struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    fn new() -> Self {
+        Self { crc: !0 }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.crc ^= u32::from(byte);
+            for _ in 0..8 {
+                self.crc = (self.crc >> 1) ^ (0xedb88320 & (self.crc & 1).wrapping_neg());
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+/// A [`StorageSink`] writing a download to an archive, holding pieces arriving out of order until
+/// they can be written.
+pub struct ArchiveSink<W: Write> {
+    writer: ArchiveWriter<W>,
+    /// How much of the content was written to the archive.
+    written: u64,
+    /// Pieces that arrived ahead of `written`, by offset.
+    ahead: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<W: Write> ArchiveSink<W> {
+    pub fn new(writer: ArchiveWriter<W>) -> Self {
+        Self {
+            writer,
+            written: 0,
+            ahead: BTreeMap::new(),
+        }
+    }
+}
+
+impl<W: Write> StorageSink for ArchiveSink<W> {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        if offset < self.written {
+            bail!("offset {offset} was written to the archive already");
+        }
+        self.ahead.insert(offset, data.to_vec());
+        while let Some(data) = self.ahead.remove(&self.written) {
+            self.writer.write(&data)?;
+            self.written += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::torrent;
+
+    #[test]
+    fn archives_in_order() {
+        let long_name = "x".repeat(120);
+        let long_path = format!("sub/{long_name}");
+        let torrent = torrent(
+            4,
+            &[
+                ("a", &b"abcdef"[..]),
+                ("sub/empty", b""),
+                (&long_path, b"ghi"),
+            ],
+        );
+
+        let mut tar = Vec::new();
+        let mut sink =
+            ArchiveSink::new(ArchiveWriter::new(ArchiveFormat::Tar, &torrent, &mut tar).unwrap());
+        sink.write_at(4, b"efgh").unwrap();
+        sink.write_at(8, b"i").unwrap();
+        assert!(sink.finish().is_err(), "the first piece is missing");
+        sink.write_at(0, b"abcd").unwrap();
+        sink.finish().unwrap();
+        drop(sink);
+
+        let header = |offset: usize| &tar[offset..offset + 512];
+        assert_eq!(&header(0)[..7], b"data/a\0");
+        assert_eq!(&tar[512..518], b"abcdef");
+        assert_eq!(&header(1024)[..15], b"data/sub/empty\0");
+        assert_eq!(header(1536)[156], b'x', "a PAX header for the long name");
+        let pax = String::from_utf8_lossy(&tar[2048..2048 + 200]);
+        let record = pax.lines().next().unwrap();
+        assert_eq!(
+            record.split(' ').next().unwrap(),
+            (record.len() + 1).to_string()
+        );
+        assert!(record.ends_with(&format!("path=data/sub/{long_name}")));
+        assert_eq!(&tar[3072..3075], b"ghi");
+        assert_eq!(tar.len(), 3584 + 1024);
+        assert!(tar[3584..].iter().all(|&byte| byte == 0));
+
+        let mut zip = Vec::new();
+        let mut sink =
+            ArchiveSink::new(ArchiveWriter::new(ArchiveFormat::Zip, &torrent, &mut zip).unwrap());
+        sink.write_at(0, b"abcd").unwrap();
+        sink.write_at(4, b"efgh").unwrap();
+        sink.write_at(8, b"i").unwrap();
+        sink.finish().unwrap();
+        drop(sink);
+        assert_eq!(&zip[..4], b"PK\x03\x04");
+        assert_eq!(&zip[30..36], b"data/a");
+        assert_eq!(&zip[36..42], b"abcdef");
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xcbf43926);
+        let mut crc = Crc32::new();
+        crc.update(b"abcdef");
+        assert_eq!(
+            &zip[46..50],
+            crc.finish().to_le_bytes(),
+            "in the data descriptor"
+        );
+        assert_eq!(&zip[zip.len() - 22..zip.len() - 18], b"PK\x05\x06");
+        assert_eq!(zip[zip.len() - 12], 3, "3 entries");
+    }
+
+    #[test]
+    fn refuse_names_leading_out_of_the_archive() {
+        for name in ["../../x", "/etc/cron.d/x", ""] {
+            for files in [&[("a", &b"abc"[..])][..], &[("a", b"abc"), ("b", b"d")]] {
+                let mut torrent = torrent(4, files);
+                torrent.info.name = name.to_string();
+                for format in [ArchiveFormat::Tar, ArchiveFormat::Zip] {
+                    assert!(
+                        ArchiveWriter::new(format, &torrent, Vec::new()).is_err(),
+                        "{name:?} with {} files as {format:?}",
+                        files.len()
+                    );
+                }
+            }
+        }
+    }
+}
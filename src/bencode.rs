@@ -0,0 +1,332 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display},
+    ops::Range,
+};
+
+use serde_bencode::value::Value;
+
+/// How deeply lists and dictionaries may nest, deeper data being refused rather than risking the
+/// stack on it.
+pub const MAX_DEPTH: usize = 64;
+
+/// Why bencoded data couldn't be decoded, with the offset of the offending byte, `TooDeep` being
+/// lists and dictionaries nested deeper than [`MAX_DEPTH`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    UnexpectedByte { byte: u8, offset: usize },
+    InvalidInteger { offset: usize },
+    InvalidLength { offset: usize },
+    TooDeep { offset: usize },
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DecodeError::*;
+        match self {
+            UnexpectedEnd => write!(f, "unexpected end of data"),
+            UnexpectedByte { byte, offset } => write!(
+                f,
+                "unexpected byte {:?} at offset {offset}",
+                char::from(*byte)
+            ),
+            InvalidInteger { offset } => write!(f, "invalid integer at offset {offset}"),
+            InvalidLength { offset } => write!(f, "invalid string length at offset {offset}"),
+            TooDeep { offset } => write!(
+                f,
+                "nested more than {MAX_DEPTH} levels deep at offset {offset}"
+            ),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Decode the bencoded value at the start of `data`, returning it along with the number of bytes
+/// it took, so that any trailing bytes can be told apart.
+pub fn decode_prefix(data: &[u8]) -> Result<(Value, usize), DecodeError> {
+    let mut decoder = Decoder {
+        data,
+        offset: 0,
+        depth: 0,
+    };
+    let value = decoder.value()?;
+    Ok((value, decoder.offset))
+}
+
+/// Decode every value of concatenated bencoded `data`.
+pub fn decode_all(mut data: &[u8]) -> Result<Vec<Value>, DecodeError> {
+    let mut values = Vec::new();
+    let mut consumed = 0;
+    while !data.is_empty() {
+        let (value, length) = decode_prefix(data).map_err(|err| err.shifted(consumed))?;
+        values.push(value);
+        data = &data[length..];
+        consumed += length;
+    }
+    Ok(values)
+}
+
+/// The raw bencoded value of `key` in the dictionary at the start of `data`, exactly as it was
+/// encoded, found without decoding any other value.
+pub fn dict_entry<'a>(data: &'a [u8], key: &[u8]) -> Result<Option<&'a [u8]>, DecodeError> {
+    let mut decoder = Decoder {
+        data,
+        offset: 0,
+        depth: 0,
+    };
+    match decoder.peek()? {
+        b'd' => decoder.enter()?,
+        byte => return Err(DecodeError::UnexpectedByte { byte, offset: 0 }),
+    }
+    while decoder.peek()? != b'e' {
+        let entry_key = decoder.byte_span()?;
+        let start = decoder.offset;
+        decoder.skip()?;
+        if &data[entry_key] == key {
+            return Ok(Some(&data[start..decoder.offset]));
+        }
+    }
+    Ok(None)
+}
+
+impl DecodeError {
+    /// The same error for data starting `by` bytes later.
+    fn shifted(self, by: usize) -> Self {
+        use DecodeError::*;
+        match self {
+            UnexpectedEnd => UnexpectedEnd,
+            UnexpectedByte { byte, offset } => UnexpectedByte {
+                byte,
+                offset: offset + by,
+            },
+            InvalidInteger { offset } => InvalidInteger {
+                offset: offset + by,
+            },
+            InvalidLength { offset } => InvalidLength {
+                offset: offset + by,
+            },
+            TooDeep { offset } => TooDeep {
+                offset: offset + by,
+            },
+        }
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    offset: usize,
+    /// How many lists and dictionaries the offset is in.
+    depth: usize,
+}
+
+impl Decoder<'_> {
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.data
+            .get(self.offset)
+            .copied()
+            .ok_or(DecodeError::UnexpectedEnd)
+    }
+
+    /// The bytes up to the next `terminator`, which is skipped.
+    fn until(&mut self, terminator: u8) -> Result<&[u8], DecodeError> {
+        let start = self.offset;
+        let length = self.data[start..]
+            .iter()
+            .position(|&byte| byte == terminator)
+            .ok_or(DecodeError::UnexpectedEnd)?;
+        self.offset += length + 1;
+        Ok(&self.data[start..start + length])
+    }
+
+    /// Move into the list or dictionary at the offset.
+    fn enter(&mut self) -> Result<(), DecodeError> {
+        if self.depth == MAX_DEPTH {
+            return Err(DecodeError::TooDeep {
+                offset: self.offset,
+            });
+        }
+        self.depth += 1;
+        self.offset += 1;
+        Ok(())
+    }
+
+    /// Move past the end of the list or dictionary the offset is at.
+    fn leave(&mut self) {
+        self.depth -= 1;
+        self.offset += 1;
+    }
+
+    fn value(&mut self) -> Result<Value, DecodeError> {
+        match self.peek()? {
+            b'i' => {
+                self.offset += 1;
+                let offset = self.offset;
+                let digits = self.until(b'e')?;
+                let invalid = || DecodeError::InvalidInteger { offset };
+                // neither a sign other than `-`, leading zeros nor a negative zero are canonical
+                if !digits
+                    .first()
+                    .is_some_and(|&byte| byte == b'-' || byte.is_ascii_digit())
+                    || digits.starts_with(b"-0")
+                    || (digits.len() > 1 && digits[0] == b'0')
+                {
+                    return Err(invalid());
+                }
+                std::str::from_utf8(digits)
+                    .ok()
+                    .and_then(|digits| digits.parse().ok())
+                    .map(Value::Int)
+                    .ok_or_else(invalid)
+            }
+            b'l' => {
+                self.enter()?;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value()?);
+                }
+                self.leave();
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.enter()?;
+                let mut dict = HashMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.bytes()?;
+                    let value = self.value()?;
+                    dict.insert(key, value);
+                }
+                self.leave();
+                Ok(Value::Dict(dict))
+            }
+            b'0'..=b'9' => Ok(Value::Bytes(self.bytes()?)),
+            byte => Err(DecodeError::UnexpectedByte {
+                byte,
+                offset: self.offset,
+            }),
+        }
+    }
+
+    /// Move past the value at the offset without building it.
+    fn skip(&mut self) -> Result<(), DecodeError> {
+        match self.peek()? {
+            b'l' | b'd' => {
+                self.enter()?;
+                while self.peek()? != b'e' {
+                    self.skip()?;
+                }
+                self.leave();
+                Ok(())
+            }
+            b'0'..=b'9' => self.byte_span().map(|_| ()),
+            _ => self.value().map(|_| ()),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let span = self.byte_span()?;
+        Ok(self.data[span].to_vec())
+    }
+
+    /// The range of the data taken by the contents of the byte string at the offset.
+    fn byte_span(&mut self) -> Result<Range<usize>, DecodeError> {
+        let offset = self.offset;
+        let byte = self.peek()?;
+        if !byte.is_ascii_digit() {
+            return Err(DecodeError::UnexpectedByte { byte, offset });
+        }
+        let length: usize = std::str::from_utf8(self.until(b':')?)
+            .ok()
+            .and_then(|length| length.parse().ok())
+            .ok_or(DecodeError::InvalidLength { offset })?;
+        let end = self
+            .offset
+            .checked_add(length)
+            .filter(|&end| end <= self.data.len())
+            .ok_or(DecodeError::UnexpectedEnd)?;
+        let span = self.offset..end;
+        self.offset = end;
+        Ok(span)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_with_trailing_data() {
+        let (value, consumed) = decode_prefix(b"d3:cow3:moo4:spaml1:ai-3eee5:hello").unwrap();
+        assert_eq!(consumed, 27);
+        let Value::Dict(dict) = value else {
+            panic!("expected a dict")
+        };
+        assert_eq!(dict[&b"cow"[..]], Value::Bytes(b"moo".to_vec()));
+        assert_eq!(
+            dict[&b"spam"[..]],
+            Value::List(vec![Value::Bytes(b"a".to_vec()), Value::Int(-3)])
+        );
+
+        let values = decode_all(b"i42e5:helloli1ee").unwrap();
+        assert_eq!(values.len(), 3);
+        assert_eq!(
+            decode_all(b"i42ei03e"),
+            Err(DecodeError::InvalidInteger { offset: 5 })
+        );
+        for invalid in [&b"i+5e"[..], b"i+0e", b"ie", b"i-e"] {
+            assert_eq!(
+                decode_all(invalid),
+                Err(DecodeError::InvalidInteger { offset: 1 }),
+                "{}",
+                String::from_utf8_lossy(invalid)
+            );
+        }
+        assert_eq!(decode_all(b"i1e5:hi"), Err(DecodeError::UnexpectedEnd));
+
+        let dict = b"d3:cow3:moo4:infod1:xli1ei2eee4:spami-3ee";
+        assert_eq!(dict_entry(dict, b"info"), Ok(Some(&b"d1:xli1ei2eee"[..])));
+        assert_eq!(dict_entry(dict, b"spam"), Ok(Some(&b"i-3e"[..])));
+        assert_eq!(dict_entry(dict, b"ham"), Ok(None));
+        assert_eq!(
+            dict_entry(b"d4:info", b"info"),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        assert_eq!(
+            decode_prefix(b"x"),
+            Err(DecodeError::UnexpectedByte {
+                byte: b'x',
+                offset: 0
+            })
+        );
+    }
+
+    #[test]
+    fn refuse_deep_nesting() {
+        let nested = |depth: usize| {
+            let mut data = "l".repeat(depth).into_bytes();
+            data.extend("e".repeat(depth).into_bytes());
+            data
+        };
+        assert!(decode_prefix(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            decode_prefix(&nested(MAX_DEPTH + 1)),
+            Err(DecodeError::TooDeep { offset: MAX_DEPTH })
+        );
+        assert_eq!(
+            decode_prefix(&b"l".repeat(100_000)),
+            Err(DecodeError::TooDeep { offset: MAX_DEPTH })
+        );
+
+        let mut dict = b"d4:info".to_vec();
+        dict.extend(nested(MAX_DEPTH));
+        dict.push(b'e');
+        assert_eq!(
+            dict_entry(&dict, b"info"),
+            Err(DecodeError::TooDeep {
+                offset: 7 + MAX_DEPTH - 1
+            })
+        );
+    }
+}
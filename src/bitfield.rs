@@ -0,0 +1,71 @@
+//! The pieces a peer has, as its `Bitfield` message lists them: a bit per piece, high bit first.
+
+/// The pieces a peer has, from its `Bitfield` message and the `Have` messages after it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield(Vec<u8>);
+
+impl Bitfield {
+    /// A bitfield of `piece_count` pieces, none of them set.
+    pub fn new(piece_count: usize) -> Self {
+        Self(vec![0; piece_count.div_ceil(8)])
+    }
+
+    pub fn has_piece(&self, piece_index: usize) -> bool {
+        self.0
+            .get(piece_index / 8)
+            .is_some_and(|byte| byte & (0x80 >> (piece_index % 8)) != 0)
+    }
+
+    /// Set `piece_index`, growing the bitfield to fit it, returning whether it wasn't set before.
+    pub fn set_piece(&mut self, piece_index: usize) -> bool {
+        if self.0.len() <= piece_index / 8 {
+            self.0.resize(piece_index / 8 + 1, 0);
+        }
+        let had = self.has_piece(piece_index);
+        self.0[piece_index / 8] |= 0x80 >> (piece_index % 8);
+        !had
+    }
+
+    /// The indices of the pieces set, in order.
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.0.len() * 8).filter(|&piece_index| self.has_piece(piece_index))
+    }
+
+    /// How many pieces are set.
+    pub fn count(&self) -> usize {
+        self.0.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl From<Vec<u8>> for Bitfield {
+    fn from(fields: Vec<u8>) -> Self {
+        Self(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_iterate_pieces() {
+        let mut bitfield = Bitfield::from(vec![0b1010_0000]);
+        assert!(bitfield.has_piece(0));
+        assert!(!bitfield.has_piece(1));
+        assert!(!bitfield.has_piece(100), "past the end");
+        assert!(bitfield.set_piece(9));
+        assert!(!bitfield.set_piece(9), "already set");
+        assert_eq!(bitfield.as_bytes(), [0b1010_0000, 0b0100_0000]);
+        assert_eq!(bitfield.iter_set().collect::<Vec<_>>(), [0, 2, 9]);
+        assert_eq!(bitfield.count(), 3);
+        assert_eq!(Bitfield::new(9).as_bytes(), [0, 0]);
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::{resume::write_atomic, torrent::Info};
+
+/// The directory this client keeps its caches in: `$XDG_CACHE_HOME/bittorrent-rust`, falling back
+/// to `~/.cache/bittorrent-rust` and then to the system's temporary directory.
+pub fn cache_dir() -> PathBuf {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(env::temp_dir);
+    base.join("bittorrent-rust")
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AnnounceEntry {
+    /// Unix timestamp after which the response must not be reused.
+    expires: u64,
+
+    /// The raw, bencoded tracker response.
+    #[serde(with = "serde_bytes")]
+    response: Vec<u8>,
+}
+
+/// Tracker responses kept on disk per (tracker, info hash) for as long as the tracker's announce
+/// interval, so repeated commands don't hammer the tracker.
+#[derive(Debug, Clone)]
+pub struct AnnounceCache {
+    dir: PathBuf,
+}
+
+impl Default for AnnounceCache {
+    fn default() -> Self {
+        Self::new(cache_dir().join("announce"))
+    }
+}
+
+impl AnnounceCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn entry_path(&self, tracker: &str, info_hash: [u8; 20]) -> PathBuf {
+        let mut hasher = Sha1::new();
+        hasher.update(tracker.as_bytes());
+        hasher.update(info_hash);
+        let key: [u8; 20] = hasher.finalize().into();
+        self.dir.join(hex::encode(key))
+    }
+
+    /// The cached response of `tracker` for `info_hash`, if it's still fresh.
+    pub fn get(&self, tracker: &str, info_hash: [u8; 20]) -> Option<Vec<u8>> {
+        let buf = fs::read(self.entry_path(tracker, info_hash)).ok()?;
+        let entry: AnnounceEntry = serde_bencode::from_bytes(&buf).ok()?;
+        (unix_now() < entry.expires).then_some(entry.response)
+    }
+
+    /// Keep `response` of `tracker` for `info_hash` around for `ttl`.
+    pub fn put(
+        &self,
+        tracker: &str,
+        info_hash: [u8; 20],
+        response: &[u8],
+        ttl: Duration,
+    ) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir).context("creating announce cache directory")?;
+        let entry = AnnounceEntry {
+            expires: unix_now() + ttl.as_secs(),
+            response: response.to_vec(),
+        };
+        let bytes = serde_bencode::to_bytes(&entry).context("bencoding announce cache entry")?;
+        write_atomic(&self.entry_path(tracker, info_hash), &bytes)
+    }
+}
+
+/// Identifies the content of a file on disk without reading it: if neither its length nor its
+/// modification time changed, neither did its content.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub length: u64,
+    #[serde(rename = "mtime secs")]
+    pub mtime_secs: u64,
+    #[serde(rename = "mtime nanos")]
+    pub mtime_nanos: u32,
+}
+
+impl Fingerprint {
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Self {
+            length: metadata.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VerificationEntry {
+    /// One per file of the torrent, in order.
+    fingerprints: Vec<Fingerprint>,
+
+    /// A bitfield of the pieces that passed their hash check, high bit first.
+    #[serde(with = "serde_bytes")]
+    verified: Vec<u8>,
+}
+
+/// The pieces of a torrent known to be valid on disk, keyed by info hash and valid only as long as
+/// the [`Fingerprint`] of none of its files changes, so re-checks can skip hashing unchanged data.
+#[derive(Debug, Clone)]
+pub struct VerificationCache {
+    dir: PathBuf,
+}
+
+impl Default for VerificationCache {
+    fn default() -> Self {
+        Self::new(cache_dir().join("verified"))
+    }
+}
+
+impl VerificationCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The bitfield of verified pieces recorded for `info_hash`, if its files still have the same
+    /// `fingerprints`.
+    pub fn get(&self, info_hash: [u8; 20], fingerprints: &[Fingerprint]) -> Option<Vec<u8>> {
+        let buf = fs::read(self.dir.join(hex::encode(info_hash))).ok()?;
+        let entry: VerificationEntry = serde_bencode::from_bytes(&buf).ok()?;
+        (entry.fingerprints == fingerprints).then_some(entry.verified)
+    }
+
+    pub fn put(
+        &self,
+        info_hash: [u8; 20],
+        fingerprints: Vec<Fingerprint>,
+        verified: &[u8],
+    ) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir).context("creating verification cache directory")?;
+        let entry = VerificationEntry {
+            fingerprints,
+            verified: verified.to_vec(),
+        };
+        let bytes =
+            serde_bencode::to_bytes(&entry).context("bencoding verification cache entry")?;
+        write_atomic(&self.dir.join(hex::encode(info_hash)), &bytes)
+    }
+}
+
+/// The info dictionaries of torrents seen before, from torrent files or fetched from the swarm for
+/// magnet links, keyed by info hash so that they don't have to be fetched again.
+#[derive(Debug, Clone)]
+pub struct MetadataCache {
+    dir: PathBuf,
+}
+
+impl Default for MetadataCache {
+    fn default() -> Self {
+        Self::new(cache_dir().join("metadata"))
+    }
+}
+
+impl MetadataCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The info dictionary of `info_hash`, if cached and still matching the hash.
+    pub fn get(&self, info_hash: [u8; 20]) -> Option<Info> {
+        let buf = fs::read(self.dir.join(hex::encode(info_hash))).ok()?;
+        let hash: [u8; 20] = Sha1::digest(&buf).into();
+        (hash == info_hash)
+            .then(|| serde_bencode::from_bytes(&buf).ok())
+            .flatten()
+    }
+
+    pub fn put(&self, info: &Info) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir).context("creating metadata cache directory")?;
+        let bytes = serde_bencode::to_bytes(info).context("bencoding info dictionary")?;
+        let info_hash: [u8; 20] = Sha1::digest(&bytes).into();
+        write_atomic(&self.dir.join(hex::encode(info_hash)), &bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::single_file, torrent::TorrentBuilder};
+
+    #[test]
+    fn announce_cache_honors_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = AnnounceCache::new(dir.path().join("announce"));
+        let tracker = "http://tracker.example.com/announce";
+
+        assert_eq!(cache.get(tracker, [0; 20]), None);
+
+        cache
+            .put(
+                tracker,
+                [0; 20],
+                b"d8:intervali60ee",
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        assert_eq!(
+            cache.get(tracker, [0; 20]),
+            Some(b"d8:intervali60ee".to_vec())
+        );
+        assert_eq!(cache.get(tracker, [1; 20]), None);
+
+        cache
+            .put(tracker, [0; 20], b"d8:intervali0ee", Duration::ZERO)
+            .unwrap();
+        assert_eq!(cache.get(tracker, [0; 20]), None);
+    }
+
+    #[test]
+    fn metadata_cache_checks_the_info_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = MetadataCache::new(dir.path().join("metadata"));
+        let torrent = single_file(TorrentBuilder::DEFAULT_PIECE_LENGTH, &[1; 100]);
+        let info_hash = torrent.calculate_info_hash();
+
+        assert_eq!(cache.get(info_hash), None);
+        cache.put(&torrent.info).unwrap();
+        assert_eq!(cache.get(info_hash), Some(torrent.info));
+
+        // an entry that doesn't hash to its name is never trusted
+        fs::write(
+            dir.path().join("metadata").join(hex::encode([2; 20])),
+            b"de",
+        )
+        .unwrap();
+        assert_eq!(cache.get([2; 20]), None);
+    }
+}
@@ -0,0 +1,424 @@
+//! A census of a swarm, taken by watching its peers without downloading anything from them.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::{self, Display},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use crate::{bitfield::Bitfield, display::Span, peer::Capabilities};
+
+/// What was learned about one peer over the observation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Observation {
+    /// The client, from the extension handshake or else the peer id.
+    pub client: Option<String>,
+    /// The extensions the peer supports.
+    pub extensions: Vec<String>,
+    /// What the peer advertised in its handshakes.
+    pub capabilities: Capabilities,
+    /// The pieces the peer has as of the end of the observation, from its bitfield and every
+    /// `Have` since, `None` when it sent neither.
+    pub bitfield: Option<Bitfield>,
+    /// How many pieces the peer announced finishing during the observation.
+    pub haves: usize,
+    /// The peers it told us about through peer exchange.
+    pub exchanged: Vec<SocketAddr>,
+    /// Why the connection ended before the observation did.
+    pub error: Option<String>,
+}
+
+impl Observation {
+    /// Record that the peer has `piece_index`, returning whether it didn't before.
+    pub fn set_piece(&mut self, piece_index: usize) -> bool {
+        self.bitfield
+            .get_or_insert_with(Bitfield::default)
+            .set_piece(piece_index)
+    }
+
+    fn has_piece(&self, piece_index: usize) -> bool {
+        self.bitfield
+            .as_ref()
+            .is_some_and(|bitfield| bitfield.has_piece(piece_index))
+    }
+}
+
+/// Every observation of a swarm of a torrent of `piece_count` pieces.
+#[derive(Debug, Clone)]
+pub struct Census {
+    piece_count: usize,
+    duration: Duration,
+    observed: BTreeMap<SocketAddr, Observation>,
+    unreachable: BTreeMap<SocketAddr, String>,
+}
+
+impl Census {
+    pub fn new(piece_count: usize, duration: Duration) -> Self {
+        Self {
+            piece_count,
+            duration,
+            observed: BTreeMap::new(),
+            unreachable: BTreeMap::new(),
+        }
+    }
+
+    pub fn observed(&mut self, peer: SocketAddr, observation: Observation) {
+        self.observed.insert(peer, observation);
+    }
+
+    pub fn unreachable(&mut self, peer: SocketAddr, err: String) {
+        self.unreachable.insert(peer, err);
+    }
+
+    fn pieces_of(&self, observation: &Observation) -> usize {
+        (0..self.piece_count)
+            .filter(|&piece_index| observation.has_piece(piece_index))
+            .count()
+    }
+
+    /// The peers having every piece.
+    pub fn seeds(&self) -> usize {
+        self.seed_peers().len()
+    }
+
+    fn seed_peers(&self) -> BTreeSet<SocketAddr> {
+        self.observed
+            .iter()
+            .filter(|(_, observation)| self.pieces_of(observation) == self.piece_count)
+            .map(|(&peer, _)| peer)
+            .collect()
+    }
+
+    /// How many observed peers have each piece.
+    pub fn availability(&self) -> Vec<usize> {
+        (0..self.piece_count)
+            .map(|piece_index| {
+                self.observed
+                    .values()
+                    .filter(|observation| observation.has_piece(piece_index))
+                    .count()
+            })
+            .collect()
+    }
+
+    /// How many full copies of the content the swarm holds: the copies of the rarest piece, plus
+    /// the fraction of the pieces more common than it.
+    pub fn distributed_copies(&self) -> f64 {
+        let availability = self.availability();
+        let Some(&rarest) = availability.iter().min() else {
+            return 0.0;
+        };
+        let above = availability.iter().filter(|&&count| count > rarest).count();
+        rarest as f64 + above as f64 / availability.len() as f64
+    }
+
+    /// How many observed peers run every client, most common first.
+    pub fn clients(&self) -> Vec<(String, usize)> {
+        let mut clients = BTreeMap::new();
+        for observation in self.observed.values() {
+            let client = observation.client.as_deref().unwrap_or("unknown");
+            *clients.entry(client.to_string()).or_insert(0) += 1;
+        }
+        let mut clients: Vec<_> = clients.into_iter().collect();
+        clients.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        clients
+    }
+
+    /// How many observed peers of every client, regardless of its version, have each of
+    /// [`Capabilities::NAMES`], by client.
+    pub fn capability_matrix(&self) -> BTreeMap<String, (usize, [usize; 6])> {
+        let mut matrix = BTreeMap::new();
+        for observation in self.observed.values() {
+            let client = client_family(observation.client.as_deref().unwrap_or("unknown"));
+            let (peers, counts) = matrix.entry(client).or_insert((0, [0; 6]));
+            *peers += 1;
+            for (count, flag) in counts.iter_mut().zip(observation.capabilities.flags()) {
+                *count += usize::from(flag);
+            }
+        }
+        matrix
+    }
+
+    /// One line on the seeds and the availability of the pieces.
+    pub fn summary(&self) -> String {
+        let unavailable = self
+            .availability()
+            .iter()
+            .filter(|&&count| count == 0)
+            .count();
+        format!(
+            "{} seeds, {} leechers, {:.2} distributed copies, {unavailable} of {} pieces on no peer.",
+            self.seeds(),
+            self.observed.len() - self.seeds(),
+            self.distributed_copies(),
+            self.piece_count
+        )
+    }
+
+    /// What changed in the swarm since the `earlier` census of it.
+    pub fn delta(&self, earlier: &Census) -> CensusDelta {
+        let (seeds, earlier_seeds) = (self.seed_peers(), earlier.seed_peers());
+        let (availability, earlier_availability) = (self.availability(), earlier.availability());
+        let mut delta = CensusDelta {
+            new_seeds: seeds.difference(&earlier_seeds).copied().collect(),
+            lost_seeds: earlier_seeds.difference(&seeds).copied().collect(),
+            copies: (earlier.distributed_copies(), self.distributed_copies()),
+            ..CensusDelta::default()
+        };
+        for (piece_index, (&now, &before)) in
+            availability.iter().zip(&earlier_availability).enumerate()
+        {
+            match (before, now) {
+                (1.., 0) => delta.lost_pieces.push(piece_index),
+                (0, 1..) => delta.recovered_pieces.push(piece_index),
+                _ if now < before => delta.rarer_pieces += 1,
+                _ => {}
+            }
+        }
+        delta
+    }
+
+    /// The peers learned through peer exchange, that weren't observed themselves.
+    pub fn exchanged(&self) -> HashSet<SocketAddr> {
+        self.observed
+            .values()
+            .flat_map(|observation| &observation.exchanged)
+            .filter(|peer| {
+                !self.observed.contains_key(peer) && !self.unreachable.contains_key(peer)
+            })
+            .copied()
+            .collect()
+    }
+}
+
+impl Display for Census {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reached = self.observed.len();
+        writeln!(
+            f,
+            "Observed {reached} peers for {}, {} unreachable.",
+            Span(self.duration),
+            self.unreachable.len()
+        )?;
+        writeln!(
+            f,
+            "Seeds: {}, leechers: {}.",
+            self.seeds(),
+            reached - self.seeds()
+        )?;
+
+        let availability = self.availability();
+        let unavailable = availability.iter().filter(|&&count| count == 0).count();
+        writeln!(
+            f,
+            "Availability: {:.2} distributed copies, {unavailable} of {} pieces on no peer.",
+            self.distributed_copies(),
+            self.piece_count
+        )?;
+        let haves: usize = self
+            .observed
+            .values()
+            .map(|observation| observation.haves)
+            .sum();
+        writeln!(f, "Pieces announced during the observation: {haves}.")?;
+        writeln!(
+            f,
+            "Peers learned through peer exchange only: {}.",
+            self.exchanged().len()
+        )?;
+
+        writeln!(f, "Clients:")?;
+        for (client, count) in self.clients() {
+            writeln!(f, "  {count:>4}  {client}")?;
+        }
+
+        writeln!(f, "Capabilities:")?;
+        write!(f, "  {:<24} {:>5}", "client", "peers")?;
+        for name in Capabilities::NAMES {
+            write!(f, " {name:>5}")?;
+        }
+        writeln!(f)?;
+        for (client, (peers, counts)) in self.capability_matrix() {
+            write!(f, "  {client:<24} {peers:>5}")?;
+            for count in counts {
+                write!(f, " {count:>5}")?;
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(f, "Peers:")?;
+        for (peer, observation) in &self.observed {
+            let pieces = self.pieces_of(observation);
+            write!(
+                f,
+                "  {peer:<21} {:>5.1}%  {}",
+                match self.piece_count {
+                    0 => 100.0,
+                    count => pieces as f64 * 100.0 / count as f64,
+                },
+                observation.client.as_deref().unwrap_or("unknown")
+            )?;
+            if !observation.extensions.is_empty() {
+                write!(f, " [{}]", observation.extensions.join(", "))?;
+            }
+            if let Some(err) = &observation.error {
+                write!(f, " (left: {err})")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// What changed between two censuses of a swarm.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CensusDelta {
+    /// The peers that became seeds, or showed up as seeds.
+    pub new_seeds: Vec<SocketAddr>,
+    /// The seeds that left, or weren't reached this time.
+    pub lost_seeds: Vec<SocketAddr>,
+    /// The pieces some peer had before, but none has now.
+    pub lost_pieces: Vec<usize>,
+    /// The pieces no peer had before, but some has now.
+    pub recovered_pieces: Vec<usize>,
+    /// How many other pieces are on fewer peers than before.
+    pub rarer_pieces: usize,
+    /// The distributed copies before and now.
+    pub copies: (f64, f64),
+}
+
+impl Display for CensusDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |items: &[String]| items.join(", ");
+        let peers =
+            |peers: &[SocketAddr]| list(&peers.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+        let pieces =
+            |pieces: &[usize]| list(&pieces.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+
+        let (before, now) = self.copies;
+        writeln!(f, "  distributed copies: {before:.2} -> {now:.2}")?;
+        if !self.new_seeds.is_empty() {
+            writeln!(f, "  new seeds: {}", peers(&self.new_seeds))?;
+        }
+        if !self.lost_seeds.is_empty() {
+            writeln!(f, "  lost seeds: {}", peers(&self.lost_seeds))?;
+        }
+        if !self.lost_pieces.is_empty() {
+            writeln!(f, "  pieces now on no peer: {}", pieces(&self.lost_pieces))?;
+        }
+        if !self.recovered_pieces.is_empty() {
+            writeln!(
+                f,
+                "  pieces available again: {}",
+                pieces(&self.recovered_pieces)
+            )?;
+        }
+        if self.rarer_pieces > 0 {
+            writeln!(f, "  pieces on fewer peers: {}", self.rarer_pieces)?;
+        }
+        Ok(())
+    }
+}
+
+/// The name of `client` without its version, e.g. `qBittorrent` for `qBittorrent v4.6.2` or
+/// `qBittorrent 4.6.2`.
+fn client_family(client: &str) -> String {
+    let is_version = |word: &str| {
+        let word = word.strip_prefix('v').unwrap_or(word);
+        word.starts_with(|c: char| c.is_ascii_digit())
+    };
+    let family: Vec<_> = client
+        .split([' ', '/'])
+        .take_while(|word| !is_version(word))
+        .collect();
+    match family.join(" ") {
+        family if family.is_empty() => client.to_string(),
+        family => family,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swarm_statistics() {
+        let mut census = Census::new(3, Duration::from_secs(60));
+        let peer = |port| SocketAddr::new([10, 0, 0, 1].into(), port);
+
+        let mut seed = Observation {
+            client: Some("qBittorrent 5".to_string()),
+            capabilities: Capabilities {
+                fast: true,
+                extension_protocol: true,
+                ..Capabilities::default()
+            },
+            bitfield: Some(Bitfield::from(vec![0b1110_0000])),
+            ..Observation::default()
+        };
+        assert!(!seed.set_piece(0), "it had the piece already");
+        census.observed(peer(1), seed);
+
+        let mut leecher = Observation {
+            exchanged: vec![peer(1), peer(3), peer(4)],
+            ..Observation::default()
+        };
+        assert!(leecher.set_piece(1));
+        leecher.haves += 1;
+        census.observed(peer(2), leecher);
+        census.unreachable(peer(3), "connection refused".to_string());
+
+        assert_eq!(census.seeds(), 1);
+        assert_eq!(census.availability(), [1, 2, 1]);
+        assert_eq!(census.distributed_copies(), 1.0 + 1.0 / 3.0);
+        assert_eq!(
+            census.clients(),
+            [("qBittorrent 5".to_string(), 1), ("unknown".to_string(), 1)]
+        );
+        assert_eq!(census.exchanged(), HashSet::from([peer(4)]));
+
+        census.observed(
+            peer(5),
+            Observation {
+                client: Some("qBittorrent v4.6.2".to_string()),
+                capabilities: Capabilities {
+                    fast: true,
+                    dht: true,
+                    ..Capabilities::default()
+                },
+                ..Observation::default()
+            },
+        );
+        assert_eq!(
+            census.capability_matrix(),
+            BTreeMap::from([
+                ("qBittorrent".to_string(), (2, [2, 1, 1, 0, 0, 0])),
+                ("unknown".to_string(), (1, [0; 6])),
+            ])
+        );
+        let report = census.to_string();
+        let earlier = census.clone();
+
+        // the seed leaves, piece 0 with it, while the others spread among the leechers
+        census.observed.remove(&peer(1));
+        census.observed(
+            peer(6),
+            Observation {
+                bitfield: Some(Bitfield::from(vec![0b0100_0000])),
+                ..Observation::default()
+            },
+        );
+        census.observed.get_mut(&peer(2)).unwrap().set_piece(2);
+        let delta = census.delta(&earlier);
+        assert_eq!(delta.new_seeds, []);
+        assert_eq!(delta.lost_seeds, [peer(1)]);
+        assert_eq!(delta.lost_pieces, [0]);
+        assert_eq!(delta.rarer_pieces, 0);
+        assert!(delta.to_string().contains("pieces now on no peer: 0"));
+        assert_eq!(earlier.delta(&census).recovered_pieces, [0]);
+
+        assert!(report.contains("Seeds: 1, leechers: 2."));
+        assert!(report.contains("  qBittorrent                  2     2     1     1"));
+    }
+}
@@ -0,0 +1,158 @@
+//! Fault injection, to exercise the download engine's retry and recovery paths in soak tests.
+
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{bail, Context};
+
+use crate::random::{self, Xorshift};
+
+/// The probability of every kind of injected fault, all zero by default.
+///
+/// Parsed from a comma separated list such as `disconnect=0.1,corrupt=0.05,seed=42`, with the keys
+/// `disconnect`, `delay`, `max-delay-ms`, `corrupt`, `tracker` and `seed`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChaosConfig {
+    /// Drop the peer connection before a piece is requested.
+    pub disconnect: f64,
+    /// Stall before a piece is requested.
+    pub delay: f64,
+    /// The longest stall injected by `delay`.
+    pub max_delay: Duration,
+    /// Flip a byte of a downloaded piece, so that it fails its hash check.
+    pub corrupt: f64,
+    /// Fail the tracker announce.
+    pub tracker_failure: f64,
+    /// Makes a run reproducible, a random seed is picked when `None`.
+    pub seed: Option<u64>,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        Self {
+            disconnect: 0.0,
+            delay: 0.0,
+            max_delay: Duration::from_secs(1),
+            corrupt: 0.0,
+            tracker_failure: 0.0,
+            seed: None,
+        }
+    }
+}
+
+impl FromStr for ChaosConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let probability = |value: &str| -> anyhow::Result<f64> {
+            let p: f64 = value
+                .parse()
+                .context(format!("invalid probability {value:?}"))?;
+            if !(0.0..=1.0).contains(&p) {
+                bail!("probability {p} isn't between 0 and 1");
+            }
+            Ok(p)
+        };
+
+        let mut config = Self::default();
+        for setting in spec.split(',').filter(|setting| !setting.is_empty()) {
+            let Some((key, value)) = setting.split_once('=') else {
+                bail!("expected key=value but found {setting:?}")
+            };
+            match key {
+                "disconnect" => config.disconnect = probability(value)?,
+                "delay" => config.delay = probability(value)?,
+                "max-delay-ms" => {
+                    config.max_delay = Duration::from_millis(
+                        value.parse().context(format!("invalid delay {value:?}"))?,
+                    )
+                }
+                "corrupt" => config.corrupt = probability(value)?,
+                "tracker" => config.tracker_failure = probability(value)?,
+                "seed" => {
+                    config.seed = Some(value.parse().context(format!("invalid seed {value:?}"))?)
+                }
+                _ => bail!("unknown chaos setting {key:?}"),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Rolls the dice for every injection point of a download session.
+#[derive(Debug, Clone)]
+pub struct Chaos {
+    config: ChaosConfig,
+    rng: Xorshift,
+}
+
+impl Chaos {
+    pub fn new(config: ChaosConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(random::seed);
+        Self {
+            config,
+            rng: Xorshift::new(seed),
+        }
+    }
+
+    fn roll(&mut self, probability: f64) -> bool {
+        self.rng.next_f64() < probability
+    }
+
+    pub fn disconnect(&mut self) -> bool {
+        self.roll(self.config.disconnect)
+    }
+
+    pub fn delay(&mut self) -> Option<Duration> {
+        if !self.roll(self.config.delay) {
+            return None;
+        }
+        let millis = self.config.max_delay.as_millis() as u64;
+        Some(Duration::from_millis(self.rng.below(millis + 1)))
+    }
+
+    /// Maybe flip one byte of `data`, returning whether it did.
+    pub fn corrupt(&mut self, data: &mut [u8]) -> bool {
+        if data.is_empty() || !self.roll(self.config.corrupt) {
+            return false;
+        }
+        let index = self.rng.below(data.len() as u64) as usize;
+        data[index] ^= 0xff;
+        true
+    }
+
+    pub fn tracker_failure(&mut self) -> bool {
+        self.roll(self.config.tracker_failure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_inject() {
+        let config: ChaosConfig = "corrupt=1,tracker=0,max-delay-ms=5,delay=1,seed=7"
+            .parse()
+            .unwrap();
+        assert_eq!(config.max_delay, Duration::from_millis(5));
+        assert!("corrupt=2".parse::<ChaosConfig>().is_err());
+        assert!("explode=0.5".parse::<ChaosConfig>().is_err());
+
+        let mut chaos = Chaos::new(config);
+        assert!(!chaos.tracker_failure());
+        assert!(!chaos.disconnect());
+        assert!(chaos.delay().unwrap() <= Duration::from_millis(5));
+
+        let mut data = [0u8; 16];
+        assert!(chaos.corrupt(&mut data));
+        assert_eq!(data.iter().filter(|&&byte| byte == 0xff).count(), 1);
+
+        let mut again = Chaos::new(config);
+        again.tracker_failure();
+        again.disconnect();
+        again.delay();
+        let mut same = [0u8; 16];
+        again.corrupt(&mut same);
+        assert_eq!(data, same, "a seed makes runs reproducible");
+    }
+}
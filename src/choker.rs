@@ -0,0 +1,289 @@
+//! Which peers may download from us, by the tit-for-tat choker of BEP 3: the peers reciprocating
+//! best get the regular unchoke slots, recomputed every [`RECHOKE_INTERVAL`], and one more peer
+//! picked at random gets an optimistic unchoke, rotated every [`OPTIMISTIC_INTERVAL`], so that
+//! newcomers get a chance to show what they're worth.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::Clock,
+    random::{self, Xorshift},
+};
+
+/// How many peers are unchoked for what they give back, besides the optimistic unchoke.
+pub const UNCHOKE_SLOTS: usize = 4;
+
+/// How often the regular unchoke slots are given out again.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the optimistic unchoke moves on to another peer.
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How much a peer transferred since the last rechoke, in bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerRates {
+    pub peer: SocketAddr,
+    /// What the peer sent us.
+    pub download: f64,
+    /// What we sent the peer.
+    pub upload: f64,
+}
+
+/// Orders the interested peers competing for the regular unchoke slots.
+pub trait ChokePolicy: fmt::Debug + Send {
+    /// Sort `peers`, the most deserving first.
+    fn rank(&self, peers: &mut [PeerRates]);
+}
+
+/// Rewards the peers sending us the most, then, since nobody does while we seed, those taking the
+/// most from us.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TitForTat;
+
+impl ChokePolicy for TitForTat {
+    fn rank(&self, peers: &mut [PeerRates]) {
+        peers.sort_by(|a, b| {
+            b.download
+                .total_cmp(&a.download)
+                .then(b.upload.total_cmp(&a.upload))
+        });
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Peer {
+    interested: bool,
+    unchoked: bool,
+    /// Bytes since the last rechoke.
+    downloaded: u64,
+    uploaded: u64,
+}
+
+/// Decides which of the connected peers are unchoked.
+#[derive(Debug)]
+pub struct Choker<C: Clock> {
+    clock: C,
+    policy: Box<dyn ChokePolicy>,
+    slots: usize,
+    peers: HashMap<SocketAddr, Peer>,
+    optimistic: Option<SocketAddr>,
+    last_rechoke: Instant,
+    last_optimistic: Instant,
+    rng: Xorshift,
+}
+
+impl<C: Clock> Choker<C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            last_rechoke: clock.now(),
+            last_optimistic: clock.now(),
+            clock,
+            policy: Box::new(TitForTat),
+            slots: UNCHOKE_SLOTS,
+            peers: HashMap::new(),
+            optimistic: None,
+            rng: Xorshift::new(random::seed()),
+        }
+    }
+
+    /// Rank the peers by `policy` instead of [`TitForTat`].
+    pub fn policy(mut self, policy: impl ChokePolicy + 'static) -> Self {
+        self.policy = Box::new(policy);
+        self
+    }
+
+    /// Give out `slots` regular unchoke slots instead of [`UNCHOKE_SLOTS`].
+    pub fn slots(mut self, slots: usize) -> Self {
+        self.slots = slots;
+        self
+    }
+
+    /// Pick the optimistic unchokes with `rng`, e.g. a seeded one in tests.
+    pub fn rng(mut self, rng: Xorshift) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    pub fn join(&mut self, peer: SocketAddr) {
+        self.peers.entry(peer).or_default();
+    }
+
+    /// Forget `peer`, its unchoke slot goes to another peer at the next rechoke.
+    pub fn leave(&mut self, peer: &SocketAddr) {
+        self.peers.remove(peer);
+        if self.optimistic == Some(*peer) {
+            self.optimistic = None;
+        }
+    }
+
+    /// Record whether `peer` is interested. An interested peer is unchoked right away while a
+    /// regular slot is free, instead of waiting for the next rechoke.
+    pub fn interested(&mut self, peer: SocketAddr, interested: bool) {
+        let regular = self.unchoked().len() - usize::from(self.optimistic.is_some());
+        let peer = self.peers.entry(peer).or_default();
+        peer.interested = interested;
+        if interested && regular < self.slots {
+            peer.unchoked = true;
+        }
+    }
+
+    /// Record `bytes` received from `peer`.
+    pub fn downloaded(&mut self, peer: &SocketAddr, bytes: u64) {
+        if let Some(peer) = self.peers.get_mut(peer) {
+            peer.downloaded += bytes;
+        }
+    }
+
+    /// Record `bytes` sent to `peer`.
+    pub fn uploaded(&mut self, peer: &SocketAddr, bytes: u64) {
+        if let Some(peer) = self.peers.get_mut(peer) {
+            peer.uploaded += bytes;
+        }
+    }
+
+    /// Whether `peer` is unchoked, rechoking first when it's due.
+    pub fn is_unchoked(&mut self, peer: &SocketAddr) -> bool {
+        if self.clock.elapsed(self.last_rechoke) >= RECHOKE_INTERVAL {
+            self.rechoke();
+        }
+        self.peers.get(peer).is_some_and(|peer| peer.unchoked)
+    }
+
+    /// The unchoked peers, in address order.
+    pub fn unchoked(&self) -> Vec<SocketAddr> {
+        let mut unchoked: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.unchoked)
+            .map(|(&addr, _)| addr)
+            .collect();
+        unchoked.sort_unstable();
+        unchoked
+    }
+
+    /// Give the regular slots to the interested peers the policy ranks best over the transfers
+    /// since the last rechoke, and move the optimistic unchoke on when it's due or its peer got
+    /// a regular slot.
+    pub fn rechoke(&mut self) {
+        let now = self.clock.now();
+        let seconds = now
+            .saturating_duration_since(self.last_rechoke)
+            .as_secs_f64()
+            .max(f64::EPSILON);
+        self.last_rechoke = now;
+
+        let mut rates: Vec<PeerRates> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.interested)
+            .map(|(&addr, peer)| PeerRates {
+                peer: addr,
+                download: peer.downloaded as f64 / seconds,
+                upload: peer.uploaded as f64 / seconds,
+            })
+            .collect();
+        // a stable start for the policy's sort, whatever the map's order
+        rates.sort_by_key(|rates| rates.peer);
+        self.policy.rank(&mut rates);
+        let regular: Vec<SocketAddr> = rates
+            .iter()
+            .take(self.slots)
+            .map(|rates| rates.peer)
+            .collect();
+
+        let optimistic_valid = self.optimistic.is_some_and(|peer| {
+            !regular.contains(&peer) && self.peers.get(&peer).is_some_and(|peer| peer.interested)
+        });
+        if !optimistic_valid
+            || now.saturating_duration_since(self.last_optimistic) >= OPTIMISTIC_INTERVAL
+        {
+            let mut candidates: Vec<SocketAddr> = rates
+                .iter()
+                .skip(self.slots)
+                .map(|rates| rates.peer)
+                .collect();
+            // on to another peer, unless there is no other
+            if candidates.len() > 1 {
+                candidates.retain(|&peer| Some(peer) != self.optimistic);
+            }
+            self.optimistic = match candidates.len() {
+                0 => None,
+                count => Some(candidates[self.rng.below(count as u64) as usize]),
+            };
+            self.last_optimistic = now;
+        }
+
+        for (addr, peer) in &mut self.peers {
+            peer.unchoked = regular.contains(addr) || self.optimistic == Some(*addr);
+            peer.downloaded = 0;
+            peer.uploaded = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn tit_for_tat_with_optimistic_unchokes() {
+        let clock = MockClock::new();
+        let mut choker = Choker::new(clock.clone()).slots(2).rng(Xorshift::new(7));
+        let peers: Vec<SocketAddr> = (1..=5)
+            .map(|i| format!("10.0.0.{i}:6881").parse().unwrap())
+            .collect();
+        for &peer in &peers {
+            choker.join(peer);
+            choker.interested(peer, true);
+        }
+        assert_eq!(
+            choker.unchoked(),
+            peers[..2],
+            "the free slots go right away"
+        );
+        assert!(!choker.is_unchoked(&peers[4]), "not due yet");
+
+        // the last two peers give back the most
+        choker.downloaded(&peers[3], 1000);
+        choker.downloaded(&peers[4], 2000);
+        choker.uploaded(&peers[0], 5000);
+        clock.advance(RECHOKE_INTERVAL);
+        assert!(choker.is_unchoked(&peers[4]));
+        let unchoked = choker.unchoked();
+        assert_eq!(
+            unchoked.len(),
+            3,
+            "two regular slots and an optimistic unchoke"
+        );
+        assert!(unchoked.contains(&peers[3]) && unchoked.contains(&peers[4]));
+        let optimistic = choker.optimistic.unwrap();
+        assert!(peers[..3].contains(&optimistic));
+
+        // the optimistic unchoke stays until it's due to rotate
+        let keep_giving = |choker: &mut Choker<MockClock>| {
+            choker.downloaded(&peers[3], 1000);
+            choker.downloaded(&peers[4], 2000);
+        };
+        keep_giving(&mut choker);
+        clock.advance(RECHOKE_INTERVAL);
+        choker.rechoke();
+        assert_eq!(choker.optimistic, Some(optimistic));
+        keep_giving(&mut choker);
+        clock.advance(OPTIMISTIC_INTERVAL);
+        choker.rechoke();
+        assert_ne!(choker.optimistic, Some(optimistic));
+
+        // a peer losing interest loses its slot, one leaving frees it
+        choker.interested(peers[4], false);
+        choker.leave(&peers[3]);
+        choker.rechoke();
+        assert_eq!(choker.unchoked().len(), 3);
+        assert!(!choker.unchoked().contains(&peers[4]));
+    }
+}
@@ -0,0 +1,266 @@
+//! A downloader to embed in other programs, without the command line around it.
+//!
+//! It downloads from one peer at a time, in piece order, moving on to the next peer when one
+//! fails or doesn't have the pieces left.
+
+use std::{io, net::SocketAddr, path::Path};
+
+use anyhow::anyhow;
+use tokio::runtime::Runtime;
+
+use crate::{
+    dial::Dial,
+    peer::{validate_piece, HandShake, PeerConnection, Timeouts, PIPELINE_DEPTH},
+    storage::Storage,
+    torrent::Torrent,
+    tracker::{TrackerRequest, TrackerTiers},
+};
+
+/// The size of the blocks pieces are requested in.
+pub const BLOCK_SIZE: u32 = 1 << 14;
+
+/// Connect to `peer` through `dialer` and exchange handshakes for the torrent with `info_hash`,
+/// giving up on the peer after `timeouts`.
+pub async fn connect(
+    dialer: &Dial,
+    peer: SocketAddr,
+    info_hash: [u8; 20],
+    timeouts: Timeouts,
+) -> anyhow::Result<(PeerConnection, HandShake)> {
+    let mut connection = PeerConnection::connect_with(dialer, peer, timeouts).await?;
+    let handshake = connection.handshake(HandShake::new(info_hash)).await?;
+    Ok((connection, handshake))
+}
+
+/// Downloads the torrents added to it.
+///
+/// Its methods block: the peer connections run on a runtime of the client's own, so it must not
+/// be used from within another tokio runtime.
+#[derive(Debug)]
+pub struct TorrentClient {
+    runtime: Runtime,
+    dialer: Dial,
+    pipeline: usize,
+    timeouts: Timeouts,
+}
+
+impl TorrentClient {
+    pub fn new() -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            runtime,
+            dialer: Dial::default(),
+            pipeline: PIPELINE_DEPTH,
+            timeouts: Timeouts::default(),
+        })
+    }
+
+    /// Connect to peers through `dialer` instead of directly.
+    pub fn dialer(mut self, dialer: Dial) -> Self {
+        self.dialer = dialer;
+        self
+    }
+
+    /// Keep `depth` block requests outstanding with every peer.
+    pub fn pipeline(mut self, depth: usize) -> Self {
+        self.pipeline = depth.max(1);
+        self
+    }
+
+    /// Give up on peers after `timeouts` instead of the defaults.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn add_torrent(&self, torrent: Torrent) -> ClientTorrent<'_> {
+        ClientTorrent {
+            client: self,
+            info_hash: torrent.calculate_info_hash(),
+            tiers: TrackerTiers::new(torrent.tracker_tiers()),
+            torrent,
+            peers: Vec::new(),
+        }
+    }
+}
+
+/// A torrent added to a [`TorrentClient`].
+#[derive(Debug)]
+pub struct ClientTorrent<'a> {
+    client: &'a TorrentClient,
+    torrent: Torrent,
+    info_hash: [u8; 20],
+    tiers: TrackerTiers,
+    peers: Vec<SocketAddr>,
+}
+
+impl ClientTorrent<'_> {
+    pub fn torrent(&self) -> &Torrent {
+        &self.torrent
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    /// Connect to these peers, before any the trackers return.
+    pub fn add_peers(&mut self, peers: impl IntoIterator<Item = SocketAddr>) {
+        for peer in peers {
+            if !self.peers.contains(&peer) {
+                self.peers.push(peer);
+            }
+        }
+    }
+
+    /// The peers of the torrent, announcing to its trackers unless some are known already.
+    pub fn peers(&mut self) -> anyhow::Result<&[SocketAddr]> {
+        if self.peers.is_empty() {
+            let request = TrackerRequest::new(self.torrent.content_length());
+            let response = self.tiers.announce(&request, self.info_hash, None)?;
+            self.add_peers(response.usable_peers(request.crypto_policy()).0);
+        }
+        Ok(&self.peers)
+    }
+
+    /// Download the piece at `piece_index`, checking its hash.
+    pub fn download_piece(&mut self, piece_index: usize) -> anyhow::Result<Vec<u8>> {
+        let piece_count = self.torrent.info.pieces.0.len();
+        if piece_index >= piece_count {
+            anyhow::bail!("index {piece_index} out of {piece_count}");
+        }
+        let mut downloaded = None;
+        self.fetch(vec![piece_index], |_, piece| {
+            downloaded = Some(piece);
+            Ok(())
+        })?;
+        Ok(downloaded.expect("the piece was fetched"))
+    }
+
+    /// Download every piece and write them to the files of the torrent at `output`.
+    pub fn download_to(&mut self, output: &Path) -> anyhow::Result<()> {
+        let mut storage = Storage::create(&self.torrent, output)?;
+        let piece_length = self.torrent.info.piece_length as u64;
+        let pieces = (0..self.torrent.info.pieces.0.len()).collect();
+        self.fetch(pieces, |piece_index, piece| {
+            storage.write_at(piece_index as u64 * piece_length, &piece)
+        })?;
+        storage.sync_data()
+    }
+
+    /// Download the `missing` pieces from every peer in turn until none is left, handing each
+    /// verified one to `on_piece`.
+    fn fetch(
+        &mut self,
+        mut missing: Vec<usize>,
+        mut on_piece: impl FnMut(usize, Vec<u8>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let peers = self.peers()?.to_vec();
+        let mut last_err = None;
+        for peer in peers {
+            if missing.is_empty() {
+                break;
+            }
+            let fetched = self.fetch_from(peer, &mut missing, &mut on_piece);
+            if let Err(err) = self.client.runtime.block_on(fetched) {
+                last_err = Some(err.context(format!("downloading from {peer}")));
+            }
+        }
+        match (missing.len(), last_err) {
+            (0, _) => Ok(()),
+            (_, Some(err)) => Err(err),
+            (left, None) => Err(anyhow!("none of the peers has the {left} pieces left")),
+        }
+    }
+
+    async fn fetch_from(
+        &self,
+        peer: SocketAddr,
+        missing: &mut Vec<usize>,
+        on_piece: &mut impl FnMut(usize, Vec<u8>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let (connection, _) = connect(
+            &self.client.dialer,
+            peer,
+            self.info_hash,
+            self.client.timeouts,
+        )
+        .await?;
+        let mut connection = connection.pipeline(self.client.pipeline);
+        let mut bitfield = connection
+            .initiate_download(self.torrent.info.pieces.0.len())
+            .await?;
+        while let Some(position) = missing.iter().position(|&index| bitfield.has_piece(index)) {
+            let piece_index = missing[position];
+            let piece = connection
+                .download_piece(&self.torrent, piece_index, BLOCK_SIZE)
+                .await?;
+            validate_piece(&self.torrent, piece_index, &piece)?;
+            on_piece(piece_index, piece)?;
+            missing.remove(position);
+            for piece_index in connection.take_announced() {
+                bitfield.set_piece(piece_index as usize);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        choker::Choker,
+        clock::SystemClock,
+        serve::serve_peer,
+        testing::{runtime, single_file},
+    };
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    #[test]
+    fn download_from_a_seeder() {
+        let content: Vec<u8> = (0..(1 << 15) + 100).map(|i| i as u8).collect();
+        let torrent = single_file(1 << 15, &content);
+        let info_hash = torrent.calculate_info_hash();
+        let dir = tempfile::tempdir().unwrap();
+        let seeded = dir.path().join("seeded");
+        std::fs::write(&seeded, &content).unwrap();
+        let storage = Arc::new(Mutex::new(Storage::open(&torrent, &seeded).unwrap()));
+        let choker = Mutex::new(Choker::new(SystemClock));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seeder = torrent.clone();
+        thread::spawn(move || {
+            let runtime = runtime();
+            runtime.block_on(async {
+                listener.set_nonblocking(true).unwrap();
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                while let Ok((stream, _)) = listener.accept().await {
+                    let mut connection = PeerConnection::accept(stream).unwrap();
+                    connection
+                        .accept_handshake(HandShake::new(info_hash))
+                        .await
+                        .unwrap();
+                    let _ = serve_peer(&mut connection, &seeder, &storage, &choker, &[0b1100_0000])
+                        .await;
+                }
+            });
+        });
+
+        let client = TorrentClient::new().unwrap().pipeline(2);
+        let mut download = client.add_torrent(torrent);
+        download.add_peers([addr]);
+        assert_eq!(download.peers().unwrap(), [addr], "no announce");
+        assert_eq!(download.download_piece(1).unwrap(), content[1 << 15..]);
+        assert!(download.download_piece(2).is_err());
+
+        let output = dir.path().join("downloaded");
+        download.download_to(&output).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+    }
+}
@@ -0,0 +1,175 @@
+//! Framing of peer wire messages: a 4-byte big-endian length, then that many bytes of message id
+//! and payload, a length of 0 being a keep-alive.
+
+use std::{
+    error::Error,
+    fmt::{self, Display},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::peer::{PeerMessage, PeerMessageError};
+
+/// The longest message accepted by default: the bitfield of 8 million pieces, far more than the
+/// 16 KiB blocks and metadata pieces which make up most messages.
+pub const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+/// Encodes messages into frames, and decodes them back from bytes however they were split across
+/// reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageCodec {
+    max_length: usize,
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new(MAX_MESSAGE_LENGTH)
+    }
+}
+
+impl MessageCodec {
+    /// A codec refusing frames longer than `max_length`, length prefix excluded.
+    pub fn new(max_length: usize) -> Self {
+        Self { max_length }
+    }
+
+    /// Append the frame of `message` to `dst`.
+    pub fn encode(&self, message: PeerMessage, dst: &mut BytesMut) {
+        let message: Vec<u8> = message.into();
+        dst.reserve(4 + message.len());
+        dst.put_u32(message.len() as u32);
+        dst.put_slice(&message);
+    }
+
+    /// The length of the frame at the start of `src`, length prefix excluded, or `None` while the
+    /// prefix itself is incomplete.
+    pub fn frame_length(&self, src: &[u8]) -> Result<Option<usize>, CodecError> {
+        let Some(prefix) = src.get(..4) else {
+            return Ok(None);
+        };
+        let length = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+        if length > self.max_length {
+            return Err(CodecError::TooLong {
+                length,
+                max_length: self.max_length,
+            });
+        }
+        Ok(Some(length))
+    }
+
+    /// Take the first message out of `src`, or return `None` and leave `src` as is until more of
+    /// it arrived, after reserving room for the rest of its frame.
+    pub fn decode(&self, src: &mut BytesMut) -> Result<Option<PeerMessage>, CodecError> {
+        let Some(length) = self.frame_length(src)? else {
+            return Ok(None);
+        };
+        if src.len() < 4 + length {
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        let frame = src.split_to(length);
+        Ok(Some(PeerMessage::try_from(&frame[..])?))
+    }
+}
+
+/// A frame that couldn't be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    /// The length prefix is over the codec's maximum, most likely not a peer wire stream at all.
+    TooLong { length: usize, max_length: usize },
+    /// The frame doesn't hold a valid message.
+    Message(PeerMessageError),
+}
+
+impl From<PeerMessageError> for CodecError {
+    fn from(err: PeerMessageError) -> Self {
+        Self::Message(err)
+    }
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::TooLong { length, max_length } => write!(
+                f,
+                "message of {length} bytes is longer than the maximum of {max_length}"
+            ),
+            CodecError::Message(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for CodecError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_split_anywhere() {
+        let codec = MessageCodec::new(64);
+        // keep-alive, have 3, extended handshake `de`, and the start of a piece
+        let fixture: &[u8] = &[
+            0, 0, 0, 0, //
+            0, 0, 0, 5, 4, 0, 0, 0, 3, //
+            0, 0, 0, 4, 20, 0, b'd', b'e', //
+            0, 0, 0, 11, 7, 0, 0, 0, 1, 0, 0, 0, 0, 0xab,
+        ];
+        let expected = [
+            PeerMessage::KeepAlive,
+            PeerMessage::Have { piece_index: 3 },
+            PeerMessage::Extended {
+                ext_id: 0,
+                payload: b"de".to_vec(),
+            },
+        ];
+
+        // one byte at a time, as the slowest of TCP segments would deliver them
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for &byte in fixture {
+            src.put_u8(byte);
+            while let Some(message) = codec.decode(&mut src).unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(decoded, expected);
+        assert_eq!(src[..], fixture[21..], "the partial piece is kept");
+
+        src.put_u8(0xcd);
+        assert_eq!(
+            codec.decode(&mut src),
+            Ok(Some(PeerMessage::Piece {
+                piece_index: 1,
+                offset: 0,
+                piece: vec![0xab, 0xcd],
+            }))
+        );
+        assert!(src.is_empty());
+
+        let mut dst = BytesMut::new();
+        for message in expected {
+            codec.encode(message, &mut dst);
+        }
+        assert_eq!(dst[..], fixture[..21]);
+
+        let mut src = BytesMut::from(&[0, 0, 0, 65][..]);
+        assert_eq!(
+            codec.decode(&mut src),
+            Err(CodecError::TooLong {
+                length: 65,
+                max_length: 64
+            })
+        );
+        let mut src = BytesMut::from(&[0, 0, 0, 3, 4, 0, 0][..]);
+        assert_eq!(
+            codec.decode(&mut src),
+            Err(CodecError::Message(PeerMessageError::Truncated {
+                code: 4,
+                length: 3
+            }))
+        );
+    }
+}
@@ -0,0 +1,798 @@
+//! A client of the mainline DHT (BEP 5), to find peers without a tracker.
+//!
+//! This node only queries others: its queries are marked read-only (BEP 43) so that nodes don't
+//! add it to their routing tables, since it never answers queries itself.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    fmt::{self, Display},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use serde_bencode::value::Value;
+
+use crate::{
+    clock::Clock,
+    random::{self, Xorshift},
+};
+
+/// Well-known nodes to join the DHT through.
+pub const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "router.utorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+];
+
+/// The size of the routing table's buckets, and how many nodes lookups converge on.
+pub const K: usize = 8;
+
+/// How many queries of a lookup are in flight at once.
+const ALPHA: usize = 3;
+
+/// How long to wait for the answers to a round of queries.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// An upper bound on the rounds of a lookup, which normally converges much sooner.
+const MAX_ROUNDS: usize = 32;
+
+/// How long nodes accept the token of their `get_peers` answer, and so how often an announce is
+/// refreshed, well before nodes forget it.
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// How soon to try again when no node accepted an announce.
+pub const ANNOUNCE_RETRY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random(rng: &mut Xorshift) -> Self {
+        let mut id = [0u8; 20];
+        for chunk in id.chunks_mut(8) {
+            chunk.copy_from_slice(&rng.next_u64().to_be_bytes()[..chunk.len()]);
+        }
+        Self(id)
+    }
+
+    /// The XOR distance to `other`, comparable as a big-endian number.
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        std::array::from_fn(|i| self.0[i] ^ other.0[i])
+    }
+
+    /// The number of leading bits shared with `other`, 160 for the same id.
+    fn common_prefix(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+        match distance.iter().position(|&byte| byte != 0) {
+            Some(i) => i * 8 + distance[i].leading_zeros() as usize,
+            None => 160,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+impl Node {
+    /// Parse the compact node info of a response: 20 bytes of id followed by 6 of address each.
+    pub fn from_compact(bytes: &[u8]) -> Vec<Node> {
+        bytes
+            .chunks_exact(26)
+            .map(|chunk| Node {
+                id: NodeId(chunk[..20].try_into().expect("chunks of 26 bytes")),
+                addr: compact_addr(&chunk[20..]),
+            })
+            .collect()
+    }
+
+    pub fn to_compact(nodes: &[Node]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(nodes.len() * 26);
+        for node in nodes {
+            bytes.extend_from_slice(&node.id.0);
+            bytes.extend_from_slice(&node.addr.ip().octets());
+            bytes.extend_from_slice(&node.addr.port().to_be_bytes());
+        }
+        bytes
+    }
+}
+
+fn compact_addr(bytes: &[u8]) -> SocketAddrV4 {
+    SocketAddrV4::new(
+        Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]),
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    )
+}
+
+/// The nodes known to us, in buckets by the number of leading bits their id shares with ours, each
+/// holding up to [`K`] nodes with the most recently seen last.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own: NodeId) -> Self {
+        Self {
+            own,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    /// Record that `node` answered, returning whether it's in the table now. A full bucket keeps
+    /// its nodes, as long-lived nodes are the most likely to stay.
+    pub fn insert(&mut self, node: Node) -> bool {
+        let prefix = self.own.common_prefix(&node.id);
+        if prefix == 160 {
+            return false;
+        }
+        let bucket = &mut self.buckets[prefix];
+        if let Some(position) = bucket.iter().position(|known| known.id == node.id) {
+            bucket.remove(position);
+        } else if bucket.len() == K {
+            return false;
+        }
+        bucket.push(node);
+        true
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        let prefix = self.own.common_prefix(id);
+        if let Some(bucket) = self.buckets.get_mut(prefix) {
+            bucket.retain(|node| node.id != *id);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Up to `count` known nodes, closest to `target` first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flatten().copied().collect();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+}
+
+/// A KRPC query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+    },
+    /// Tell the node we're a peer of `info_hash` on `port`, with the `token` of its answer to our
+    /// `GetPeers`.
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        token: Vec<u8>,
+    },
+}
+
+impl Query {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+        }
+    }
+
+    /// The bencoded query message sent by the node `own`.
+    pub fn to_bytes(&self, transaction: &[u8], own: NodeId) -> Vec<u8> {
+        let bytes = |bytes: &[u8]| Value::Bytes(bytes.to_vec());
+        let mut arguments = HashMap::from([(b"id".to_vec(), bytes(&own.0))]);
+        match self {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                arguments.insert(b"target".to_vec(), bytes(&target.0));
+            }
+            Query::GetPeers { info_hash } => {
+                arguments.insert(b"info_hash".to_vec(), bytes(info_hash));
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                token,
+            } => {
+                arguments.insert(b"info_hash".to_vec(), bytes(info_hash));
+                arguments.insert(b"port".to_vec(), Value::Int(i64::from(*port)));
+                arguments.insert(b"token".to_vec(), bytes(token));
+            }
+        }
+        let message = Value::Dict(HashMap::from([
+            (b"t".to_vec(), bytes(transaction)),
+            (b"y".to_vec(), bytes(b"q")),
+            (b"q".to_vec(), bytes(self.name().as_bytes())),
+            (b"a".to_vec(), Value::Dict(arguments)),
+            (b"ro".to_vec(), Value::Int(1)),
+        ]));
+        serde_bencode::to_bytes(&message).expect("guaranteed to be a valid bencode")
+    }
+}
+
+/// The answer to a [`Query`], with the fields that don't apply to it left empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    /// The nodes closest to the target of a `FindNode` or `GetPeers`.
+    pub nodes: Vec<Node>,
+    /// The peers of the info hash of a `GetPeers`.
+    pub values: Vec<SocketAddrV4>,
+    /// What an `AnnouncePeer` to the node must carry.
+    pub token: Option<Vec<u8>>,
+    /// Our address as the node sees it (BEP 42).
+    pub ip: Option<SocketAddrV4>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KrpcError {
+    Malformed(&'static str),
+    /// The node answered with an error message.
+    Remote {
+        code: i64,
+        message: String,
+    },
+}
+
+impl Display for KrpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KrpcError::Malformed(reason) => write!(f, "malformed KRPC message: {reason}"),
+            KrpcError::Remote { code, message } => write!(f, "node error {code}: {message}"),
+        }
+    }
+}
+
+impl Error for KrpcError {}
+
+/// Parse a KRPC response or error, returning its transaction id along with it.
+pub fn parse_response(bytes: &[u8]) -> Result<(Vec<u8>, Response), KrpcError> {
+    use KrpcError::Malformed;
+
+    let Ok(Value::Dict(message)) = serde_bencode::from_bytes(bytes) else {
+        return Err(Malformed("not a dictionary"));
+    };
+    let Some(Value::Bytes(transaction)) = message.get(&b"t"[..]) else {
+        return Err(Malformed("missing transaction id"));
+    };
+    match message.get(&b"y"[..]) {
+        Some(Value::Bytes(kind)) if kind == b"r" => {}
+        Some(Value::Bytes(kind)) if kind == b"e" => {
+            return Err(match message.get(&b"e"[..]) {
+                Some(Value::List(error)) => match &error[..] {
+                    [Value::Int(code), Value::Bytes(text)] => KrpcError::Remote {
+                        code: *code,
+                        message: String::from_utf8_lossy(text).into_owned(),
+                    },
+                    _ => Malformed("invalid error"),
+                },
+                _ => Malformed("missing error"),
+            })
+        }
+        _ => return Err(Malformed("not a response")),
+    }
+    let Some(Value::Dict(fields)) = message.get(&b"r"[..]) else {
+        return Err(Malformed("missing return values"));
+    };
+
+    let id = match fields.get(&b"id"[..]) {
+        Some(Value::Bytes(id)) => id.as_slice().try_into().map(NodeId),
+        _ => return Err(Malformed("missing node id")),
+    }
+    .map_err(|_| Malformed("invalid node id"))?;
+    let nodes = match fields.get(&b"nodes"[..]) {
+        Some(Value::Bytes(nodes)) => Node::from_compact(nodes),
+        _ => Vec::new(),
+    };
+    let values = match fields.get(&b"values"[..]) {
+        Some(Value::List(values)) => values
+            .iter()
+            .filter_map(|value| match value {
+                Value::Bytes(peer) if peer.len() == 6 => Some(compact_addr(peer)),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    let token = match fields.get(&b"token"[..]) {
+        Some(Value::Bytes(token)) => Some(token.clone()),
+        _ => None,
+    };
+    let ip = match message.get(&b"ip"[..]) {
+        Some(Value::Bytes(ip)) if ip.len() == 6 => Some(compact_addr(ip)),
+        _ => None,
+    };
+
+    Ok((
+        transaction.clone(),
+        Response {
+            id,
+            nodes,
+            values,
+            token,
+            ip,
+        },
+    ))
+}
+
+/// What a `get_peers` lookup found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerLookup {
+    pub peers: Vec<SocketAddrV4>,
+    /// The closest nodes that answered, with the token to announce to them.
+    pub tokens: Vec<(Node, Vec<u8>)>,
+}
+
+/// Our node: a UDP socket, an id and the routing table.
+#[derive(Debug)]
+pub struct Dht {
+    socket: UdpSocket,
+    id: NodeId,
+    table: RoutingTable,
+    next_transaction: u16,
+    external_ip: Option<Ipv4Addr>,
+}
+
+impl Dht {
+    /// Open our node on `port` of every interface, 0 picking any free one.
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        let id = NodeId::random(&mut Xorshift::new(random::seed()));
+        Ok(Self {
+            socket,
+            id,
+            table: RoutingTable::new(id),
+            next_transaction: 0,
+            external_ip: None,
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn table(&self) -> &RoutingTable {
+        &self.table
+    }
+
+    /// Our address as the last node to tell it sees it.
+    pub fn external_ip(&self) -> Option<Ipv4Addr> {
+        self.external_ip
+    }
+
+    /// Send every query at once and collect the answers arriving within [`QUERY_TIMEOUT`], adding
+    /// the nodes that answered to the routing table.
+    pub fn query_all(
+        &mut self,
+        queries: &[(SocketAddrV4, Query)],
+    ) -> Vec<(SocketAddrV4, Result<Response, KrpcError>)> {
+        let mut pending = HashMap::new();
+        for (addr, query) in queries {
+            self.next_transaction = self.next_transaction.wrapping_add(1);
+            let transaction = self.next_transaction.to_be_bytes().to_vec();
+            if self
+                .socket
+                .send_to(&query.to_bytes(&transaction, self.id), addr)
+                .is_ok()
+            {
+                pending.insert(transaction, *addr);
+            }
+        }
+
+        let mut answers = Vec::new();
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        let mut buf = [0u8; 1500];
+        while !pending.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() || self.socket.set_read_timeout(Some(left)).is_err() {
+                break;
+            }
+            let Ok((length, SocketAddr::V4(from))) = self.socket.recv_from(&mut buf) else {
+                continue;
+            };
+            let answer = match parse_response(&buf[..length]) {
+                Ok((transaction, response)) => pending
+                    .get(&transaction)
+                    .filter(|&&addr| addr == from)
+                    .map(|_| (transaction, Ok(response))),
+                Err(err) => {
+                    // errors can't always be told apart by transaction, blame the sender
+                    let transaction = pending
+                        .iter()
+                        .find(|(_, &addr)| addr == from)
+                        .map(|(transaction, _)| transaction.clone());
+                    transaction.map(|transaction| (transaction, Err(err)))
+                }
+            };
+            let Some((transaction, answer)) = answer else {
+                continue;
+            };
+            pending.remove(&transaction);
+            if let Ok(response) = &answer {
+                self.table.insert(Node {
+                    id: response.id,
+                    addr: from,
+                });
+                if let Some(ip) = response.ip {
+                    self.external_ip = Some(*ip.ip());
+                }
+            }
+            answers.push((from, answer));
+        }
+        answers
+    }
+
+    /// Join the DHT through the `routers`, filling the routing table with the nodes closest to us.
+    pub fn bootstrap(&mut self, routers: &[SocketAddrV4]) -> anyhow::Result<()> {
+        let target = self.id;
+        let queries: Vec<_> = routers
+            .iter()
+            .map(|&addr| (addr, Query::FindNode { target }))
+            .collect();
+        for (_, answer) in self.query_all(&queries) {
+            if let Ok(response) = answer {
+                self.lookup_candidates(&response.nodes);
+            }
+        }
+        if self.table.is_empty() {
+            anyhow::bail!("none of the {} bootstrap nodes answered", routers.len());
+        }
+        self.lookup(target, || Query::FindNode { target });
+        Ok(())
+    }
+
+    /// Nodes learned from an answer aren't known to be alive, they only enter the routing table
+    /// once they answer themselves, so they're pinged.
+    fn lookup_candidates(&mut self, nodes: &[Node]) {
+        let queries: Vec<_> = nodes.iter().map(|node| (node.addr, Query::Ping)).collect();
+        self.query_all(&queries);
+    }
+
+    /// Query the nodes closest to `target` with `query`, moving closer with the nodes every
+    /// answer returns, until the [`K`] closest nodes answered.
+    fn lookup(&mut self, target: NodeId, query: impl Fn() -> Query) -> Vec<(Node, Response)> {
+        let mut candidates: BTreeMap<[u8; 20], Node> = self
+            .table
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (node.id.distance(&target), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut answered = Vec::new();
+
+        for _ in 0..MAX_ROUNDS {
+            let batch: Vec<Node> = candidates
+                .values()
+                .take(K)
+                .filter(|node| !queried.contains(&node.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            queried.extend(batch.iter().map(|node| node.addr));
+            let queries: Vec<_> = batch.iter().map(|node| (node.addr, query())).collect();
+            let answers = self.query_all(&queries);
+            for node in &batch {
+                let answer = answers.iter().find(|(addr, _)| *addr == node.addr);
+                match answer {
+                    Some((_, Ok(response))) => {
+                        for node in &response.nodes {
+                            candidates.insert(node.id.distance(&target), *node);
+                        }
+                        answered.push((*node, response.clone()));
+                    }
+                    _ => {
+                        self.table.remove(&node.id);
+                        candidates.remove(&node.id.distance(&target));
+                    }
+                }
+            }
+        }
+
+        answered.sort_by_key(|(node, _)| node.id.distance(&target));
+        answered
+    }
+
+    /// Look the peers of `info_hash` up.
+    pub fn get_peers(&mut self, info_hash: [u8; 20]) -> PeerLookup {
+        let mut lookup = PeerLookup::default();
+        for (node, response) in self.lookup(NodeId(info_hash), || Query::GetPeers { info_hash }) {
+            for peer in response.values {
+                if !lookup.peers.contains(&peer) {
+                    lookup.peers.push(peer);
+                }
+            }
+            if let Some(token) = response.token {
+                if lookup.tokens.len() < K {
+                    lookup.tokens.push((node, token));
+                }
+            }
+        }
+        lookup
+    }
+
+    /// Announce ourselves as a peer of `info_hash` on `port` to the nodes of a previous
+    /// [`Dht::get_peers`], returning the nodes that accepted.
+    pub fn announce_peer(
+        &mut self,
+        info_hash: [u8; 20],
+        port: u16,
+        lookup: &PeerLookup,
+    ) -> Vec<Node> {
+        let queries: Vec<_> = lookup
+            .tokens
+            .iter()
+            .map(|(node, token)| {
+                let query = Query::AnnouncePeer {
+                    info_hash,
+                    port,
+                    token: token.clone(),
+                };
+                (node.addr, query)
+            })
+            .collect();
+        let answers = self.query_all(&queries);
+        lookup
+            .tokens
+            .iter()
+            .map(|(node, _)| *node)
+            .filter(|node| {
+                answers
+                    .iter()
+                    .any(|(addr, answer)| *addr == node.addr && answer.is_ok())
+            })
+            .collect()
+    }
+}
+
+/// When our announce of a torrent was last accepted, and by which nodes.
+#[derive(Debug, Clone, Default)]
+struct Announced {
+    at: Option<Instant>,
+    accepted: Vec<Node>,
+}
+
+/// Keeps the announces of every active torrent fresh: each one is refreshed once its tokens
+/// expire, and all of them at once when our address changes, since nodes hold on to the old one.
+#[derive(Debug)]
+pub struct AnnounceRefresh<C: Clock> {
+    clock: C,
+    torrents: HashMap<[u8; 20], Announced>,
+    external_ip: Option<IpAddr>,
+}
+
+impl<C: Clock> AnnounceRefresh<C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            torrents: HashMap::new(),
+            external_ip: None,
+        }
+    }
+
+    /// Start announcing `info_hash`, right away.
+    pub fn add(&mut self, info_hash: [u8; 20]) {
+        self.torrents.entry(info_hash).or_default();
+    }
+
+    /// Stop announcing `info_hash`, the nodes forget it on their own.
+    pub fn remove(&mut self, info_hash: &[u8; 20]) {
+        self.torrents.remove(info_hash);
+    }
+
+    /// The torrents to announce now.
+    pub fn due(&self) -> Vec<[u8; 20]> {
+        self.torrents
+            .iter()
+            .filter(|(_, announced)| match announced.at {
+                None => true,
+                Some(at) if announced.accepted.is_empty() => {
+                    self.clock.elapsed(at) >= ANNOUNCE_RETRY
+                }
+                Some(at) => self.clock.elapsed(at) >= TOKEN_LIFETIME,
+            })
+            .map(|(&info_hash, _)| info_hash)
+            .collect()
+    }
+
+    /// Record that `info_hash` was just announced, and the nodes that `accepted` it.
+    pub fn announced(&mut self, info_hash: [u8; 20], accepted: Vec<Node>) {
+        if let Some(announced) = self.torrents.get_mut(&info_hash) {
+            *announced = Announced {
+                at: Some(self.clock.now()),
+                accepted,
+            };
+        }
+    }
+
+    /// The nodes that accepted the last announce of `info_hash`.
+    pub fn accepted(&self, info_hash: &[u8; 20]) -> &[Node] {
+        self.torrents
+            .get(info_hash)
+            .map_or(&[], |announced| &announced.accepted)
+    }
+
+    /// Take note of our address as seen by a node, peer or tracker. Returns whether it changed,
+    /// in which case every torrent is due again.
+    pub fn observed_ip(&mut self, ip: IpAddr) -> bool {
+        let changed = self.external_ip.is_some_and(|known| known != ip);
+        self.external_ip = Some(ip);
+        if changed {
+            for announced in self.torrents.values_mut() {
+                announced.at = None;
+            }
+        }
+        changed
+    }
+}
+
+/// Resolve `nodes` given as `host:port` to their IPv4 addresses.
+pub fn resolve(nodes: &[String]) -> Vec<SocketAddrV4> {
+    use std::net::ToSocketAddrs;
+
+    nodes
+        .iter()
+        .filter_map(|node| node.to_socket_addrs().ok())
+        .flatten()
+        .filter_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(addr),
+            SocketAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+impl Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::thread;
+
+    /// A node answering every query with `nodes` and, for `get_peers`, with `peers`.
+    fn fake_node(id: NodeId, nodes: Vec<Node>, peers: Vec<SocketAddrV4>) -> SocketAddrV4 {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let SocketAddr::V4(addr) = socket.local_addr().unwrap() else {
+            unreachable!("bound to an IPv4 address")
+        };
+        socket
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1500];
+            while let Ok((length, from)) = socket.recv_from(&mut buf) {
+                let Ok(Value::Dict(query)) = serde_bencode::from_bytes(&buf[..length]) else {
+                    continue;
+                };
+                let mut fields = HashMap::from([
+                    (b"id".to_vec(), Value::Bytes(id.0.to_vec())),
+                    (b"nodes".to_vec(), Value::Bytes(Node::to_compact(&nodes))),
+                ]);
+                if query[&b"q"[..]] == Value::Bytes(b"get_peers".to_vec()) {
+                    let values = peers
+                        .iter()
+                        .map(|peer| {
+                            let mut compact = peer.ip().octets().to_vec();
+                            compact.extend(peer.port().to_be_bytes());
+                            Value::Bytes(compact)
+                        })
+                        .collect();
+                    fields.insert(b"values".to_vec(), Value::List(values));
+                    fields.insert(b"token".to_vec(), Value::Bytes(b"secret".to_vec()));
+                }
+                let SocketAddr::V4(from) = from else { continue };
+                let mut ip = from.ip().octets().to_vec();
+                ip.extend(from.port().to_be_bytes());
+                let response = Value::Dict(HashMap::from([
+                    (b"t".to_vec(), query[&b"t"[..]].clone()),
+                    (b"y".to_vec(), Value::Bytes(b"r".to_vec())),
+                    (b"r".to_vec(), Value::Dict(fields)),
+                    (b"ip".to_vec(), Value::Bytes(ip)),
+                ]));
+                let _ = socket.send_to(&serde_bencode::to_bytes(&response).unwrap(), from);
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn find_peers_through_a_router() {
+        let peer = "10.0.0.1:6881".parse().unwrap();
+        let close_id = NodeId([0xab; 20]);
+        let close = fake_node(close_id, Vec::new(), vec![peer]);
+        let router = fake_node(
+            NodeId([1; 20]),
+            vec![Node {
+                id: close_id,
+                addr: close,
+            }],
+            Vec::new(),
+        );
+
+        let mut dht = Dht::bind(0).unwrap();
+        dht.bootstrap(&[router]).unwrap();
+        assert_eq!(dht.table().len(), 2, "the router and the node it returned");
+
+        let lookup = dht.get_peers([0xab; 20]);
+        assert_eq!(lookup.peers, [peer]);
+        assert_eq!(lookup.tokens[0].0.id, close_id, "closest first");
+        assert_eq!(lookup.tokens[0].1, b"secret");
+        assert_eq!(dht.announce_peer([0xab; 20], 6881, &lookup).len(), 2);
+        assert_eq!(dht.external_ip(), Some(Ipv4Addr::LOCALHOST));
+
+        let mut table = RoutingTable::new(NodeId([0; 20]));
+        assert!(!table.insert(Node {
+            id: NodeId([0; 20]),
+            addr: peer
+        }));
+        for i in 0..=K as u8 {
+            let mut id = [0x80; 20];
+            id[19] = i;
+            let inserted = table.insert(Node {
+                id: NodeId(id),
+                addr: peer,
+            });
+            assert_eq!(inserted, (i as usize) < K, "the bucket holds {K} nodes");
+        }
+    }
+
+    #[test]
+    fn refresh_announces() {
+        let clock = MockClock::new();
+        let mut refresh = AnnounceRefresh::new(clock.clone());
+        let (a, b) = ([0xaa; 20], [0xbb; 20]);
+        refresh.add(a);
+        assert_eq!(refresh.due(), [a], "announced right away");
+
+        let node = Node {
+            id: NodeId([1; 20]),
+            addr: "10.0.0.1:6881".parse().unwrap(),
+        };
+        refresh.announced(a, vec![node]);
+        refresh.add(b);
+        refresh.announced(b, Vec::new());
+        assert!(refresh.due().is_empty());
+        assert_eq!(refresh.accepted(&a), [node]);
+
+        clock.advance(ANNOUNCE_RETRY);
+        assert_eq!(refresh.due(), [b], "no node accepted it");
+        refresh.announced(b, vec![node]);
+        clock.advance(TOKEN_LIFETIME - ANNOUNCE_RETRY);
+        assert_eq!(refresh.due(), [a], "its tokens expired");
+        refresh.announced(a, vec![node]);
+
+        assert!(!refresh.observed_ip("192.0.2.1".parse().unwrap()));
+        assert!(refresh.due().is_empty(), "the first address isn't a change");
+        assert!(!refresh.observed_ip("192.0.2.1".parse().unwrap()));
+        assert!(refresh.observed_ip("192.0.2.2".parse().unwrap()));
+        let mut due = refresh.due();
+        due.sort();
+        assert_eq!(due, [a, b], "every announce has the old address");
+
+        refresh.remove(&a);
+        assert_eq!(refresh.due(), [b]);
+    }
+}
@@ -0,0 +1,251 @@
+//! Outbound connections to peers, opened directly or through a proxy.
+
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// A way of opening connections to peers, so that transports and proxies compose with the rest of
+/// the peer wire protocol instead of special-casing it.
+pub trait Dialer {
+    /// Open a connection to the peer at `addr`.
+    fn dial(&self, addr: SocketAddr) -> impl Future<Output = io::Result<TcpStream>> + Send;
+}
+
+/// Connect straight to the peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Direct;
+
+impl Dialer for Direct {
+    async fn dial(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        TcpStream::connect(addr).await
+    }
+}
+
+/// Connect through a SOCKS5 proxy (RFC 1928), optionally authenticating with a username and
+/// password (RFC 1929).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5 {
+    /// The `host:port` of the proxy.
+    pub proxy: String,
+    pub credentials: Option<(String, String)>,
+}
+
+impl Socks5 {
+    const VERSION: u8 = 5;
+    const NO_AUTHENTICATION: u8 = 0;
+    const USERNAME_PASSWORD: u8 = 2;
+    const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+    const CONNECT: u8 = 1;
+    const IPV4: u8 = 1;
+    const DOMAIN_NAME: u8 = 3;
+    const IPV6: u8 = 4;
+
+    async fn negotiate(&self, stream: &mut TcpStream, addr: SocketAddr) -> io::Result<()> {
+        let method = match self.credentials {
+            Some(_) => Self::USERNAME_PASSWORD,
+            None => Self::NO_AUTHENTICATION,
+        };
+        stream.write_all(&[Self::VERSION, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != Self::VERSION {
+            return Err(socks_error("the proxy doesn't speak SOCKS5"));
+        }
+        match (reply[1], &self.credentials) {
+            (Self::NO_AUTHENTICATION, None) => {}
+            (Self::USERNAME_PASSWORD, Some((username, password))) => {
+                let mut request = vec![1, username.len() as u8];
+                request.extend_from_slice(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request).await?;
+                stream.read_exact(&mut reply).await?;
+                if reply[1] != 0 {
+                    return Err(socks_error("the proxy rejected the credentials"));
+                }
+            }
+            (Self::NO_ACCEPTABLE_METHOD, _) => {
+                return Err(socks_error("the proxy refused every authentication method"))
+            }
+            (method, _) => {
+                return Err(socks_error(format!(
+                    "the proxy picked authentication method {method}, which wasn't offered"
+                )))
+            }
+        }
+
+        let mut request = vec![Self::VERSION, Self::CONNECT, 0];
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                request.push(Self::IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                request.push(Self::IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+        }
+        request.extend_from_slice(&addr.port().to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(socks_error(format!(
+                "the proxy couldn't connect to {addr}, reply {}",
+                reply[1]
+            )));
+        }
+        // skip the address the proxy bound, which is of no use to us
+        let address_length = match reply[3] {
+            Self::IPV4 => 4,
+            Self::IPV6 => 16,
+            Self::DOMAIN_NAME => stream.read_u8().await? as usize,
+            kind => return Err(socks_error(format!("unknown address type {kind}"))),
+        };
+        let mut bound = vec![0u8; address_length + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+}
+
+fn socks_error(message: impl Into<String>) -> io::Error {
+    io::Error::other(format!("SOCKS5: {}", message.into()))
+}
+
+impl Dialer for Socks5 {
+    async fn dial(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.proxy).await?;
+        self.negotiate(&mut stream, addr).await?;
+        Ok(stream)
+    }
+}
+
+/// The dialer picked by the user, parsed from `direct` or `socks5://[user:password@]host:port`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Dial {
+    #[default]
+    Direct,
+    Socks5(Socks5),
+}
+
+impl Dialer for Dial {
+    async fn dial(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        match self {
+            Dial::Direct => Direct.dial(addr).await,
+            Dial::Socks5(socks5) => socks5.dial(addr).await,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidProxy {
+    UnsupportedScheme(String),
+    MissingPort(String),
+    /// Usernames and passwords are limited to 255 bytes by the protocol.
+    CredentialsTooLong,
+}
+
+impl Display for InvalidProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use InvalidProxy::*;
+        match self {
+            UnsupportedScheme(proxy) => write!(
+                f,
+                "unsupported proxy {proxy:?}, expected \"direct\" or \"socks5://host:port\""
+            ),
+            MissingPort(proxy) => write!(f, "proxy {proxy:?} doesn't have a port"),
+            CredentialsTooLong => write!(f, "proxy username or password is too long"),
+        }
+    }
+}
+
+impl Error for InvalidProxy {}
+
+impl FromStr for Dial {
+    type Err = InvalidProxy;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        if spec == "direct" {
+            return Ok(Dial::Direct);
+        }
+        let Some(rest) = spec.strip_prefix("socks5://") else {
+            return Err(InvalidProxy::UnsupportedScheme(spec.to_string()));
+        };
+        let rest = rest.trim_end_matches('/');
+
+        let (credentials, proxy) = match rest.rsplit_once('@') {
+            Some((credentials, proxy)) => {
+                let (username, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(InvalidProxy::CredentialsTooLong);
+                }
+                (Some((username.to_string(), password.to_string())), proxy)
+            }
+            None => (None, rest),
+        };
+        let port = proxy.rsplit_once(':').map(|(_, port)| port.parse::<u16>());
+        let Some(Ok(_)) = port else {
+            return Err(InvalidProxy::MissingPort(spec.to_string()));
+        };
+
+        Ok(Dial::Socks5(Socks5 {
+            proxy: proxy.to_string(),
+            credentials,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::runtime;
+    use std::{io::Read, io::Write, net::TcpListener, thread};
+
+    #[test]
+    fn socks5_connect() {
+        assert_eq!("direct".parse(), Ok(Dial::Direct));
+        assert!("http://proxy:8080".parse::<Dial>().is_err());
+        assert!("socks5://proxy".parse::<Dial>().is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 1, 2]);
+            stream.write_all(&[5, 2]).unwrap();
+            let mut auth = [0u8; 11];
+            stream.read_exact(&mut auth).unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            stream.write_all(&[1, 0]).unwrap();
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(request, [5, 1, 0, 1, 10, 0, 0, 1, 0x1a, 0xe1]);
+            stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+            stream.write_all(b"hello").unwrap();
+        });
+
+        let dial: Dial = format!("socks5://user:pass@{proxy}").parse().unwrap();
+        let runtime = runtime();
+        let hello = runtime.block_on(async {
+            let mut stream = dial.dial("10.0.0.1:6881".parse().unwrap()).await.unwrap();
+            let mut hello = [0u8; 5];
+            stream.read_exact(&mut hello).await.unwrap();
+            hello
+        });
+        assert_eq!(&hello, b"hello");
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,838 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt::{self, Display},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bitfield::Bitfield,
+    clock::Clock,
+    history::AssignmentHistory,
+    priority::Priority,
+    stats::{Quota, TransferStats},
+    torrent::Torrent,
+};
+
+/// What a peer should do next, as decided by [`Scheduler::take`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assignment {
+    Piece(usize),
+    /// Nothing for the peer right now, but pieces in flight elsewhere may still be handed back.
+    Wait,
+    Done,
+}
+
+/// How many times a piece may fail before it's given up on, and how long to wait before retrying
+/// it, doubling with every failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub budget: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            budget: 5,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before retrying a piece that failed `failures` times.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        self.backoff
+            .saturating_mul(1 << failures.saturating_sub(1).min(16))
+            .min(self.max_backoff)
+    }
+}
+
+/// What became of a piece handed back by [`Scheduler::requeue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Requeued {
+    /// The piece is retried once `after` has passed.
+    Retry { after: Duration },
+    /// The piece exhausted its retry budget and won't be handed out again.
+    Failed,
+}
+
+/// Some pieces exhausted their retry budget, everything else was downloaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PiecesFailed {
+    pub pieces: Vec<usize>,
+    pub wanted: usize,
+}
+
+impl Display for PiecesFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} pieces failed after exhausting their retries: {:?}",
+            self.pieces.len(),
+            self.wanted,
+            self.pieces
+        )
+    }
+}
+
+impl Error for PiecesFailed {}
+
+/// The user interrupted the download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted;
+
+impl Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "interrupted")
+    }
+}
+
+impl Error for Interrupted {}
+
+/// Notices a download making no progress, no peer having unchoked us or delivered a piece for
+/// longer than a timeout.
+#[derive(Debug, Clone)]
+pub struct Starvation<C: Clock> {
+    clock: C,
+    timeout: Option<Duration>,
+    last_progress: Instant,
+}
+
+impl<C: Clock> Starvation<C> {
+    /// Starve after `timeout` without progress, or never when `None`.
+    pub fn new(clock: C, timeout: Option<Duration>) -> Self {
+        Self {
+            last_progress: clock.now(),
+            clock,
+            timeout,
+        }
+    }
+
+    /// Record that a peer unchoked us or delivered a piece, or that more peers were found.
+    pub fn progressed(&mut self) {
+        self.last_progress = self.clock.now();
+    }
+
+    /// How long the download went without progress.
+    pub fn stalled(&self) -> Duration {
+        self.clock.elapsed(self.last_progress)
+    }
+
+    pub fn is_starved(&self) -> bool {
+        self.timeout
+            .is_some_and(|timeout| self.stalled() >= timeout)
+    }
+}
+
+/// What [`Scheduler::snapshot`] saw.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchedulerSnapshot<P> {
+    /// The pieces waiting for a peer, in the order they're handed out.
+    pub pending: Vec<usize>,
+    /// How many of the pending pieces wait for their backoff to pass.
+    pub backing_off: usize,
+    /// The pieces every peer is downloading, including the peers that left while holding some.
+    pub in_flight: Vec<(P, Vec<usize>)>,
+    pub failed: Vec<usize>,
+}
+
+impl<P: Display> Display for SchedulerSnapshot<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pending ({} backing off), {} failed, in flight:",
+            self.pending.len(),
+            self.backing_off,
+            self.failed.len()
+        )?;
+        for (peer, pieces) in &self.in_flight {
+            write!(f, " {peer}={pieces:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Hands out the pieces of a download to the peers connected to it.
+///
+/// Of the pieces a peer has, the ones of the highest priority go first, retried pieces before the
+/// others, and then the rarest among the connected peers, so that pieces only few peers have get
+/// downloaded while those peers are still around.
+///
+/// A piece handed back after failing with a peer goes to another peer having it, and only goes
+/// back to the same peer once its [`AssignmentHistory`] cooldown passed and no peer that failed it
+/// fewer times could download it. Every failure of a piece delays its next attempt further, until
+/// it runs out of its [`RetryPolicy::budget`].
+#[derive(Debug, Clone)]
+pub struct Scheduler<P, C: Clock> {
+    clock: C,
+    retry: RetryPolicy,
+    pending: VecDeque<usize>,
+    /// Pieces taken by a peer that are neither finished nor handed back yet, and who took them.
+    in_flight: HashMap<usize, P>,
+    sizes: Vec<u64>,
+    /// Bytes of the pieces taken and not handed back, checked against `quota` before taking more.
+    reserved: u64,
+    quota: Quota,
+    exhausted: bool,
+    /// No piece is handed out anymore, the download is shutting down.
+    stopped: bool,
+    /// The bitfields of the connected peers.
+    peers: HashMap<P, Bitfield>,
+    /// How many of the connected peers have every piece.
+    availability: Vec<usize>,
+    priorities: Vec<Priority>,
+    /// Hand pieces out in index order, for consumers that can only append.
+    sequential: bool,
+    /// Which peers failed which pieces, cooling down before they may take them again.
+    history: AssignmentHistory<P, C>,
+    /// How many times every piece failed.
+    failures: Vec<u32>,
+    /// When every piece may be retried, if it failed before.
+    not_before: Vec<Option<Instant>>,
+    /// The pieces given up on, in the order they failed.
+    failed: Vec<usize>,
+}
+
+impl<P: Eq + Hash + Clone, C: Clock + Clone> Scheduler<P, C> {
+    /// Schedule every piece of `torrent`, within `quota`.
+    pub fn new(torrent: &Torrent, quota: Quota, clock: C) -> Self {
+        let piece_count = torrent.info.pieces.0.len();
+        let retry = RetryPolicy::default();
+        Self {
+            history: AssignmentHistory::new(clock.clone())
+                .base(retry.backoff)
+                .max(retry.max_backoff),
+            clock,
+            retry,
+            pending: (0..piece_count).collect(),
+            in_flight: HashMap::new(),
+            sizes: (0..piece_count)
+                .map(|piece_index| torrent.piece_size(piece_index) as u64)
+                .collect(),
+            reserved: 0,
+            quota,
+            exhausted: false,
+            stopped: false,
+            peers: HashMap::new(),
+            availability: vec![0; piece_count],
+            priorities: vec![Priority::default(); piece_count],
+            sequential: false,
+            failures: vec![0; piece_count],
+            not_before: vec![None; piece_count],
+            failed: Vec::new(),
+        }
+    }
+
+    /// Retry failed pieces as told by `retry`, a peer cooling down on a piece it failed as long
+    /// as the piece itself backs off.
+    pub fn retry(self, retry: RetryPolicy) -> Self {
+        Self {
+            history: self.history.base(retry.backoff).max(retry.max_backoff),
+            retry,
+            ..self
+        }
+    }
+
+    /// Hand out the lowest piece a peer has first, instead of the rarest.
+    pub fn sequential(self) -> Self {
+        Self {
+            sequential: true,
+            ..self
+        }
+    }
+
+    /// Download the pieces in order of decreasing `priorities`, one for every piece, and never
+    /// those to [`Priority::Skip`].
+    pub fn prioritize(&mut self, priorities: &[Priority]) {
+        self.priorities = priorities.to_vec();
+        self.pending
+            .retain(|&piece_index| priorities[piece_index] != Priority::Skip);
+        self.pending
+            .make_contiguous()
+            .sort_by_key(|&piece_index| Reverse(priorities[piece_index]));
+    }
+
+    /// Whether a piece was held back because it would have gone over the quota.
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    /// Stop handing out pieces, letting the peers finish those they took.
+    pub fn stop(&mut self) {
+        self.stopped = true;
+    }
+
+    /// How many pieces were taken and are neither finished nor handed back.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Once no piece is pending anymore, the pieces in flight with other peers that `peer` has,
+    /// and who took them, for `peer` to download them too instead of waiting for the slowest
+    /// peers to finish.
+    pub fn endgame(&self, peer: &P) -> Vec<(usize, P)> {
+        if !self.pending.is_empty() || self.exhausted || self.stopped {
+            return Vec::new();
+        }
+        let mut pieces: Vec<(usize, P)> = self
+            .in_flight
+            .iter()
+            .filter(|&(&piece_index, owner)| owner != peer && self.has_piece(peer, piece_index))
+            .map(|(&piece_index, owner)| (piece_index, owner.clone()))
+            .collect();
+        pieces.sort_unstable_by_key(|&(piece_index, _)| piece_index);
+        pieces
+    }
+
+    /// The pieces that exhausted their retry budget, in the order they failed.
+    pub fn failed(&self) -> &[usize] {
+        &self.failed
+    }
+
+    /// The state of the queue and of every peer, for debugging.
+    pub fn snapshot(&self) -> SchedulerSnapshot<P> {
+        let now = self.clock.now();
+        let mut in_flight: Vec<(P, Vec<usize>)> = self
+            .peers
+            .keys()
+            .map(|peer| (peer.clone(), Vec::new()))
+            .collect();
+        for (&piece_index, owner) in &self.in_flight {
+            match in_flight.iter_mut().find(|(peer, _)| peer == owner) {
+                Some((_, pieces)) => pieces.push(piece_index),
+                None => in_flight.push((owner.clone(), vec![piece_index])),
+            }
+        }
+        for (_, pieces) in &mut in_flight {
+            pieces.sort_unstable();
+        }
+        SchedulerSnapshot {
+            pending: self.pending.iter().copied().collect(),
+            backing_off: self
+                .pending
+                .iter()
+                .filter(|&&piece_index| self.not_before[piece_index].is_some_and(|at| now < at))
+                .count(),
+            in_flight,
+            failed: self.failed.clone(),
+        }
+    }
+
+    /// Register `peer`, which has the pieces set in `bitfield`.
+    pub fn join(&mut self, peer: P, bitfield: Bitfield) {
+        self.leave(&peer);
+        self.count(&bitfield, true);
+        self.peers.insert(peer, bitfield);
+    }
+
+    /// Forget `peer`, the pieces that failed with it may go back to the remaining peers. Its
+    /// failures are remembered, in case it joins again.
+    pub fn leave(&mut self, peer: &P) {
+        if let Some(bitfield) = self.peers.remove(peer) {
+            self.count(&bitfield, false);
+        }
+    }
+
+    /// Record that `peer` announced having `piece_index`.
+    pub fn peer_has(&mut self, peer: &P, piece_index: usize) {
+        if piece_index >= self.availability.len() || self.has_piece(peer, piece_index) {
+            return;
+        }
+        let Some(bitfield) = self.peers.get_mut(peer) else {
+            return;
+        };
+        bitfield.set_piece(piece_index);
+        self.availability[piece_index] += 1;
+    }
+
+    /// How many of the connected peers have `piece_index`.
+    pub fn availability(&self, piece_index: usize) -> usize {
+        self.availability[piece_index]
+    }
+
+    fn count(&mut self, bitfield: &Bitfield, joined: bool) {
+        for (piece_index, available) in self.availability.iter_mut().enumerate() {
+            match (bitfield.has_piece(piece_index), joined) {
+                (false, _) => {}
+                (true, true) => *available += 1,
+                (true, false) => *available -= 1,
+            }
+        }
+    }
+
+    fn has_piece(&self, peer: &P, piece_index: usize) -> bool {
+        self.peers
+            .get(peer)
+            .is_some_and(|bitfield| bitfield.has_piece(piece_index))
+    }
+
+    /// Whether `piece_index` should be left for another peer than `peer`: `peer` failed it, and
+    /// is either still cooling down or not the peer having it that failed it the fewest times.
+    fn left_to_others(&self, peer: &P, piece_index: usize) -> bool {
+        if self.history.failures(peer, piece_index) == 0 {
+            return false;
+        }
+        let others = self
+            .peers
+            .keys()
+            .filter(|&other| other != peer && self.has_piece(other, piece_index));
+        self.history
+            .pick(piece_index, std::iter::once(peer).chain(others))
+            != Some(peer)
+    }
+
+    /// Take the next piece for `peer`, which must have joined.
+    pub fn take(&mut self, peer: &P) -> Assignment {
+        if self.exhausted || self.stopped {
+            return Assignment::Done;
+        }
+
+        let now = self.clock.now();
+        let mut held_back = false;
+        let mut best = None;
+        for (position, &piece_index) in self.pending.iter().enumerate() {
+            if !self.has_piece(peer, piece_index) {
+                continue;
+            }
+            let backing_off = self.not_before[piece_index].is_some_and(|retry| now < retry);
+            if backing_off || self.left_to_others(peer, piece_index) {
+                held_back = true;
+                continue;
+            }
+            let rank = match self.sequential {
+                true => (Reverse(Priority::default()), false, piece_index),
+                false => (
+                    Reverse(self.priorities[piece_index]),
+                    self.failures[piece_index] == 0,
+                    self.availability[piece_index],
+                ),
+            };
+            if best.is_none_or(|(_, best)| rank < best) {
+                best = Some((position, rank));
+            }
+        }
+        let Some((position, _)) = best else {
+            return match (self.in_flight.len(), held_back) {
+                (0, false) => Assignment::Done,
+                _ => Assignment::Wait,
+            };
+        };
+
+        let piece_index = self.pending[position];
+        let size = self.sizes[piece_index];
+        let reserved = TransferStats {
+            downloaded: self.reserved,
+            ..TransferStats::default()
+        };
+        if self.quota.check_download(&reserved, size).is_err() {
+            self.exhausted = true;
+            return Assignment::Done;
+        }
+
+        self.pending.remove(position);
+        self.in_flight.insert(piece_index, peer.clone());
+        self.reserved += size;
+        Assignment::Piece(piece_index)
+    }
+
+    /// Mark `piece_index` as downloaded already, so that it's never handed out.
+    pub fn have(&mut self, piece_index: usize) {
+        self.pending.retain(|&pending| pending != piece_index);
+    }
+
+    /// Mark `piece_index`, taken before, as downloaded, even if it was handed back since, e.g. by
+    /// a peer that failed it while another peer downloaded it too during endgame.
+    pub fn finish(&mut self, piece_index: usize) {
+        match self.in_flight.remove(&piece_index) {
+            Some(peer) => self.history.succeeded(peer, piece_index),
+            None => self.have(piece_index),
+        }
+    }
+
+    /// Hand `piece_index` back after it failed with `peer`, to be downloaded again first once its
+    /// backoff passed, unless it's out of retries.
+    ///
+    /// A piece handed back already, by another peer downloading it too during endgame, only
+    /// counts against `peer`.
+    pub fn requeue(&mut self, peer: P, piece_index: usize) -> Requeued {
+        if self.in_flight.remove(&piece_index).is_none() {
+            self.history.failed(peer, piece_index);
+            return match self.failed.contains(&piece_index) {
+                true => Requeued::Failed,
+                false => Requeued::Retry {
+                    after: self.not_before[piece_index].map_or(Duration::ZERO, |at| {
+                        at.saturating_duration_since(self.clock.now())
+                    }),
+                },
+            };
+        }
+        self.reserved -= self.sizes[piece_index];
+        self.failures[piece_index] += 1;
+        if self.failures[piece_index] > self.retry.budget {
+            self.failed.push(piece_index);
+            return Requeued::Failed;
+        }
+
+        let after = self.retry.backoff(self.failures[piece_index]);
+        self.not_before[piece_index] = Some(self.clock.now() + after);
+        self.pending.push_front(piece_index);
+        self.history.failed(peer, piece_index);
+        Requeued::Retry { after }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::MockClock, random::Xorshift, testing::single_file};
+    use std::collections::HashSet;
+
+    fn torrent(pieces: usize) -> Torrent {
+        single_file(1 << 14, &vec![0; pieces << 14])
+    }
+
+    #[test]
+    fn failed_pieces_go_to_other_peers() {
+        let mut scheduler =
+            Scheduler::new(&torrent(2), Quota::default(), MockClock::new()).retry(RetryPolicy {
+                backoff: Duration::ZERO,
+                ..RetryPolicy::default()
+            });
+        scheduler.join("a", Bitfield::from(vec![0b1100_0000]));
+        scheduler.join("b", Bitfield::from(vec![0b1100_0000]));
+
+        assert_eq!(scheduler.take(&"a"), Assignment::Piece(0));
+        scheduler.requeue("a", 0);
+        assert_eq!(scheduler.take(&"a"), Assignment::Piece(1), "0 is left to b");
+        assert_eq!(scheduler.take(&"b"), Assignment::Piece(0));
+        scheduler.requeue("b", 0);
+        scheduler.finish(1);
+
+        assert_eq!(
+            scheduler.take(&"a"),
+            Assignment::Piece(0),
+            "b failed it too"
+        );
+        scheduler.requeue("a", 0);
+        scheduler.leave(&"b");
+        assert_eq!(scheduler.take(&"a"), Assignment::Piece(0), "b left");
+        scheduler.finish(0);
+        assert_eq!(scheduler.take(&"a"), Assignment::Done);
+    }
+
+    #[test]
+    fn failed_pieces_go_to_the_peer_failing_them_least() {
+        let clock = MockClock::new();
+        let mut scheduler =
+            Scheduler::new(&torrent(1), Quota::default(), clock.clone()).retry(RetryPolicy {
+                backoff: Duration::from_secs(1),
+                ..RetryPolicy::default()
+            });
+        scheduler.join("a", Bitfield::from(vec![0b1000_0000]));
+        scheduler.join("b", Bitfield::from(vec![0b1000_0000]));
+
+        for peer in ["a", "b", "a"] {
+            assert_eq!(scheduler.take(&peer), Assignment::Piece(0));
+            scheduler.requeue(peer, 0);
+            clock.advance(Duration::from_secs(8));
+        }
+        assert_eq!(
+            scheduler.take(&"a"),
+            Assignment::Wait,
+            "b failed it once only"
+        );
+        assert_eq!(scheduler.take(&"b"), Assignment::Piece(0));
+        scheduler.finish(0);
+        assert_eq!(scheduler.history.failures(&"b", 0), 0, "b delivered it");
+    }
+
+    #[test]
+    fn priorities_order_pieces() {
+        let mut scheduler = Scheduler::new(&torrent(4), Quota::default(), MockClock::new());
+        scheduler.prioritize(&[
+            Priority::Low,
+            Priority::Skip,
+            Priority::High,
+            Priority::Normal,
+        ]);
+        scheduler.join((), Bitfield::from(vec![0b1111_0000]));
+
+        for piece_index in [2, 3, 0] {
+            assert_eq!(scheduler.take(&()), Assignment::Piece(piece_index));
+            scheduler.finish(piece_index);
+        }
+        assert_eq!(scheduler.take(&()), Assignment::Done);
+    }
+
+    #[test]
+    fn rarest_pieces_first() {
+        let mut scheduler = Scheduler::new(&torrent(4), Quota::default(), MockClock::new());
+        scheduler.prioritize(&[
+            Priority::Normal,
+            Priority::Normal,
+            Priority::Normal,
+            Priority::High,
+        ]);
+        scheduler.join(0, Bitfield::from(vec![0b1111_0000]));
+        scheduler.join(1, Bitfield::from(vec![0b1010_0000]));
+        scheduler.join(2, Bitfield::from(vec![0b1000_0000]));
+        scheduler.peer_has(&2, 2);
+        scheduler.peer_has(&2, 2);
+        assert_eq!(
+            (0..4)
+                .map(|index| scheduler.availability(index))
+                .collect::<Vec<_>>(),
+            [3, 1, 3, 1]
+        );
+
+        assert_eq!(
+            scheduler.take(&0),
+            Assignment::Piece(3),
+            "priorities come first"
+        );
+        assert_eq!(scheduler.take(&0), Assignment::Piece(1), "the rarest");
+        scheduler.leave(&1);
+        assert_eq!(scheduler.availability(2), 2);
+        scheduler.peer_has(&1, 0);
+        assert_eq!(
+            scheduler.availability(0),
+            2,
+            "a peer that left is forgotten"
+        );
+        assert_eq!(scheduler.take(&0), Assignment::Piece(0), "ties go in order");
+
+        let mut scheduler =
+            Scheduler::new(&torrent(3), Quota::default(), MockClock::new()).sequential();
+        scheduler.join(0, Bitfield::from(vec![0b1110_0000]));
+        scheduler.join(1, Bitfield::from(vec![0b1000_0000]));
+        assert_eq!(
+            scheduler.take(&0),
+            Assignment::Piece(0),
+            "even though it's common"
+        );
+    }
+
+    #[test]
+    fn quota_holds_pieces_back() {
+        let mut scheduler = Scheduler::new(
+            &torrent(3),
+            Quota {
+                download: Some(2 << 14),
+            },
+            MockClock::new(),
+        );
+        scheduler.join(0, Bitfield::from(vec![0b1110_0000]));
+        scheduler.join(1, Bitfield::from(vec![0b0000_0000]));
+
+        assert_eq!(scheduler.take(&1), Assignment::Done);
+        assert_eq!(scheduler.take(&0), Assignment::Piece(0));
+        assert_eq!(scheduler.take(&0), Assignment::Piece(1));
+        assert_eq!(scheduler.take(&1), Assignment::Wait);
+        assert_eq!(scheduler.take(&0), Assignment::Done);
+        assert!(scheduler.is_exhausted());
+    }
+
+    #[test]
+    fn stopping_lets_pieces_in_flight_finish() {
+        let mut scheduler = Scheduler::new(&torrent(3), Quota::default(), MockClock::new());
+        scheduler.join(0, Bitfield::from(vec![0b1110_0000]));
+        scheduler.join(1, Bitfield::from(vec![0b1110_0000]));
+
+        assert_eq!(scheduler.take(&0), Assignment::Piece(0));
+        scheduler.stop();
+        assert_eq!(scheduler.take(&1), Assignment::Done);
+        assert_eq!(scheduler.in_flight(), 1);
+        scheduler.finish(0);
+        assert_eq!(scheduler.in_flight(), 0);
+        assert_eq!(scheduler.take(&0), Assignment::Done);
+        assert!(!scheduler.is_exhausted(), "stopping isn't the quota");
+    }
+
+    #[test]
+    fn endgame_offers_pieces_in_flight_elsewhere() {
+        let mut scheduler =
+            Scheduler::new(&torrent(3), Quota::default(), MockClock::new()).retry(RetryPolicy {
+                backoff: Duration::ZERO,
+                ..RetryPolicy::default()
+            });
+        scheduler.join("a", Bitfield::from(vec![0b1110_0000]));
+        scheduler.join("b", Bitfield::from(vec![0b1010_0000]));
+
+        assert_eq!(scheduler.take(&"a"), Assignment::Piece(1));
+        assert!(
+            scheduler.endgame(&"b").is_empty(),
+            "pieces are still pending"
+        );
+        assert_eq!(scheduler.take(&"b"), Assignment::Piece(0));
+        assert_eq!(scheduler.take(&"a"), Assignment::Piece(2));
+
+        assert_eq!(scheduler.take(&"b"), Assignment::Wait);
+        assert_eq!(scheduler.endgame(&"b"), [(2, "a")], "b doesn't have 1");
+        assert_eq!(scheduler.endgame(&"a"), [(0, "b")]);
+        scheduler.finish(2);
+        assert!(scheduler.endgame(&"b").is_empty());
+
+        // b fails 0 while a downloads it too, and a's copy turns out corrupt
+        scheduler.requeue("b", 0);
+        assert_eq!(
+            scheduler.requeue("a", 0),
+            Requeued::Retry {
+                after: Duration::ZERO
+            }
+        );
+        assert_eq!(scheduler.snapshot().pending, [0], "handed back once");
+        assert_eq!(scheduler.take(&"a"), Assignment::Piece(0));
+        scheduler.requeue("a", 0);
+        // b's copy is verified in the meantime
+        scheduler.finish(0);
+        assert!(scheduler.snapshot().pending.is_empty());
+
+        scheduler.stop();
+        assert!(scheduler.endgame(&"a").is_empty());
+    }
+
+    #[test]
+    fn starving_without_progress() {
+        let clock = MockClock::new();
+        let mut starvation = Starvation::new(clock.clone(), Some(Duration::from_secs(10)));
+        clock.advance(Duration::from_secs(9));
+        assert!(!starvation.is_starved());
+        clock.advance(Duration::from_secs(1));
+        assert!(starvation.is_starved());
+        assert_eq!(starvation.stalled(), Duration::from_secs(10));
+
+        starvation.progressed();
+        assert_eq!(starvation.stalled(), Duration::ZERO);
+        assert!(!starvation.is_starved());
+
+        let never = Starvation::new(clock.clone(), None);
+        clock.advance(Duration::from_secs(3600));
+        assert!(!never.is_starved());
+    }
+
+    #[test]
+    fn failing_pieces_back_off_until_out_of_retries() {
+        let clock = MockClock::new();
+        let retry = RetryPolicy {
+            budget: 2,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        };
+        let mut scheduler =
+            Scheduler::new(&torrent(2), Quota::default(), clock.clone()).retry(retry);
+        scheduler.join((), Bitfield::from(vec![0b1100_0000]));
+
+        assert_eq!(scheduler.take(&()), Assignment::Piece(0));
+        assert_eq!(
+            scheduler.requeue((), 0),
+            Requeued::Retry {
+                after: Duration::from_secs(1)
+            }
+        );
+        assert_eq!(scheduler.take(&()), Assignment::Piece(1), "0 backs off");
+        scheduler.finish(1);
+        assert_eq!(scheduler.take(&()), Assignment::Wait);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(scheduler.take(&()), Assignment::Piece(0));
+        assert_eq!(
+            scheduler.requeue((), 0),
+            Requeued::Retry {
+                after: Duration::from_secs(2)
+            }
+        );
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(scheduler.take(&()), Assignment::Piece(0));
+        assert_eq!(scheduler.requeue((), 0), Requeued::Failed);
+        assert_eq!(scheduler.failed(), [0]);
+        assert_eq!(scheduler.take(&()), Assignment::Done);
+    }
+
+    /// Drive schedulers through random sequences of peers taking, finishing, failing and leaving,
+    /// checking after every step that no piece is handed out twice and that every peer holds at
+    /// most the one piece it's working on.
+    #[test]
+    fn random_schedules_hand_every_piece_out_once() {
+        for seed in 1..=200 {
+            let mut rng = Xorshift::new(seed);
+            let piece_count = 1 + rng.below(12) as usize;
+            let peer_count = 1 + rng.below(4) as usize;
+            let clock = MockClock::new();
+            let mut scheduler =
+                Scheduler::new(&torrent(piece_count), Quota::default(), clock.clone());
+            // peer 0 has every piece, so that every piece can be downloaded
+            let bitfield = |rng: &mut Xorshift, peer: usize| -> Bitfield {
+                let mut bitfield = Bitfield::new(piece_count);
+                for piece_index in 0..piece_count {
+                    if peer == 0 || rng.below(2) == 0 {
+                        bitfield.set_piece(piece_index);
+                    }
+                }
+                bitfield
+            };
+            for peer in 0..peer_count {
+                let bitfield = bitfield(&mut rng, peer);
+                scheduler.join(peer, bitfield);
+            }
+
+            let mut holding = vec![None; peer_count];
+            let mut done = HashSet::new();
+            for _ in 0..10_000 {
+                let peer = rng.below(peer_count as u64) as usize;
+                match holding[peer] {
+                    None => match scheduler.take(&peer) {
+                        Assignment::Piece(piece_index) => {
+                            assert!(!done.contains(&piece_index), "seed {seed}");
+                            holding[peer] = Some(piece_index);
+                        }
+                        Assignment::Wait => clock.advance(Duration::from_secs(1)),
+                        Assignment::Done => {}
+                    },
+                    Some(piece_index) => {
+                        holding[peer] = None;
+                        match rng.below(10) {
+                            0..=6 => {
+                                scheduler.finish(piece_index);
+                                done.insert(piece_index);
+                            }
+                            7..=8 => {
+                                scheduler.requeue(peer, piece_index);
+                            }
+                            _ => {
+                                scheduler.requeue(peer, piece_index);
+                                scheduler.leave(&peer);
+                                let bitfield = bitfield(&mut rng, peer);
+                                scheduler.join(peer, bitfield);
+                            }
+                        }
+                    }
+                }
+
+                let snapshot = scheduler.snapshot();
+                for (peer, pieces) in &snapshot.in_flight {
+                    assert_eq!(pieces, &Vec::from_iter(holding[*peer]), "seed {seed}");
+                }
+                let mut queued: Vec<usize> = snapshot.pending.clone();
+                queued.extend(holding.iter().flatten());
+                queued.extend(&done);
+                queued.extend(&snapshot.failed);
+                queued.sort_unstable();
+                assert_eq!(queued, Vec::from_iter(0..piece_count), "seed {seed}");
+                if snapshot.pending.is_empty() && holding.iter().all(Option::is_none) {
+                    break;
+                }
+            }
+            assert_eq!(
+                done.len() + scheduler.failed().len(),
+                piece_count,
+                "seed {seed}: every piece was eventually assigned"
+            );
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    hash::Hash,
+};
+
+use crate::{peer::PeerMessage, torrent::Torrent};
+
+/// A block of a piece, as named by a `Request`/`Piece`/`Cancel` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Block {
+    pub piece_index: u32,
+    pub offset: u32,
+    pub length: u32,
+}
+
+impl Block {
+    pub fn request(self) -> PeerMessage {
+        PeerMessage::Request {
+            piece_index: self.piece_index,
+            offset: self.offset,
+            length: self.length,
+        }
+    }
+
+    pub fn cancel(self) -> PeerMessage {
+        PeerMessage::Cancel {
+            piece_index: self.piece_index,
+            offset: self.offset,
+            length: self.length,
+        }
+    }
+}
+
+/// Bookkeeping of the blocks requested from several peers at once during endgame.
+///
+/// When the first copy of a block arrives every other peer it was requested from must be sent a
+/// `Cancel`, so that no peer is left with a dangling request.
+#[derive(Debug, Clone)]
+pub struct EndgameRequests<P> {
+    outstanding: HashMap<Block, Vec<P>>,
+}
+
+impl<P> Default for EndgameRequests<P> {
+    fn default() -> Self {
+        Self {
+            outstanding: HashMap::new(),
+        }
+    }
+}
+
+impl<P: Eq + Hash + Clone> EndgameRequests<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that `block` was requested from `peer`, returning `false` if it already was.
+    pub fn requested(&mut self, block: Block, peer: P) -> bool {
+        let peers = self.outstanding.entry(block).or_default();
+        if peers.contains(&peer) {
+            return false;
+        }
+        peers.push(peer);
+        true
+    }
+
+    /// Record the arrival of `block` from `peer`.
+    ///
+    /// Returns the peers that must now be sent a `Cancel` for it, or `None` if the block wasn't
+    /// outstanding anymore, i.e. it's a redundant copy that should be discarded.
+    pub fn received(&mut self, block: Block, peer: &P) -> Option<Vec<P>> {
+        match self.outstanding.entry(block) {
+            Entry::Occupied(entry) => {
+                let mut peers = entry.remove();
+                peers.retain(|other| other != peer);
+                Some(peers)
+            }
+            Entry::Vacant(_) => None,
+        }
+    }
+
+    /// Forget every request made to `peer`, e.g. after it disconnected, returning the blocks that
+    /// no other peer is working on anymore.
+    pub fn peer_gone(&mut self, peer: &P) -> Vec<Block> {
+        let mut orphaned = Vec::new();
+        self.outstanding.retain(|block, peers| {
+            peers.retain(|other| other != peer);
+            if peers.is_empty() {
+                orphaned.push(*block);
+                return false;
+            }
+            true
+        });
+        orphaned.sort();
+        orphaned
+    }
+
+    /// The peers `block` is still outstanding at.
+    pub fn peers(&self, block: Block) -> &[P] {
+        self.outstanding.get(&block).map_or(&[], Vec::as_slice)
+    }
+
+    /// The blocks still outstanding at `peer`.
+    pub fn pending_for<'a>(&'a self, peer: &'a P) -> impl Iterator<Item = Block> + 'a {
+        self.outstanding
+            .iter()
+            .filter(move |(_, peers)| peers.contains(peer))
+            .map(|(block, _)| *block)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+}
+
+/// The end of a download, when every piece left is in flight: peers done with their own pieces
+/// download those of the slower peers too, and whichever copy arrives first wins.
+#[derive(Debug, Clone)]
+pub struct Endgame<P> {
+    block_size: u32,
+    sizes: Vec<u32>,
+    requests: EndgameRequests<P>,
+    /// The pieces delivered, until the verifier is done with them, and then those written.
+    downloaded: HashSet<usize>,
+    /// The peers to give up on a piece another peer delivered first.
+    cancelled: HashSet<(P, usize)>,
+}
+
+impl<P: Eq + Hash + Clone> Endgame<P> {
+    /// The endgame of `torrent`, downloaded in blocks of `block_size`.
+    pub fn new(torrent: &Torrent, block_size: u32) -> Self {
+        Self {
+            block_size,
+            sizes: (0..torrent.info.pieces.0.len())
+                .map(|piece_index| torrent.piece_size(piece_index) as u32)
+                .collect(),
+            requests: EndgameRequests::new(),
+            downloaded: HashSet::new(),
+            cancelled: HashSet::new(),
+        }
+    }
+
+    fn blocks(&self, piece_index: usize) -> impl Iterator<Item = Block> {
+        let (block_size, size) = (self.block_size, self.sizes[piece_index]);
+        (0..size)
+            .step_by(block_size as usize)
+            .map(move |offset| Block {
+                piece_index: piece_index as u32,
+                offset,
+                length: block_size.min(size - offset),
+            })
+    }
+
+    /// Pick one of the pieces `in_flight` with other peers, and who took them, for `peer` to
+    /// download too: of those not delivered yet and not requested from `peer` already, the one
+    /// requested from the fewest peers.
+    pub fn duplicate(&mut self, peer: &P, in_flight: &[(usize, P)]) -> Option<usize> {
+        let (piece_index, owner) = in_flight
+            .iter()
+            .filter(|(piece_index, _)| !self.downloaded.contains(piece_index))
+            .filter_map(|(piece_index, owner)| {
+                let first = self.blocks(*piece_index).next()?;
+                let peers = self.requests.peers(first);
+                (!peers.contains(peer)).then_some((peers.len(), *piece_index, owner))
+            })
+            .min_by_key(|&(peers, piece_index, _)| (peers, piece_index))
+            .map(|(_, piece_index, owner)| (piece_index, owner.clone()))?;
+        for block in self.blocks(piece_index).collect::<Vec<_>>() {
+            self.requests.requested(block, owner.clone());
+            self.requests.requested(block, peer.clone());
+        }
+        Some(piece_index)
+    }
+
+    /// Whether `peer` should give up on `piece_index`, which another peer delivered first.
+    pub fn is_cancelled(&mut self, peer: &P, piece_index: usize) -> bool {
+        self.cancelled.remove(&(peer.clone(), piece_index))
+    }
+
+    /// Record that `peer` delivered `piece_index`, telling the other peers downloading it to give
+    /// up. Returns `false` if it's a redundant copy, which should be discarded.
+    pub fn delivered(&mut self, peer: &P, piece_index: usize) -> bool {
+        if self.is_cancelled(peer, piece_index) || !self.downloaded.insert(piece_index) {
+            return false;
+        }
+        for block in self.blocks(piece_index).collect::<Vec<_>>() {
+            for other in self.requests.received(block, peer).into_iter().flatten() {
+                self.cancelled.insert((other, piece_index));
+            }
+        }
+        true
+    }
+
+    /// Record that the verifier is done with `piece_index`, which is downloaded again unless it
+    /// was `written`.
+    pub fn verified(&mut self, piece_index: usize, written: bool) {
+        if !written {
+            self.downloaded.remove(&piece_index);
+        }
+    }
+
+    /// Forget every request made to `peer`, which disconnected. The scheduler hands back the
+    /// piece it took, those it only downloaded too are left to the peers having taken them.
+    pub fn peer_gone(&mut self, peer: &P) {
+        self.requests.peer_gone(peer);
+        self.cancelled.retain(|(other, _)| other != peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::single_file;
+
+    #[test]
+    fn first_copy_cancels_everyone_else() {
+        let block = |offset| Block {
+            piece_index: 3,
+            offset,
+            length: 1 << 14,
+        };
+        let mut requests = EndgameRequests::new();
+
+        for peer in ["a", "b", "c"] {
+            assert!(requests.requested(block(0), peer));
+        }
+        assert!(!requests.requested(block(0), "b"));
+        assert!(requests.requested(block(1 << 14), "a"));
+        assert!(requests.requested(block(1 << 14), "c"));
+        assert_eq!(requests.peers(block(1 << 14)), ["a", "c"]);
+
+        assert_eq!(requests.received(block(0), &"b"), Some(vec!["a", "c"]));
+        assert_eq!(requests.received(block(0), &"a"), None, "redundant copy");
+        assert_eq!(requests.received(block(1 << 14), &"c"), Some(vec!["a"]));
+        assert!(requests.peers(block(1 << 14)).is_empty());
+
+        for peer in ["a", "b", "c"] {
+            assert_eq!(requests.pending_for(&peer).count(), 0);
+        }
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn disconnect_orphans_blocks_nobody_else_has() {
+        let mut requests = EndgameRequests::new();
+        let shared = Block {
+            piece_index: 0,
+            offset: 0,
+            length: 10,
+        };
+        let exclusive = Block {
+            offset: 10,
+            ..shared
+        };
+
+        requests.requested(shared, 1);
+        requests.requested(shared, 2);
+        requests.requested(exclusive, 1);
+
+        assert_eq!(requests.peer_gone(&1), vec![exclusive]);
+        assert_eq!(requests.received(shared, &2), Some(vec![]));
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn endgame_downloads_pieces_twice() {
+        // 2 pieces of 2 blocks, then one of a single block
+        let torrent = single_file(8, &[0; 20]);
+        let mut endgame = Endgame::new(&torrent, 4);
+        let in_flight = [(1, "a"), (2, "b")];
+
+        assert_eq!(endgame.duplicate(&"c", &in_flight), Some(1));
+        assert_eq!(endgame.duplicate(&"c", &in_flight), Some(2));
+        assert_eq!(
+            endgame.duplicate(&"c", &in_flight),
+            None,
+            "c has both already"
+        );
+        assert_eq!(endgame.duplicate(&"d", &in_flight), Some(1));
+        assert_eq!(
+            endgame.requests.pending_for(&"b").count(),
+            1,
+            "the last piece is a single block"
+        );
+        assert_eq!(endgame.requests.pending_for(&"a").count(), 2);
+
+        // the first copy wins, the others give up
+        assert!(endgame.delivered(&"c", 1));
+        assert!(endgame.is_cancelled(&"a", 1));
+        assert!(!endgame.is_cancelled(&"a", 1), "once");
+        assert!(!endgame.delivered(&"d", 1), "d was cancelled");
+        assert_eq!(
+            endgame.duplicate(&"e", &in_flight),
+            Some(2),
+            "1 is verifying"
+        );
+
+        // a corrupt copy is downloaded again, a written one never
+        endgame.verified(1, false);
+        assert!(endgame.delivered(&"a", 1));
+        endgame.verified(1, true);
+        assert!(!endgame.delivered(&"d", 1));
+        assert_eq!(endgame.duplicate(&"d", &in_flight), Some(2));
+
+        endgame.peer_gone(&"b");
+        assert!(endgame.delivered(&"e", 2));
+        assert!(endgame.is_cancelled(&"c", 2));
+        assert!(!endgame.is_cancelled(&"b", 2));
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    fmt::{self, Display},
+    io,
+    net::SocketAddr,
+    time::Duration,
+};
+
+use crate::{
+    display::Span,
+    peer::{ConversionError, HashMismatch, PeerMessageError},
+    serve::InvalidRequest,
+    stats::QuotaExhausted,
+    tracker::InvalidResponse,
+    udp_tracker::UdpTrackerError,
+};
+
+/// The category of a failure, shared by everything consuming [`SessionEvent`]s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The tracker couldn't be reached or returned an unusable response.
+    Tracker,
+    /// A peer connection couldn't be established or was lost.
+    Connection,
+    /// A peer didn't follow the wire protocol.
+    PeerViolation,
+    /// A downloaded piece didn't match its hash.
+    HashMismatch,
+    /// Reading or writing local files failed.
+    Disk,
+}
+
+impl ErrorKind {
+    /// Find the category of `err` by looking for known error types along its chain of causes.
+    pub fn classify(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if cause.is::<HashMismatch>() {
+                return Self::HashMismatch;
+            }
+            if cause.is::<ConversionError>()
+                || cause.is::<PeerMessageError>()
+                || cause.is::<InvalidRequest>()
+            {
+                return Self::PeerViolation;
+            }
+            if cause.is::<reqwest::Error>()
+                || cause.is::<serde_bencode::Error>()
+                || cause.is::<InvalidResponse>()
+                || cause.is::<UdpTrackerError>()
+            {
+                return Self::Tracker;
+            }
+            if let Some(err) = cause.downcast_ref::<io::Error>() {
+                use io::ErrorKind::*;
+                return match err.kind() {
+                    ConnectionRefused | ConnectionReset | ConnectionAborted | NotConnected
+                    | BrokenPipe | TimedOut | UnexpectedEof | WouldBlock => Self::Connection,
+                    _ => Self::Disk,
+                };
+            }
+        }
+
+        Self::PeerViolation
+    }
+
+    /// Whether `err` was caused by a peer going silent for too long.
+    pub fn is_timeout(err: &anyhow::Error) -> bool {
+        err.chain()
+            .filter_map(|cause| cause.downcast_ref::<io::Error>())
+            .any(|err| {
+                matches!(
+                    err.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                )
+            })
+    }
+
+    /// Whether trying the same operation again (possibly with another peer) may succeed.
+    pub fn is_retryable(self) -> bool {
+        use ErrorKind::*;
+        match self {
+            Tracker | Connection | HashMismatch => true,
+            PeerViolation | Disk => false,
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ErrorKind::*;
+        match self {
+            Tracker => "tracker",
+            Connection => "connection",
+            PeerViolation => "peer-violation",
+            HashMismatch => "hash-mismatch",
+            Disk => "disk",
+        }
+        .fmt(f)
+    }
+}
+
+/// Something that happened while running a download, reported over a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    PieceVerified {
+        piece: usize,
+    },
+    /// The download stopped because it reached its byte quota.
+    QuotaExhausted(QuotaExhausted),
+    /// A peer sent nothing for too long and its connection was closed.
+    PeerTimedOut {
+        peer: SocketAddr,
+    },
+    /// None of the `peers` connected to unchoked us or sent a piece for `stalled`, so the trackers
+    /// were asked for more peers and returned `found` new ones.
+    Starved {
+        stalled: Duration,
+        peers: usize,
+        found: usize,
+    },
+    Error {
+        kind: ErrorKind,
+        peer: Option<SocketAddr>,
+        piece: Option<usize>,
+        retryable: bool,
+        message: String,
+    },
+}
+
+impl SessionEvent {
+    pub fn error(err: &anyhow::Error, peer: Option<SocketAddr>, piece: Option<usize>) -> Self {
+        let kind = ErrorKind::classify(err);
+        Self::Error {
+            kind,
+            peer,
+            piece,
+            retryable: kind.is_retryable(),
+            message: format!("{err:#}"),
+        }
+    }
+}
+
+impl Display for SessionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SessionEvent::*;
+        match self {
+            PieceVerified { piece } => write!(f, "piece {piece} verified"),
+            QuotaExhausted(exhausted) => exhausted.fmt(f),
+            PeerTimedOut { peer } => write!(f, "peer {peer} timed out"),
+            Starved {
+                stalled,
+                peers,
+                found,
+            } => write!(
+                f,
+                "starved: none of {peers} peers unchoked us or sent a piece for {}, \
+                 re-announced and found {found} new peers",
+                Span(*stalled)
+            ),
+            Error {
+                kind,
+                peer,
+                piece,
+                retryable,
+                message,
+            } => {
+                write!(f, "error[{kind}]")?;
+                if let Some(peer) = peer {
+                    write!(f, " peer={peer}")?;
+                }
+                if let Some(piece) = piece {
+                    write!(f, " piece={piece}")?;
+                }
+                write!(f, " retryable={retryable}: {message}")
+            }
+        }
+    }
+}
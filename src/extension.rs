@@ -0,0 +1,157 @@
+//! The extension protocol (BEP 10), on top of which extensions such as `ut_metadata` are built.
+
+use std::{collections::BTreeMap, net::Ipv4Addr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::tracker::{deserialize_peers6, Peers};
+
+/// The name of the metadata exchange extension (BEP 9).
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// The extended message id peers must use for the `ut_metadata` messages they send us.
+pub const UT_METADATA_ID: u8 = 1;
+
+/// The name of the peer exchange extension (BEP 11).
+pub const UT_PEX: &str = "ut_pex";
+
+/// The extended message id peers must use for the `ut_pex` messages they send us, when it's
+/// advertised: it isn't one of the [`SUPPORTED`] extensions, as downloads don't expect them.
+pub const UT_PEX_ID: u8 = 2;
+
+/// The name of the hole punching extension (BEP 55), which only works over uTP.
+pub const UT_HOLEPUNCH: &str = "ut_holepunch";
+
+/// The extensions this client supports, with the extended message ids peers must send them with.
+pub const SUPPORTED: [(&str, u8); 1] = [(UT_METADATA, UT_METADATA_ID)];
+
+/// The extended message id of the extension handshake itself.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// The payload of the extension handshake, a bencoded dictionary in which every key is optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    /// The extended message id of every extension the sender supports, by name. An id of 0
+    /// disables an extension advertised in an earlier handshake.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// The port the sender listens on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// The name and version of the sender's client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// The number of outstanding requests the sender accepts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    /// The IP the sender sees the receiver at, in its compact form.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub yourip: Option<Vec<u8>>,
+    /// The size of the info dictionary, sent by peers having the metadata (BEP 9).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+    /// Whether the sender prefers encrypted connections (MSE), 1 if it does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<u8>,
+}
+
+impl ExtensionHandshake {
+    /// The handshake of this client, advertising every [`SUPPORTED`] extension.
+    pub fn ours() -> Self {
+        Self {
+            m: SUPPORTED
+                .iter()
+                .map(|&(name, id)| (name.to_string(), id))
+                .collect(),
+            v: Some(format!("bittorrent-rust {}", env!("CARGO_PKG_VERSION"))),
+            ..Self::default()
+        }
+    }
+
+    /// The id the sender wants the messages of extension `name` sent with, `None` when it doesn't
+    /// support the extension.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|&id| id != 0)
+    }
+
+    /// The extensions supported by the sender, by name.
+    pub fn extensions(&self) -> impl Iterator<Item = &str> {
+        self.m
+            .iter()
+            .filter(|(_, &id)| id != 0)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The IPv4 address the sender sees the receiver at.
+    pub fn your_ip(&self) -> Option<Ipv4Addr> {
+        let octets: [u8; 4] = self.yourip.as_deref()?.try_into().ok()?;
+        Some(Ipv4Addr::from(octets))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("guaranteed to be a valid bencode")
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, serde_bencode::Error> {
+        serde_bencode::from_bytes(payload)
+    }
+}
+
+/// A peer exchange message: the peers the sender connected to and disconnected from since its
+/// previous one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: Peers,
+    #[serde(default, deserialize_with = "deserialize_peers6")]
+    pub added6: Peers,
+    #[serde(default)]
+    pub dropped: Peers,
+    #[serde(default, deserialize_with = "deserialize_peers6")]
+    pub dropped6: Peers,
+}
+
+impl PexMessage {
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, serde_bencode::Error> {
+        serde_bencode::from_bytes(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_dictionary() {
+        let ours = ExtensionHandshake::ours();
+        assert_eq!(ours.extension_id(UT_METADATA), Some(UT_METADATA_ID));
+        assert_eq!(
+            ExtensionHandshake::from_bytes(&ours.to_bytes()).unwrap(),
+            ours
+        );
+
+        let theirs = ExtensionHandshake::from_bytes(
+            b"d1:md11:ut_metadatai3e6:ut_pexi0ee1:pi6881e4:reqqi250e\
+              13:metadata_sizei132e1:v13:qBittorrent 56:yourip4:\x0a\x00\x00\x01e",
+        )
+        .unwrap();
+        assert_eq!(theirs.extension_id(UT_METADATA), Some(3));
+        assert_eq!(theirs.extension_id("ut_pex"), None);
+        assert_eq!(theirs.extensions().collect::<Vec<_>>(), [UT_METADATA]);
+        assert_eq!(theirs.p, Some(6881));
+        assert_eq!(theirs.reqq, Some(250));
+        assert_eq!(theirs.metadata_size, Some(132));
+        assert_eq!(theirs.v.as_deref(), Some("qBittorrent 5"));
+        assert_eq!(theirs.your_ip(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+
+        assert_eq!(
+            ExtensionHandshake::from_bytes(b"de").unwrap(),
+            ExtensionHandshake::default()
+        );
+
+        let pex =
+            PexMessage::from_bytes(b"d5:added6:\x0a\x00\x00\x02\x1a\xe17:added.f1:\x00e").unwrap();
+        assert_eq!(pex.added.0, ["10.0.0.2:6881".parse().unwrap()]);
+        assert!(pex.dropped.0.is_empty());
+    }
+}
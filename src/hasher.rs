@@ -0,0 +1,173 @@
+//! How pieces are checked against the hashes in the metadata, behind [`PieceHasher`]: SHA-1 for
+//! v1 torrents, SHA-256 merkle roots for v2 ones, and [`NoopHasher`] to skip the check or fail
+//! chosen pieces on purpose.
+
+use std::{collections::BTreeSet, fmt};
+
+use sha1::{Digest, Sha1};
+
+use crate::{peer::HashMismatch, sha256::Sha256};
+
+/// The size of the blocks hashed into the leaves of a v2 merkle tree.
+pub const MERKLE_BLOCK_SIZE: usize = 1 << 14;
+
+pub trait PieceHasher: fmt::Debug + Send + Sync {
+    /// The hash of `piece`, in the form the metadata lists it.
+    fn hash(&self, piece: &[u8]) -> Vec<u8>;
+
+    /// Check `piece`, the one at `piece_index`, against `expected`, its hash in the metadata.
+    fn verify(
+        &self,
+        piece_index: usize,
+        piece: &[u8],
+        expected: &[u8],
+    ) -> Result<(), HashMismatch> {
+        let found = self.hash(piece);
+        if found != expected {
+            return Err(HashMismatch {
+                piece_index,
+                expected: expected.to_vec(),
+                found,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The SHA-1 of the whole piece, as in v1 torrents.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha1Hasher;
+
+impl PieceHasher for Sha1Hasher {
+    fn hash(&self, piece: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(piece);
+        hasher.finalize().to_vec()
+    }
+}
+
+/// The SHA-256 merkle root of the 16 KiB blocks of a piece, as in the piece layers of v2 torrents
+/// (BEP 52).
+#[derive(Debug, Clone, Copy)]
+pub struct MerkleHasher {
+    /// How many leaves a piece spans, those past the end of a short piece being zeros.
+    leaves: usize,
+}
+
+impl MerkleHasher {
+    /// A hasher for pieces of `piece_length`, a power of two of at least 16 KiB in v2 torrents.
+    pub fn new(piece_length: usize) -> Self {
+        Self {
+            leaves: piece_length.div_ceil(MERKLE_BLOCK_SIZE).next_power_of_two(),
+        }
+    }
+}
+
+impl PieceHasher for MerkleHasher {
+    fn hash(&self, piece: &[u8]) -> Vec<u8> {
+        let leaves: Vec<[u8; 32]> = piece
+            .chunks(MERKLE_BLOCK_SIZE)
+            .map(Sha256::digest)
+            .collect();
+        merkle_root(&leaves, self.leaves).to_vec()
+    }
+}
+
+/// The root of the merkle tree over `leaves`, padded with zero leaves to `width`, rounded up to
+/// a power of two.
+pub fn merkle_root(leaves: &[[u8; 32]], width: usize) -> [u8; 32] {
+    let width = width.max(leaves.len()).next_power_of_two();
+    let mut layer = leaves.to_vec();
+    layer.resize(width, [0; 32]);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                let mut hasher = Sha256::new();
+                hasher.update(&pair[0]);
+                hasher.update(&pair[1]);
+                hasher.finalize()
+            })
+            .collect();
+    }
+    layer[0]
+}
+
+/// Takes every piece as valid, but for the ones it's told to fail, so that tests can see what
+/// happens to bad pieces without having to corrupt them.
+#[derive(Debug, Clone, Default)]
+pub struct NoopHasher {
+    failing: BTreeSet<usize>,
+}
+
+impl NoopHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail the piece at `piece_index`, every time it's checked.
+    pub fn fail(mut self, piece_index: usize) -> Self {
+        self.failing.insert(piece_index);
+        self
+    }
+}
+
+impl PieceHasher for NoopHasher {
+    fn hash(&self, _piece: &[u8]) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn verify(
+        &self,
+        piece_index: usize,
+        _piece: &[u8],
+        expected: &[u8],
+    ) -> Result<(), HashMismatch> {
+        if self.failing.contains(&piece_index) {
+            return Err(HashMismatch {
+                piece_index,
+                expected: expected.to_vec(),
+                found: Vec::new(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashers_verify_pieces() {
+        let piece = vec![7u8; MERKLE_BLOCK_SIZE + 100];
+
+        let sha1 = Sha1Hasher.hash(&piece);
+        assert_eq!(sha1.len(), 20);
+        assert!(Sha1Hasher.verify(0, &piece, &sha1).is_ok());
+        let mismatch = Sha1Hasher.verify(3, &piece[1..], &sha1).unwrap_err();
+        assert_eq!((mismatch.piece_index, &mismatch.expected), (3, &sha1));
+
+        // two blocks of a four block piece: the last two leaves are zeros
+        let merkle = MerkleHasher::new(4 * MERKLE_BLOCK_SIZE);
+        let (a, b) = (
+            Sha256::digest(&piece[..MERKLE_BLOCK_SIZE]),
+            Sha256::digest(&piece[MERKLE_BLOCK_SIZE..]),
+        );
+        let pair = |left: [u8; 32], right: [u8; 32]| Sha256::digest(&[left, right].concat());
+        let root = pair(pair(a, b), pair([0; 32], [0; 32]));
+        assert_eq!(merkle.hash(&piece), root);
+        assert!(merkle.verify(0, &piece, &root).is_ok());
+        assert!(merkle.verify(0, &piece[1..], &root).is_err());
+        assert_eq!(merkle_root(&[a], 1), a, "a single leaf is the root");
+        assert_eq!(
+            merkle_root(&[a, b, a], 0),
+            pair(pair(a, b), pair(a, [0; 32]))
+        );
+
+        let noop = NoopHasher::new().fail(2);
+        assert!(noop.verify(1, &piece, &sha1).is_ok());
+        assert!(noop.verify(2, &piece, &sha1).is_err());
+        assert!(noop.verify(2, &piece, &sha1).is_err(), "fails every time");
+    }
+}
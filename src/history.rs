@@ -0,0 +1,138 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use crate::clock::Clock;
+
+#[derive(Debug, Clone, Copy)]
+struct Failure {
+    count: u32,
+    until: Instant,
+}
+
+/// Remembers which peers failed (or timed out on) which pieces, so that a piece isn't immediately
+/// handed back to a peer that just failed it.
+///
+/// Every failure of a (peer, piece) pair doubles its cooldown, starting at `base` and capped at
+/// `max`, which keeps the scheduler from ping-ponging a piece between two bad peers.
+#[derive(Debug, Clone)]
+pub struct AssignmentHistory<P, C: Clock> {
+    clock: C,
+    base: Duration,
+    max: Duration,
+    failures: HashMap<(P, usize), Failure>,
+}
+
+impl<P: Eq + Hash + Clone, C: Clock> AssignmentHistory<P, C> {
+    pub const DEFAULT_BASE: Duration = Duration::from_secs(5);
+    pub const DEFAULT_MAX: Duration = Duration::from_secs(300);
+
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            base: Self::DEFAULT_BASE,
+            max: Self::DEFAULT_MAX,
+            failures: HashMap::new(),
+        }
+    }
+
+    pub fn base(self, base: Duration) -> Self {
+        Self { base, ..self }
+    }
+
+    pub fn max(self, max: Duration) -> Self {
+        Self { max, ..self }
+    }
+
+    /// Record that `peer` failed or timed out downloading `piece_index`.
+    pub fn failed(&mut self, peer: P, piece_index: usize) {
+        let now = self.clock.now();
+        let failure = self.failures.entry((peer, piece_index)).or_insert(Failure {
+            count: 0,
+            until: now,
+        });
+        failure.count += 1;
+
+        let cooldown = self
+            .base
+            .saturating_mul(1 << (failure.count - 1).min(16))
+            .min(self.max);
+        failure.until = now + cooldown;
+    }
+
+    /// Record that `peer` delivered `piece_index`, forgetting its past failures on it.
+    pub fn succeeded(&mut self, peer: P, piece_index: usize) {
+        self.failures.remove(&(peer, piece_index));
+    }
+
+    /// How many times `peer` failed `piece_index` since it last delivered it.
+    pub fn failures(&self, peer: &P, piece_index: usize) -> u32 {
+        self.failures
+            .get(&(peer.clone(), piece_index))
+            .map_or(0, |failure| failure.count)
+    }
+
+    /// The time left before `piece_index` may be assigned to `peer` again.
+    pub fn cooldown(&self, peer: &P, piece_index: usize) -> Option<Duration> {
+        let failure = self.failures.get(&(peer.clone(), piece_index))?;
+        let remaining = failure.until.saturating_duration_since(self.clock.now());
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    pub fn can_assign(&self, peer: &P, piece_index: usize) -> bool {
+        self.cooldown(peer, piece_index).is_none()
+    }
+
+    /// Pick the first of `peers` that may be assigned `piece_index`, preferring peers that never
+    /// failed it over ones whose cooldown merely expired.
+    pub fn pick<'a>(
+        &self,
+        piece_index: usize,
+        peers: impl IntoIterator<Item = &'a P>,
+    ) -> Option<&'a P>
+    where
+        P: 'a,
+    {
+        peers
+            .into_iter()
+            .filter(|peer| self.can_assign(peer, piece_index))
+            .min_by_key(|peer| self.failures(peer, piece_index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn failed_peers_cool_down_exponentially() {
+        let clock = MockClock::new();
+        let mut history = AssignmentHistory::new(clock.clone())
+            .base(Duration::from_secs(1))
+            .max(Duration::from_secs(3));
+
+        history.failed("a", 0);
+        assert_eq!(history.pick(0, &["a", "b"]), Some(&"b"));
+        assert!(history.can_assign(&"a", 1), "cooldowns are per piece");
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(history.pick(0, &["a"]), Some(&"a"));
+
+        history.failed("a", 0);
+        assert_eq!(history.cooldown(&"a", 0), Some(Duration::from_secs(2)));
+        history.failed("a", 0);
+        assert_eq!(history.cooldown(&"a", 0), Some(Duration::from_secs(3)));
+
+        history.failed("b", 0);
+        assert_eq!(history.pick(0, &["a", "b"]), None);
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(history.pick(0, &["a", "b"]), Some(&"b"), "b failed less");
+
+        history.succeeded("a", 0);
+        assert_eq!(history.pick(0, &["b", "a"]), Some(&"a"));
+    }
+}
@@ -0,0 +1,248 @@
+//! The commands typed into a `debug_peer` session to send peer wire messages by hand, and readable
+//! descriptions of the messages the peer sends back.
+
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use crate::{
+    extension::{ExtensionHandshake, HANDSHAKE_ID},
+    peer::PeerMessage,
+};
+
+/// The length of a block request when none is given, the usual block size.
+pub const DEFAULT_BLOCK_LENGTH: u32 = 1 << 14;
+
+/// How many bytes of a payload a description shows.
+const PREVIEW_LENGTH: usize = 16;
+
+pub const HELP: &str = "\
+keep-alive | choke | unchoke | interested | not-interested
+have <piece>
+bitfield <hex>
+request <piece> <offset> [<length>]
+cancel <piece> <offset> [<length>]
+piece <piece> <offset> <hex>
+extended <id> <hex>
+help | quit";
+
+/// A line typed into the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Send(PeerMessage),
+    Help,
+    Quit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    Unknown(String),
+    /// The arguments don't fit the command, with how it's used.
+    Usage(&'static str),
+    InvalidNumber(String),
+    InvalidHex(String),
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use CommandError::*;
+        match self {
+            Unknown(command) => write!(f, "unknown command {command:?}, try `help`"),
+            Usage(usage) => write!(f, "usage: {usage}"),
+            InvalidNumber(number) => write!(f, "{number:?} isn't a number"),
+            InvalidHex(hex) => write!(f, "{hex:?} isn't hex"),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+impl FromStr for Command {
+    type Err = CommandError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        use CommandError::*;
+
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+        let number = |arg: &str| arg.parse::<u32>().map_err(|_| InvalidNumber(arg.into()));
+        let bytes = |arg: &str| hex::decode(arg).map_err(|_| InvalidHex(arg.into()));
+        let block = |usage| match args[..] {
+            [piece_index, offset] => {
+                Ok((number(piece_index)?, number(offset)?, DEFAULT_BLOCK_LENGTH))
+            }
+            [piece_index, offset, length] => {
+                Ok((number(piece_index)?, number(offset)?, number(length)?))
+            }
+            _ => Err(Usage(usage)),
+        };
+
+        let message = match (name, &args[..]) {
+            ("help", []) => return Ok(Command::Help),
+            ("quit" | "exit", []) => return Ok(Command::Quit),
+            ("keep-alive", []) => PeerMessage::KeepAlive,
+            ("choke", []) => PeerMessage::Choke,
+            ("unchoke", []) => PeerMessage::UnChoke,
+            ("interested", []) => PeerMessage::Interested,
+            ("not-interested", []) => PeerMessage::NotInterested,
+            ("have", &[piece_index]) => PeerMessage::Have {
+                piece_index: number(piece_index)?,
+            },
+            ("have", _) => return Err(Usage("have <piece>")),
+            ("bitfield", &[fields]) => PeerMessage::Bitfield {
+                fields: bytes(fields)?,
+            },
+            ("bitfield", _) => return Err(Usage("bitfield <hex>")),
+            ("request", _) => {
+                let (piece_index, offset, length) = block("request <piece> <offset> [<length>]")?;
+                PeerMessage::Request {
+                    piece_index,
+                    offset,
+                    length,
+                }
+            }
+            ("cancel", _) => {
+                let (piece_index, offset, length) = block("cancel <piece> <offset> [<length>]")?;
+                PeerMessage::Cancel {
+                    piece_index,
+                    offset,
+                    length,
+                }
+            }
+            ("piece", &[piece_index, offset, piece]) => PeerMessage::Piece {
+                piece_index: number(piece_index)?,
+                offset: number(offset)?,
+                piece: bytes(piece)?,
+            },
+            ("piece", _) => return Err(Usage("piece <piece> <offset> <hex>")),
+            ("extended", &[ext_id, payload]) => PeerMessage::Extended {
+                ext_id: number(ext_id)?
+                    .try_into()
+                    .map_err(|_| InvalidNumber(ext_id.into()))?,
+                payload: bytes(payload)?,
+            },
+            ("extended", _) => return Err(Usage("extended <id> <hex>")),
+            ("keep-alive" | "choke" | "unchoke" | "interested" | "not-interested", _) => {
+                return Err(Usage("the command takes no arguments"))
+            }
+            (name, _) => return Err(Unknown(name.into())),
+        };
+        Ok(Command::Send(message))
+    }
+}
+
+/// The first bytes of `payload` in hex, and how many there are.
+fn preview(payload: &[u8]) -> String {
+    let shown = hex::encode(&payload[..payload.len().min(PREVIEW_LENGTH)]);
+    match payload.len() > PREVIEW_LENGTH {
+        true => format!("{} bytes {shown}…", payload.len()),
+        false => format!("{} bytes {shown}", payload.len()),
+    }
+}
+
+/// A one-line description of `message`, for a torrent of `piece_count` pieces, in the syntax of
+/// the commands sending it where there is one.
+pub fn describe(message: &PeerMessage, piece_count: usize) -> String {
+    match message {
+        PeerMessage::KeepAlive => "keep-alive".to_string(),
+        PeerMessage::Choke => "choke".to_string(),
+        PeerMessage::UnChoke => "unchoke".to_string(),
+        PeerMessage::Interested => "interested".to_string(),
+        PeerMessage::NotInterested => "not-interested".to_string(),
+        PeerMessage::Have { piece_index } => format!("have {piece_index}"),
+        PeerMessage::Bitfield { fields } => {
+            let have = (0..piece_count)
+                .filter(|&index| {
+                    fields
+                        .get(index / 8)
+                        .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+                })
+                .count();
+            format!(
+                "bitfield {} ({have} of {piece_count} pieces)",
+                hex::encode(fields)
+            )
+        }
+        PeerMessage::Request {
+            piece_index,
+            offset,
+            length,
+        } => format!("request {piece_index} {offset} {length}"),
+        PeerMessage::Cancel {
+            piece_index,
+            offset,
+            length,
+        } => format!("cancel {piece_index} {offset} {length}"),
+        PeerMessage::Piece {
+            piece_index,
+            offset,
+            piece,
+        } => format!("piece {piece_index} {offset}: {}", preview(piece)),
+        PeerMessage::Extended { ext_id, payload } if *ext_id == HANDSHAKE_ID => {
+            match ExtensionHandshake::from_bytes(payload) {
+                Ok(handshake) => format!("extended {ext_id}: {handshake:?}"),
+                Err(_) => format!("extended {ext_id}: invalid handshake, {}", preview(payload)),
+            }
+        }
+        PeerMessage::Extended { ext_id, payload } => {
+            format!("extended {ext_id}: {}", preview(payload))
+        }
+        message => format!("{message:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_and_descriptions() {
+        let parse = |line: &str| line.parse::<Command>();
+        assert_eq!(parse("quit"), Ok(Command::Quit));
+        assert_eq!(
+            parse("  interested "),
+            Ok(Command::Send(PeerMessage::Interested))
+        );
+        let request = PeerMessage::Request {
+            piece_index: 3,
+            offset: 16384,
+            length: DEFAULT_BLOCK_LENGTH,
+        };
+        assert_eq!(parse("request 3 16384"), Ok(Command::Send(request.clone())));
+        assert_eq!(describe(&request, 10), "request 3 16384 16384");
+        assert_eq!(
+            parse("request 3"),
+            Err(CommandError::Usage("request <piece> <offset> [<length>]"))
+        );
+        assert_eq!(
+            parse("have x"),
+            Err(CommandError::InvalidNumber("x".to_string()))
+        );
+        assert_eq!(
+            parse("choke now"),
+            Err(CommandError::Usage("the command takes no arguments"))
+        );
+        assert_eq!(
+            parse("extended 256 00"),
+            Err(CommandError::InvalidNumber("256".to_string()))
+        );
+        assert!(matches!(parse("hello"), Err(CommandError::Unknown(_))));
+
+        let Ok(Command::Send(bitfield)) = parse("bitfield ffc0") else {
+            panic!("a bitfield")
+        };
+        assert_eq!(describe(&bitfield, 10), "bitfield ffc0 (10 of 10 pieces)");
+        let piece = PeerMessage::Piece {
+            piece_index: 1,
+            offset: 0,
+            piece: (0..20).collect(),
+        };
+        assert_eq!(
+            describe(&piece, 10),
+            "piece 1 0: 20 bytes 000102030405060708090a0b0c0d0e0f…"
+        );
+    }
+}
@@ -1,3 +1,41 @@
+pub mod archive;
+pub mod bencode;
+pub mod bitfield;
+pub mod cache;
+pub mod census;
+pub mod chaos;
+pub mod choker;
+pub mod client;
+pub mod clock;
+pub mod codec;
+pub mod dht;
+pub mod dial;
+pub mod display;
+pub mod download;
+pub mod endgame;
+pub mod events;
+pub mod extension;
+pub mod hasher;
+pub mod history;
+pub mod inspect;
+pub mod magnet;
 pub mod peer;
+pub mod peer_list;
+pub mod piece_map;
+pub mod priority;
+pub mod progress;
+pub mod random;
+pub mod relocate;
+pub mod reputation;
+pub mod resume;
+pub mod serve;
+pub mod sha256;
+pub mod stats;
+pub mod status;
+pub mod storage;
+#[cfg(test)]
+mod testing;
 pub mod torrent;
 pub mod tracker;
+pub mod udp_tracker;
+pub mod verifier;
@@ -0,0 +1,317 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    net::SocketAddr,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bencode::decode_prefix,
+    torrent::{Info, Torrent},
+};
+
+/// Metadata is exchanged in pieces of this size, only the last one may be shorter.
+pub const METADATA_PIECE_SIZE: usize = 1 << 14;
+
+/// The largest metadata accepted from a peer, so that a bogus `metadata_size` can't exhaust
+/// memory.
+pub const MAX_METADATA_SIZE: usize = 1 << 24;
+
+/// A parsed `magnet:` URI, see BEP 9 (and BEP 52 for `urn:btmh:`).
+///
+/// Parameters this client doesn't understand are dropped, so re-serializing yields a normalized
+/// link: hex info hashes, v1 before v2, followed by `dn`, `tr`, `x.pe` and `ws` in that order.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MagnetLink {
+    /// The v1 info hash, from `xt=urn:btih:` (hex or base32).
+    pub info_hash: Option<[u8; 20]>,
+    /// The v2 info hash, from `xt=urn:btmh:` (a sha2-256 multihash).
+    pub info_hash_v2: Option<[u8; 32]>,
+    /// The suggested name, from `dn`.
+    pub display_name: Option<String>,
+    /// Tracker URLs, from `tr` (also `tr.1`, `tr.2`, …), without duplicates.
+    pub trackers: Vec<String>,
+    /// Peers to contact directly, from `x.pe`.
+    pub peers: Vec<SocketAddr>,
+    /// Web seed URLs (BEP 19), from `ws`.
+    pub web_seeds: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidMagnet {
+    NotAMagnet,
+    Malformed(String),
+    InvalidInfoHash(String),
+    InvalidPeer(String),
+    /// Neither a v1 nor a v2 info hash was given.
+    MissingInfoHash,
+}
+
+impl Display for InvalidMagnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use InvalidMagnet::*;
+        match self {
+            NotAMagnet => write!(f, "link doesn't start with \"magnet:?\""),
+            Malformed(err) => write!(f, "malformed magnet link: {err}"),
+            InvalidInfoHash(xt) => write!(f, "invalid exact topic {xt:?}"),
+            InvalidPeer(peer) => write!(f, "invalid peer address {peer:?}"),
+            MissingInfoHash => write!(f, "magnet link doesn't have an info hash"),
+        }
+    }
+}
+
+impl Error for InvalidMagnet {}
+
+impl MagnetLink {
+    /// The first tracker of the link, if any.
+    pub fn tracker(&self) -> Option<&str> {
+        self.trackers.first().map(String::as_str)
+    }
+
+    /// The display name, falling back to the hex-encoded info hash.
+    pub fn name(&self) -> String {
+        match (&self.display_name, self.info_hash, self.info_hash_v2) {
+            (Some(name), _, _) => name.clone(),
+            (None, Some(info_hash), _) => hex::encode(info_hash),
+            (None, None, Some(info_hash)) => hex::encode(info_hash),
+            (None, None, None) => String::new(),
+        }
+    }
+
+    /// The torrent described by `info`, the metadata fetched for this link, announcing to the
+    /// link's trackers.
+    pub fn torrent(&self, info: Info) -> Torrent {
+        Torrent {
+            announce: self.tracker().unwrap_or_default().to_string(),
+            announce_list: (self.trackers.len() > 1).then(|| vec![self.trackers.clone()]),
+            creation_date: None,
+            info,
+        }
+    }
+
+    fn add_exact_topic(&mut self, xt: &str) -> Result<(), InvalidMagnet> {
+        let invalid = || InvalidMagnet::InvalidInfoHash(xt.to_string());
+
+        if let Some(hash) = xt.strip_prefix("urn:btih:") {
+            let bytes = match hash.len() {
+                40 => hex::decode(hash).map_err(|_| invalid())?,
+                32 => base32_decode(hash).ok_or_else(invalid)?,
+                _ => return Err(invalid()),
+            };
+            self.info_hash = Some(bytes.try_into().map_err(|_| invalid())?);
+        } else if let Some(multihash) = xt.strip_prefix("urn:btmh:") {
+            // 0x12 is sha2-256 and 0x20 its digest length
+            let hash = multihash.strip_prefix("1220").ok_or_else(invalid)?;
+            let bytes = hex::decode(hash).map_err(|_| invalid())?;
+            self.info_hash_v2 = Some(bytes.try_into().map_err(|_| invalid())?);
+        }
+        // other kinds of topics (ed2k, sha1 of the content, …) aren't useful to us
+        Ok(())
+    }
+}
+
+impl FromStr for MagnetLink {
+    type Err = InvalidMagnet;
+
+    fn from_str(link: &str) -> Result<Self, Self::Err> {
+        let query = link
+            .trim()
+            .strip_prefix("magnet:?")
+            .ok_or(InvalidMagnet::NotAMagnet)?;
+        let params: Vec<(String, String)> = serde_urlencoded::from_str(query)
+            .map_err(|err| InvalidMagnet::Malformed(err.to_string()))?;
+
+        let mut magnet = Self::default();
+        for (key, value) in params {
+            // BEP 9 allows numbering repeated keys, e.g. `xt.1`, `tr.2`
+            let key = key.rsplit_once('.').map_or(key.as_str(), |(base, index)| {
+                if index.chars().all(|c| c.is_ascii_digit()) {
+                    base
+                } else {
+                    &key
+                }
+            });
+            match key {
+                "xt" => magnet.add_exact_topic(&value)?,
+                "dn" => magnet.display_name = Some(value),
+                "tr" if !magnet.trackers.contains(&value) => magnet.trackers.push(value),
+                "x.pe" => {
+                    let peer = value
+                        .parse()
+                        .map_err(|_| InvalidMagnet::InvalidPeer(value))?;
+                    if !magnet.peers.contains(&peer) {
+                        magnet.peers.push(peer);
+                    }
+                }
+                "ws" if !magnet.web_seeds.contains(&value) => magnet.web_seeds.push(value),
+                _ => {}
+            }
+        }
+
+        if magnet.info_hash.is_none() && magnet.info_hash_v2.is_none() {
+            return Err(InvalidMagnet::MissingInfoHash);
+        }
+        Ok(magnet)
+    }
+}
+
+impl Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut topics = Vec::new();
+        if let Some(info_hash) = self.info_hash {
+            topics.push(format!("xt=urn:btih:{}", hex::encode(info_hash)));
+        }
+        if let Some(info_hash) = self.info_hash_v2 {
+            topics.push(format!("xt=urn:btmh:1220{}", hex::encode(info_hash)));
+        }
+
+        let mut params = Vec::new();
+        if let Some(name) = &self.display_name {
+            params.push(("dn", name.clone()));
+        }
+        params.extend(self.trackers.iter().map(|tracker| ("tr", tracker.clone())));
+        params.extend(self.peers.iter().map(|peer| ("x.pe", peer.to_string())));
+        params.extend(self.web_seeds.iter().map(|seed| ("ws", seed.clone())));
+        let params = serde_urlencoded::to_string(params).map_err(|_| fmt::Error)?;
+
+        write!(f, "magnet:?{}", topics.join("&"))?;
+        if !params.is_empty() {
+            write!(f, "&{params}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The kinds of `ut_metadata` messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataMessageType {
+    Request = 0,
+    Data = 1,
+    Reject = 2,
+}
+
+/// The bencoded header of a `ut_metadata` message, data messages carry the piece after it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataMessage {
+    pub msg_type: u8,
+    pub piece: usize,
+    /// The size of the whole metadata, only in data messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<usize>,
+}
+
+impl MetadataMessage {
+    pub fn request(piece: usize) -> Self {
+        Self {
+            msg_type: MetadataMessageType::Request as u8,
+            piece,
+            total_size: None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("guaranteed to be a valid bencode")
+    }
+
+    /// Split an extended message `payload` into its header and the metadata piece following it.
+    pub fn from_payload(payload: &[u8]) -> anyhow::Result<(Self, &[u8])> {
+        let (_, consumed) = decode_prefix(payload)?;
+        let message = serde_bencode::from_bytes(&payload[..consumed])?;
+        Ok((message, &payload[consumed..]))
+    }
+}
+
+/// Decode RFC 4648 base32 without padding, as used by older magnet links for info hashes.
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for c in encoded.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = buffer << 5 | u64::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_normalize() {
+        let link = "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165\
+            &dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce\
+            &tr.1=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce\
+            &x.pe=127.0.0.1:6881&ws=http%3A%2F%2Fexample.com%2Fmagnet1.gif&foo=bar";
+        let magnet: MagnetLink = link.parse().unwrap();
+
+        assert_eq!(
+            magnet.info_hash.map(hex::encode).as_deref(),
+            Some("ad42ce8109f54c99613ce38f9b4d87e70f24a165")
+        );
+        assert_eq!(magnet.name(), "magnet1.gif");
+        assert_eq!(
+            magnet.tracker(),
+            Some("http://bittorrent-test-tracker.codecrafters.io/announce")
+        );
+        assert_eq!(magnet.trackers.len(), 1);
+        assert_eq!(magnet.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(magnet.web_seeds, vec!["http://example.com/magnet1.gif"]);
+
+        let reparsed: MagnetLink = magnet.to_string().parse().unwrap();
+        assert_eq!(reparsed, magnet);
+    }
+
+    #[test]
+    fn metadata_messages() {
+        assert_eq!(
+            MetadataMessage::request(2).to_bytes(),
+            b"d8:msg_typei0e5:piecei2ee"
+        );
+        let (message, piece) = MetadataMessage::from_payload(
+            b"d8:msg_typei1e5:piecei0e10:total_sizei4ee\x01\x02\x03\x04",
+        )
+        .unwrap();
+        assert_eq!(message.msg_type, MetadataMessageType::Data as u8);
+        assert_eq!(message.total_size, Some(4));
+        assert_eq!(piece, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn info_hash_encodings() {
+        let hex: MagnetLink = "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a"
+            .parse()
+            .unwrap();
+        let base32: MagnetLink = "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK"
+            .parse()
+            .unwrap();
+        assert_eq!(hex.info_hash, base32.info_hash);
+
+        let v2: MagnetLink = format!("magnet:?xt=urn:btmh:1220{}", "ab".repeat(32))
+            .parse()
+            .unwrap();
+        assert_eq!(v2.info_hash, None);
+        assert_eq!(v2.info_hash_v2, Some([0xab; 32]));
+
+        assert_eq!(
+            "magnet:?dn=nothing".parse::<MagnetLink>(),
+            Err(InvalidMagnet::MissingInfoHash)
+        );
+        assert!("magnet:?xt=urn:btih:1234".parse::<MagnetLink>().is_err());
+        assert_eq!(
+            "http://example.com".parse::<MagnetLink>(),
+            Err(InvalidMagnet::NotAMagnet)
+        );
+    }
+}
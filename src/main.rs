@@ -1,98 +1,2093 @@
-use anyhow::{bail, Context};
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, bail, Context};
+use clap::{Args, Parser, Subcommand};
 use serde_bencode::value::Value as BenValue;
 use serde_json::Value as JsonValue;
 use std::{
-    fs::{read, File},
-    io::{Read, Write},
-    net::{SocketAddrV4, TcpStream},
-    path::PathBuf,
+    collections::{HashMap, HashSet},
+    fs::{self, read, File},
+    future::{self, Future},
+    io::{self, BufWriter, IsTerminal, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::pin,
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    signal,
+    sync::{broadcast, mpsc::UnboundedSender},
+    task, time,
 };
 
 use bittorrent_starter_rust::{
+    archive::{ArchiveFormat, ArchiveSink, ArchiveWriter},
+    bencode::{decode_all, decode_prefix},
+    bitfield::Bitfield,
+    cache::{AnnounceCache, Fingerprint, MetadataCache, VerificationCache},
+    census::{Census, Observation},
+    chaos::{Chaos, ChaosConfig},
+    choker::Choker,
+    client::{self, BLOCK_SIZE},
+    clock::{Clock, SystemClock},
+    dht::{self, AnnounceRefresh, Dht},
+    dial::Dial,
+    display::{ByteSize, Span, Timestamp},
+    download::{Assignment, Interrupted, PiecesFailed, RetryPolicy, Scheduler, Starvation},
+    endgame::Endgame,
+    events::{ErrorKind, SessionEvent},
+    extension::{self, ExtensionHandshake, PexMessage, UT_METADATA, UT_PEX, UT_PEX_ID},
+    hasher::{NoopHasher, PieceHasher, Sha1Hasher},
+    inspect::{describe, Command, HELP},
+    magnet::MagnetLink,
     peer::{
-        download_piece, initiate_download, send_message, validate_piece, HandShake, PeerMessage,
+        validate_piece, HandShake, HashMismatch, PeerConnection, PeerId, PeerMessage, Timeouts,
+        CONNECT_TIMEOUT, PIPELINE_DEPTH,
+    },
+    peer_list::{Cidr, PeerList, PeerSort},
+    piece_map::{PieceMap, PieceState},
+    priority::{FilePriorities, Priority},
+    progress::{ProgressMeter, StatusLine},
+    relocate::{move_download, move_file, remove_download},
+    reputation::Reputation,
+    resume::{Checkpointer, ResumeState, Snapshot},
+    serve::serve_peer,
+    sha256::Sha256,
+    stats::{Quota, QuotaExhausted, Stage, Timings, TransferStats},
+    status::{Progress, State, StatusFile},
+    storage::{layout, plain_name, FileSpan, Fsync, FsyncPolicy, Storage, StorageSink},
+    torrent::{info_hash_from_bytes, Torrent, TorrentBuilder},
+    tracker::{
+        AnnounceSchedule, Event, ExtraParam, InvalidResponse, Peers, TrackerPolicy, TrackerRequest,
+        TrackerResponse, TrackerTiers,
     },
-    torrent::Torrent,
-    tracker::{Peers, TrackerRequest, TrackerResponse},
+    verifier::{self, Verdict, Verified, Verifier},
 };
 
-const BLOCK_SIZE: u32 = 1 << 14;
+/// How many peers `observe` watches at most, counting those learned through peer exchange.
+const MAX_OBSERVED: usize = 200;
+
+/// How many peers a download connects to at once.
+const MAX_PEERS: usize = 5;
+
+/// How many peers a starved download asks the trackers for, instead of their default of about 50.
+const STARVED_NUMWANT: usize = 200;
+
+/// How long a stopping download waits for the pieces in flight.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// How often the progress line is redrawn.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many times a download reconnects to a peer whose connection failed.
+const MAX_PEER_FAILURES: usize = 3;
+
+/// How many verified pieces a peer task may fall behind on before missing some `Have`s.
+const VERIFIED_BACKLOG: usize = 1024;
 
 fn bencode_to_json(bencode: &BenValue) -> JsonValue {
     // TODO: find a way to make this work
     // serde_json::to_value(&bencode).expect("failed to json serialize bencode")
 
-    use BenValue::*;
+    use BenValue::*;
+
+    match bencode {
+        Bytes(bytes) => JsonValue::String(String::from_utf8_lossy(bytes).into()),
+        Int(num) => JsonValue::Number(serde_json::value::Number::from(*num)),
+        List(list) => {
+            let mut arr = Vec::new();
+            for elem in list {
+                arr.push(bencode_to_json(elem));
+            }
+            JsonValue::Array(arr)
+        }
+        Dict(dict) => {
+            let mut map = serde_json::value::Map::new();
+
+            for (key, value) in dict {
+                let key = String::from_utf8_lossy(key);
+                let value = bencode_to_json(value);
+                map.insert(key.into(), value);
+            }
+
+            JsonValue::Object(map)
+        }
+    }
+}
+
+/// Announce to the session's `tiers` in order, through the announce cache unless disabled.
+fn extract_peers(
+    request: &TrackerRequest,
+    info_hash: [u8; 20],
+    trackers: &TrackerArgs,
+    tiers: &mut TrackerTiers,
+) -> anyhow::Result<TrackerResponse> {
+    let cache = (!trackers.no_cache).then(AnnounceCache::default);
+    tiers.announce(request, info_hash, cache.as_ref())
+}
+
+#[derive(Debug, Clone, Args)]
+struct TrackerArgs {
+    /// Only announce to trackers matching this URL or host (repeatable)
+    #[clap(long = "allow-tracker", value_name = "PATTERN")]
+    allow: Vec<String>,
+    /// Never announce to trackers matching this URL or host (repeatable)
+    #[clap(long = "deny-tracker", value_name = "PATTERN")]
+    deny: Vec<String>,
+    /// Always ask the tracker, ignoring recently cached responses
+    #[clap(long = "no-cache")]
+    no_cache: bool,
+    /// Add this query parameter to the announces of the trackers matching the pattern, or of
+    /// every tracker without one (repeatable)
+    #[clap(long = "tracker-param", value_name = "[PATTERN:]KEY=VALUE")]
+    params: Vec<ExtraParam>,
+}
+
+impl TrackerArgs {
+    fn policy(&self) -> TrackerPolicy {
+        TrackerPolicy {
+            allow: self.allow.clone(),
+            deny: self.deny.clone(),
+        }
+    }
+
+    /// The tracker tiers of `torrent` permitted by the policy, shuffled for a new session.
+    fn tiers(&self, torrent: &Torrent) -> TrackerTiers {
+        TrackerTiers::new(self.policy().apply(torrent.tracker_tiers()))
+            .extra_params(self.params.clone())
+    }
+
+    /// The trackers of `magnet` permitted by the policy, as a single tier.
+    fn magnet_tiers(&self, magnet: &MagnetLink) -> TrackerTiers {
+        TrackerTiers::new(self.policy().apply(vec![magnet.trackers.clone()]))
+            .extra_params(self.params.clone())
+    }
+}
+
+/// Peers pinned or banned by the user, on top of what the tracker returns.
+#[derive(Debug, Args)]
+struct PeerArgs {
+    /// Known-good peer to connect to before any tracker peer (repeatable)
+    #[clap(long = "add-peer", value_name = "ADDR")]
+    add: Vec<SocketAddr>,
+    /// Peer to never connect to (repeatable)
+    #[clap(long = "ban-peer", value_name = "ADDR")]
+    ban: Vec<SocketAddr>,
+    /// Never contact trackers, only connect to pinned peers on private or link-local addresses
+    #[clap(long = "lan-only")]
+    lan_only: bool,
+    /// How to connect to peers, `direct` or `socks5://[user:password@]host:port`
+    #[clap(long, value_name = "URL", default_value = "direct")]
+    proxy: Dial,
+    /// How many block requests to keep outstanding with every peer
+    #[clap(long, value_name = "N", default_value_t = PIPELINE_DEPTH)]
+    pipeline: usize,
+    /// How long connecting to a peer may take
+    #[clap(
+        long = "connect-timeout",
+        value_name = "DURATION",
+        default_value = "10s"
+    )]
+    connect_timeout: Span,
+    /// How long a peer may stay silent, not even sending keep-alives
+    #[clap(long = "read-timeout", value_name = "DURATION", default_value = "2m")]
+    read_timeout: Span,
+    /// How long sending a message to a peer may take
+    #[clap(long = "write-timeout", value_name = "DURATION", default_value = "30s")]
+    write_timeout: Span,
+    /// How long a peer may take to answer a block request
+    #[clap(
+        long = "request-timeout",
+        value_name = "DURATION",
+        default_value = "1m"
+    )]
+    request_timeout: Span,
+    /// How many times to retry a failing piece, with another peer where there is one
+    #[clap(long, value_name = "N", default_value_t = RetryPolicy::default().budget)]
+    retries: u32,
+}
+
+impl PeerArgs {
+    fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            connect: self.connect_timeout.0,
+            read: self.read_timeout.0,
+            write: self.write_timeout.0,
+            request: self.request_timeout.0,
+        }
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            budget: self.retries,
+            ..RetryPolicy::default()
+        }
+    }
+}
+
+fn is_lan_address(addr: &SocketAddr) -> bool {
+    match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(ip) => ip.is_unique_local() || ip.is_unicast_link_local() || ip.is_loopback(),
+    }
+}
+
+/// Announce `request` for the torrent with `info_hash` to get its peers, applying the user's pins
+/// and bans, along with the tracker's response unless it wasn't asked or failed.
+///
+/// Tracker peers are ordered by their [`Reputation`] from past sessions, with the pinned peers
+/// last so that they're popped first. A failing tracker is only fatal when there are no pinned
+/// peers to fall back on, and in LAN-only mode the tracker isn't asked at all.
+fn gather_peers(
+    request: &TrackerRequest,
+    info_hash: [u8; 20],
+    trackers: &TrackerArgs,
+    tiers: &mut TrackerTiers,
+    peer_args: &PeerArgs,
+) -> anyhow::Result<(Peers, Option<TrackerResponse>)> {
+    if peer_args.lan_only {
+        if peer_args.add.is_empty() {
+            bail!("--lan-only doesn't contact trackers, pin peers with --add-peer");
+        }
+        if let Some(peer) = peer_args.add.iter().find(|peer| !is_lan_address(peer)) {
+            bail!("--lan-only refuses to connect to {peer}, it isn't a local network address");
+        }
+    }
+
+    let response = match peer_args.lan_only {
+        true => None,
+        false => match extract_peers(request, info_hash, trackers, tiers) {
+            Ok(response) => Some(response),
+            Err(err) if !peer_args.add.is_empty() => {
+                eprintln!("warning: {err:#}, using pinned peers only");
+                None
+            }
+            Err(err) => return Err(err),
+        },
+    };
+    let mut peers = response.as_ref().map_or_else(
+        || Peers(Vec::new()),
+        |response| response.usable_peers(request.crypto_policy()),
+    );
+
+    peers
+        .0
+        .retain(|peer| !peer_args.ban.contains(peer) && !peer_args.add.contains(peer));
+    Reputation::load(&Reputation::path_for(info_hash)).rank(&mut peers.0);
+    peers.0.extend(
+        peer_args
+            .add
+            .iter()
+            .rev()
+            .filter(|peer| !peer_args.ban.contains(peer)),
+    );
+
+    Ok((peers, response))
+}
+
+/// Run `download` of a single piece of `piece_size` bytes from `peer` while showing its progress,
+/// going by the bytes `received` on the connection.
+async fn with_progress<T>(
+    download: impl Future<Output = T>,
+    peer: SocketAddr,
+    received: &AtomicU64,
+    piece_size: u64,
+) -> T {
+    let mut meter = ProgressMeter::new(SystemClock);
+    let mut status_line = StatusLine::new(SystemClock, io::stderr(), io::stderr().is_terminal());
+    let mut ticker = time::interval(PROGRESS_INTERVAL);
+    let mut download = pin!(download);
+    let mut recorded = 0;
+    loop {
+        tokio::select! {
+            result = &mut download => return result,
+            _ = ticker.tick() => {
+                let bytes_done = received.load(Ordering::Relaxed);
+                meter.record(peer, bytes_done - recorded);
+                recorded = bytes_done;
+                let line = meter.line(Progress {
+                    pieces_done: 0,
+                    pieces_wanted: 1,
+                    bytes_done,
+                    bytes_wanted: piece_size,
+                    peers: 1,
+                });
+                status_line.draw(&line).ok();
+            }
+        }
+    }
+}
+
+/// Connect and handshake with all of `peers` at once, returning how long it took with those that
+/// answered within [`CONNECT_TIMEOUT`].
+async fn probe_peers(peers: &[SocketAddr], info_hash: [u8; 20]) -> Vec<(SocketAddr, Duration)> {
+    let timeouts = Timeouts {
+        read: CONNECT_TIMEOUT,
+        ..Timeouts::default()
+    };
+    let probes: Vec<_> = peers
+        .iter()
+        .map(|&peer| {
+            tokio::spawn(async move {
+                let started = Instant::now();
+                client::connect(&Dial::default(), peer, info_hash, timeouts)
+                    .await
+                    .ok()
+                    .map(|_| (peer, started.elapsed()))
+            })
+        })
+        .collect();
+    let mut answered = Vec::new();
+    for probe in probes {
+        if let Ok(Some(probed)) = probe.await {
+            answered.push(probed);
+        }
+    }
+    answered
+}
+
+/// Run `future` to completion on a new tokio runtime.
+///
+/// The blocking tracker client must never run inside a runtime, so only the peer I/O is async.
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("building the tokio runtime")
+        .block_on(future)
+}
+
+/// Download the piece at `piece_index` of `torrent` from `peer` and check its hash, showing its
+/// progress on stderr unless `quiet`.
+async fn fetch_piece(
+    torrent: &Torrent,
+    piece_index: usize,
+    peer: SocketAddr,
+    peer_args: &PeerArgs,
+    quiet: bool,
+) -> anyhow::Result<Vec<u8>> {
+    let info_hash = torrent.calculate_info_hash();
+    let (connection, _) =
+        client::connect(&peer_args.proxy, peer, info_hash, peer_args.timeouts()).await?;
+    let mut connection = connection.pipeline(peer_args.pipeline);
+    let bitfield = connection
+        .initiate_download(torrent.info.pieces.0.len())
+        .await?;
+    if !bitfield.has_piece(piece_index) {
+        bail!("peer doesn't have piece {piece_index}");
+    }
+    let received = connection.received();
+    let download = connection.download_piece(torrent, piece_index, BLOCK_SIZE);
+    let piece = match quiet {
+        true => download.await?,
+        false => {
+            let piece_size = torrent.piece_size(piece_index) as u64;
+            with_progress(download, peer, &received, piece_size).await?
+        }
+    };
+    validate_piece(torrent, piece_index, &piece)?;
+    Ok(piece)
+}
+
+/// Download the piece at `piece_index` of `torrent` and save it to `output`. Its progress is shown
+/// on stderr unless `quiet`.
+///
+/// A piece failing with a peer is retried with the next one, the peers that failed going to the
+/// back of the line, until it runs out of retries.
+fn download_piece_to(
+    torrent: &Torrent,
+    piece_index: usize,
+    output: &Path,
+    trackers: &TrackerArgs,
+    peer_args: &PeerArgs,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    let pieces_count = torrent.info.pieces.0.len();
+    if piece_index >= pieces_count {
+        bail!("index {piece_index} out of {pieces_count}")
+    }
+
+    let info_hash = torrent.calculate_info_hash();
+    let mut tiers = trackers.tiers(torrent);
+    let (mut peers, _) = gather_peers(
+        &TrackerRequest::new(torrent.content_length()),
+        info_hash,
+        trackers,
+        &mut tiers,
+        peer_args,
+    )?;
+    if peers.0.is_empty() {
+        bail!("the torrent doesn't have any peers")
+    }
+
+    let retry = peer_args.retry_policy();
+    let mut failures = 0;
+    let piece = loop {
+        let peer = peers.0.pop().expect("failed peers are put back");
+        let err = match block_on(fetch_piece(torrent, piece_index, peer, peer_args, quiet)) {
+            Ok(piece) => break piece,
+            Err(err) => err.context(format!("downloading from {peer}")),
+        };
+        failures += 1;
+        if failures > retry.budget {
+            return Err(err.context(format!("piece {piece_index} failed {failures} times")));
+        }
+        eprintln!("warning: {err:#}, retrying");
+        peers.0.insert(0, peer);
+        thread::sleep(retry.backoff(failures));
+    };
+
+    // saving to disk
+    let mut piece_file = File::create(output).context("creating output file")?;
+    piece_file
+        .write_all(&piece)
+        .context("writing piece to file")?;
+
+    println!("Piece {piece_index} downloaded to {}.", output.display());
+    Ok(())
+}
+
+/// [`download`], logging the session's events to stderr.
+fn download_with_events(
+    torrent: &Torrent,
+    output: &Path,
+    trackers: &TrackerArgs,
+    peer_args: &PeerArgs,
+    options: &DownloadOptions,
+) -> anyhow::Result<()> {
+    let (events, receiver) = mpsc::channel();
+    let event_logger = thread::spawn(move || {
+        for event in receiver {
+            if !matches!(event, SessionEvent::PieceVerified { .. }) {
+                eprintln!("{event}");
+            }
+        }
+    });
+    let result = download(torrent, output, trackers, peer_args, options, &events);
+    drop(events);
+    event_logger.join().expect("event logger panicked");
+    result
+}
+
+/// What trackers are told is left to download before the metadata, and so the length, is known,
+/// as they treat announces without anything left as seeds.
+const UNKNOWN_LEFT: usize = 999;
+
+/// The user's pins and bans, plus the peers listed in `magnet`.
+fn magnet_peer_args(magnet: &MagnetLink, peer_args: &PeerArgs) -> PeerArgs {
+    let mut add = peer_args.add.clone();
+    for peer in &magnet.peers {
+        if !add.contains(peer) {
+            add.push(*peer);
+        }
+    }
+    PeerArgs {
+        add,
+        ban: peer_args.ban.clone(),
+        lan_only: peer_args.lan_only,
+        proxy: peer_args.proxy.clone(),
+        pipeline: peer_args.pipeline,
+        connect_timeout: peer_args.connect_timeout,
+        read_timeout: peer_args.read_timeout,
+        write_timeout: peer_args.write_timeout,
+        request_timeout: peer_args.request_timeout,
+        retries: peer_args.retries,
+    }
+}
+
+/// The v1 info hash of `magnet`, which is all peers can be asked for.
+fn magnet_info_hash(magnet: &MagnetLink) -> anyhow::Result<[u8; 20]> {
+    magnet
+        .info_hash
+        .context("only magnet links with a v1 (btih) info hash are supported")
+}
+
+/// Handshake with `peer`, advertising the extension protocol, and exchange extension handshakes.
+async fn magnet_handshake(
+    dialer: &Dial,
+    peer: SocketAddr,
+    info_hash: [u8; 20],
+    timeouts: Timeouts,
+) -> anyhow::Result<(PeerConnection, HandShake, ExtensionHandshake)> {
+    let mut connection = PeerConnection::connect_with(dialer, peer, timeouts).await?;
+    let handshake = connection
+        .handshake(HandShake::new(info_hash).extension_protocol())
+        .await?;
+    if !handshake.supports_extension_protocol() {
+        bail!("peer doesn't support the extension protocol");
+    }
+    let extensions = connection
+        .extension_handshake(&ExtensionHandshake::ours())
+        .await?;
+    Ok((connection, handshake, extensions))
+}
+
+/// Fetch the metadata of `magnet` from the first of its peers able to send it.
+fn fetch_magnet_torrent(
+    magnet: &MagnetLink,
+    trackers: &TrackerArgs,
+    peer_args: &PeerArgs,
+) -> anyhow::Result<Torrent> {
+    let info_hash = magnet_info_hash(magnet)?;
+    let cache = MetadataCache::default();
+    if let Some(info) = cache.get(info_hash) {
+        return Ok(magnet.torrent(info));
+    }
+    let (mut peers, _) = gather_peers(
+        &TrackerRequest::new(UNKNOWN_LEFT),
+        info_hash,
+        trackers,
+        &mut trackers.magnet_tiers(magnet),
+        &magnet_peer_args(magnet, peer_args),
+    )?;
+
+    let mut last_err = None;
+    while let Some(peer) = peers.0.pop() {
+        let info = block_on(async {
+            let (mut connection, _, extensions) =
+                magnet_handshake(&peer_args.proxy, peer, info_hash, peer_args.timeouts()).await?;
+            connection.fetch_metadata(info_hash, &extensions).await
+        });
+        match info {
+            Ok(info) => {
+                let torrent = magnet.torrent(info);
+                // the metadata is verified, but re-encoding it drops the keys `Info` doesn't know
+                if torrent.calculate_info_hash() != info_hash {
+                    bail!("the metadata has keys this client doesn't support");
+                }
+                if let Err(err) = cache.put(&torrent.info) {
+                    eprintln!("warning: {err:#}");
+                }
+                return Ok(torrent);
+            }
+            Err(err) => {
+                eprintln!("warning: fetching metadata from {peer}: {err:#}");
+                last_err = Some(err.context(format!("fetching metadata from {peer}")));
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| anyhow!("the torrent doesn't have any peers")))
+}
+
+/// Parse the torrent file at `path`, keeping its metadata in the [`MetadataCache`] for magnet links
+/// of the same torrent.
+fn read_torrent(path: &Path) -> anyhow::Result<Torrent> {
+    let buf = read(path).context("opening torrent file")?;
+    let torrent: Torrent = serde_bencode::from_bytes(&buf).context("parse torrent file")?;
+    if let Err(err) = MetadataCache::default().put(&torrent.info) {
+        eprintln!("warning: {err:#}");
+    }
+    Ok(torrent)
+}
+
+/// Pick the download of `torrent` to `output` up where its resume state left it, re-verifying every
+/// piece the state records, or start it over when there's nothing to resume.
+fn open_download(torrent: &Torrent, output: &Path) -> anyhow::Result<(Storage, ResumeState)> {
+    let info_hash = torrent.calculate_info_hash();
+    let piece_count = torrent.info.pieces.0.len();
+    let resume_path = ResumeState::path_for(output);
+    let resume = match resume_path.exists() && output.exists() {
+        true => match ResumeState::load(&resume_path) {
+            Ok(resume) => Some(resume),
+            Err(err) => {
+                eprintln!("warning: starting over, {err:#}");
+                None
+            }
+        },
+        false => None,
+    };
+    let Some(mut resume) = resume.filter(|resume| resume.info_hash == info_hash) else {
+        return Ok((
+            Storage::create(torrent, output)?,
+            ResumeState::new(info_hash, piece_count),
+        ));
+    };
+
+    let mut storage = Storage::open(torrent, output)?;
+    let mut piece = vec![0u8; torrent.info.piece_length];
+    let mut verified = 0;
+    for piece_index in 0..piece_count {
+        if !resume.has_piece(piece_index) {
+            continue;
+        }
+        match verify_stored_piece(torrent, &mut storage, piece_index, &mut piece) {
+            Ok(()) => verified += 1,
+            Err(_) => resume.clear_piece(piece_index),
+        }
+    }
+    eprintln!(
+        "resuming {}: {verified} of {piece_count} pieces verified on disk",
+        output.display()
+    );
+    Ok((storage, resume))
+}
+
+/// Open the archive `output` is to become, `-` standing for stdout.
+fn open_archive(
+    torrent: &Torrent,
+    output: &Path,
+    format: ArchiveFormat,
+) -> anyhow::Result<Box<dyn StorageSink + Send>> {
+    let writer: Box<dyn Write + Send> = match output == Path::new("-") {
+        true => Box::new(io::stdout()),
+        false => Box::new(File::create(output).context(format!("creating {}", output.display()))?),
+    };
+    let writer = ArchiveWriter::new(format, torrent, BufWriter::new(writer))?;
+    Ok(Box::new(ArchiveSink::new(writer)))
+}
+
+/// Check the piece at `piece_index` as stored in `storage` against its hash, reading it into `buf`,
+/// which must fit any piece.
+fn verify_stored_piece(
+    torrent: &Torrent,
+    storage: &mut Storage,
+    piece_index: usize,
+    buf: &mut [u8],
+) -> anyhow::Result<()> {
+    let piece = storage.read_piece(piece_index, buf)?;
+    validate_piece(torrent, piece_index, piece)
+}
+
+/// Serve the pieces of `torrent` that are valid at `output` to every peer connecting on `port`,
+/// after telling the trackers about it, and the DHT too when given the `dht_nodes` to join it
+/// through.
+fn seed(
+    torrent: Torrent,
+    output: &Path,
+    port: u16,
+    trackers: &TrackerArgs,
+    dht_nodes: Option<Vec<String>>,
+) -> anyhow::Result<()> {
+    if !output.exists() {
+        bail!(
+            "{} doesn't exist, there is nothing to seed",
+            output.display()
+        );
+    }
+    let mut storage = Storage::open(&torrent, output)?;
+    let piece_count = torrent.info.pieces.0.len();
+    let mut bitfield = Bitfield::new(piece_count);
+    let mut piece = vec![0u8; torrent.info.piece_length];
+    let mut left = 0;
+    for piece_index in 0..piece_count {
+        match verify_stored_piece(&torrent, &mut storage, piece_index, &mut piece) {
+            Ok(()) => {
+                bitfield.set_piece(piece_index);
+            }
+            Err(_) => left += torrent.piece_size(piece_index),
+        }
+    }
+    if left == torrent.content_length() {
+        bail!("none of the pieces at {} are valid", output.display());
+    }
+    if left > 0 {
+        eprintln!(
+            "warning: only seeding {} of {}, the rest is missing or corrupt",
+            ByteSize((torrent.content_length() - left) as u64),
+            ByteSize(torrent.content_length() as u64)
+        );
+    }
+
+    let info_hash = torrent.calculate_info_hash();
+    let request = TrackerRequest::new(left).port(port);
+    let external_ip =
+        match extract_peers(&request, info_hash, trackers, &mut trackers.tiers(&torrent)) {
+            Ok(response) => response.external_ip(),
+            Err(err) => {
+                eprintln!("warning: {err:#}, only peers finding us otherwise will connect");
+                None
+            }
+        };
+    if let Some(nodes) = dht_nodes {
+        let dht = Dht::bind(port).context("opening the DHT socket")?;
+        thread::spawn(move || refresh_dht(dht, &nodes, info_hash, port, external_ip));
+    }
+
+    let torrent = Arc::new(torrent);
+    let storage = Arc::new(Mutex::new(storage));
+    let bitfield = Arc::new(bitfield.into_bytes());
+    let choker = Arc::new(Mutex::new(Choker::new(SystemClock)));
+    block_on(async {
+        // dual-stack where IPv6 is available, IPv4 only otherwise
+        let addrs = [
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+        ];
+        let listener = tokio::net::TcpListener::bind(&addrs[..])
+            .await
+            .context(format!("listening on port {port}"))?;
+        println!("Seeding {} on port {port}.", output.display());
+        loop {
+            let (stream, addr) = listener.accept().await.context("accepting a peer")?;
+            let (torrent, storage, choker, bitfield) = (
+                torrent.clone(),
+                storage.clone(),
+                choker.clone(),
+                bitfield.clone(),
+            );
+            tokio::spawn(async move {
+                let served = async {
+                    let mut connection = PeerConnection::accept(stream)?;
+                    connection
+                        .accept_handshake(HandShake::new(info_hash))
+                        .await?;
+                    serve_peer(&mut connection, &torrent, &storage, &choker, &bitfield).await
+                };
+                match served.await {
+                    Ok(uploaded) => eprintln!("peer {addr} left after {}", ByteSize(uploaded)),
+                    Err(err) => eprintln!("peer {addr}: {err:#}"),
+                }
+            });
+        }
+    })
+}
+
+/// Keep `info_hash` announced on the DHT as a peer on `port` for as long as the process runs,
+/// joining it through the bootstrap `nodes` again whenever every node of the routing table is gone.
+fn refresh_dht(
+    mut dht: Dht,
+    nodes: &[String],
+    info_hash: [u8; 20],
+    port: u16,
+    external_ip: Option<IpAddr>,
+) {
+    let mut refresh = AnnounceRefresh::new(SystemClock);
+    refresh.add(info_hash);
+    if let Some(ip) = external_ip {
+        refresh.observed_ip(ip);
+    }
+    loop {
+        if dht.table().is_empty() {
+            if let Err(err) = dht.bootstrap(&dht::resolve(nodes)) {
+                eprintln!("warning: joining the DHT: {err:#}");
+            }
+        }
+        for info_hash in refresh.due() {
+            let lookup = dht.get_peers(info_hash);
+            let accepted = dht.announce_peer(info_hash, port, &lookup);
+            if accepted.is_empty() {
+                eprintln!("warning: no DHT node accepted our announce");
+            }
+            refresh.announced(info_hash, accepted);
+        }
+        if let Some(ip) = dht.external_ip() {
+            if refresh.observed_ip(ip.into()) {
+                eprintln!("our address changed to {ip}, announcing it on the DHT");
+                continue;
+            }
+        }
+        thread::sleep(dht::ANNOUNCE_RETRY);
+    }
+}
+
+/// How many pieces of the download of `torrent` at `output` are missing, going by its resume
+/// state when there is one, and re-hashing every piece otherwise: its files are full length from
+/// the start, so their length tells nothing.
+fn missing_pieces(torrent: &Torrent, output: &Path) -> anyhow::Result<usize> {
+    let piece_count = torrent.info.pieces.0.len();
+    let resume_path = ResumeState::path_for(output);
+    if resume_path.exists() {
+        check_resume_state(torrent, &resume_path)?;
+        let resume = ResumeState::load(&resume_path)?;
+        return Ok((0..piece_count)
+            .filter(|&piece_index| !resume.has_piece(piece_index))
+            .count());
+    }
+
+    let mut storage = Storage::open_read_only(torrent, output)?;
+    let mut piece = vec![0u8; torrent.info.piece_length];
+    Ok((0..piece_count)
+        .filter(|&piece_index| {
+            verify_stored_piece(torrent, &mut storage, piece_index, &mut piece).is_err()
+        })
+        .count())
+}
+
+/// Stream the file of `span` through SHA-256, failing unless it's full length.
+fn sha256_of_file(span: &FileSpan) -> anyhow::Result<[u8; 32]> {
+    let mut file = File::open(&span.path).context(format!("opening {}", span.path.display()))?;
+    let length = file.metadata().context("reading file metadata")?.len();
+    if length != span.length {
+        bail!(
+            "{} is {length} bytes instead of {}, the download isn't complete",
+            span.path.display(),
+            span.length
+        );
+    }
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        match file
+            .read(&mut buf)
+            .context(format!("reading {}", span.path.display()))?
+        {
+            0 => return Ok(hasher.finalize()),
+            n => hasher.update(&buf[..n]),
+        }
+    }
+}
+
+/// Make sure the resume state at `path`, if any, belongs to `torrent`, before acting on the download
+/// it describes.
+fn check_resume_state(torrent: &Torrent, path: &Path) -> anyhow::Result<()> {
+    if path.exists() && ResumeState::load(path)?.info_hash != torrent.calculate_info_hash() {
+        bail!("{} belongs to another torrent", path.display())
+    }
+    Ok(())
+}
+
+/// Check whether `path` already holds the complete content of `torrent`, by comparing the length
+/// of each of its files and re-hashing every piece.
+///
+/// Pieces recorded in the `cache` for the current [`Fingerprint`] of every file aren't hashed
+/// again.
+fn is_complete_on_disk(
+    torrent: &Torrent,
+    path: &Path,
+    cache: &VerificationCache,
+) -> anyhow::Result<bool> {
+    let files = layout(torrent, path)?;
+    let mut fingerprints = Vec::with_capacity(files.len());
+    for file in &files {
+        match Fingerprint::of(&file.path) {
+            Ok(fingerprint) if fingerprint.length == file.length => fingerprints.push(fingerprint),
+            _ => return Ok(false),
+        }
+    }
+
+    let info_hash = torrent.calculate_info_hash();
+    let piece_count = torrent.info.pieces.0.len();
+    let mut verified = cache
+        .get(info_hash, &fingerprints)
+        .unwrap_or_else(|| vec![0; piece_count.div_ceil(8)]);
+
+    let mut storage = Storage::open_read_only(torrent, path).context("opening existing output")?;
+    let mut piece = vec![0u8; torrent.info.piece_length];
+    let mut complete = true;
+    for piece_index in 0..piece_count {
+        let mask = 0x80 >> (piece_index % 8);
+        if verified[piece_index / 8] & mask != 0 {
+            continue;
+        }
+
+        let piece = storage
+            .read_piece(piece_index, &mut piece)
+            .context(format!("reading piece {piece_index} from existing output"))?;
+        if validate_piece(torrent, piece_index, piece).is_err() {
+            complete = false;
+            break;
+        }
+        verified[piece_index / 8] |= mask;
+    }
+
+    if let Err(err) = cache.put(info_hash, fingerprints, &verified) {
+        eprintln!("warning: {err:#}");
+    }
+
+    Ok(complete)
+}
+
+/// Pass `result` through, reporting its error (if any) as a [`SessionEvent::Error`].
+fn report<T>(
+    events: &Sender<SessionEvent>,
+    result: anyhow::Result<T>,
+    peer: Option<SocketAddr>,
+    piece: Option<usize>,
+) -> anyhow::Result<T> {
+    if let Err(err) = &result {
+        let _ = events.send(SessionEvent::error(err, peer, piece));
+        if let Some(peer) = peer.filter(|_| ErrorKind::is_timeout(err)) {
+            let _ = events.send(SessionEvent::PeerTimedOut { peer });
+        }
+    }
+    result
+}
+
+/// How [`download`] behaves, apart from what it downloads from where to where.
+#[derive(Debug, Default)]
+struct DownloadOptions<'a> {
+    /// Keep a JSON dump of every piece's state and availability at this path.
+    dump_piece_map: Option<&'a Path>,
+    quota: Quota,
+    /// Write pieces without checking their hashes, only meant for benchmarking.
+    no_verify: bool,
+    /// Faults to inject, for soak testing.
+    chaos: ChaosConfig,
+    /// Print how long every stage of the pieces took once done.
+    timings: bool,
+    /// Which files to download first, or not at all.
+    priorities: Option<&'a FilePriorities>,
+    /// Keep a JSON summary of the download's progress at this path.
+    status_file: Option<&'a Path>,
+    /// Abort as soon as a piece runs out of retries, instead of downloading everything else first.
+    strict: bool,
+    /// Log every decision of the scheduler along with its state to stderr.
+    debug_scheduler: bool,
+    /// Write an archive of this format to the output instead of the files, in piece order.
+    archive: Option<ArchiveFormat>,
+    /// Re-announce for more peers after this long without any peer unchoking us or sending a
+    /// piece.
+    starvation_timeout: Option<Duration>,
+    /// Re-announce under a new peer id when starved, in case the trackers hold the old one back.
+    rotate_peer_id: bool,
+    /// Don't show the progress line on stderr.
+    quiet: bool,
+    /// When the pieces written are synced to disk.
+    fsync: FsyncPolicy,
+    /// How often [`FsyncPolicy::Interval`] syncs.
+    fsync_interval: Duration,
+}
+
+/// What a peer task, or the verifier checking its pieces, reports back to [`download`].
+#[derive(Debug)]
+enum PeerUpdate {
+    Connected {
+        peer: SocketAddr,
+        peer_id: [u8; 20],
+        bitfield: Bitfield,
+        connect: Duration,
+        unchoke: Duration,
+    },
+    Started {
+        piece_index: usize,
+    },
+    /// The piece was downloaded, and is for the verifier to check.
+    Piece {
+        peer: SocketAddr,
+        piece_index: usize,
+        piece: Vec<u8>,
+        transfer: Duration,
+    },
+    /// Bytes of a piece another peer delivered first during endgame, downloaded in vain.
+    Redundant {
+        bytes: u64,
+    },
+    Verified(Verified<Delivery>),
+    Failed {
+        peer: SocketAddr,
+        piece_index: Option<usize>,
+        err: anyhow::Error,
+    },
+    /// The task ended, after a failure or because there's nothing left for it.
+    Gone,
+}
+
+/// Where a piece handed to the verifier came from.
+#[derive(Debug, Clone, Copy)]
+struct Delivery {
+    peer: SocketAddr,
+    transfer: Duration,
+}
+
+/// Everything a peer task shares with the download driving it.
+#[derive(Debug, Clone)]
+struct PeerTask {
+    torrent: Arc<Torrent>,
+    info_hash: [u8; 20],
+    scheduler: Arc<Mutex<Scheduler<SocketAddr, SystemClock>>>,
+    updates: UnboundedSender<PeerUpdate>,
+    dialer: Dial,
+    pipeline: usize,
+    timeouts: Timeouts,
+    /// The pieces verified and written, for every peer to be told about.
+    verified: broadcast::Sender<usize>,
+    endgame: Arc<Mutex<Endgame<SocketAddr>>>,
+    debug_scheduler: bool,
+}
+
+/// A piece for a peer task to download.
+#[derive(Debug, Clone, Copy)]
+enum Taken {
+    /// Taken from the scheduler, and handed back if the peer fails.
+    Piece(usize),
+    /// Taken by another peer, downloaded too during endgame.
+    Duplicate(usize),
+}
+
+impl PeerTask {
+    /// Download pieces from `peer` until there are none left for it or it fails, handing back the
+    /// piece it was working on in the latter case.
+    async fn run(self, peer: SocketAddr, chaos: Chaos) {
+        let mut current = None;
+        let result = self.download_from(peer, chaos, &mut current).await;
+        self.endgame
+            .lock()
+            .expect("endgame poisoned")
+            .peer_gone(&peer);
+        if let Err(err) = result {
+            let mut scheduler = self.scheduler.lock().expect("scheduler poisoned");
+            if let Some(piece_index) = current {
+                scheduler.requeue(peer, piece_index);
+            }
+            scheduler.leave(&peer);
+            drop(scheduler);
+            let _ = self.updates.send(PeerUpdate::Failed {
+                peer,
+                piece_index: current,
+                err,
+            });
+        } else {
+            self.scheduler
+                .lock()
+                .expect("scheduler poisoned")
+                .leave(&peer);
+        }
+        let _ = self.updates.send(PeerUpdate::Gone);
+    }
+
+    /// The next piece to download from `peer`, keeping `connection` alive while none is ready,
+    /// and telling the peer about the pieces `verified` since. Once every piece left is in
+    /// flight, the pieces of other peers are downloaded too.
+    async fn take(
+        &self,
+        peer: SocketAddr,
+        connection: &mut PeerConnection,
+        verified: &mut broadcast::Receiver<usize>,
+    ) -> anyhow::Result<Option<Taken>> {
+        let mut waiting = false;
+        loop {
+            loop {
+                match verified.try_recv() {
+                    Ok(piece_index) => {
+                        connection
+                            .send(PeerMessage::Have {
+                                piece_index: piece_index as u32,
+                            })
+                            .await?
+                    }
+                    // missing a few `Have`s only makes the peer's picture of us out of date
+                    Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+                    Err(_) => break,
+                }
+            }
+            let assignment = {
+                let mut scheduler = self.scheduler.lock().expect("scheduler poisoned");
+                let assignment = scheduler.take(&peer);
+                // a waiting peer polls, only its first wait is worth logging
+                if self.debug_scheduler && !(waiting && assignment == Assignment::Wait) {
+                    eprintln!(
+                        "scheduler: {peer} -> {assignment:?}; {}",
+                        scheduler.snapshot()
+                    );
+                }
+                assignment
+            };
+            waiting = assignment == Assignment::Wait;
+            match assignment {
+                Assignment::Piece(piece_index) => return Ok(Some(Taken::Piece(piece_index))),
+                Assignment::Wait => {
+                    let in_flight = self
+                        .scheduler
+                        .lock()
+                        .expect("scheduler poisoned")
+                        .endgame(&peer);
+                    let duplicate = self
+                        .endgame
+                        .lock()
+                        .expect("endgame poisoned")
+                        .duplicate(&peer, &in_flight);
+                    if let Some(piece_index) = duplicate {
+                        return Ok(Some(Taken::Duplicate(piece_index)));
+                    }
+                    connection.keep_alive().await?;
+                    time::sleep(Duration::from_millis(50)).await;
+                }
+                Assignment::Done => return Ok(None),
+            }
+        }
+    }
+
+    async fn download_from(
+        &self,
+        peer: SocketAddr,
+        mut chaos: Chaos,
+        current: &mut Option<usize>,
+    ) -> anyhow::Result<()> {
+        let torrent = &self.torrent;
+
+        let mut verified = self.verified.subscribe();
+        let started = Instant::now();
+        let (connection, handshake) =
+            client::connect(&self.dialer, peer, self.info_hash, self.timeouts).await?;
+        let mut connection = connection.pipeline(self.pipeline);
+        let connect = started.elapsed();
+        let started = Instant::now();
+        let bitfield = connection
+            .initiate_download(torrent.info.pieces.0.len())
+            .await?;
+        let _ = self.updates.send(PeerUpdate::Connected {
+            peer,
+            peer_id: handshake.peer_id,
+            bitfield: bitfield.clone(),
+            connect,
+            unchoke: started.elapsed(),
+        });
+        self.scheduler
+            .lock()
+            .expect("scheduler poisoned")
+            .join(peer, bitfield);
+
+        let received = connection.received();
+        while let Some(taken) = self.take(peer, &mut connection, &mut verified).await? {
+            let piece_index = match taken {
+                Taken::Piece(piece_index) => {
+                    *current = Some(piece_index);
+                    piece_index
+                }
+                Taken::Duplicate(piece_index) => piece_index,
+            };
+            let _ = self.updates.send(PeerUpdate::Started { piece_index });
+
+            if let Some(delay) = chaos.delay() {
+                time::sleep(delay).await;
+            }
+            if chaos.disconnect() {
+                connection.shutdown().await?;
+            }
+            let started = Instant::now();
+            let before = received.load(Ordering::Relaxed);
+            let cancelled = || {
+                self.endgame
+                    .lock()
+                    .expect("endgame poisoned")
+                    .is_cancelled(&peer, piece_index)
+            };
+            let piece = connection
+                .download_piece_unless(torrent, piece_index, BLOCK_SIZE, cancelled)
+                .await?;
+            let transfer = started.elapsed();
+            let Some(mut piece) = piece else {
+                *current = None;
+                let bytes = received.load(Ordering::Relaxed) - before;
+                let _ = self.updates.send(PeerUpdate::Redundant { bytes });
+                continue;
+            };
+            chaos.corrupt(&mut piece);
+
+            // the piece stays in flight until the verifier is done with it
+            *current = None;
+            {
+                let mut scheduler = self.scheduler.lock().expect("scheduler poisoned");
+                for piece_index in connection.take_announced() {
+                    scheduler.peer_has(&peer, piece_index as usize);
+                }
+            }
+            let first = self
+                .endgame
+                .lock()
+                .expect("endgame poisoned")
+                .delivered(&peer, piece_index);
+            if !first {
+                let bytes = piece.len() as u64;
+                let _ = self.updates.send(PeerUpdate::Redundant { bytes });
+                continue;
+            }
+            let _ = self.updates.send(PeerUpdate::Piece {
+                peer,
+                piece_index,
+                piece,
+                transfer,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Download the whole content of `torrent` into `output`, from up to [`MAX_PEERS`] peers at once.
+fn download(
+    torrent: &Torrent,
+    output: &Path,
+    trackers: &TrackerArgs,
+    peer_args: &PeerArgs,
+    options: &DownloadOptions,
+    events: &Sender<SessionEvent>,
+) -> anyhow::Result<()> {
+    let mut chaos = Chaos::new(options.chaos);
+    let info_hash = torrent.calculate_info_hash();
+    let mut tiers = trackers.tiers(torrent);
+    let (peers, response) = report(
+        events,
+        match chaos.tracker_failure() {
+            true => Err(InvalidResponse::Failure("injected by chaos mode".to_string()).into()),
+            false => gather_peers(
+                &TrackerRequest::new(torrent.content_length()).event(Event::Started),
+                info_hash,
+                trackers,
+                &mut tiers,
+                peer_args,
+            ),
+        },
+        None,
+        None,
+    )?;
+    if peers.0.is_empty() {
+        bail!("the torrent doesn't have any peers")
+    }
+
+    let mut announcer = Announcer {
+        trackers,
+        tiers,
+        schedule: AnnounceSchedule::new(SystemClock, response.as_ref()),
+        stats: TransferStats::default(),
+        left: torrent.content_length(),
+    };
+    let result = block_on(download_from_peers(
+        torrent,
+        output,
+        peers,
+        &mut announcer,
+        peer_args,
+        options,
+        events,
+    ));
+    if !peer_args.lan_only {
+        let stopped = announcer.notify(Event::Stopped, info_hash);
+        report(events, stopped, None, None).ok();
+    }
+    result
+}
+
+/// The trackers of a running download.
+struct Announcer<'a> {
+    trackers: &'a TrackerArgs,
+    tiers: TrackerTiers,
+    schedule: AnnounceSchedule<SystemClock>,
+    /// What the download transferred, as reported to the trackers.
+    stats: TransferStats,
+    /// How many bytes of the content aren't verified yet.
+    left: usize,
+}
+
+impl Announcer<'_> {
+    /// The request of a regular announce.
+    fn request(&self) -> TrackerRequest {
+        TrackerRequest::new(self.left).stats(&self.stats)
+    }
+
+    /// Announce `request` from within the download, returning the peers that weren't `known` yet,
+    /// and aren't banned.
+    fn announce(
+        &mut self,
+        request: &TrackerRequest,
+        info_hash: [u8; 20],
+        ban: &[SocketAddr],
+        known: &mut HashSet<SocketAddr>,
+    ) -> anyhow::Result<Vec<SocketAddr>> {
+        // a cached response would only hold the peers we already have
+        let trackers = TrackerArgs {
+            no_cache: true,
+            ..self.trackers.clone()
+        };
+        let response =
+            task::block_in_place(|| extract_peers(request, info_hash, &trackers, &mut self.tiers));
+        self.schedule.announced(response.as_ref().ok());
+        Ok(response?
+            .usable_peers(request.crypto_policy())
+            .0
+            .into_iter()
+            .filter(|peer| !ban.contains(peer) && known.insert(*peer))
+            .collect())
+    }
+
+    /// Tell the trackers about `event`, whatever peers they return.
+    fn notify(&mut self, event: Event, info_hash: [u8; 20]) -> anyhow::Result<()> {
+        let request = self.request().event(event);
+        self.announce(&request, info_hash, &[], &mut HashSet::new())
+            .map(drop)
+    }
+}
+
+/// Download from `peers`, asking the trackers for more at their interval or when starved.
+async fn download_from_peers(
+    torrent: &Torrent,
+    output: &Path,
+    mut peers: Peers,
+    announcer: &mut Announcer<'_>,
+    peer_args: &PeerArgs,
+    options: &DownloadOptions<'_>,
+    events: &Sender<SessionEvent>,
+) -> anyhow::Result<()> {
+    let DownloadOptions {
+        dump_piece_map,
+        quota,
+        no_verify,
+        chaos,
+        timings: print_timings,
+        priorities,
+        status_file,
+        strict,
+        debug_scheduler,
+        archive,
+        starvation_timeout,
+        rotate_peer_id,
+        quiet,
+        fsync,
+        fsync_interval,
+    } = *options;
+    let info_hash = torrent.calculate_info_hash();
+    let reputation_path = Reputation::path_for(info_hash);
+    let mut reputation = Reputation::load(&reputation_path);
+
+    let (storage, mut resume): (Box<dyn StorageSink + Send>, _) = match archive {
+        // an archive is written front to back, there is nothing to resume from
+        Some(format) => (
+            report(events, open_archive(torrent, output, format), None, None)?,
+            ResumeState::new(info_hash, torrent.info.pieces.0.len()),
+        ),
+        None => {
+            let (storage, resume) = report(events, open_download(torrent, output), None, None)?;
+            (Box::new(storage), resume)
+        }
+    };
+    let layout = report(events, layout(torrent, output), None, None)?;
+    let mut storage = Fsync::new(storage, layout, fsync, SystemClock).interval(fsync_interval);
+    let checkpoints = !no_verify && archive.is_none();
+    let resume_path = ResumeState::path_for(output);
+    let piece_count = torrent.info.pieces.0.len();
+    let mut checkpointer = Checkpointer::new(SystemClock);
+    let mut piece_map = PieceMap::new(piece_count);
+    let mut timings = Timings::default();
+    let mut peer_failures = HashMap::new();
+
+    let mut scheduler = Scheduler::new(torrent, quota, SystemClock).retry(peer_args.retry_policy());
+    if archive.is_some() {
+        scheduler = scheduler.sequential();
+    }
+    let priorities = priorities
+        .map(|priorities| priorities.for_pieces(torrent))
+        .transpose()?;
+    if let Some(priorities) = &priorities {
+        scheduler.prioritize(priorities);
+    }
+    let is_wanted = |piece_index: usize| {
+        priorities
+            .as_ref()
+            .is_none_or(|priorities| priorities[piece_index] != Priority::Skip)
+    };
+    let wanted = (0..piece_count).filter(|&index| is_wanted(index)).count();
+    let wanted_bytes: u64 = (0..piece_count)
+        .filter(|&index| is_wanted(index))
+        .map(|index| torrent.piece_size(index) as u64)
+        .sum();
+
+    // pieces verified by an earlier run of the download
+    let mut written = 0;
+    let mut resumed_bytes = 0;
+    for piece_index in (0..piece_count).filter(|&index| resume.has_piece(index)) {
+        scheduler.have(piece_index);
+        storage.already_written(
+            (piece_index * torrent.info.piece_length) as u64,
+            torrent.piece_size(piece_index) as u64,
+        );
+        announcer.left -= torrent.piece_size(piece_index);
+        piece_map.set_state(piece_index, PieceState::Verified);
+        if is_wanted(piece_index) {
+            written += 1;
+            resumed_bytes += torrent.piece_size(piece_index) as u64;
+        }
+    }
+    // shared with the verifier writing the pieces
+    let storage = Arc::new(Mutex::new(storage));
+    let sync_storage = || storage.lock().expect("storage poisoned").sync_data();
+    let status_file = status_file.map(|path| StatusFile::new(SystemClock, path));
+    let progress = |pieces_done, bytes_done, peers| Progress {
+        pieces_done,
+        pieces_wanted: wanted,
+        bytes_done,
+        bytes_wanted: wanted_bytes,
+        peers,
+    };
+    let mut status_ticker = time::interval(StatusFile::<SystemClock>::INTERVAL);
+    let mut meter = ProgressMeter::new(SystemClock);
+    let mut status_line =
+        (!quiet).then(|| StatusLine::new(SystemClock, io::stderr(), io::stderr().is_terminal()));
+    let mut progress_ticker = time::interval(PROGRESS_INTERVAL);
+
+    let (updates, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let task = PeerTask {
+        torrent: Arc::new(torrent.clone()),
+        info_hash,
+        scheduler: Arc::new(Mutex::new(scheduler)),
+        updates,
+        dialer: peer_args.proxy.clone(),
+        pipeline: peer_args.pipeline,
+        timeouts: peer_args.timeouts(),
+        verified: broadcast::channel(VERIFIED_BACKLOG).0,
+        endgame: Arc::new(Mutex::new(Endgame::new(torrent, BLOCK_SIZE))),
+        debug_scheduler,
+    };
+    let hasher: Arc<dyn PieceHasher> = match no_verify {
+        true => Arc::new(NoopHasher::new()),
+        false => Arc::new(Sha1Hasher),
+    };
+    let (results, mut verified) = tokio::sync::mpsc::unbounded_channel();
+    let verifier = Verifier::new(
+        verifier::default_workers(),
+        task.torrent.clone(),
+        hasher,
+        storage.clone(),
+        results,
+    );
+    // pieces handed to the verifier that it isn't done with
+    let mut verifying = 0;
+    let mut active = 0;
+    let mut spawned = 0;
+    let mut given_up = 0;
+    // since a peer last unchoked us or sent a piece
+    let mut starvation = Starvation::new(SystemClock, starvation_timeout);
+    let mut max_peers = MAX_PEERS;
+    let mut known: HashSet<SocketAddr> = peers.0.iter().copied().collect();
+    let has_failed = |piece_index| {
+        task.scheduler
+            .lock()
+            .expect("scheduler poisoned")
+            .failed()
+            .contains(&piece_index)
+    };
+
+    // caught for the whole download, so that it stops where it can resume from
+    let mut shutdown = pin!(shutdown_signal());
+    // when the shutdown started, the pieces in flight are finished until the grace period is over
+    let mut stopping: Option<Instant> = None;
+
+    while written + given_up < wanted {
+        // replace peers that are gone, `peers` has the preferred ones last
+        while stopping.is_none() && active < max_peers {
+            let Some(peer) = peers.0.pop() else { break };
+            let chaos = Chaos::new(ChaosConfig {
+                // every task gets its own sequence, reproducibly
+                seed: chaos.seed.map(|seed| seed.wrapping_add(spawned)),
+                ..chaos
+            });
+            tokio::spawn(task.clone().run(peer, chaos));
+            active += 1;
+            spawned += 1;
+        }
+        if active == 0 && verifying == 0 {
+            if stopping.is_some() {
+                break;
+            }
+            if task
+                .scheduler
+                .lock()
+                .expect("scheduler poisoned")
+                .is_exhausted()
+            {
+                if checkpointer.has_unsaved() {
+                    sync_storage()?;
+                    checkpointer.save(&resume, &resume_path)?;
+                }
+                reputation.save(&reputation_path)?;
+                let exhausted = quota
+                    .check_download(&announcer.stats, u64::MAX - announcer.stats.downloaded)
+                    .expect_err("the quota was exhausted");
+                let _ = events.send(SessionEvent::QuotaExhausted(exhausted));
+                return Err(exhausted.into());
+            }
+            if checkpointer.has_unsaved() {
+                sync_storage()?;
+                checkpointer.save(&resume, &resume_path)?;
+            }
+            reputation.save(&reputation_path)?;
+            bail!(
+                "every peer failed or left, {} of {wanted} pieces are missing",
+                wanted - written - given_up
+            );
+        }
+
+        let update = tokio::select! {
+            update = receiver.recv() => update.expect("the download holds a sender"),
+            piece = verified.recv() => {
+                PeerUpdate::Verified(piece.expect("the verifier holds a sender"))
+            }
+            _ = &mut shutdown => {
+                if stopping.is_some() {
+                    break;
+                }
+                stopping = Some(SystemClock.now());
+                let mut scheduler = task.scheduler.lock().expect("scheduler poisoned");
+                scheduler.stop();
+                eprintln!(
+                    "stopping: finishing {} pieces in flight, interrupt again to stop now",
+                    scheduler.in_flight()
+                );
+                drop(scheduler);
+                shutdown.set(shutdown_signal());
+                continue;
+            }
+            _ = status_ticker.tick() => {
+                if stopping.is_some_and(|since| SystemClock.elapsed(since) >= SHUTDOWN_GRACE) {
+                    break;
+                }
+                if let Some(status_file) = &status_file {
+                    let progress =
+                        progress(written, resumed_bytes + announcer.stats.downloaded, active);
+                    if let Err(err) = status_file.write(State::Downloading, progress) {
+                        eprintln!("warning: {err:#}");
+                    }
+                }
+
+                let stalled = starvation.stalled();
+                let starved = starvation.is_starved() && announcer.schedule.may_announce();
+                if !peer_args.lan_only
+                    && stopping.is_none()
+                    && (starved || announcer.schedule.is_due())
+                {
+                    let mut request = announcer.request();
+                    if starved {
+                        request = request.numwant(STARVED_NUMWANT);
+                        if rotate_peer_id {
+                            request = request.peer_id(PeerId::random());
+                        }
+                    }
+                    let found = report(
+                        events,
+                        announcer.announce(&request, info_hash, &peer_args.ban, &mut known),
+                        None,
+                        None,
+                    )
+                    .unwrap_or_default();
+                    if starved {
+                        let _ = events.send(SessionEvent::Starved {
+                            stalled,
+                            peers: active,
+                            found: found.len(),
+                        });
+                        // the peers choking us keep their connections, the new ones get extra slots
+                        max_peers = max_peers.max(active + found.len().min(MAX_PEERS));
+                        starvation.progressed();
+                    }
+                    peers.0.extend(found);
+                }
+                continue;
+            }
+            _ = progress_ticker.tick(), if status_line.is_some() => {
+                let progress =
+                    progress(written, resumed_bytes + announcer.stats.downloaded, active);
+                if let Some(status_line) = &mut status_line {
+                    status_line.draw(&meter.line(progress)).ok();
+                }
+                continue;
+            }
+        };
+        match update {
+            PeerUpdate::Connected {
+                peer,
+                peer_id,
+                bitfield,
+                connect,
+                unchoke,
+            } => {
+                reputation.identified(peer.ip(), peer_id);
+                starvation.progressed();
+                piece_map.add_bitfield(bitfield.as_bytes());
+                timings.record(Stage::Connect, connect);
+                timings.record(Stage::Unchoke, unchoke);
+            }
+            PeerUpdate::Started { piece_index } => {
+                piece_map.set_state(piece_index, PieceState::Downloading);
+                if let Some(path) = dump_piece_map {
+                    piece_map.dump(path)?;
+                }
+            }
+            PeerUpdate::Piece {
+                peer,
+                piece_index,
+                piece,
+                transfer,
+            } => {
+                timings.record(Stage::Transfer, transfer);
+                verifier.submit(piece_index, piece, Delivery { peer, transfer });
+                verifying += 1;
+            }
+            PeerUpdate::Redundant { bytes } => announcer.stats.redundant += bytes,
+            PeerUpdate::Verified(Verified {
+                piece_index,
+                piece_size,
+                verdict,
+                verify,
+                write,
+                tag: Delivery { peer, transfer },
+            }) => {
+                verifying -= 1;
+                timings.record(Stage::Verify, verify);
+                task.endgame
+                    .lock()
+                    .expect("endgame poisoned")
+                    .verified(piece_index, matches!(verdict, Verdict::Written));
+                match verdict {
+                    Verdict::Written => {
+                        task.scheduler
+                            .lock()
+                            .expect("scheduler poisoned")
+                            .finish(piece_index);
+                        let _ = task.verified.send(piece_index);
+                    }
+                    Verdict::WriteFailed(err) => {
+                        report(events, Err::<(), _>(err), None, Some(piece_index))?;
+                    }
+                    Verdict::Corrupt(err) => {
+                        task.scheduler
+                            .lock()
+                            .expect("scheduler poisoned")
+                            .requeue(peer, piece_index);
+                        let err = report(events, Err::<(), _>(err), Some(peer), Some(piece_index))
+                            .expect_err("reporting an error");
+                        // the piece was handed back and is downloaded again, unless out of retries
+                        announcer.stats.corrupt += piece_size as u64;
+                        reputation.peer(peer.ip()).corrupt += 1;
+                        piece_map.set_state(piece_index, PieceState::Missing);
+                        if has_failed(piece_index) {
+                            given_up += 1;
+                            if strict {
+                                blame(&mut reputation, &reputation_path, &peer);
+                                return Err(err);
+                            }
+                        }
+                        continue;
+                    }
+                }
+
+                timings.record(Stage::Write, write);
+                written += 1;
+                starvation.progressed();
+                let piece_size = piece_size as u64;
+                meter.record(peer, piece_size);
+                announcer.stats.downloaded += piece_size;
+                announcer.left -= piece_size as usize;
+                timings.piece_done();
+                reputation
+                    .peer(peer.ip())
+                    .delivered(piece_size, transfer + verify);
+                let _ = events.send(SessionEvent::PieceVerified { piece: piece_index });
+
+                piece_map.set_state(piece_index, PieceState::Verified);
+                if let Some(path) = dump_piece_map {
+                    piece_map.dump(path)?;
+                }
+
+                // unchecked pieces must never be trusted by a later resume
+                if checkpoints {
+                    resume.set_piece(piece_index);
+                    if checkpointer.piece_verified() || written + given_up == wanted {
+                        // the checkpoint must never claim pieces that aren't durable yet
+                        sync_storage()?;
+                        checkpointer.save(&resume, &resume_path)?;
+                    }
+                }
+            }
+            PeerUpdate::Failed {
+                peer,
+                piece_index,
+                err,
+            } => {
+                let err = report(events, Err::<(), _>(err), Some(peer), piece_index)
+                    .expect_err("reporting an error");
+                blame(&mut reputation, &reputation_path, &peer);
+                if let Some(piece_index) = piece_index {
+                    piece_map.set_state(piece_index, PieceState::Missing);
+                    if has_failed(piece_index) {
+                        given_up += 1;
+                        if strict {
+                            return Err(err);
+                        }
+                    }
+                }
+                // reconnect later, after the peers that weren't tried yet
+                let failures = peer_failures.entry(peer).or_insert(0);
+                *failures += 1;
+                if *failures < MAX_PEER_FAILURES {
+                    peers.0.insert(0, peer);
+                }
+            }
+            PeerUpdate::Gone => active -= 1,
+        }
+    }
+    // end the progress line before anything else is printed
+    drop(status_line);
+
+    if stopping.is_some() && written + given_up < wanted {
+        // the connections of the peers still at it close as the runtime drops their tasks
+        if checkpoints {
+            sync_storage()?;
+            checkpointer.save(&resume, &resume_path)?;
+        }
+        reputation.save(&reputation_path)?;
+        if let Some(status_file) = &status_file {
+            let progress = progress(written, resumed_bytes + announcer.stats.downloaded, active);
+            status_file.write(State::Stopped, progress)?;
+        }
+        return Err(Interrupted.into());
+    }
+
+    if print_timings {
+        eprintln!("{timings}");
+    }
+    if given_up > 0 {
+        if checkpointer.has_unsaved() {
+            sync_storage()?;
+            checkpointer.save(&resume, &resume_path)?;
+        }
+        reputation.save(&reputation_path)?;
+        let pieces = task
+            .scheduler
+            .lock()
+            .expect("scheduler poisoned")
+            .failed()
+            .to_vec();
+        return Err(PiecesFailed { pieces, wanted }.into());
+    }
+    let finished = storage.lock().expect("storage poisoned").finish();
+    report(events, finished, None, None)?;
+    if let Some(status_file) = &status_file {
+        status_file.write(
+            State::Complete,
+            progress(written, resumed_bytes + announcer.stats.downloaded, active),
+        )?;
+    }
+    // only a download finishing now completes, not one resuming an already complete one
+    if announcer.left == 0 && announcer.stats.downloaded > 0 && !peer_args.lan_only {
+        let completed = announcer.notify(Event::Completed, info_hash);
+        report(events, completed, None, None).ok();
+    }
+    reputation.save(&reputation_path)
+}
+
+/// Wait for Ctrl-C, or for SIGTERM on unix.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if signal::ctrl_c().await.is_err() {
+            future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => drop(terminate.recv().await),
+            Err(_) => future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+/// Record a failure of `peer` in its reputation, saving it right away since the failure may abort
+/// the download.
+fn blame(reputation: &mut Reputation, path: &Path, peer: &SocketAddr) {
+    reputation.peer(peer.ip()).failures += 1;
+    if let Err(err) = reputation.save(path) {
+        eprintln!("warning: {err:#}");
+    }
+}
+
+/// What a peer watched by [`observe`] reports.
+enum Sighting {
+    Exchanged(Vec<SocketAddr>),
+    Observed(SocketAddr, Observation),
+    Unreachable(SocketAddr, anyhow::Error),
+}
+
+/// Watch the swarm of `torrent` for `duration`, connecting to the peers of the trackers and to
+/// those they exchange, without requesting any piece.
+fn observe(
+    torrent: &Torrent,
+    duration: Duration,
+    trackers: &TrackerArgs,
+    peer_args: &PeerArgs,
+) -> anyhow::Result<Census> {
+    let info_hash = torrent.calculate_info_hash();
+    let (peers, _) = gather_peers(
+        &TrackerRequest::new(torrent.content_length()),
+        info_hash,
+        trackers,
+        &mut trackers.tiers(torrent),
+        peer_args,
+    )?;
+    let piece_count = torrent.info.pieces.0.len();
+    let mut census = Census::new(piece_count, duration);
 
-    match bencode {
-        Bytes(bytes) => JsonValue::String(String::from_utf8_lossy(bytes).into()),
-        Int(num) => JsonValue::Number(serde_json::value::Number::from(*num)),
-        List(list) => {
-            let mut arr = Vec::new();
-            for elem in list {
-                arr.push(bencode_to_json(elem));
+    block_on(async {
+        let deadline = time::Instant::now() + duration;
+        let (sightings, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut seen = HashSet::new();
+        let mut watching = 0;
+        let mut watch = |peer: SocketAddr, watching: &mut usize| {
+            if peer_args.ban.contains(&peer) || seen.len() >= MAX_OBSERVED || !seen.insert(peer) {
+                return;
             }
-            JsonValue::Array(arr)
+            *watching += 1;
+            let sightings = sightings.clone();
+            let dialer = peer_args.proxy.clone();
+            tokio::spawn(async move {
+                let sighting =
+                    match watch_peer(&dialer, peer, info_hash, piece_count, deadline, &sightings)
+                        .await
+                    {
+                        Ok(observation) => Sighting::Observed(peer, observation),
+                        Err(err) => Sighting::Unreachable(peer, err),
+                    };
+                let _ = sightings.send(sighting);
+            });
+        };
+        for peer in peers.0.into_iter().rev() {
+            watch(peer, &mut watching);
         }
-        Dict(dict) => {
-            let mut map = serde_json::value::Map::new();
 
-            for (key, value) in dict {
-                let key = String::from_utf8_lossy(key);
-                let value = bencode_to_json(value);
-                map.insert(key.into(), value);
+        while watching > 0 {
+            match receiver.recv().await.expect("observe holds a sender") {
+                Sighting::Exchanged(peers) => {
+                    if time::Instant::now() < deadline {
+                        for peer in peers {
+                            watch(peer, &mut watching);
+                        }
+                    }
+                }
+                Sighting::Observed(peer, observation) => {
+                    census.observed(peer, observation);
+                    watching -= 1;
+                }
+                Sighting::Unreachable(peer, err) => {
+                    census.unreachable(peer, format!("{err:#}"));
+                    watching -= 1;
+                }
             }
-
-            JsonValue::Object(map)
         }
-    }
+    });
+    Ok(census)
 }
 
-fn urlencode<B: AsRef<[u8]>>(bytes: B) -> String {
-    let mut result = String::with_capacity(3 * bytes.as_ref().len());
-    for &byte in bytes.as_ref() {
-        result.push('%');
-        result.push_str(&hex::encode([byte]));
-    }
-    result
+/// How long a `debug_peer` session keeps printing what the peer sends once stdin is closed.
+const DEBUG_LINGER: Duration = Duration::from_secs(2);
+
+/// Handshake with `peer`, then send it the messages of the commands read from stdin while
+/// printing every message it sends, until stdin is closed or either side quits.
+fn debug_peer(torrent: &Torrent, peer: SocketAddr, dialer: &Dial) -> anyhow::Result<()> {
+    let info_hash = torrent.calculate_info_hash();
+    let piece_count = torrent.info.pieces.0.len();
+
+    // reading stdin blocks, so it's done on a thread of its own
+    let (lines, mut commands) = tokio::sync::mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if lines.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    block_on(async {
+        let connection = PeerConnection::connect(dialer, peer).await?;
+        // the peer may be left alone for as long as the user thinks, keep-alives still go out
+        let mut connection = connection.idle_timeout(Duration::from_secs(24 * 60 * 60));
+        let handshake = connection
+            .handshake(HandShake::new(info_hash).extension_protocol())
+            .await?;
+        println!(
+            "connected to {peer} ({}), type `help` for the commands",
+            handshake.client().as_deref().unwrap_or("unknown client")
+        );
+
+        loop {
+            tokio::select! {
+                line = commands.recv() => {
+                    let Some(line) = line else {
+                        while let Ok(message) = time::timeout(DEBUG_LINGER, connection.recv()).await {
+                            println!("<- {}", describe(&message?, piece_count));
+                        }
+                        return Ok(());
+                    };
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match line.parse() {
+                        Ok(Command::Send(message)) => {
+                            println!("-> {}", describe(&message, piece_count));
+                            connection.send(message).await?;
+                        }
+                        Ok(Command::Help) => println!("{HELP}"),
+                        Ok(Command::Quit) => return Ok(()),
+                        Err(err) => eprintln!("{err}"),
+                    }
+                }
+                message = connection.recv() => {
+                    println!("<- {}", describe(&message?, piece_count));
+                }
+            }
+        }
+    })
 }
 
-fn extract_peers(torrent: &Torrent, info_hash: Option<[u8; 20]>) -> anyhow::Result<Peers> {
-    let tracker_url = {
-        let announce = &torrent.announce;
-        let info_hash_url = urlencode(info_hash.unwrap_or_else(|| torrent.calculate_info_hash()));
-        let tracker_request = TrackerRequest::new(torrent.content_length());
-        let tracker_request =
-            serde_urlencoded::to_string(tracker_request).context("url-encoding tracker")?;
-        format!("{announce}?{tracker_request}&info_hash={info_hash_url}")
+/// Listen to `peer` until `deadline`, only ever sending it the handshakes.
+async fn watch_peer(
+    dialer: &Dial,
+    peer: SocketAddr,
+    info_hash: [u8; 20],
+    piece_count: usize,
+    deadline: time::Instant,
+    sightings: &UnboundedSender<Sighting>,
+) -> anyhow::Result<Observation> {
+    let (mut connection, handshake) = time::timeout_at(deadline, async {
+        let mut connection = PeerConnection::connect(dialer, peer).await?;
+        let handshake = connection
+            .handshake(HandShake::new(info_hash).extension_protocol())
+            .await?;
+        anyhow::Ok((connection, handshake))
+    })
+    .await
+    .context("the observation ended first")??;
+    let mut observation = Observation {
+        client: handshake.client(),
+        ..Observation::default()
     };
 
-    let response = reqwest::blocking::get(tracker_url)
-        .context("tracker get request")?
-        .bytes()
-        .context("reading response bytes")?;
-    let response: TrackerResponse =
-        serde_bencode::from_bytes(&response).context("bendecoding response")?;
+    if handshake.supports_extension_protocol() {
+        let mut ours = ExtensionHandshake::ours();
+        ours.m.insert(UT_PEX.to_string(), UT_PEX_ID);
+        connection
+            .send(PeerMessage::Extended {
+                ext_id: extension::HANDSHAKE_ID,
+                payload: ours.to_bytes(),
+            })
+            .await?;
+    }
 
-    Ok(response.peers)
+    let left = deadline.saturating_duration_since(time::Instant::now());
+    let mut connection = connection.idle_timeout(left + Duration::from_secs(1));
+    loop {
+        let message = match time::timeout_at(deadline, connection.recv()).await {
+            Err(_) => break,
+            Ok(Err(err)) => {
+                observation.error = Some(format!("{err:#}"));
+                break;
+            }
+            Ok(Ok(message)) => message,
+        };
+        if let Err(err) = message.validate(piece_count) {
+            observation.error = Some(err.to_string());
+            break;
+        }
+        match message {
+            PeerMessage::Bitfield { fields } => observation.bitfield = Some(Bitfield::from(fields)),
+            PeerMessage::Have { piece_index } if observation.set_piece(piece_index as usize) => {
+                observation.haves += 1;
+            }
+            PeerMessage::Extended {
+                ext_id: extension::HANDSHAKE_ID,
+                payload,
+            } => {
+                if let Ok(theirs) = ExtensionHandshake::from_bytes(&payload) {
+                    connection.note_extension_handshake(&theirs);
+                    observation.extensions = theirs.extensions().map(String::from).collect();
+                    if theirs.v.is_some() {
+                        observation.client = theirs.v;
+                    }
+                }
+            }
+            PeerMessage::Extended {
+                ext_id: UT_PEX_ID,
+                payload,
+            } => {
+                if let Ok(pex) = PexMessage::from_bytes(&payload) {
+                    let added: Vec<_> = pex.added.0.into_iter().chain(pex.added6.0).collect();
+                    observation.exchanged.extend(&added);
+                    let _ = sightings.send(Sighting::Exchanged(added));
+                }
+            }
+            _ => {}
+        }
+    }
+    observation.capabilities = connection.capabilities();
+    Ok(observation)
 }
 
-type PeerId = [u8; 20];
-
-fn establish_handshake(
+/// Report what [`download`] would do: which peers answer and what they have, and which peers the
+/// pieces would be fetched from, without requesting any piece data.
+fn plan_download(
     torrent: &Torrent,
-    peer: &SocketAddrV4,
-    info_hash: Option<[u8; 20]>,
-) -> anyhow::Result<(TcpStream, PeerId)> {
-    let mut stream = TcpStream::connect(peer).context("establishing connection with peer")?;
-    let handshake = HandShake::new(info_hash.unwrap_or_else(|| torrent.calculate_info_hash()));
+    trackers: &TrackerArgs,
+    peer_args: &PeerArgs,
+) -> anyhow::Result<()> {
+    let info_hash = torrent.calculate_info_hash();
+    let (peers, _) = gather_peers(
+        &TrackerRequest::new(torrent.content_length()),
+        info_hash,
+        trackers,
+        &mut trackers.tiers(torrent),
+        peer_args,
+    )?;
+    let piece_count = torrent.info.pieces.0.len();
+    let mut piece_map = PieceMap::new(piece_count);
+
+    // `download` pops its peers from the back
+    for peer in peers.0.iter().rev() {
+        let bitfield = block_on(async {
+            let (connection, handshake) =
+                client::connect(&peer_args.proxy, *peer, info_hash, peer_args.timeouts()).await?;
+            let mut connection = connection.idle_timeout(Duration::from_secs(10));
+            let client = handshake
+                .client()
+                .unwrap_or_else(|| hex::encode(handshake.peer_id));
+            let message = connection
+                .recv_skipping_keep_alives()
+                .await
+                .context("waiting for bitfield")?;
+            message.validate(piece_count)?;
+            match message {
+                PeerMessage::Bitfield { fields } => Ok((client, fields)),
+                message => bail!("expected a Bitfield but found a {message:?}"),
+            }
+        });
+        match bitfield {
+            Ok((client, bitfield)) => {
+                let mut peer_map = PieceMap::new(piece_count);
+                peer_map.add_bitfield(&bitfield);
+                piece_map.add_bitfield(&bitfield);
+                let has = peer_map.availability.iter().filter(|&&n| n > 0).count();
+                println!("Peer {peer} ({client}): has {has}/{piece_count} pieces");
+            }
+            Err(err) => println!("Peer {peer}: unusable: {err:#}"),
+        }
+    }
+
+    let first: Vec<_> = peers
+        .0
+        .iter()
+        .rev()
+        .take(MAX_PEERS)
+        .map(ToString::to_string)
+        .collect();
+    match first.is_empty() {
+        true => println!("Would fail: the torrent doesn't have any peers"),
+        false => println!(
+            "Would download {piece_count} pieces ({}) from {}",
+            ByteSize(torrent.content_length() as u64),
+            first.join(", ")
+        ),
+    }
+    let missing: Vec<_> = (0..piece_count)
+        .filter(|&piece_index| piece_map.availability[piece_index] == 0)
+        .map(|piece_index| piece_index.to_string())
+        .collect();
+    if !missing.is_empty() {
+        println!("No reachable peer has pieces {}", missing.join(", "));
+    }
 
-    let mut bytes: [u8; 68] = handshake.into();
-    stream.write_all(&bytes).context("sending handshake")?;
-    stream.read(&mut bytes).context("receiving handshake")?;
-    let handshake: HandShake = bytes.try_into().context("converting handshake")?;
-    Ok((stream, handshake.peer_id))
+    Ok(())
 }
 
 #[derive(Debug, Parser)]
@@ -108,16 +2103,92 @@ enum SubCommand {
     Decode {
         /// The bencoded data
         bencode: String,
+        /// Decode concatenated values into a JSON array
+        #[clap(long)]
+        multi: bool,
     },
     /// Extract torrent file info
     Info {
         /// Path to the torrent file
         file_path: PathBuf,
+        /// Only print the info hash, of the info dictionary exactly as it's encoded in the file
+        #[clap(long = "hash-only")]
+        hash_only: bool,
+    },
+    /// Create a torrent file of a file or directory
+    Create {
+        /// The file or directory to share
+        path: PathBuf,
+        /// Announce URL of the tracker
+        #[clap(long)]
+        announce: String,
+        /// Length of the pieces, e.g. `256KiB`
+        #[clap(
+            long = "piece-length",
+            value_name = "SIZE",
+            default_value_t = ByteSize(TorrentBuilder::DEFAULT_PIECE_LENGTH as u64)
+        )]
+        piece_length: ByteSize,
+        /// Path to write the torrent file to, `<name>.torrent` by default
+        #[clap(short, long)]
+        output: Option<PathBuf>,
     },
     /// Extract torrent file peers
     Peers {
         /// Path to the torrent file
         file_path: PathBuf,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+        /// Connect and handshake with every peer listed, printing how long it took
+        #[clap(long)]
+        probe: bool,
+        /// List the peers by `ip`, `port` or `latency` (with `--probe`), instead of as the tracker
+        /// returned them
+        #[clap(long, value_name = "ORDER")]
+        sort: Option<PeerSort>,
+        /// List at most N peers
+        #[clap(long, value_name = "N")]
+        limit: Option<usize>,
+        /// Only list the peers in this network, e.g. `10.0.0.0/8` (repeatable)
+        #[clap(long = "filter-cidr", value_name = "CIDR")]
+        filter_cidr: Vec<Cidr>,
+    },
+    /// Watch a torrent's swarm without downloading anything, and report on its peers, their
+    /// pieces and clients
+    Observe {
+        /// Path to the torrent file
+        file_path: PathBuf,
+        /// How long to watch the swarm for, e.g. `90s` or `1h`
+        #[clap(long, value_name = "DURATION", default_value = "5m")]
+        duration: Span,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+        #[command(flatten)]
+        peers: PeerArgs,
+    },
+    /// Sample a torrent's swarm for its seeds and the availability of its pieces, and with
+    /// `--watch` keep sampling it, reporting what changed since the previous sample
+    Health {
+        /// Path to the torrent file
+        file_path: PathBuf,
+        /// Keep sampling the swarm until interrupted
+        #[clap(long)]
+        watch: bool,
+        /// How long from the start of a sample to the start of the next, with `--watch`
+        #[clap(
+            long,
+            value_name = "DURATION",
+            default_value = "60s",
+            requires = "watch"
+        )]
+        interval: Span,
+        /// How long each sample watches the swarm for
+        #[clap(long, value_name = "DURATION", default_value = "20s")]
+        sample: Span,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+        #[command(flatten)]
+        peers: PeerArgs,
     },
     /// Establish a peer handshake for a given torrent file
     #[clap(name = "handshake")]
@@ -125,7 +2196,27 @@ enum SubCommand {
         /// Path to the torrent file
         file_path: PathBuf,
         /// Add of the peer
-        peer: SocketAddrV4,
+        peer: SocketAddr,
+        /// Also print the peer's reserved bits, capabilities and client
+        #[clap(long, conflicts_with = "json")]
+        details: bool,
+        /// Print everything learned from the handshake as json
+        #[clap(long)]
+        json: bool,
+        /// How to connect to the peer, `direct` or `socks5://[user:password@]host:port`
+        #[clap(long, value_name = "URL", default_value = "direct")]
+        proxy: Dial,
+    },
+    /// Handshake with a peer, then send it the messages typed on stdin and print every message it
+    /// sends
+    DebugPeer {
+        /// Path to the torrent file
+        file_path: PathBuf,
+        /// Address of the peer
+        peer: SocketAddr,
+        /// How to connect to the peer, `direct` or `socks5://[user:password@]host:port`
+        #[clap(long, value_name = "URL", default_value = "direct")]
+        proxy: Dial,
     },
     /// Download a specific piece from a torrent
     DownloadPiece {
@@ -136,6 +2227,13 @@ enum SubCommand {
         file_path: PathBuf,
         /// Piece index to download
         piece_index: usize,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+        #[command(flatten)]
+        peers: PeerArgs,
+        /// Don't show the progress line
+        #[clap(short, long)]
+        quiet: bool,
     },
     /// Download a  torrent
     Download {
@@ -144,111 +2242,810 @@ enum SubCommand {
         output: PathBuf,
         /// Path to the torrent file
         file_path: PathBuf,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+        #[command(flatten)]
+        peers: PeerArgs,
+        /// Keep a JSON dump of every piece's state and availability at this path
+        #[clap(long = "dump-piece-map", value_name = "PATH")]
+        dump_piece_map: Option<PathBuf>,
+        /// Stop (with exit code 3) before downloading more than this much, e.g. `2GiB`
+        #[clap(long = "download-quota", value_name = "SIZE")]
+        download_quota: Option<ByteSize>,
+        /// DANGEROUS: skip checking piece hashes, corrupt data is written as is (benchmarking only)
+        #[clap(long = "no-verify")]
+        no_verify: bool,
+        /// Only announce, handshake and collect bitfields, then report what would be downloaded
+        #[clap(long = "dry-run")]
+        dry_run: bool,
+        /// Inject faults, e.g. `disconnect=0.1,delay=0.2,max-delay-ms=500,corrupt=0.05,tracker=0.1,seed=42`
+        #[clap(long, hide = true, value_name = "SPEC")]
+        chaos: Option<ChaosConfig>,
+        /// Print a breakdown of the time spent connecting, waiting, transferring, verifying and
+        /// writing
+        #[clap(long)]
+        timings: bool,
+        /// Per-file priorities, e.g. `0:high,3:skip` (levels: high, normal, low, skip)
+        #[clap(long, value_name = "FILE:LEVEL,...")]
+        priority: Option<FilePriorities>,
+        /// Keep a JSON summary of the progress, rates, peers and ETA at this path, updated every
+        /// few seconds
+        #[clap(long = "status-file", value_name = "PATH")]
+        status_file: Option<PathBuf>,
+        /// Abort as soon as a piece fails too many times, instead of downloading every other piece
+        /// and exiting with code 4
+        #[clap(long)]
+        strict: bool,
+        /// Log every piece the scheduler hands out, with the pending queue and the pieces in
+        /// flight with every peer, to stderr
+        #[clap(long = "debug-scheduler")]
+        debug_scheduler: bool,
+        /// Write a `tar` or `zip` archive of the files to the output (`-` for stdout) instead of
+        /// the files themselves, downloading the pieces in order
+        #[clap(long, value_name = "FORMAT", conflicts_with = "priority")]
+        archive: Option<ArchiveFormat>,
+        /// Ask the trackers for more peers after this long without any peer unchoking us or
+        /// sending a piece
+        #[clap(
+            long = "starvation-timeout",
+            value_name = "DURATION",
+            default_value = "2m"
+        )]
+        starvation_timeout: Span,
+        /// Also announce under a new peer id when starved
+        #[clap(long = "rotate-peer-id")]
+        rotate_peer_id: bool,
+        /// Don't show the progress line
+        #[clap(short, long)]
+        quiet: bool,
+        /// When to sync the pieces written to disk: after every `piece`, every `file` once
+        /// complete, every `--fsync-interval`, or `never`, leaving it to the OS
+        #[clap(long, value_name = "POLICY", default_value = "file")]
+        fsync: FsyncPolicy,
+        /// How often `--fsync interval` syncs
+        #[clap(
+            long = "fsync-interval",
+            value_name = "DURATION",
+            default_value = "30s"
+        )]
+        fsync_interval: Span,
+        /// Keep the data in this directory while it downloads, moving it to the output once
+        /// complete
+        #[clap(
+            long = "incomplete-dir",
+            value_name = "DIR",
+            conflicts_with_all = ["priority", "archive"]
+        )]
+        incomplete_dir: Option<PathBuf>,
+    },
+    /// Bundle a download's torrent and resume state into a snapshot file
+    Export {
+        /// Path the torrent is downloaded to
+        #[clap(short, long)]
+        output: PathBuf,
+        /// Path to the torrent file
+        file_path: PathBuf,
+        /// Path to write the snapshot to
+        snapshot: PathBuf,
+    },
+    /// Restore the torrent and resume state of a snapshot into a directory
+    Import {
+        /// Path to the snapshot file
+        snapshot: PathBuf,
+        /// Directory holding (or about to hold) the download's data
+        directory: PathBuf,
+    },
+    /// Upload the verified pieces of a download to the peers connecting to us, until interrupted
+    Seed {
+        /// Path the torrent is downloaded to
+        #[clap(short, long)]
+        output: PathBuf,
+        /// Path to the torrent file
+        file_path: PathBuf,
+        /// Port to listen on for peers, and for the DHT with `--dht`
+        #[clap(long, default_value_t = 6881)]
+        port: u16,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+        /// Also announce on the DHT, refreshing the announce as long as seeding
+        #[clap(long)]
+        dht: bool,
+        /// Node to join the DHT through, as `host:port`, instead of the well-known ones
+        #[clap(long = "bootstrap-node", value_name = "HOST:PORT", requires = "dht")]
+        bootstrap_nodes: Vec<String>,
+    },
+    /// Print the SHA-256 of every file of a completed download, in the format of sha256sum
+    Checksums {
+        /// Path to the torrent file
+        file_path: PathBuf,
+        /// Path the torrent is downloaded to
+        output: PathBuf,
+    },
+    /// Re-hash every piece of a download and report which ones are valid, exiting with code 1 if
+    /// any isn't
+    Verify {
+        /// Path to the torrent file
+        file_path: PathBuf,
+        /// Path the torrent is downloaded to
+        output: PathBuf,
+    },
+    /// Find the peers of an info hash on the DHT, without any tracker
+    DhtPeers {
+        /// The info hash, in hex
+        info_hash: String,
+        /// Node to join the DHT through, as `host:port`, instead of the well-known ones
+        #[clap(long = "bootstrap-node", value_name = "HOST:PORT")]
+        bootstrap_nodes: Vec<String>,
+        /// UDP port of our node, any free one by default
+        #[clap(long, default_value_t = 0)]
+        port: u16,
+    },
+    /// Move a download, along with its resume state, to another directory
+    Move {
+        /// Path the torrent is downloaded to
+        #[clap(short, long)]
+        output: PathBuf,
+        /// Path to the torrent file
+        file_path: PathBuf,
+        /// Directory to move the download to
+        directory: PathBuf,
+    },
+    /// Forget a download's resume state, and optionally delete its data
+    Remove {
+        /// Path the torrent is downloaded to
+        #[clap(short, long)]
+        output: PathBuf,
+        /// Path to the torrent file
+        file_path: PathBuf,
+        /// Also delete the downloaded data
+        #[clap(long = "with-data")]
+        with_data: bool,
+    },
+    /// Parse a magnet link
+    MagnetParse {
+        /// The magnet link
+        link: MagnetLink,
+    },
+    /// Establish a peer handshake and extension handshake for a magnet link
+    MagnetHandshake {
+        /// The magnet link
+        link: MagnetLink,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+        #[command(flatten)]
+        peers: PeerArgs,
+    },
+    /// Fetch the metadata of a magnet link from its peers and print it
+    MagnetInfo {
+        /// The magnet link
+        link: MagnetLink,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+        #[command(flatten)]
+        peers: PeerArgs,
+    },
+    /// Download a single piece of a magnet link
+    MagnetDownloadPiece {
+        /// Path to place the piece in
+        #[clap(short, long)]
+        output: PathBuf,
+        /// The magnet link
+        link: MagnetLink,
+        /// Piece index to download
+        piece_index: usize,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+        #[command(flatten)]
+        peers: PeerArgs,
+    },
+    /// Download the content of a magnet link
+    MagnetDownload {
+        /// Path to place the content
+        #[clap(short, long)]
+        output: PathBuf,
+        /// The magnet link
+        link: MagnetLink,
+        #[command(flatten)]
+        trackers: TrackerArgs,
+        #[command(flatten)]
+        peers: PeerArgs,
     },
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<ExitCode> {
     let cli = Cli::parse();
 
     match cli.command {
-        SubCommand::Decode { bencode } => {
-            let value =
-                serde_bencode::from_str::<BenValue>(&bencode).context("bencode decoding")?;
-            println!("{}", bencode_to_json(&value));
+        SubCommand::Decode { bencode, multi } => {
+            let data = bencode.as_bytes();
+            if multi {
+                let values = decode_all(data).context("bencode decoding")?;
+                let values = values.iter().map(bencode_to_json).collect();
+                println!("{}", JsonValue::Array(values));
+            } else {
+                let (value, consumed) = decode_prefix(data).context("bencode decoding")?;
+                println!("{}", bencode_to_json(&value));
+                if consumed < data.len() {
+                    eprintln!(
+                        "warning: ignored {} trailing bytes after the first {consumed}, use --multi to decode them",
+                        data.len() - consumed
+                    );
+                }
+            }
         }
-        SubCommand::Info { file_path } => {
-            let buf = read(file_path).context("opening torrent file")?;
-            let torrent: Torrent = serde_bencode::from_bytes(&buf).context("parse torrent file")?;
+        SubCommand::Info {
+            file_path,
+            hash_only: true,
+        } => {
+            let bytes = read(&file_path).context("opening torrent file")?;
+            println!("{}", hex::encode(info_hash_from_bytes(&bytes)?));
+        }
+        SubCommand::Info { file_path, .. } => {
+            let torrent = read_torrent(&file_path)?;
             println!("{torrent}");
         }
-        SubCommand::Peers { file_path } => {
-            let buf = read(file_path).context("opening torrent file")?;
-            let torrent: Torrent = serde_bencode::from_bytes(&buf).context("parse torrent file")?;
+        SubCommand::Create {
+            path,
+            announce,
+            piece_length,
+            output,
+        } => {
+            let creation_date = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs() as i64);
+            let torrent = TorrentBuilder::from_path(announce, &path)?
+                .piece_length(piece_length.0 as usize)
+                .creation_date(creation_date)
+                .build()?;
+            let output =
+                output.unwrap_or_else(|| PathBuf::from(format!("{}.torrent", torrent.info.name)));
+            if output.exists() {
+                bail!("{} already exists", output.display());
+            }
+            fs::write(&output, torrent.to_bytes())
+                .context(format!("writing {}", output.display()))?;
+            println!(
+                "Created {} with {} pieces, info hash {}.",
+                output.display(),
+                torrent.info.pieces.0.len(),
+                hex::encode(torrent.calculate_info_hash())
+            );
+        }
+        SubCommand::Peers {
+            file_path,
+            trackers,
+            probe,
+            sort,
+            limit,
+            filter_cidr,
+        } => {
+            let sort = sort.unwrap_or_default();
+            if sort == PeerSort::Latency && !probe {
+                bail!("sorting by latency needs --probe");
+            }
+            let torrent = read_torrent(&file_path)?;
 
-            for peer in extract_peers(&torrent, None)?.0.iter() {
-                println!("{peer}");
+            let mut tiers = trackers.tiers(&torrent);
+            let info_hash = torrent.calculate_info_hash();
+            let request = TrackerRequest::new(torrent.content_length());
+            let mut peers = PeerList::from(
+                extract_peers(&request, info_hash, &trackers, &mut tiers)?
+                    .usable_peers(request.crypto_policy()),
+            );
+            peers.filter(&filter_cidr);
+            if probe {
+                let addrs: Vec<SocketAddr> = peers.0.iter().map(|entry| entry.addr).collect();
+                for (addr, latency) in block_on(probe_peers(&addrs, info_hash)) {
+                    peers.probed(addr, latency);
+                }
+            }
+            peers.sort(sort);
+            if let Some(limit) = limit {
+                peers.limit(limit);
+            }
+            for entry in &peers.0 {
+                match (probe, entry.latency) {
+                    (false, _) => println!("{}", entry.addr),
+                    (true, Some(latency)) => println!("{} {}", entry.addr, Span(latency)),
+                    (true, None) => println!("{} unreachable", entry.addr),
+                }
             }
         }
-        SubCommand::HandShake { file_path, peer } => {
-            let buf = read(file_path).context("opening torrent file")?;
-            let torrent: Torrent = serde_bencode::from_bytes(&buf).context("parse torrent file")?;
-            let (_, peer_id) = establish_handshake(&torrent, &peer, None)?;
-            println!("Peer ID: {}", hex::encode(peer_id));
+        SubCommand::Observe {
+            file_path,
+            duration,
+            trackers,
+            peers: peer_args,
+        } => {
+            let torrent = read_torrent(&file_path)?;
+            print!("{}", observe(&torrent, duration.0, &trackers, &peer_args)?);
+        }
+        SubCommand::Health {
+            file_path,
+            watch,
+            interval,
+            sample,
+            trackers,
+            peers: peer_args,
+        } => {
+            let torrent = read_torrent(&file_path)?;
+            let mut previous: Option<Census> = None;
+            loop {
+                let started = Instant::now();
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs() as i64);
+                match observe(&torrent, sample.0, &trackers, &peer_args) {
+                    Ok(census) => {
+                        println!("{} {}", Timestamp(now), census.summary());
+                        if let Some(previous) = &previous {
+                            print!("{}", census.delta(previous));
+                        }
+                        previous = Some(census);
+                    }
+                    // a tracker being down for a while shouldn't end the watch
+                    Err(err) if watch => eprintln!("warning: sampling the swarm: {err:#}"),
+                    Err(err) => return Err(err),
+                }
+                if !watch {
+                    break;
+                }
+                thread::sleep(interval.0.saturating_sub(started.elapsed()));
+            }
+        }
+        SubCommand::HandShake {
+            file_path,
+            peer,
+            details,
+            json,
+            proxy,
+        } => {
+            let torrent = read_torrent(&file_path)?;
+            let (_, handshake) = block_on(client::connect(
+                &proxy,
+                peer,
+                torrent.calculate_info_hash(),
+                Timeouts::default(),
+            ))?;
+
+            let peer_id = hex::encode(handshake.peer_id);
+            let reserved = hex::encode(handshake.reserved());
+            let capabilities = handshake.capabilities();
+            let client = handshake.client();
+            if json {
+                let json = serde_json::json!({
+                    "peer_id": peer_id,
+                    "reserved": reserved,
+                    "capabilities": capabilities,
+                    "client": client,
+                });
+                println!("{json}");
+            } else {
+                println!("Peer ID: {peer_id}");
+                if details {
+                    println!("Reserved: {reserved}");
+                    match capabilities.is_empty() {
+                        true => println!("Capabilities: none"),
+                        false => println!("Capabilities: {}", capabilities.join(", ")),
+                    }
+                    println!("Client: {}", client.as_deref().unwrap_or("unknown"));
+                }
+            }
+        }
+        SubCommand::DebugPeer {
+            file_path,
+            peer,
+            proxy,
+        } => {
+            let torrent = read_torrent(&file_path)?;
+            debug_peer(&torrent, peer, &proxy)?;
         }
         SubCommand::DownloadPiece {
             output,
             file_path,
             piece_index,
+            trackers,
+            peers: peer_args,
+            quiet,
         } => {
-            let buf = read(file_path).context("opening torrent file")?;
-            let torrent: Torrent = serde_bencode::from_bytes(&buf).context("parse torrent file")?;
+            let torrent = read_torrent(&file_path)?;
+            download_piece_to(&torrent, piece_index, &output, &trackers, &peer_args, quiet)?;
+        }
+        SubCommand::Download {
+            output,
+            file_path,
+            trackers,
+            peers: peer_args,
+            dump_piece_map,
+            download_quota,
+            no_verify,
+            dry_run,
+            chaos,
+            timings,
+            priority,
+            status_file,
+            strict,
+            debug_scheduler,
+            archive,
+            starvation_timeout,
+            rotate_peer_id,
+            quiet,
+            fsync,
+            fsync_interval,
+            incomplete_dir,
+        } => {
+            let torrent = read_torrent(&file_path)?;
 
-            let pieces_count = torrent.info.pieces.0.len();
-            if piece_index >= pieces_count {
-                bail!("index {piece_index} out of {pieces_count}")
+            if dry_run {
+                plan_download(&torrent, &trackers, &peer_args)?;
+                return Ok(ExitCode::SUCCESS);
             }
 
-            let info_hash = torrent.calculate_info_hash();
-            let mut peers = extract_peers(&torrent, Some(info_hash))?;
-            // TODO: pick peers in smarter way
-            let Some(peer) = peers.0.pop() else {
-                bail!("the torrent doesn't have any peers")
-            };
+            if archive.is_none()
+                && is_complete_on_disk(&torrent, &output, &VerificationCache::default())?
+            {
+                println!(
+                    "{} is already complete at {}.",
+                    file_path.display(),
+                    output.as_path().display()
+                );
+                return Ok(ExitCode::SUCCESS);
+            }
 
-            let (mut stream, _) = establish_handshake(&torrent, &peer, Some(info_hash))?;
+            if no_verify {
+                eprintln!(
+                    "WARNING: --no-verify is set, piece hashes are NOT checked and {} may end up \
+                     corrupt. Only use it to benchmark network and disk throughput.",
+                    output.display()
+                );
+            }
 
-            initiate_download(&mut stream)?;
-            let piece = download_piece(&mut stream, &torrent, piece_index, BLOCK_SIZE)?;
-            validate_piece(&torrent, piece_index, &piece)?;
+            let incomplete = match &incomplete_dir {
+                Some(dir) => {
+                    let Some(name) = output.file_name() else {
+                        bail!("{} doesn't name a file or directory", output.display());
+                    };
+                    if output.exists() {
+                        bail!(
+                            "{} exists but is incomplete, resume it without --incomplete-dir",
+                            output.display()
+                        );
+                    }
+                    Some(dir.join(name))
+                }
+                None => None,
+            };
+            let result = download_with_events(
+                &torrent,
+                incomplete.as_deref().unwrap_or(&output),
+                &trackers,
+                &peer_args,
+                &DownloadOptions {
+                    dump_piece_map: dump_piece_map.as_deref(),
+                    quota: Quota {
+                        download: download_quota.map(|quota| quota.0),
+                    },
+                    no_verify,
+                    chaos: chaos.unwrap_or_default(),
+                    timings,
+                    priorities: priority.as_ref(),
+                    status_file: status_file.as_deref(),
+                    strict,
+                    debug_scheduler,
+                    archive,
+                    starvation_timeout: Some(starvation_timeout.0),
+                    rotate_peer_id,
+                    quiet,
+                    fsync,
+                    fsync_interval: fsync_interval.0,
+                },
+            );
+            if let Err(err) = &result {
+                if err.is::<QuotaExhausted>() {
+                    return Ok(ExitCode::from(3));
+                }
+                if err.is::<PiecesFailed>() {
+                    eprintln!("{err}");
+                    return Ok(ExitCode::from(4));
+                }
+                if err.is::<Interrupted>() {
+                    eprintln!("{err}");
+                    return Ok(ExitCode::from(130));
+                }
+            }
+            result?;
 
-            // saving to disk
-            let mut piece_file = File::create(&output).context("creating output file")?;
-            piece_file
-                .write_all(&piece)
-                .context("writing piece to file")?;
+            if let Some(incomplete) = &incomplete {
+                move_download(&torrent, incomplete, &output)?;
+                let resume_path = ResumeState::path_for(incomplete);
+                if resume_path.exists() {
+                    move_file(&resume_path, &ResumeState::path_for(&output))?;
+                }
+            }
 
-            println!(
-                "Piece {piece_index} downloaded to {}.",
+            let done = format!(
+                "Downloaded {} to {}.",
+                file_path.display(),
                 output.as_path().display()
             );
+            // stdout may carry the archive
+            match archive.is_some() && output == Path::new("-") {
+                true => eprintln!("{done}"),
+                false => println!("{done}"),
+            }
         }
-        SubCommand::Download { output, file_path } => {
+        SubCommand::Export {
+            output,
+            file_path,
+            snapshot,
+        } => {
             let buf = read(&file_path).context("opening torrent file")?;
             let torrent: Torrent = serde_bencode::from_bytes(&buf).context("parse torrent file")?;
 
-            let info_hash = torrent.calculate_info_hash();
-            let mut peers = extract_peers(&torrent, Some(info_hash))?;
-            // TODO: pick peers in smarter way
-            let Some(peer) = peers.0.pop() else {
-                bail!("the torrent doesn't have any peers")
+            let resume_path = ResumeState::path_for(&output);
+            let resume = match resume_path.exists() {
+                true => Some(ResumeState::load(&resume_path)?),
+                false => None,
             };
-            let mut file = File::create(&output).context("creating output file")?;
-
-            // TODO : propbably some async 😅
-            for piece_index in 0..torrent.info.pieces.0.len() {
-                let (mut stream, _) = establish_handshake(&torrent, &peer, Some(info_hash))?;
-                initiate_download(&mut stream)?;
-                let piece = download_piece(&mut stream, &torrent, piece_index, BLOCK_SIZE)?;
-                validate_piece(&torrent, piece_index, &piece)?;
-                file.write_all(&piece)
-                    .context(format!("writing piece {piece_index} to file"))?;
-                send_message(
-                    &mut stream,
-                    PeerMessage::Have {
-                        piece_index: piece_index as u32,
-                    },
-                )?;
+            if let Some(resume) = &resume {
+                if resume.info_hash != torrent.calculate_info_hash() {
+                    bail!("{} belongs to another torrent", resume_path.display())
+                }
+            }
+
+            let Some(output_name) = output.file_name() else {
+                bail!("{} isn't a file path", output.display())
+            };
+            Snapshot {
+                torrent: buf,
+                output: output_name.to_string_lossy().into(),
+                resume,
             }
+            .save(&snapshot)?;
 
             println!(
-                "Downloaded {} to {}.",
+                "Exported {} to {}.",
                 file_path.display(),
-                output.as_path().display()
+                snapshot.display()
+            );
+        }
+        SubCommand::Import {
+            snapshot,
+            directory,
+        } => {
+            let snapshot = Snapshot::load(&snapshot)?;
+            let torrent: Torrent =
+                serde_bencode::from_bytes(&snapshot.torrent).context("parse torrent file")?;
+            // neither may lead out of `directory`
+            if plain_name(&snapshot.output).is_none() {
+                bail!(
+                    "snapshot output {:?} isn't a plain file name",
+                    snapshot.output
+                )
+            }
+            let torrent_name = format!("{}.torrent", torrent.info.name);
+            if plain_name(&torrent_name).is_none() {
+                bail!(
+                    "the torrent's name {:?} isn't a plain file name",
+                    torrent.info.name
+                )
+            }
+
+            fs::create_dir_all(&directory).context("creating import directory")?;
+            let torrent_path = directory.join(torrent_name);
+            fs::write(&torrent_path, &snapshot.torrent).context("writing torrent file")?;
+            if let Some(resume) = &snapshot.resume {
+                resume.save(&ResumeState::path_for(&directory.join(&snapshot.output)))?;
+            }
+
+            println!(
+                "Imported {} to {}.",
+                torrent_path.display(),
+                directory.join(&snapshot.output).display()
             );
         }
+        SubCommand::Seed {
+            output,
+            file_path,
+            port,
+            mut trackers,
+            dht,
+            bootstrap_nodes,
+        } => {
+            let torrent = read_torrent(&file_path)?;
+            // a cached response wouldn't tell the trackers about us
+            trackers.no_cache = true;
+            let dht_nodes = dht.then(|| match bootstrap_nodes.is_empty() {
+                true => dht::BOOTSTRAP_NODES.map(String::from).to_vec(),
+                false => bootstrap_nodes,
+            });
+            seed(torrent, &output, port, &trackers, dht_nodes)?;
+        }
+        SubCommand::Checksums { file_path, output } => {
+            let torrent = read_torrent(&file_path)?;
+            // relative to the directory holding the download, where `sha256sum -c` is to run
+            let base = output.parent().unwrap_or(Path::new(""));
+            let missing = missing_pieces(&torrent, &output)?;
+            if missing > 0 {
+                bail!(
+                    "{} is missing {missing} of {} pieces, the download isn't complete",
+                    output.display(),
+                    torrent.info.pieces.0.len()
+                );
+            }
+            for span in layout(&torrent, &output)? {
+                let digest = sha256_of_file(&span)?;
+                let name = span.path.strip_prefix(base).unwrap_or(&span.path);
+                println!("{}  {}", hex::encode(digest), name.display());
+            }
+        }
+        SubCommand::Verify { file_path, output } => {
+            let torrent = read_torrent(&file_path)?;
+            let mut storage = Storage::open_read_only(&torrent, &output)?;
+            let piece_count = torrent.info.pieces.0.len();
+            let mut piece = vec![0u8; torrent.info.piece_length];
+            let mut valid = 0;
+            for piece_index in 0..piece_count {
+                match verify_stored_piece(&torrent, &mut storage, piece_index, &mut piece) {
+                    Ok(()) => {
+                        valid += 1;
+                        println!("piece {piece_index}: ok");
+                    }
+                    Err(err) if err.is::<HashMismatch>() => {
+                        println!("piece {piece_index}: FAILED (hash mismatch)")
+                    }
+                    Err(err) => println!("piece {piece_index}: FAILED ({err:#})"),
+                }
+            }
+            let percent = match piece_count {
+                0 => 100.0,
+                _ => valid as f64 * 100.0 / piece_count as f64,
+            };
+            println!("{valid} of {piece_count} pieces valid ({percent:.1}%)");
+            if valid < piece_count {
+                return Ok(ExitCode::from(1));
+            }
+        }
+        SubCommand::DhtPeers {
+            info_hash,
+            bootstrap_nodes,
+            port,
+        } => {
+            let info_hash: [u8; 20] = hex::decode(&info_hash)
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .context("the info hash must be 40 hex digits")?;
+            let bootstrap_nodes = match bootstrap_nodes.is_empty() {
+                true => dht::BOOTSTRAP_NODES.map(String::from).to_vec(),
+                false => bootstrap_nodes,
+            };
+            let mut dht = Dht::bind(port).context("opening the DHT socket")?;
+            dht.bootstrap(&dht::resolve(&bootstrap_nodes))?;
+            for peer in dht.get_peers(info_hash).peers {
+                println!("{peer}");
+            }
+        }
+        SubCommand::Move {
+            output,
+            file_path,
+            directory,
+        } => {
+            let torrent = read_torrent(&file_path)?;
+            let resume_path = ResumeState::path_for(&output);
+            check_resume_state(&torrent, &resume_path)?;
+
+            let Some(output_name) = output.file_name() else {
+                bail!("{} isn't a file path", output.display())
+            };
+            let moved = directory.join(output_name);
+            fs::create_dir_all(&directory).context("creating target directory")?;
+            move_download(&torrent, &output, &moved)?;
+            if resume_path.exists() {
+                move_file(&resume_path, &ResumeState::path_for(&moved))?;
+            }
+
+            println!("Moved {} to {}.", output.display(), moved.display());
+        }
+        SubCommand::Remove {
+            output,
+            file_path,
+            with_data,
+        } => {
+            let torrent = read_torrent(&file_path)?;
+            let resume_path = ResumeState::path_for(&output);
+            check_resume_state(&torrent, &resume_path)?;
+
+            // the data first, so that a failure leaves what's left of it resumable
+            if with_data {
+                remove_download(&torrent, &output).context("removing downloaded data")?;
+            }
+            if resume_path.exists() {
+                fs::remove_file(&resume_path).context("removing resume state")?;
+            }
+            match with_data {
+                true => println!("Removed {} and its data.", output.display()),
+                false => println!("Removed {}.", output.display()),
+            }
+        }
+        SubCommand::MagnetParse { link } => {
+            if let Some(tracker) = link.tracker() {
+                println!("Tracker URL: {tracker}");
+            }
+            if let Some(info_hash) = link.info_hash {
+                println!("Info Hash: {}", hex::encode(info_hash));
+            }
+            if let Some(info_hash) = link.info_hash_v2 {
+                println!("Info Hash v2: {}", hex::encode(info_hash));
+            }
+        }
+        SubCommand::MagnetHandshake {
+            link,
+            trackers,
+            peers: peer_args,
+        } => {
+            let info_hash = magnet_info_hash(&link)?;
+            let (mut peers, _) = gather_peers(
+                &TrackerRequest::new(UNKNOWN_LEFT),
+                info_hash,
+                &trackers,
+                &mut trackers.magnet_tiers(&link),
+                &magnet_peer_args(&link, &peer_args),
+            )?;
+            let Some(peer) = peers.0.pop() else {
+                bail!("the torrent doesn't have any peers")
+            };
+
+            let (_, handshake, extensions) = block_on(magnet_handshake(
+                &peer_args.proxy,
+                peer,
+                info_hash,
+                peer_args.timeouts(),
+            ))?;
+            println!("Peer ID: {}", hex::encode(handshake.peer_id));
+            if let Some(ext_id) = extensions.extension_id(UT_METADATA) {
+                println!("Peer Metadata Extension ID: {ext_id}");
+            }
+        }
+        SubCommand::MagnetInfo {
+            link,
+            trackers,
+            peers: peer_args,
+        } => {
+            let torrent = fetch_magnet_torrent(&link, &trackers, &peer_args)?;
+            print!("{torrent}");
+        }
+        SubCommand::MagnetDownloadPiece {
+            output,
+            link,
+            piece_index,
+            trackers,
+            peers: peer_args,
+        } => {
+            let torrent = fetch_magnet_torrent(&link, &trackers, &peer_args)?;
+            let peer_args = magnet_peer_args(&link, &peer_args);
+            download_piece_to(&torrent, piece_index, &output, &trackers, &peer_args, false)?;
+        }
+        SubCommand::MagnetDownload {
+            output,
+            link,
+            trackers,
+            peers: peer_args,
+        } => {
+            let torrent = fetch_magnet_torrent(&link, &trackers, &peer_args)?;
+            let peer_args = magnet_peer_args(&link, &peer_args);
+            download_with_events(
+                &torrent,
+                &output,
+                &trackers,
+                &peer_args,
+                &DownloadOptions::default(),
+            )?;
+
+            println!("Downloaded {} to {}.", link.name(), output.display());
+        }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
 #[cfg(test)]
@@ -265,6 +3062,8 @@ mod tests {
 
             let expected_torrent = Torrent {
                 announce: "http://bittorrent-test-tracker.codecrafters.io/announce".to_string(),
+                announce_list: None,
+                creation_date: None,
                 info: Info {
                     name: "sample.txt".to_string(),
                     piece_length: 32768,
@@ -288,5 +3087,105 @@ mod tests {
 
             assert_eq!(torrent, expected_torrent);
         }
+
+        #[test]
+        fn torrent_info_display() {
+            let buf = read("sample.torrent").unwrap();
+            let mut torrent: Torrent = serde_bencode::from_bytes(&buf).unwrap();
+
+            let expected = "\
+Tracker URL: http://bittorrent-test-tracker.codecrafters.io/announce
+Length: 92063
+Info Hash: d69f91e6b2ae4c542468d1073a71d4ea13879a7f
+Piece Length: 32768
+Piece Hashes:
+e876f67a2a8886e8f36b136726c30fa29703022d
+6e2275e604a0766656736e81ff10b55204ad8d35
+f00d937a0213df1982bc8d097227ad9e909acc17
+";
+            assert_eq!(torrent.to_string(), expected);
+
+            torrent.creation_date = Some(1_690_893_000);
+            assert!(torrent
+                .to_string()
+                .contains("Piece Length: 32768\nCreation Date: 2023-08-01T12:30:00Z\n"));
+        }
+    }
+
+    mod checksums {
+        use super::super::{is_complete_on_disk, missing_pieces};
+        use bittorrent_starter_rust::{
+            cache::VerificationCache,
+            resume::ResumeState,
+            storage::Storage,
+            torrent::{Torrent, TorrentBuilder},
+        };
+        use std::fs;
+
+        /// A torrent of 40 bytes in 3 pieces, over two files of 20, and its content.
+        fn two_files() -> (Torrent, Vec<u8>) {
+            let content: Vec<u8> = (0..40).collect();
+            let torrent = TorrentBuilder::new("http://tracker.example.com/announce", "data")
+                .piece_length(16)
+                .bytes(vec!["a".to_string()], content[..20].to_vec())
+                .bytes(vec!["b".to_string()], content[20..].to_vec())
+                .build()
+                .unwrap();
+            (torrent, content)
+        }
+
+        #[test]
+        fn partial_downloads_are_incomplete() {
+            let (torrent, content) = two_files();
+            let dir = tempfile::tempdir().unwrap();
+            let output = dir.path().join("data");
+
+            // interrupted after the first piece, every file already full length
+            let mut storage = Storage::create(&torrent, &output).unwrap();
+            storage.write_at(0, &content[..16]).unwrap();
+            drop(storage);
+            assert_eq!(fs::metadata(output.join("b")).unwrap().len(), 20);
+            assert_eq!(missing_pieces(&torrent, &output).unwrap(), 2);
+
+            let mut resume = ResumeState::new(torrent.calculate_info_hash(), 3);
+            resume.set_piece(0);
+            let resume_path = ResumeState::path_for(&output);
+            resume.save(&resume_path).unwrap();
+            assert_eq!(missing_pieces(&torrent, &output).unwrap(), 2);
+
+            let mut storage = Storage::open(&torrent, &output).unwrap();
+            storage.write_at(16, &content[16..]).unwrap();
+            drop(storage);
+            assert_eq!(
+                missing_pieces(&torrent, &output).unwrap(),
+                2,
+                "the resume state has the last word"
+            );
+            fs::remove_file(&resume_path).unwrap();
+            assert_eq!(missing_pieces(&torrent, &output).unwrap(), 0);
+        }
+
+        #[test]
+        fn complete_multi_file_downloads_are_found() {
+            let (torrent, content) = two_files();
+            let dir = tempfile::tempdir().unwrap();
+            let cache = VerificationCache::new(dir.path().join("cache"));
+            let output = dir.path().join("data");
+            assert!(!is_complete_on_disk(&torrent, &output, &cache).unwrap());
+
+            let mut storage = Storage::create(&torrent, &output).unwrap();
+            storage.write_at(0, &content[..16]).unwrap();
+            assert!(!is_complete_on_disk(&torrent, &output, &cache).unwrap());
+            storage.write_at(16, &content[16..]).unwrap();
+            drop(storage);
+            assert!(is_complete_on_disk(&torrent, &output, &cache).unwrap());
+            assert!(
+                is_complete_on_disk(&torrent, &output, &cache).unwrap(),
+                "from the cache"
+            );
+
+            fs::write(output.join("b"), &content[20..39]).unwrap();
+            assert!(!is_complete_on_disk(&torrent, &output, &cache).unwrap());
+        }
     }
 }
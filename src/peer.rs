@@ -1,16 +1,70 @@
 use std::{
+    collections::VecDeque,
     error::Error,
     fmt::{self, Display},
-    io::{Read, Write},
-    net::TcpStream,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
 };
 
 use anyhow::{bail, Context};
-use bytes::BufMut;
+use bytes::{Buf, BufMut, BytesMut};
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+use crate::{
+    bitfield::Bitfield,
+    codec::MessageCodec,
+    dial::Dialer,
+    display::Span,
+    endgame::Block,
+    extension::{ExtensionHandshake, HANDSHAKE_ID, UT_HOLEPUNCH, UT_METADATA, UT_METADATA_ID},
+    hasher::{merkle_root, PieceHasher, Sha1Hasher},
+    magnet::{MetadataMessage, MetadataMessageType, MAX_METADATA_SIZE, METADATA_PIECE_SIZE},
+    random::{self, Xorshift},
+    torrent::{Info, Torrent},
+};
 
-use crate::torrent::Torrent;
+/// A peer id of this client, Azureus-style: [`PeerId::PREFIX`] then 12 random digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId(pub [u8; 20]);
+
+impl PeerId {
+    /// `RS` for this client, at version 0.1.0.0.
+    pub const PREFIX: &[u8; 8] = b"-RS0100-";
+
+    /// A new random id.
+    pub fn random() -> Self {
+        let mut rng = Xorshift::new(random::seed());
+        let mut id = [0; 20];
+        id[..8].copy_from_slice(Self::PREFIX);
+        for digit in &mut id[8..] {
+            *digit = b'0' + rng.below(10) as u8;
+        }
+        Self(id)
+    }
+
+    /// The id of this process, generated on first use, so that trackers and peers see the same one.
+    pub fn ours() -> Self {
+        static OURS: OnceLock<PeerId> = OnceLock::new();
+        *OURS.get_or_init(Self::random)
+    }
+}
+
+impl Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        String::from_utf8_lossy(&self.0).fmt(f)
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HandShake {
@@ -37,13 +91,200 @@ impl HandShake {
             length: 19,
             protocol: *b"BitTorrent protocol",
             reserved: [0; 8],
-            peer_id: *b"00112233445566778899",
+            peer_id: PeerId::ours().0,
         }
     }
 
     pub fn peer_id(self, peer_id: [u8; 20]) -> Self {
         Self { peer_id, ..self }
     }
+
+    pub fn reserved(&self) -> [u8; 8] {
+        self.reserved
+    }
+
+    /// Advertise support for the extension protocol (BEP 10).
+    pub fn extension_protocol(mut self) -> Self {
+        self.reserved[5] |= 0x10;
+        self
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    /// The names of the capabilities advertised in the reserved bytes.
+    pub fn capabilities(&self) -> Vec<&'static str> {
+        const CAPABILITIES: [(usize, u8, &str); 5] = [
+            (0, 0x80, "azureus-messaging"),
+            (5, 0x10, "extension-protocol"),
+            (7, 0x01, "dht"),
+            (7, 0x04, "fast"),
+            (7, 0x10, "v2"),
+        ];
+
+        CAPABILITIES
+            .into_iter()
+            .filter(|&(byte, mask, _)| self.reserved[byte] & mask != 0)
+            .map(|(_, _, name)| name)
+            .collect()
+    }
+
+    /// The client software of the peer, as far as it can be told from its peer id.
+    pub fn client(&self) -> Option<String> {
+        client_name(&self.peer_id)
+    }
+}
+
+/// Who chokes and who is interested on a connection, both ways. Connections start out choked
+/// and uninterested on both sides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChokeState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
+
+impl Default for ChokeState {
+    fn default() -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+
+impl ChokeState {
+    /// Follow `message`, just sent to the peer.
+    pub fn sent(&mut self, message: &PeerMessage) {
+        match message {
+            PeerMessage::Choke => self.am_choking = true,
+            PeerMessage::UnChoke => self.am_choking = false,
+            PeerMessage::Interested => self.am_interested = true,
+            PeerMessage::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+    }
+
+    /// Follow `message`, just received from the peer.
+    pub fn received(&mut self, message: &PeerMessage) {
+        match message {
+            PeerMessage::Choke => self.peer_choking = true,
+            PeerMessage::UnChoke => self.peer_choking = false,
+            PeerMessage::Interested => self.peer_interested = true,
+            PeerMessage::NotInterested => self.peer_interested = false,
+            _ => {}
+        }
+    }
+
+    /// Whether the peer answers our requests.
+    pub fn can_request(&self) -> bool {
+        self.am_interested && !self.peer_choking
+    }
+}
+
+/// What a peer can do beyond the base protocol, from its handshake and extension handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Capabilities {
+    /// The fast extension (BEP 6).
+    pub fast: bool,
+    /// The extension protocol (BEP 10).
+    pub extension_protocol: bool,
+    /// A DHT node (BEP 5).
+    pub dht: bool,
+    /// Message stream encryption, as far as the peer said it prefers it.
+    pub encryption: bool,
+    /// uTP (BEP 29), as far as the peer supports hole punching, which only works over it.
+    pub utp: bool,
+    /// Version 2 hashes (BEP 52).
+    pub v2: bool,
+}
+
+impl Capabilities {
+    /// The short name of every capability, in the order of [`Capabilities::flags`].
+    pub const NAMES: [&'static str; 6] = ["fast", "ext", "dht", "mse", "utp", "v2"];
+
+    /// The capabilities advertised in the reserved bytes of `handshake`, as named by
+    /// [`HandShake::capabilities`].
+    pub fn from_handshake(handshake: &HandShake) -> Self {
+        let names = handshake.capabilities();
+        let has = |name| names.contains(&name);
+        Self {
+            fast: has("fast"),
+            extension_protocol: has("extension-protocol"),
+            dht: has("dht"),
+            v2: has("v2"),
+            ..Self::default()
+        }
+    }
+
+    /// Add what the peer's extension handshake tells about it.
+    pub fn note_extension_handshake(&mut self, theirs: &ExtensionHandshake) {
+        self.encryption |= theirs.e == Some(1);
+        self.utp |= theirs.extension_id(UT_HOLEPUNCH).is_some();
+    }
+
+    pub fn flags(&self) -> [bool; 6] {
+        [
+            self.fast,
+            self.extension_protocol,
+            self.dht,
+            self.encryption,
+            self.utp,
+            self.v2,
+        ]
+    }
+}
+
+/// Identify the client that generated `peer_id`, for Azureus-style (`-qB4250-...`) and
+/// mainline-style (`M7-2-2--...`) ids.
+pub fn client_name(peer_id: &[u8; 20]) -> Option<String> {
+    const AZUREUS_CLIENTS: [(&[u8; 2], &str); 12] = [
+        (b"AZ", "Vuze"),
+        (b"BC", "BitComet"),
+        (b"BT", "mainline BitTorrent"),
+        (b"DE", "Deluge"),
+        (b"KT", "KTorrent"),
+        (b"LT", "libtorrent (Rasterbar)"),
+        (b"lt", "libTorrent (Rakshasa)"),
+        (b"qB", "qBittorrent"),
+        (b"RS", "bittorrent-rust"),
+        (b"TR", "Transmission"),
+        (b"UT", "µTorrent"),
+        (b"WW", "WebTorrent"),
+    ];
+
+    if peer_id[0] == b'-' && peer_id[7] == b'-' {
+        let code = &peer_id[1..3];
+        let version = String::from_utf8_lossy(&peer_id[3..7]);
+        let name = AZUREUS_CLIENTS
+            .iter()
+            .find(|(known, _)| known.as_slice() == code)
+            .map_or_else(
+                || String::from_utf8_lossy(code).into(),
+                |(_, name)| name.to_string(),
+            );
+        return Some(format!("{name} {version}"));
+    }
+
+    // the version runs up to the first `--`, whatever the width of its numbers
+    if peer_id[0] == b'M' && peer_id[1].is_ascii_digit() {
+        if let Some(end) = peer_id.windows(2).position(|pair| pair == b"--") {
+            let version = &peer_id[1..end];
+            if version
+                .iter()
+                .all(|&byte| byte.is_ascii_digit() || byte == b'-')
+            {
+                let version = String::from_utf8_lossy(version).replace('-', ".");
+                return Some(format!("mainline BitTorrent {version}"));
+            }
+        }
+    }
+
+    None
 }
 
 impl From<HandShake> for [u8; 68] {
@@ -133,6 +374,8 @@ impl TryFrom<[u8; 68]> for HandShake {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PeerMessage {
+    /// The message of length 0, only telling the connection is still in use.
+    KeepAlive,
     Choke,
     UnChoke,
     Interested,
@@ -158,6 +401,38 @@ pub enum PeerMessage {
         offset: u32,
         length: u32,
     },
+    /// Request a range of hashes from one layer of a file's merkle tree (BEP 52).
+    HashRequest {
+        pieces_root: [u8; 32],
+        base_layer: u32,
+        index: u32,
+        length: u32,
+        proof_layers: u32,
+    },
+    /// Answer to a [`PeerMessage::HashRequest`], carrying the requested hashes followed by the
+    /// uncle hashes needed to verify them.
+    Hashes {
+        pieces_root: [u8; 32],
+        base_layer: u32,
+        index: u32,
+        length: u32,
+        proof_layers: u32,
+        hashes: Vec<[u8; 32]>,
+    },
+    /// Refusal of a [`PeerMessage::HashRequest`], echoing the request back.
+    HashReject {
+        pieces_root: [u8; 32],
+        base_layer: u32,
+        index: u32,
+        length: u32,
+        proof_layers: u32,
+    },
+    /// A message of the extension protocol (BEP 10), `ext_id` 0 being the extension handshake
+    /// and the others as negotiated by it.
+    Extended {
+        ext_id: u8,
+        payload: Vec<u8>,
+    },
 }
 
 impl From<PeerMessage> for Vec<u8> {
@@ -166,6 +441,7 @@ impl From<PeerMessage> for Vec<u8> {
         let mut buf = vec![];
 
         match value {
+            KeepAlive => {}
             Choke => buf.push(0),
             UnChoke => buf.push(1),
             Interested => buf.push(2),
@@ -208,6 +484,60 @@ impl From<PeerMessage> for Vec<u8> {
                 buf.put_u32(offset);
                 buf.put_u32(length);
             }
+            HashRequest {
+                pieces_root,
+                base_layer,
+                index,
+                length,
+                proof_layers,
+            } => {
+                buf.push(21);
+                buf.put_slice(&pieces_root);
+                buf.put_u32(base_layer);
+                buf.put_u32(index);
+                buf.put_u32(length);
+                buf.put_u32(proof_layers);
+            }
+            Hashes {
+                pieces_root,
+                base_layer,
+                index,
+                length,
+                proof_layers,
+                hashes,
+            } => {
+                buf.push(22);
+                buf.put_slice(&pieces_root);
+                buf.put_u32(base_layer);
+                buf.put_u32(index);
+                buf.put_u32(length);
+                buf.put_u32(proof_layers);
+                for hash in hashes {
+                    buf.put_slice(&hash);
+                }
+            }
+            HashReject {
+                pieces_root,
+                base_layer,
+                index,
+                length,
+                proof_layers,
+            } => {
+                buf.push(23);
+                buf.put_slice(&pieces_root);
+                buf.put_u32(base_layer);
+                buf.put_u32(index);
+                buf.put_u32(length);
+                buf.put_u32(proof_layers);
+            }
+            Extended {
+                ext_id,
+                mut payload,
+            } => {
+                buf.push(20);
+                buf.push(ext_id);
+                buf.append(&mut payload);
+            }
         }
 
         buf
@@ -217,6 +547,25 @@ impl From<PeerMessage> for Vec<u8> {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PeerMessageError {
     UnknownCode(u8),
+    /// A bitfield whose length doesn't match the torrent's piece count.
+    BitfieldLength {
+        expected: usize,
+        found: usize,
+    },
+    /// A bitfield with bits set past the torrent's last piece.
+    BitfieldSpareBits,
+    /// A piece index past the torrent's last piece.
+    PieceIndexOutOfRange {
+        piece_index: u32,
+        piece_count: usize,
+    },
+    /// A message shorter than the fixed fields of its kind.
+    Truncated {
+        code: u8,
+        length: usize,
+    },
+    /// Hashes taking a number of bytes that isn't a multiple of a hash's 32.
+    HashesLength(usize),
 }
 
 impl Display for PeerMessageError {
@@ -225,12 +574,67 @@ impl Display for PeerMessageError {
 
         match self {
             UnknownCode(code) => format!("unknown peer message code {code}").fmt(f),
+            BitfieldLength { expected, found } => {
+                write!(
+                    f,
+                    "bitfield should be {expected} bytes long, but found {found}"
+                )
+            }
+            BitfieldSpareBits => write!(f, "bitfield has spare bits set"),
+            PieceIndexOutOfRange {
+                piece_index,
+                piece_count,
+            } => write!(
+                f,
+                "piece index {piece_index} is out of range for {piece_count} pieces"
+            ),
+            Truncated { code, length } => {
+                write!(f, "message with code {code} is truncated to {length} bytes")
+            }
+            HashesLength(length) => {
+                write!(
+                    f,
+                    "{length} bytes of hashes aren't a whole number of hashes"
+                )
+            }
         }
     }
 }
 
 impl Error for PeerMessageError {}
 
+impl PeerMessage {
+    /// Check the piece indices carried by a `Have` or `Bitfield` against a torrent of
+    /// `piece_count` pieces, so they can't corrupt availability accounting.
+    pub fn validate(&self, piece_count: usize) -> Result<(), PeerMessageError> {
+        use PeerMessageError::*;
+
+        match self {
+            PeerMessage::Have { piece_index } if *piece_index as usize >= piece_count => {
+                Err(PieceIndexOutOfRange {
+                    piece_index: *piece_index,
+                    piece_count,
+                })
+            }
+            PeerMessage::Bitfield { fields } => {
+                let expected = piece_count.div_ceil(8);
+                if fields.len() != expected {
+                    return Err(BitfieldLength {
+                        expected,
+                        found: fields.len(),
+                    });
+                }
+                let spare_bits = (8 - piece_count % 8) % 8;
+                match fields.last() {
+                    Some(last) if last & ((1 << spare_bits) - 1) != 0 => Err(BitfieldSpareBits),
+                    _ => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 impl TryFrom<&[u8]> for PeerMessage {
     type Error = PeerMessageError;
 
@@ -238,9 +642,26 @@ impl TryFrom<&[u8]> for PeerMessage {
         use PeerMessage::*;
         use PeerMessageError::*;
 
+        if value.is_empty() {
+            return Ok(KeepAlive);
+        }
         let mut offset = 0;
         let code = value[offset];
         offset += 1;
+        let fixed_length = match code {
+            4 => 5,
+            6 | 8 => 13,
+            7 => 9,
+            20 => 2,
+            21..=23 => 49,
+            _ => 1,
+        };
+        if value.len() < fixed_length {
+            return Err(Truncated {
+                code,
+                length: value.len(),
+            });
+        }
         Ok(match code {
             0 => Choke,
             1 => UnChoke,
@@ -304,34 +725,888 @@ impl TryFrom<&[u8]> for PeerMessage {
                     length,
                 }
             }
+            20 => {
+                let ext_id = value[offset];
+                offset += 1;
+
+                Extended {
+                    ext_id,
+                    payload: value[offset..].to_vec(),
+                }
+            }
+            21..=23 => {
+                let pieces_root = value[offset..offset + 32].try_into().unwrap();
+                offset += 32;
+
+                let base_layer = u32::from_be_bytes(value[offset..offset + 4].try_into().unwrap());
+                offset += 4;
+
+                let index = u32::from_be_bytes(value[offset..offset + 4].try_into().unwrap());
+                offset += 4;
+
+                let length = u32::from_be_bytes(value[offset..offset + 4].try_into().unwrap());
+                offset += 4;
+
+                let proof_layers =
+                    u32::from_be_bytes(value[offset..offset + 4].try_into().unwrap());
+                offset += 4;
+
+                if code == 22 && !(value.len() - offset).is_multiple_of(32) {
+                    return Err(HashesLength(value.len() - offset));
+                }
+                match code {
+                    21 => HashRequest {
+                        pieces_root,
+                        base_layer,
+                        index,
+                        length,
+                        proof_layers,
+                    },
+                    22 => Hashes {
+                        pieces_root,
+                        base_layer,
+                        index,
+                        length,
+                        proof_layers,
+                        hashes: value[offset..]
+                            .chunks_exact(32)
+                            .map(|hash| hash.try_into().unwrap())
+                            .collect(),
+                    },
+                    _ => HashReject {
+                        pieces_root,
+                        base_layer,
+                        index,
+                        length,
+                        proof_layers,
+                    },
+                }
+            }
             code => return Err(UnknownCode(code)),
         })
     }
 }
 
-pub fn receive_message(stream: &mut TcpStream) -> anyhow::Result<PeerMessage> {
-    let mut length_buf = [0; 4];
-    stream
-        .read_exact(&mut length_buf)
-        .context("reading message length")?;
-    let length = u32::from_be_bytes(length_buf);
-    let mut message = vec![0u8; length as usize];
-    stream
-        .read_exact(&mut message)
-        .context(format!("reading message slice of length {length}"))?;
-    Ok(message.as_slice().try_into()?)
-}
-
-pub fn send_message(stream: &mut TcpStream, message: PeerMessage) -> anyhow::Result<()> {
-    let message_buf: Vec<u8> = message.into();
-    stream
-        .write(&(message_buf.len() as u32).to_be_bytes())
-        .context(format!("sending message length of {}", message_buf.len()))?;
-    stream
-        .write_all(message_buf.as_slice())
-        .context("sending message message")?;
-    stream.flush()?;
-    Ok(())
+/// How long a peer may stay completely silent, not even sending keep-alives, before its
+/// connection is considered half-open and dropped.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long opening a connection to a peer may take.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long sending a message may take, while a peer doesn't read what we send.
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a peer may take to answer a block request, even while sending other messages.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a connection waits on each step before giving up on the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Duration,
+    /// The peer staying completely silent, see [`IDLE_TIMEOUT`].
+    pub read: Duration,
+    pub write: Duration,
+    /// The peer answering a block request.
+    pub request: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: CONNECT_TIMEOUT,
+            read: IDLE_TIMEOUT,
+            write: WRITE_TIMEOUT,
+            request: REQUEST_TIMEOUT,
+        }
+    }
+}
+
+/// A peer that didn't get something done within its timeout.
+///
+/// It's carried by an [`io::Error`] of kind [`io::ErrorKind::TimedOut`], so that it's told apart
+/// like any other connection timing out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerTimeout {
+    pub peer: SocketAddr,
+    /// What the peer, or its connection, was at, e.g. `connecting`.
+    pub doing: &'static str,
+    pub after: Duration,
+}
+
+impl PeerTimeout {
+    fn error(peer: SocketAddr, doing: &'static str, after: Duration) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, Self { peer, doing, after })
+    }
+}
+
+impl Display for PeerTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "peer {} timed out {} after {}",
+            self.peer,
+            self.doing,
+            Span(self.after)
+        )
+    }
+}
+
+impl Error for PeerTimeout {}
+
+/// How many block requests are kept outstanding with a peer by default, so a piece isn't
+/// downloaded at the pace of one round trip per block.
+pub const PIPELINE_DEPTH: usize = 5;
+
+/// The most hashes asked for in a single `HashRequest`, as BEP 52 allows.
+pub const MAX_HASHES_PER_REQUEST: u32 = 512;
+
+/// How long we stay silent on a connection before sending a keep-alive, well within the
+/// [`IDLE_TIMEOUT`] of most peers.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An established TCP connection to a peer, speaking the peer wire protocol.
+#[derive(Debug)]
+pub struct PeerConnection {
+    addr: SocketAddr,
+    stream: TcpStream,
+    codec: MessageCodec,
+    /// What was read from the stream but not decoded yet.
+    read_buf: BytesMut,
+    timeouts: Timeouts,
+    /// How many block requests are kept outstanding.
+    pipeline: usize,
+    /// When we last sent a message, keep-alives included.
+    last_sent: time::Instant,
+    /// The pieces the peer announced with `Have` while blocks were awaited.
+    announced: Vec<u32>,
+    /// The blocks a `Cancel` was sent for, which the peer may still answer.
+    cancelled: Vec<Block>,
+    state: ChokeState,
+    /// What the peer advertised in its handshakes so far.
+    capabilities: Capabilities,
+    /// The bytes of blocks received.
+    received: Arc<AtomicU64>,
+}
+
+impl PeerConnection {
+    /// Connect to the peer at `addr` through `dialer`, with the default [`Timeouts`].
+    pub async fn connect(dialer: &impl Dialer, addr: SocketAddr) -> anyhow::Result<Self> {
+        Self::connect_with(dialer, addr, Timeouts::default()).await
+    }
+
+    /// Connect to the peer at `addr` through `dialer`, giving up on it after `timeouts`.
+    pub async fn connect_with(
+        dialer: &impl Dialer,
+        addr: SocketAddr,
+        timeouts: Timeouts,
+    ) -> anyhow::Result<Self> {
+        let stream = match time::timeout(timeouts.connect, dialer.dial(addr)).await {
+            Ok(stream) => stream,
+            Err(_) => Err(PeerTimeout::error(addr, "connecting", timeouts.connect)),
+        }
+        .context("establishing connection with peer")?;
+        Ok(Self {
+            addr,
+            stream,
+            codec: MessageCodec::default(),
+            read_buf: BytesMut::new(),
+            timeouts,
+            pipeline: PIPELINE_DEPTH,
+            last_sent: time::Instant::now(),
+            announced: Vec::new(),
+            cancelled: Vec::new(),
+            state: ChokeState::default(),
+            capabilities: Capabilities::default(),
+            received: Arc::default(),
+        })
+    }
+
+    /// Take over a connection opened to us by a peer.
+    pub fn accept(stream: TcpStream) -> anyhow::Result<Self> {
+        let addr = stream.peer_addr().context("getting the peer's address")?;
+        // a dual-stack listener sees IPv4 peers at IPv4-mapped IPv6 addresses
+        let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+        Ok(Self {
+            addr,
+            stream,
+            codec: MessageCodec::default(),
+            read_buf: BytesMut::new(),
+            timeouts: Timeouts::default(),
+            pipeline: PIPELINE_DEPTH,
+            last_sent: time::Instant::now(),
+            announced: Vec::new(),
+            cancelled: Vec::new(),
+            state: ChokeState::default(),
+            capabilities: Capabilities::default(),
+            received: Arc::default(),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// A counter of the bytes of blocks received, which goes on counting while the connection
+    /// downloads, e.g. to show its progress.
+    pub fn received(&self) -> Arc<AtomicU64> {
+        self.received.clone()
+    }
+
+    /// What the peer advertised in its handshake, and its extension handshake once one was seen
+    /// through [`PeerConnection::extension_handshake`] or [`PeerConnection::note_extension_handshake`].
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Record the extension handshake of the peer, for when it was received outside of
+    /// [`PeerConnection::extension_handshake`].
+    pub fn note_extension_handshake(&mut self, theirs: &ExtensionHandshake) {
+        self.capabilities.note_extension_handshake(theirs);
+    }
+
+    /// Who chokes and who is interested, as of the messages sent and received so far.
+    pub fn state(&self) -> ChokeState {
+        self.state
+    }
+
+    /// The pieces the peer announced having since the last call, while blocks were awaited.
+    pub fn take_announced(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.announced)
+    }
+
+    /// Fail any read blocking for longer than `idle_timeout` (by default [`IDLE_TIMEOUT`]), since
+    /// every message (keep-alives included) resets the wait.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.timeouts.read = idle_timeout;
+        self
+    }
+
+    /// Give up on the peer after `timeouts`, e.g. for a connection accepted from it.
+    pub fn timeouts(self, timeouts: Timeouts) -> Self {
+        Self { timeouts, ..self }
+    }
+
+    /// Keep up to `depth` (by default [`PIPELINE_DEPTH`], at least 1) block requests outstanding
+    /// while downloading a piece.
+    pub fn pipeline(self, depth: usize) -> Self {
+        Self {
+            pipeline: depth.max(1),
+            ..self
+        }
+    }
+
+    /// Read exactly `buf.len()` bytes, what was already buffered first, treating a silence longer
+    /// than the idle timeout as a half-open connection.
+    async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let buffered = buf.len().min(self.read_buf.len());
+        self.read_buf.copy_to_slice(&mut buf[..buffered]);
+        let buf = &mut buf[buffered..];
+        if buf.is_empty() {
+            return Ok(());
+        }
+        match time::timeout(self.timeouts.read, self.stream.read_exact(buf)).await {
+            Ok(result) => result.map(|_| ()),
+            Err(_) => Err(self.silent()),
+        }
+    }
+
+    fn silent(&self) -> io::Error {
+        PeerTimeout::error(self.addr, "sending anything", self.timeouts.read)
+    }
+
+    /// Write all of `bytes`, unless the peer stops reading them for longer than the write timeout.
+    async fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match time::timeout(self.timeouts.write, self.stream.write_all(bytes)).await {
+            Ok(result) => result,
+            Err(_) => Err(PeerTimeout::error(
+                self.addr,
+                "reading what we send",
+                self.timeouts.write,
+            )),
+        }
+    }
+
+    /// Exchange handshakes, returning the peer's.
+    pub async fn handshake(&mut self, handshake: HandShake) -> anyhow::Result<HandShake> {
+        let info_hash = handshake.info_hash;
+        let mut bytes: [u8; 68] = handshake.into();
+        self.write_all(&bytes).await.context("sending handshake")?;
+        self.read_exact(&mut bytes)
+            .await
+            .context("receiving handshake")?;
+        let theirs: HandShake = bytes.try_into().context("converting handshake")?;
+        if theirs.info_hash != info_hash {
+            bail!(
+                "peer answered for another torrent: {}",
+                hex::encode(theirs.info_hash)
+            );
+        }
+        self.capabilities = Capabilities::from_handshake(&theirs);
+        Ok(theirs)
+    }
+
+    /// Answer the handshake of a peer that connected to us with ours, once it's known to be for
+    /// the same torrent, returning the peer's.
+    pub async fn accept_handshake(&mut self, handshake: HandShake) -> anyhow::Result<HandShake> {
+        let mut bytes = [0u8; 68];
+        self.read_exact(&mut bytes)
+            .await
+            .context("receiving handshake")?;
+        let theirs: HandShake = bytes.try_into().context("converting handshake")?;
+        self.capabilities = Capabilities::from_handshake(&theirs);
+        if theirs.info_hash != handshake.info_hash {
+            bail!(
+                "peer asked for another torrent: {}",
+                hex::encode(theirs.info_hash)
+            );
+        }
+        let bytes: [u8; 68] = handshake.into();
+        self.write_all(&bytes).await.context("sending handshake")?;
+        Ok(theirs)
+    }
+
+    pub async fn send(&mut self, message: PeerMessage) -> anyhow::Result<()> {
+        self.state.sent(&message);
+        let mut frame = BytesMut::new();
+        self.codec.encode(message, &mut frame);
+        self.write_all(&frame)
+            .await
+            .context(format!("sending message of length {}", frame.len() - 4))?;
+        self.last_sent = time::Instant::now();
+        Ok(())
+    }
+
+    /// Send a keep-alive if we stayed silent for [`KEEP_ALIVE_INTERVAL`], for connections kept
+    /// open while not reading from them, e.g. while waiting for work.
+    pub async fn keep_alive(&mut self) -> anyhow::Result<()> {
+        if self.last_sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+            self.send(PeerMessage::KeepAlive)
+                .await
+                .context("sending keep-alive")?;
+        }
+        Ok(())
+    }
+
+    /// Read more of the stream into the buffer.
+    ///
+    /// This is where a connection waits for the peer the longest, so keep-alives are sent from
+    /// here whenever [`KEEP_ALIVE_INTERVAL`] passes without us sending anything.
+    async fn fill_buf(&mut self) -> anyhow::Result<()> {
+        enum Wake {
+            Read(io::Result<usize>),
+            KeepAlive,
+            Silent,
+        }
+
+        let silent_at = time::Instant::now() + self.timeouts.read;
+        loop {
+            let keep_alive_at = self.last_sent + KEEP_ALIVE_INTERVAL;
+            // reading into a buffer is cancellation safe, unlike `read_exact`
+            let wake = tokio::select! {
+                read = self.stream.read_buf(&mut self.read_buf) => Wake::Read(read),
+                _ = time::sleep_until(keep_alive_at) => Wake::KeepAlive,
+                _ = time::sleep_until(silent_at) => Wake::Silent,
+            };
+            match wake {
+                Wake::Read(Ok(0)) => {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+                        .context("reading message")
+                }
+                Wake::Read(read) => {
+                    read.context("reading message")?;
+                    return Ok(());
+                }
+                Wake::KeepAlive => self.keep_alive().await?,
+                Wake::Silent => return Err(self.silent()).context("reading message"),
+            }
+        }
+    }
+
+    /// Receive the next message, keep-alives included.
+    pub async fn recv(&mut self) -> anyhow::Result<PeerMessage> {
+        loop {
+            if let Some(message) = self.codec.decode(&mut self.read_buf)? {
+                self.state.received(&message);
+                if message == PeerMessage::Choke {
+                    // a choking peer drops every request, the cancelled ones included
+                    self.cancelled.clear();
+                }
+                return Ok(message);
+            }
+            self.fill_buf().await?;
+        }
+    }
+
+    /// Receive the next message that isn't a keep-alive.
+    pub async fn recv_skipping_keep_alives(&mut self) -> anyhow::Result<PeerMessage> {
+        loop {
+            match self.recv().await? {
+                PeerMessage::KeepAlive => continue,
+                message => return Ok(message),
+            }
+        }
+    }
+
+    /// Receive the next `Piece` message of `piece_index`, for one of the `pending` requested
+    /// `(offset, length)` blocks, into its place in `piece`, returning the block's offset once it
+    /// is no longer pending, or `None` if the peer choked us first, dropping every pending
+    /// request.
+    ///
+    /// Only the message header has to be buffered, the rest of the payload goes straight into
+    /// `piece` instead of through an intermediate message buffer. `Have` messages arriving
+    /// first are kept for [`PeerConnection::take_announced`], and blocks answering requests
+    /// cancelled before are dropped.
+    pub async fn recv_block(
+        &mut self,
+        piece_index: u32,
+        pending: &mut Vec<(u32, u32)>,
+        piece: &mut [u8],
+    ) -> anyhow::Result<Option<u32>> {
+        const PIECE_ID: u8 = 7;
+        const HEADER_LENGTH: usize = 4 + 9;
+
+        loop {
+            let length = loop {
+                let length = loop {
+                    match self.codec.frame_length(&self.read_buf)? {
+                        Some(length) if length <= 9 || self.read_buf.len() > 4 => break length,
+                        _ => self.fill_buf().await?,
+                    }
+                };
+                if length > 9 && self.read_buf[4] == PIECE_ID {
+                    while self.read_buf.len() < HEADER_LENGTH {
+                        self.fill_buf().await?;
+                    }
+                    break length;
+                }
+                match self.recv().await? {
+                    PeerMessage::KeepAlive
+                    | PeerMessage::UnChoke
+                    | PeerMessage::Interested
+                    | PeerMessage::NotInterested => {}
+                    PeerMessage::Have { piece_index } => self.announced.push(piece_index),
+                    PeerMessage::Choke => return Ok(None),
+                    message => {
+                        bail!("expected a block of piece {piece_index} but found a {message:?}")
+                    }
+                }
+            };
+
+            let header = self.read_buf.split_to(HEADER_LENGTH);
+            let header = &header[4..];
+            let received = Block {
+                piece_index: u32::from_be_bytes(header[1..5].try_into().unwrap()),
+                offset: u32::from_be_bytes(header[5..9].try_into().unwrap()),
+                length: (length - header.len()) as u32,
+            };
+            let Block { offset, length, .. } = received;
+            if let Some(position) = pending
+                .iter()
+                .position(|&block| received.piece_index == piece_index && block == (offset, length))
+            {
+                pending.swap_remove(position);
+
+                let block = &mut piece[offset as usize..(offset + length) as usize];
+                self.read_exact(block)
+                    .await
+                    .context(format!("reading piece[{piece_index}][{offset}]"))?;
+                self.received.fetch_add(length as u64, Ordering::Relaxed);
+                return Ok(Some(offset));
+            }
+
+            let Some(position) = self.cancelled.iter().position(|&block| block == received) else {
+                bail!(
+                    "expected a requested block of piece {piece_index} but found \
+                     piece[{}][{offset}] of length {length}",
+                    received.piece_index
+                );
+            };
+            // answered before the peer got our cancel
+            self.cancelled.swap_remove(position);
+            let mut block = vec![0; length as usize];
+            self.read_exact(&mut block).await.context(format!(
+                "reading cancelled piece[{}][{offset}]",
+                received.piece_index
+            ))?;
+            self.received.fetch_add(length as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Wait for the peer to unchoke us, if it chokes us. `Have` messages arriving meanwhile are
+    /// kept for [`PeerConnection::take_announced`], and blocks of requests it dropped when
+    /// choking us are ignored.
+    pub async fn wait_unchoke(&mut self) -> anyhow::Result<()> {
+        while self.state.peer_choking {
+            match self.recv().await? {
+                PeerMessage::KeepAlive
+                | PeerMessage::Choke
+                | PeerMessage::UnChoke
+                | PeerMessage::Interested
+                | PeerMessage::NotInterested
+                | PeerMessage::Piece { .. } => {}
+                PeerMessage::Have { piece_index } => self.announced.push(piece_index),
+                message => bail!("expected an Unchoke but found a {message:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Exchange the messages needed before requesting blocks, returning the peer's bitfield of
+    /// the torrent's `piece_count` pieces.
+    pub async fn initiate_download(&mut self, piece_count: usize) -> anyhow::Result<Bitfield> {
+        let message = self
+            .recv_skipping_keep_alives()
+            .await
+            .context("waiting for bitfield")?;
+        message.validate(piece_count)?;
+        let bitfield = match message {
+            PeerMessage::Bitfield { fields } => Bitfield::from(fields),
+            message => bail!("expected a Bitfield but found a {message:?}"),
+        };
+
+        self.send(PeerMessage::Interested)
+            .await
+            .context("sending interested")?;
+
+        self.wait_unchoke().await.context("waiting for unchoke")?;
+
+        Ok(bitfield)
+    }
+
+    /// Download the piece at `piece_index` in blocks of `block_size`, keeping up to the
+    /// [`PeerConnection::pipeline`] depth of requests outstanding, whatever the order the peer
+    /// answers them in.
+    ///
+    /// When the peer chokes us midway, the requests it dropped are sent again once it unchokes
+    /// us, if it does so within the request timeout.
+    pub async fn download_piece(
+        &mut self,
+        torrent: &Torrent,
+        piece_index: usize,
+        block_size: u32,
+    ) -> anyhow::Result<Vec<u8>> {
+        let piece = self
+            .download_piece_unless(torrent, piece_index, block_size, || false)
+            .await?;
+        Ok(piece.expect("never cancelled"))
+    }
+
+    /// [`PeerConnection::download_piece`], giving up on the piece as soon as `cancelled` says so
+    /// between two blocks, e.g. once another peer delivered it during endgame.
+    ///
+    /// Returns `None` once given up, after sending a `Cancel` for every block still requested.
+    /// Those the peer answers anyway are dropped when receiving the next blocks.
+    pub async fn download_piece_unless(
+        &mut self,
+        torrent: &Torrent,
+        piece_index: usize,
+        block_size: u32,
+        cancelled: impl Fn() -> bool,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let (piece_length, block_count, last_block_length) =
+            calculate_block_length(torrent, piece_index, block_size);
+        let piece_index = piece_index as u32;
+
+        let mut piece = vec![0u8; piece_length];
+        let mut blocks: VecDeque<_> = (0..block_count)
+            .map(|i| {
+                let length = match i == block_count - 1 {
+                    true => last_block_length,
+                    false => block_size,
+                };
+                (i * block_size, length)
+            })
+            .collect();
+        let mut pending = Vec::with_capacity(self.pipeline);
+        loop {
+            if cancelled() {
+                for (offset, length) in pending.drain(..) {
+                    let block = Block {
+                        piece_index,
+                        offset,
+                        length,
+                    };
+                    self.send(block.cancel())
+                        .await
+                        .context(format!("cancelling piece[{piece_index}][{offset}]"))?;
+                    self.cancelled.push(block);
+                }
+                return Ok(None);
+            }
+            while pending.len() < self.pipeline {
+                let Some((offset, length)) = blocks.pop_front() else {
+                    break;
+                };
+                self.send(PeerMessage::Request {
+                    piece_index,
+                    offset,
+                    length,
+                })
+                .await
+                .context(format!("requesting piece[{piece_index}][{offset}]"))?;
+                pending.push((offset, length));
+            }
+            if pending.is_empty() {
+                break;
+            }
+            let received = time::timeout(
+                self.timeouts.request,
+                self.recv_block(piece_index, &mut pending, &mut piece),
+            )
+            .await
+            .unwrap_or_else(|_| {
+                Err(PeerTimeout::error(
+                    self.addr,
+                    "answering a block request",
+                    self.timeouts.request,
+                )
+                .into())
+            });
+            let received =
+                received.context(format!("waiting for blocks of piece {piece_index}"))?;
+            if received.is_none() {
+                // the peer dropped our requests, they're sent again in order once it unchokes us
+                pending.sort_unstable();
+                for block in pending.drain(..).rev() {
+                    blocks.push_front(block);
+                }
+                time::timeout(self.timeouts.request, self.wait_unchoke())
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(
+                            PeerTimeout::error(self.addr, "unchoking us", self.timeouts.request)
+                                .into(),
+                        )
+                    })
+                    .context(format!("choked while downloading piece {piece_index}"))?;
+            }
+        }
+
+        Ok(Some(piece))
+    }
+
+    /// Fetch `length` hashes starting at `index` from the `base_layer` of the merkle tree rooted
+    /// at `pieces_root`, followed by the `proof_layers` uncle hashes sent by the peer to verify
+    /// them, which is up to the caller, e.g. [`PeerConnection::fetch_layer`].
+    ///
+    /// Messages received in the meantime are dropped, but for `Have`s, which are kept for
+    /// [`PeerConnection::take_announced`].
+    pub async fn fetch_hashes(
+        &mut self,
+        pieces_root: [u8; 32],
+        base_layer: u32,
+        index: u32,
+        length: u32,
+        proof_layers: u32,
+    ) -> anyhow::Result<Vec<[u8; 32]>> {
+        let request = PeerMessage::HashRequest {
+            pieces_root,
+            base_layer,
+            index,
+            length,
+            proof_layers,
+        };
+        let name = format!("{}[{base_layer}][{index}]", hex::encode(pieces_root));
+        self.send(request)
+            .await
+            .context(format!("requesting hashes {name}"))?;
+
+        loop {
+            match self.recv().await.context("waiting for hashes")? {
+                PeerMessage::Hashes {
+                    pieces_root: root,
+                    base_layer: layer,
+                    index: first,
+                    length: count,
+                    proof_layers: proofs,
+                    hashes,
+                } if (root, layer, first, count, proofs)
+                    == (pieces_root, base_layer, index, length, proof_layers) =>
+                {
+                    if hashes.len() != (length + proof_layers) as usize {
+                        bail!(
+                            "expected {length} hashes and {proof_layers} uncle hashes for {name} \
+                             but found {} hashes",
+                            hashes.len()
+                        );
+                    }
+                    return Ok(hashes);
+                }
+                PeerMessage::HashReject {
+                    pieces_root: root,
+                    base_layer: layer,
+                    index: first,
+                    length: count,
+                    proof_layers: proofs,
+                } if (root, layer, first, count, proofs)
+                    == (pieces_root, base_layer, index, length, proof_layers) =>
+                {
+                    bail!("peer rejected hash request for {name}")
+                }
+                PeerMessage::Have { piece_index } => self.announced.push(piece_index),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Fetch the whole `base_layer` of `count` hashes of the merkle tree rooted at `pieces_root`,
+    /// e.g. the piece layer of a v2 file whose `piece layers` are missing from the metadata.
+    ///
+    /// The layer is fetched [`MAX_HASHES_PER_REQUEST`] hashes at a time, every page checked
+    /// against `pieces_root` through the uncle hashes sent along with it.
+    pub async fn fetch_layer(
+        &mut self,
+        pieces_root: [u8; 32],
+        base_layer: u32,
+        count: usize,
+    ) -> anyhow::Result<Vec<[u8; 32]>> {
+        if count <= 1 {
+            // a layer of a single hash is the root
+            return Ok(vec![pieces_root; count]);
+        }
+        let width = count.next_power_of_two();
+        let length = width.min(MAX_HASHES_PER_REQUEST as usize);
+        let proof_layers = (width / length).trailing_zeros();
+
+        let mut layer = Vec::with_capacity(width);
+        for index in (0..count).step_by(length) {
+            let hashes = self
+                .fetch_hashes(
+                    pieces_root,
+                    base_layer,
+                    index as u32,
+                    length as u32,
+                    proof_layers,
+                )
+                .await?;
+            let (hashes, uncles) = hashes.split_at(length);
+            let mut root = merkle_root(hashes, length);
+            let mut position = index / length;
+            for uncle in uncles {
+                root = match position % 2 {
+                    0 => merkle_root(&[root, *uncle], 2),
+                    _ => merkle_root(&[*uncle, root], 2),
+                };
+                position /= 2;
+            }
+            if root != pieces_root {
+                bail!(
+                    "the hashes {}[{base_layer}][{index}] don't match their root",
+                    hex::encode(pieces_root)
+                );
+            }
+            layer.extend_from_slice(hashes);
+        }
+        layer.truncate(count);
+        Ok(layer)
+    }
+
+    /// Exchange extension handshakes (BEP 10), returning the peer's.
+    ///
+    /// Only meaningful once both handshakes advertised the extension protocol. Messages received
+    /// in the meantime, e.g. the peer's bitfield, are dropped.
+    pub async fn extension_handshake(
+        &mut self,
+        ours: &ExtensionHandshake,
+    ) -> anyhow::Result<ExtensionHandshake> {
+        let payload = ours.to_bytes();
+        self.send(PeerMessage::Extended {
+            ext_id: HANDSHAKE_ID,
+            payload,
+        })
+        .await
+        .context("sending extension handshake")?;
+
+        loop {
+            match self
+                .recv()
+                .await
+                .context("waiting for extension handshake")?
+            {
+                PeerMessage::Extended {
+                    ext_id: HANDSHAKE_ID,
+                    payload,
+                } => {
+                    let theirs = ExtensionHandshake::from_bytes(&payload)
+                        .context("parsing extension handshake")?;
+                    self.note_extension_handshake(&theirs);
+                    return Ok(theirs);
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// Fetch the info dictionary of the torrent with `info_hash` through `ut_metadata` (BEP 9),
+    /// from a peer that sent `theirs` as its extension handshake.
+    pub async fn fetch_metadata(
+        &mut self,
+        info_hash: [u8; 20],
+        theirs: &ExtensionHandshake,
+    ) -> anyhow::Result<Info> {
+        let Some(ext_id) = theirs.extension_id(UT_METADATA) else {
+            bail!("peer doesn't support metadata exchange");
+        };
+        let size = match theirs.metadata_size {
+            Some(0) | None => bail!("peer didn't announce the metadata size"),
+            Some(size) if size > MAX_METADATA_SIZE => {
+                bail!("peer announced metadata of {size} bytes, more than the {MAX_METADATA_SIZE} bytes limit")
+            }
+            Some(size) => size,
+        };
+
+        let mut metadata = Vec::with_capacity(size);
+        for piece in 0..size.div_ceil(METADATA_PIECE_SIZE) {
+            let payload = MetadataMessage::request(piece).to_bytes();
+            self.send(PeerMessage::Extended { ext_id, payload })
+                .await
+                .context(format!("requesting metadata piece {piece}"))?;
+
+            let payload = loop {
+                match self.recv().await.context("waiting for metadata")? {
+                    PeerMessage::Extended {
+                        ext_id: UT_METADATA_ID,
+                        payload,
+                    } => break payload,
+                    _ => continue,
+                }
+            };
+            let (message, data) = MetadataMessage::from_payload(&payload)
+                .context(format!("parsing metadata piece {piece}"))?;
+            if message.msg_type == MetadataMessageType::Reject as u8 {
+                bail!("peer rejected the request for metadata piece {piece}");
+            }
+            if message.msg_type != MetadataMessageType::Data as u8 || message.piece != piece {
+                bail!("expected metadata piece {piece} but found {message:?}");
+            }
+            let expected = METADATA_PIECE_SIZE.min(size - metadata.len());
+            if data.len() != expected {
+                bail!(
+                    "metadata piece {piece} should be {expected} bytes long, but found {}",
+                    data.len()
+                );
+            }
+            metadata.extend_from_slice(data);
+        }
+
+        let hash: [u8; 20] = Sha1::digest(&metadata).into();
+        if hash != info_hash {
+            bail!(
+                "metadata doesn't match the info hash, found {}",
+                hex::encode(hash)
+            );
+        }
+        serde_bencode::from_bytes(&metadata).context("parsing metadata")
+    }
+
+    /// Close the sending half of the connection.
+    pub async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.stream
+            .shutdown()
+            .await
+            .context("shutting down connection")
+    }
 }
 
 fn calculate_block_length(
@@ -369,111 +1644,548 @@ fn calculate_block_length(
     (piece_length, block_count, last_block_length)
 }
 
-pub fn download_piece(
-    stream: &mut TcpStream,
-    torrent: &Torrent,
-    piece_index: usize,
-    block_size: u32,
-) -> anyhow::Result<Vec<u8>> {
-    let (piece_length, block_count, last_block_length) =
-        calculate_block_length(torrent, piece_index, block_size);
-
-    let mut piece = vec![0u8; piece_length];
+/// A downloaded piece whose hash doesn't match the one listed in the torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashMismatch {
+    pub piece_index: usize,
+    pub expected: Vec<u8>,
+    pub found: Vec<u8>,
+}
 
-    for i in 0..(block_count - 1) {
-        let offset = i * block_size;
-        download_block(stream, piece_index as u32, offset, block_size, &mut piece)?;
+impl Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "hashes don't match expected: {}, but found: {}",
+            hex::encode(&self.expected),
+            hex::encode(&self.found)
+        )
     }
+}
 
-    // download last blocks
-    let offset = (block_count - 1) * block_size;
-    download_block(
-        stream,
-        piece_index as u32,
-        offset,
-        last_block_length,
-        &mut piece,
-    )?;
+impl Error for HashMismatch {}
 
-    Ok(piece)
+/// Check the piece at `piece_index` against its SHA-1 in `torrent`.
+pub fn validate_piece(torrent: &Torrent, piece_index: usize, piece: &[u8]) -> anyhow::Result<()> {
+    verify_piece(&Sha1Hasher, torrent, piece_index, piece)
 }
 
-fn download_block(
-    stream: &mut TcpStream,
-    piece_index: u32,
-    offset: u32,
-    length: u32,
-    piece: &mut [u8],
+/// Check the piece at `piece_index` against its hash in `torrent` with `hasher`.
+pub fn verify_piece(
+    hasher: &dyn PieceHasher,
+    torrent: &Torrent,
+    piece_index: usize,
+    piece: &[u8],
 ) -> anyhow::Result<()> {
-    let message = PeerMessage::Request {
-        piece_index,
-        offset,
-        length,
-    };
-    send_message(stream, message).context(format!("requesting piece[{piece_index}][{offset}]"))?;
-    let (block_piece_index, block_offset, block) = match receive_message(stream)
-        .context(format!("waiting for piece[{piece_index}][{offset}]"))?
-    {
-        PeerMessage::Piece {
-            piece_index: _,
-            offset,
-            piece,
-        } => (piece_index, offset, piece),
-        message => bail!("expected a Unchoke but found a {message:?}"),
-    };
+    hasher.verify(piece_index, piece, &torrent.info.pieces.0[piece_index])?;
+    Ok(())
+}
 
-    debug_assert_eq!(
-        piece_index, block_piece_index,
-        "requestd piece index doesn't match recieved piece index"
-    );
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{runtime, single_file};
+
+    #[test]
+    fn hash_messages_roundtrip() {
+        let request = PeerMessage::HashRequest {
+            pieces_root: [7; 32],
+            base_layer: 2,
+            index: 4,
+            length: 2,
+            proof_layers: 1,
+        };
+        let hashes = PeerMessage::Hashes {
+            pieces_root: [7; 32],
+            base_layer: 2,
+            index: 4,
+            length: 2,
+            proof_layers: 1,
+            hashes: vec![[1; 32], [2; 32], [3; 32]],
+        };
+
+        for message in [request, hashes.clone()] {
+            let bytes: Vec<u8> = message.clone().into();
+            assert_eq!(PeerMessage::try_from(bytes.as_slice()), Ok(message));
+        }
 
-    debug_assert_eq!(
-        offset, block_offset,
-        "requestd block offset doesn't match recieved block offset"
-    );
+        let mut bytes: Vec<u8> = hashes.into();
+        bytes.pop();
+        assert_eq!(
+            PeerMessage::try_from(bytes.as_slice()),
+            Err(PeerMessageError::HashesLength(95))
+        );
+        for code in 21..=23 {
+            let mut truncated = vec![code];
+            truncated.extend([0; 47]);
+            assert_eq!(
+                PeerMessage::try_from(truncated.as_slice()),
+                Err(PeerMessageError::Truncated { code, length: 48 })
+            );
+        }
+    }
 
-    let block_length = block.len() as u32;
-    debug_assert_eq!(
-        length, block_length,
-        "requestd block length doesn't match recieved block length"
-    );
+    #[test]
+    fn validate_piece_indices() {
+        let bitfield = |fields: &[u8]| PeerMessage::Bitfield {
+            fields: fields.to_vec(),
+        };
+        assert_eq!(bitfield(&[0xff, 0xc0]).validate(10), Ok(()));
+        assert_eq!(bitfield(&[0xff]).validate(8), Ok(()));
+        assert_eq!(
+            bitfield(&[0xff, 0xe0]).validate(10),
+            Err(PeerMessageError::BitfieldSpareBits)
+        );
+        assert_eq!(
+            bitfield(&[0xff]).validate(10),
+            Err(PeerMessageError::BitfieldLength {
+                expected: 2,
+                found: 1
+            })
+        );
+
+        assert_eq!(PeerMessage::Have { piece_index: 9 }.validate(10), Ok(()));
+        assert_eq!(
+            PeerMessage::Have { piece_index: 10 }.validate(10),
+            Err(PeerMessageError::PieceIndexOutOfRange {
+                piece_index: 10,
+                piece_count: 10
+            })
+        );
+    }
 
-    piece[block_offset as usize..(block_offset + block_length) as usize].copy_from_slice(&block);
+    #[test]
+    fn capabilities_from_reserved_bytes() {
+        let mut handshake = HandShake::new([0; 20]).extension_protocol();
+        handshake.reserved[7] = 0x01 | 0x04 | 0x10;
+        assert_eq!(
+            Capabilities::from_handshake(&handshake).flags(),
+            [true, true, true, false, false, true]
+        );
+        handshake.reserved[7] = 0x04;
+        assert_eq!(
+            Capabilities::from_handshake(&handshake),
+            Capabilities {
+                fast: true,
+                extension_protocol: true,
+                ..Capabilities::default()
+            }
+        );
+    }
 
-    Ok(())
-}
+    #[test]
+    fn client_names() {
+        assert_eq!(
+            client_name(b"-qB4250-abcdefghijkl").as_deref(),
+            Some("qBittorrent 4250")
+        );
+        assert_eq!(
+            client_name(b"-LT2090-abcdefghijkl").as_deref(),
+            Some("libtorrent (Rasterbar) 2090")
+        );
+        assert_eq!(
+            client_name(b"-lt0D80-abcdefghijkl").as_deref(),
+            Some("libTorrent (Rakshasa) 0D80")
+        );
+        assert_eq!(
+            client_name(b"-XX0001-abcdefghijkl").as_deref(),
+            Some("XX 0001")
+        );
+        assert_eq!(
+            client_name(b"M7-2-2--abcdefghijkl").as_deref(),
+            Some("mainline BitTorrent 7.2.2")
+        );
+        assert_eq!(
+            client_name(b"M10-1-2--abcdefghijk").as_deref(),
+            Some("mainline BitTorrent 10.1.2")
+        );
+        assert_eq!(
+            client_name(b"M7-2-2-xabcdefghijkl"),
+            None,
+            "no end to the version"
+        );
+        assert_eq!(client_name(b"00112233445566778899"), None);
+
+        let ours = PeerId::ours();
+        assert_eq!(ours, PeerId::ours(), "generated once");
+        assert_eq!(HandShake::new([0; 20]).peer_id, ours.0);
+        assert!(ours.to_string().starts_with("-RS0100-"));
+        assert!(ours.0[8..].iter().all(u8::is_ascii_digit));
+        assert_eq!(
+            client_name(&ours.0).as_deref(),
+            Some("bittorrent-rust 0100")
+        );
+        assert_ne!(PeerId::random(), ours);
+    }
 
-pub fn initiate_download(stream: &mut TcpStream) -> anyhow::Result<()> {
-    match receive_message(stream).context("waiting for bitfield")? {
-        PeerMessage::Bitfield { .. } => (),
-        message => bail!("expected a Bitfield but found a {message:?}"),
+    #[test]
+    fn pipelined_blocks_in_any_order() {
+        use crate::dial::Direct;
+
+        let content: Vec<u8> = (0..32).collect();
+        let torrent = single_file(16, &content);
+
+        let runtime = runtime();
+        let (mut connection, piece) = runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let seeder = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut connection = PeerConnection::accept(stream).unwrap();
+                let mut requests = Vec::new();
+                // answer every window of 3 outstanding requests back to front, the 4th block
+                // only being asked for once the first answer arrived
+                for window in [3, 1] {
+                    for _ in 0..window {
+                        requests.push(connection.recv().await.unwrap());
+                    }
+                    connection
+                        .send(PeerMessage::Have { piece_index: 1 })
+                        .await
+                        .unwrap();
+                    while let Some(PeerMessage::Request {
+                        piece_index,
+                        offset,
+                        length,
+                    }) = requests.pop()
+                    {
+                        let start = (piece_index * 16 + offset) as usize;
+                        let piece = content[start..start + length as usize].to_vec();
+                        connection
+                            .send(PeerMessage::Piece {
+                                piece_index,
+                                offset,
+                                piece,
+                            })
+                            .await
+                            .unwrap();
+                        if window == 3 {
+                            break;
+                        }
+                    }
+                }
+                connection
+            };
+            let leecher = async {
+                let connection = PeerConnection::connect(&Direct, addr).await.unwrap();
+                let mut connection = connection.pipeline(3);
+                let piece = connection.download_piece(&torrent, 1, 4).await.unwrap();
+                (connection, piece)
+            };
+            let (_, (connection, piece)) = tokio::join!(seeder, leecher);
+            (connection, piece)
+        });
+
+        assert_eq!(piece, content[16..]);
+        assert_eq!(connection.take_announced(), [1, 1]);
     }
 
-    send_message(stream, PeerMessage::Interested).context("sending interested")?;
+    #[test]
+    fn keep_alives_both_ways() {
+        use crate::dial::Direct;
+
+        assert_eq!(PeerMessage::try_from(&[][..]), Ok(PeerMessage::KeepAlive));
+        assert_eq!(Vec::<u8>::from(PeerMessage::KeepAlive), Vec::<u8>::new());
+
+        let runtime = runtime();
+        runtime.block_on(async {
+            // over IPv6, to both ends
+            let listener = tokio::net::TcpListener::bind("[::1]:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut ours = PeerConnection::connect(&Direct, addr).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut theirs = PeerConnection::accept(stream).unwrap();
+            assert_eq!(theirs.addr().ip(), addr.ip());
+
+            // we've been silent for long enough, waiting for the peer sends a keep-alive first
+            ours.last_sent -= KEEP_ALIVE_INTERVAL;
+            let peer = async {
+                assert_eq!(theirs.recv().await.unwrap(), PeerMessage::KeepAlive);
+                theirs.send(PeerMessage::KeepAlive).await.unwrap();
+                theirs.send(PeerMessage::UnChoke).await.unwrap();
+            };
+            let (message, ()) = tokio::join!(ours.recv(), peer);
+            assert_eq!(message.unwrap(), PeerMessage::KeepAlive);
+            assert_eq!(
+                ours.recv_skipping_keep_alives().await.unwrap(),
+                PeerMessage::UnChoke
+            );
+
+            // a recent message puts the next keep-alive off
+            ours.keep_alive().await.unwrap();
+            drop(ours);
+            assert!(theirs.recv().await.is_err());
+        });
+    }
 
-    match receive_message(stream).context("waiting for unchoke")? {
-        PeerMessage::UnChoke => (),
-        message => bail!("expected a Unchoke but found a {message:?}"),
+    #[test]
+    fn timeouts_name_the_peer() {
+        use crate::{dial::Direct, events::ErrorKind};
+
+        let torrent = single_file(32, &[0; 32]);
+        let timeouts = Timeouts {
+            read: Duration::from_millis(300),
+            request: Duration::from_millis(100),
+            ..Timeouts::default()
+        };
+        let timed_out = |err: anyhow::Error| -> PeerTimeout {
+            assert!(ErrorKind::is_timeout(&err));
+            *err.chain()
+                .find_map(|cause| cause.downcast_ref::<io::Error>()?.get_ref()?.downcast_ref())
+                .expect("the error names the peer")
+        };
+
+        let runtime = runtime();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            // a peer that never answers the handshake
+            let mut silent = PeerConnection::connect_with(&Direct, addr, timeouts)
+                .await
+                .unwrap();
+            let (_stream, _) = listener.accept().await.unwrap();
+            let err = silent.handshake(HandShake::new([0; 20])).await.unwrap_err();
+            let timeout = timed_out(err);
+            assert_eq!((timeout.peer, timeout.doing), (addr, "sending anything"));
+
+            // a peer that sends keep-alives, but never the blocks requested
+            let mut ours = PeerConnection::connect_with(&Direct, addr, timeouts)
+                .await
+                .unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut theirs = PeerConnection::accept(stream).unwrap();
+            let peer = async {
+                for _ in 0..4 {
+                    time::sleep(Duration::from_millis(50)).await;
+                    theirs.send(PeerMessage::KeepAlive).await.unwrap();
+                }
+            };
+            let (piece, ()) = tokio::join!(ours.download_piece(&torrent, 0, 16), peer);
+            let timeout = timed_out(piece.unwrap_err());
+            assert_eq!(timeout.doing, "answering a block request");
+            assert_eq!(
+                timeout.to_string(),
+                format!("peer {addr} timed out answering a block request after 100ms")
+            );
+        });
     }
 
-    Ok(())
-}
+    #[test]
+    fn choked_midway_requests_again() {
+        use crate::dial::Direct;
 
-pub fn validate_piece(torrent: &Torrent, piece_index: usize, piece: &[u8]) -> anyhow::Result<()> {
-    let slice_hash = torrent.info.pieces.0[piece_index];
-    let current_hash: [u8; 20] = {
-        let mut hasher = Sha1::new();
-        hasher.update(piece);
-        hasher.finalize().into()
-    };
+        let content: Vec<u8> = (0..12).collect();
+        let torrent = single_file(12, &content);
+        let request = |offset| PeerMessage::Request {
+            piece_index: 0,
+            offset,
+            length: 4,
+        };
+        let block = |offset: u32| PeerMessage::Piece {
+            piece_index: 0,
+            offset,
+            piece: content[offset as usize..offset as usize + 4].to_vec(),
+        };
+
+        let runtime = runtime();
+        let (state, piece, rerequested) = runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let seeder = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut theirs = PeerConnection::accept(stream).unwrap();
+                assert_eq!(theirs.recv().await.unwrap(), PeerMessage::Interested);
+                theirs.send(PeerMessage::UnChoke).await.unwrap();
+                assert_eq!(theirs.recv().await.unwrap(), request(0));
+                assert_eq!(theirs.recv().await.unwrap(), request(4));
+                theirs.send(block(0)).await.unwrap();
+                assert_eq!(theirs.recv().await.unwrap(), request(8));
+                theirs.send(PeerMessage::Choke).await.unwrap();
+                // answered anyway, on its way before the choke took effect
+                theirs.send(block(4)).await.unwrap();
+                theirs
+                    .send(PeerMessage::Have { piece_index: 0 })
+                    .await
+                    .unwrap();
+                theirs.send(PeerMessage::UnChoke).await.unwrap();
+                let rerequested = [theirs.recv().await.unwrap(), theirs.recv().await.unwrap()];
+                theirs.send(block(8)).await.unwrap();
+                theirs.send(block(4)).await.unwrap();
+                (theirs, rerequested)
+            };
+            let leecher = async {
+                let mut ours = PeerConnection::connect(&Direct, addr).await.unwrap();
+                ours.send(PeerMessage::Interested).await.unwrap();
+                ours.wait_unchoke().await.unwrap();
+                let mut ours = ours.pipeline(2);
+                let piece = ours.download_piece(&torrent, 0, 4).await.unwrap();
+                (ours.state(), piece)
+            };
+            let ((_, rerequested), (state, piece)) = tokio::join!(seeder, leecher);
+            (state, piece, rerequested)
+        });
+
+        assert_eq!(piece, content);
+        assert_eq!(rerequested, [request(4), request(8)], "in order");
+        assert!(state.can_request());
+        assert!(state.am_choking && !state.peer_interested);
+    }
 
-    if slice_hash != current_hash {
-        bail!(
-            "hashes don't match expected: {}, but found: {}",
-            hex::encode(slice_hash),
-            hex::encode(current_hash)
-        )
+    #[test]
+    fn cancelled_blocks_are_dropped() {
+        use crate::dial::Direct;
+        use std::cell::Cell;
+
+        let content: Vec<u8> = (0..16).collect();
+        let torrent = single_file(8, &content);
+        let request = |piece_index, offset| PeerMessage::Request {
+            piece_index,
+            offset,
+            length: 4,
+        };
+        let block = |piece_index: u32, offset: u32| {
+            let start = (piece_index * 8 + offset) as usize;
+            PeerMessage::Piece {
+                piece_index,
+                offset,
+                piece: content[start..start + 4].to_vec(),
+            }
+        };
+
+        let runtime = runtime();
+        let (cancelled, piece) = runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let seeder = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut theirs = PeerConnection::accept(stream).unwrap();
+                assert_eq!(theirs.recv().await.unwrap(), request(0, 0));
+                assert_eq!(theirs.recv().await.unwrap(), request(0, 4));
+                theirs.send(block(0, 0)).await.unwrap();
+                assert_eq!(
+                    theirs.recv().await.unwrap(),
+                    PeerMessage::Cancel {
+                        piece_index: 0,
+                        offset: 4,
+                        length: 4
+                    }
+                );
+                // answered anyway, on its way before the cancel arrived
+                theirs.send(block(0, 4)).await.unwrap();
+                assert_eq!(theirs.recv().await.unwrap(), request(1, 0));
+                assert_eq!(theirs.recv().await.unwrap(), request(1, 4));
+                theirs.send(block(1, 4)).await.unwrap();
+                theirs.send(block(1, 0)).await.unwrap();
+            };
+            let leecher = async {
+                let ours = PeerConnection::connect(&Direct, addr).await.unwrap();
+                let mut ours = ours.pipeline(2);
+                // given up on once the first block arrived
+                let checks = Cell::new(0);
+                let cancelled = ours
+                    .download_piece_unless(&torrent, 0, 4, || {
+                        checks.set(checks.get() + 1);
+                        checks.get() > 1
+                    })
+                    .await
+                    .unwrap();
+                let piece = ours.download_piece(&torrent, 1, 4).await.unwrap();
+                (cancelled, piece)
+            };
+            let ((), result) = tokio::join!(seeder, leecher);
+            result
+        });
+
+        assert_eq!(cancelled, None);
+        assert_eq!(piece, content[8..]);
     }
 
-    Ok(())
+    #[test]
+    fn merkle_layers_are_checked_against_their_root() {
+        use crate::{dial::Direct, sha256::Sha256};
+
+        // a layer of 1000 hashes, padded to 1024, fetched in two pages of 512
+        let layer: Vec<[u8; 32]> = (0..1024u32)
+            .map(|i| match i < 1000 {
+                true => Sha256::digest(&i.to_be_bytes()),
+                false => [0; 32],
+            })
+            .collect();
+        let root = merkle_root(&layer[..1000], 1024);
+        let pages = [
+            merkle_root(&layer[..512], 512),
+            merkle_root(&layer[512..], 512),
+        ];
+
+        let runtime = runtime();
+        let (fetched, tampered, truncated) = runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let seeder = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut theirs = PeerConnection::accept(stream).unwrap();
+                for answer in 0..4 {
+                    let PeerMessage::HashRequest {
+                        pieces_root,
+                        base_layer,
+                        index,
+                        length,
+                        proof_layers,
+                    } = theirs.recv().await.unwrap()
+                    else {
+                        panic!("expected a HashRequest");
+                    };
+                    assert_eq!((length, proof_layers), (512, 1));
+                    let page = index as usize / 512;
+                    let mut hashes = layer[index as usize..][..512].to_vec();
+                    hashes.push(pages[1 - page]);
+                    match answer {
+                        2 => hashes[512][0] ^= 1,
+                        3 => drop(hashes.pop()),
+                        _ => {}
+                    }
+                    // unrelated messages come first
+                    theirs
+                        .send(PeerMessage::Have { piece_index: 3 })
+                        .await
+                        .unwrap();
+                    theirs.send(PeerMessage::UnChoke).await.unwrap();
+                    theirs
+                        .send(PeerMessage::Hashes {
+                            pieces_root,
+                            base_layer,
+                            index,
+                            length,
+                            proof_layers,
+                            hashes,
+                        })
+                        .await
+                        .unwrap();
+                }
+            };
+            let leecher = async {
+                let mut ours = PeerConnection::connect(&Direct, addr).await.unwrap();
+                let fetched = ours.fetch_layer(root, 2, 1000).await.unwrap();
+                let tampered = ours.fetch_layer(root, 2, 1000).await.unwrap_err();
+                let truncated = ours.fetch_layer(root, 2, 1000).await.unwrap_err();
+                assert_eq!(ours.take_announced(), [3; 4]);
+                (fetched, tampered, truncated)
+            };
+            let ((), result) = tokio::join!(seeder, leecher);
+            result
+        });
+
+        assert_eq!(fetched, layer[..1000]);
+        assert!(
+            tampered.to_string().contains("don't match their root"),
+            "{tampered:#}"
+        );
+        assert!(
+            truncated
+                .to_string()
+                .contains("expected 512 hashes and 1 uncle hashes"),
+            "{truncated:#}"
+        );
+    }
 }
@@ -0,0 +1,248 @@
+//! The peers listed by the `peers` command, with what probing them found, sorted, filtered by
+//! network and cut to length so that a large swarm stays readable.
+
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::tracker::Peers;
+
+/// A peer, and how long connecting and handshaking with it took when it was probed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerEntry {
+    pub addr: SocketAddr,
+    /// `None` when the peer wasn't probed, or didn't answer.
+    pub latency: Option<Duration>,
+}
+
+/// The order peers are listed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerSort {
+    /// As the tracker returned them.
+    #[default]
+    Tracker,
+    Ip,
+    Port,
+    /// The fastest first, then those that didn't answer.
+    Latency,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownPeerSort(String);
+
+impl Display for UnknownPeerSort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown sort {:?}, expected `ip`, `port` or `latency`",
+            self.0
+        )
+    }
+}
+
+impl Error for UnknownPeerSort {}
+
+impl FromStr for PeerSort {
+    type Err = UnknownPeerSort;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(Self::Ip),
+            "port" => Ok(Self::Port),
+            "latency" => Ok(Self::Latency),
+            _ => Err(UnknownPeerSort(s.to_string())),
+        }
+    }
+}
+
+/// A network, e.g. `10.0.0.0/8` or `2001:db8::/32`, a bare address being a network of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidCidr {
+    Address(String),
+    Prefix(String),
+}
+
+impl Display for InvalidCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidCidr::Address(address) => write!(f, "{address:?} isn't an IP address"),
+            InvalidCidr::Prefix(prefix) => write!(f, "invalid prefix length {prefix:?}"),
+        }
+    }
+}
+
+impl Error for InvalidCidr {}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = address
+            .parse()
+            .map_err(|_| InvalidCidr::Address(address.to_string()))?;
+        let max = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            None => max,
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= max)
+                .ok_or_else(|| InvalidCidr::Prefix(prefix.to_string()))?,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl Cidr {
+    /// Whether `ip` is in the network, IPv4-mapped IPv6 addresses counting as IPv4 ones.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The peers to list, in the order they're listed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerList(pub Vec<PeerEntry>);
+
+impl From<Peers> for PeerList {
+    fn from(peers: Peers) -> Self {
+        Self(
+            peers
+                .0
+                .into_iter()
+                .map(|addr| PeerEntry {
+                    addr,
+                    latency: None,
+                })
+                .collect(),
+        )
+    }
+}
+
+impl PeerList {
+    /// Record that probing `addr` took `latency`.
+    pub fn probed(&mut self, addr: SocketAddr, latency: Duration) {
+        for entry in self.0.iter_mut().filter(|entry| entry.addr == addr) {
+            entry.latency = Some(latency);
+        }
+    }
+
+    /// Keep the peers in any of `networks`, or every peer when there are none.
+    pub fn filter(&mut self, networks: &[Cidr]) {
+        if !networks.is_empty() {
+            self.0.retain(|entry| {
+                networks
+                    .iter()
+                    .any(|network| network.contains(entry.addr.ip()))
+            });
+        }
+    }
+
+    pub fn sort(&mut self, sort: PeerSort) {
+        match sort {
+            PeerSort::Tracker => {}
+            PeerSort::Ip => self
+                .0
+                .sort_by_key(|entry| (entry.addr.ip(), entry.addr.port())),
+            PeerSort::Port => self
+                .0
+                .sort_by_key(|entry| (entry.addr.port(), entry.addr.ip())),
+            PeerSort::Latency => self
+                .0
+                .sort_by_key(|entry| (entry.latency.is_none(), entry.latency)),
+        }
+    }
+
+    /// Keep the first `limit` peers.
+    pub fn limit(&mut self, limit: usize) {
+        self.0.truncate(limit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sort_filter_and_limit() {
+        let addr = |addr: &str| addr.parse::<SocketAddr>().unwrap();
+        let mut peers = PeerList::from(Peers(vec![
+            addr("10.0.0.2:6881"),
+            addr("192.168.1.5:51413"),
+            addr("10.0.0.1:7000"),
+            addr("[2001:db8::1]:6881"),
+        ]));
+        peers.probed(addr("10.0.0.1:7000"), Duration::from_millis(80));
+        peers.probed(addr("192.168.1.5:51413"), Duration::from_millis(20));
+        let order = |peers: &PeerList| -> Vec<SocketAddr> {
+            peers.0.iter().map(|entry| entry.addr).collect()
+        };
+
+        peers.sort(PeerSort::Latency);
+        assert_eq!(
+            order(&peers)[..2],
+            [addr("192.168.1.5:51413"), addr("10.0.0.1:7000")],
+            "the unanswered peers last"
+        );
+        peers.sort(PeerSort::Port);
+        assert_eq!(order(&peers)[0], addr("10.0.0.2:6881"));
+        peers.sort(PeerSort::Ip);
+        assert_eq!(order(&peers)[0], addr("10.0.0.1:7000"));
+
+        let networks = [
+            "10.0.0.0/8".parse().unwrap(),
+            "2001:db8::/32".parse().unwrap(),
+        ];
+        peers.filter(&networks);
+        assert_eq!(peers.0.len(), 3);
+        peers.limit(2);
+        assert_eq!(
+            order(&peers),
+            [addr("10.0.0.1:7000"), addr("10.0.0.2:6881")]
+        );
+
+        let cidr: Cidr = "10.0.0.1".parse().unwrap();
+        assert!(cidr.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("10.0.0.2".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+        assert_eq!(
+            "10.0.0.0/33".parse::<Cidr>(),
+            Err(InvalidCidr::Prefix("33".to_string()))
+        );
+        assert!("ipx".parse::<PeerSort>().is_err());
+    }
+}
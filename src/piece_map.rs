@@ -0,0 +1,55 @@
+use std::path::Path;
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::resume::write_atomic;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PieceState {
+    Missing,
+    Downloading,
+    Verified,
+}
+
+/// A snapshot of every piece's state and how many known peers advertise it, exported for external
+/// visualization.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct PieceMap {
+    pub states: Vec<PieceState>,
+
+    /// The number of peers whose bitfield has the piece at the corresponding index.
+    pub availability: Vec<u32>,
+}
+
+impl PieceMap {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            states: vec![PieceState::Missing; piece_count],
+            availability: vec![0; piece_count],
+        }
+    }
+
+    pub fn set_state(&mut self, piece_index: usize, state: PieceState) {
+        self.states[piece_index] = state;
+    }
+
+    /// Account for a peer advertising the pieces set in `bitfield`.
+    pub fn add_bitfield(&mut self, bitfield: &[u8]) {
+        for (piece_index, count) in self.availability.iter_mut().enumerate() {
+            let has_piece = bitfield
+                .get(piece_index / 8)
+                .is_some_and(|byte| byte & (0x80 >> (piece_index % 8)) != 0);
+            if has_piece {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Atomically replace the JSON dump at `path` with the current state.
+    pub fn dump(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_vec(self).context("serializing piece map")?;
+        write_atomic(path, &json).context("dumping piece map")
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use crate::torrent::{Content, Torrent};
+
+/// How urgently a file is wanted, pieces of higher priority files are downloaded first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Don't download the file at all.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = InvalidPriority;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "skip" => Ok(Priority::Skip),
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(InvalidPriority::UnknownLevel(level.to_string())),
+        }
+    }
+}
+
+impl Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Priority::*;
+        match self {
+            Skip => "skip",
+            Low => "low",
+            Normal => "normal",
+            High => "high",
+        }
+        .fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidPriority {
+    Malformed(String),
+    UnknownLevel(String),
+    FileIndexOutOfRange { index: usize, file_count: usize },
+}
+
+impl Display for InvalidPriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use InvalidPriority::*;
+        match self {
+            Malformed(setting) => write!(f, "expected FILE:LEVEL but found {setting:?}"),
+            UnknownLevel(level) => write!(
+                f,
+                "unknown priority {level:?}, expected high, normal, low or skip"
+            ),
+            FileIndexOutOfRange { index, file_count } => write!(
+                f,
+                "file index {index} is out of range for {file_count} files"
+            ),
+        }
+    }
+}
+
+impl Error for InvalidPriority {}
+
+/// The priorities given to some files of a torrent by index, parsed from e.g. `0:high,3:skip`.
+/// Files without one have [`Priority::Normal`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilePriorities(pub Vec<(usize, Priority)>);
+
+impl FromStr for FilePriorities {
+    type Err = InvalidPriority;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        spec.split(',')
+            .filter(|setting| !setting.is_empty())
+            .map(|setting| {
+                let malformed = || InvalidPriority::Malformed(setting.to_string());
+                let (index, level) = setting.split_once(':').ok_or_else(malformed)?;
+                Ok((index.parse().map_err(|_| malformed())?, level.parse()?))
+            })
+            .collect::<Result<_, _>>()
+            .map(FilePriorities)
+    }
+}
+
+impl FilePriorities {
+    /// The priority of every file of `torrent`, the later of conflicting settings winning.
+    pub fn for_files(&self, torrent: &Torrent) -> Result<Vec<Priority>, InvalidPriority> {
+        let file_count = match &torrent.info.content {
+            Content::SingleFile { .. } => 1,
+            Content::MultiFile { files } => files.len(),
+        };
+        let mut priorities = vec![Priority::Normal; file_count];
+        for &(index, priority) in &self.0 {
+            *priorities
+                .get_mut(index)
+                .ok_or(InvalidPriority::FileIndexOutOfRange { index, file_count })? = priority;
+        }
+        Ok(priorities)
+    }
+
+    /// The priority of every piece of `torrent`, the highest of the files it overlaps.
+    pub fn for_pieces(&self, torrent: &Torrent) -> Result<Vec<Priority>, InvalidPriority> {
+        let file_priorities = self.for_files(torrent)?;
+        let file_lengths: Vec<usize> = match &torrent.info.content {
+            Content::SingleFile { length } => vec![*length],
+            Content::MultiFile { files } => files.iter().map(|file| file.length).collect(),
+        };
+
+        let piece_length = torrent.info.piece_length;
+        let mut priorities = vec![Priority::Skip; torrent.info.pieces.0.len()];
+        let mut start = 0;
+        for (length, priority) in file_lengths.into_iter().zip(file_priorities) {
+            if length > 0 {
+                let (first, last) = (start / piece_length, (start + length - 1) / piece_length);
+                for piece in &mut priorities[first..=last] {
+                    *piece = (*piece).max(priority);
+                }
+            }
+            start += length;
+        }
+        Ok(priorities)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::torrent;
+
+    #[test]
+    fn pieces_take_the_highest_priority_of_their_files() {
+        // pieces of 4 bytes over files of 6, 2 and 8 bytes
+        let torrent = torrent(4, &[("a", &[0; 6]), ("b", &[0; 2]), ("c", &[0; 8])]);
+        assert!(matches!(torrent.info.content, Content::MultiFile { .. }));
+
+        let priorities: FilePriorities = "0:skip,2:low,1:high".parse().unwrap();
+        assert_eq!(
+            priorities.for_pieces(&torrent).unwrap(),
+            [Priority::Skip, Priority::High, Priority::Low, Priority::Low]
+        );
+        assert_eq!(
+            "3:high"
+                .parse::<FilePriorities>()
+                .unwrap()
+                .for_files(&torrent),
+            Err(InvalidPriority::FileIndexOutOfRange {
+                index: 3,
+                file_count: 3
+            })
+        );
+        assert!("0:urgent".parse::<FilePriorities>().is_err());
+        assert!("high".parse::<FilePriorities>().is_err());
+    }
+}
@@ -0,0 +1,253 @@
+//! Live progress of a transfer, its recent rates overall and per peer, and a status line showing
+//! them on stderr.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Write},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::Clock,
+    display::{ByteSize, Span},
+    status::Progress,
+};
+
+/// How far back the rates look, long enough to smooth over pieces arriving in bursts.
+pub const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// The bytes received over the last [`RATE_WINDOW`].
+#[derive(Debug, Clone, Default)]
+struct Samples(VecDeque<(Instant, u64)>);
+
+impl Samples {
+    fn record(&mut self, now: Instant, bytes: u64) {
+        self.0.push_back((now, bytes));
+    }
+
+    /// Drop the samples older than the window.
+    fn expire(&mut self, now: Instant) {
+        while self
+            .0
+            .front()
+            .is_some_and(|&(at, _)| now.saturating_duration_since(at) > RATE_WINDOW)
+        {
+            self.0.pop_front();
+        }
+    }
+
+    /// The bytes per second over the window, or since `start` when it's shorter.
+    fn rate(&self, now: Instant, start: Instant) -> f64 {
+        let span = now.saturating_duration_since(start).min(RATE_WINDOW);
+        if span.is_zero() {
+            return 0.0;
+        }
+        let bytes: u64 = self.0.iter().map(|&(_, bytes)| bytes).sum();
+        bytes as f64 / span.as_secs_f64()
+    }
+}
+
+/// Measures how fast a transfer goes, overall and with every peer.
+#[derive(Debug)]
+pub struct ProgressMeter<C: Clock> {
+    clock: C,
+    start: Instant,
+    total: Samples,
+    peers: HashMap<SocketAddr, Samples>,
+}
+
+impl<C: Clock> ProgressMeter<C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            start: clock.now(),
+            clock,
+            total: Samples::default(),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Record `bytes` just received from `peer`.
+    pub fn record(&mut self, peer: SocketAddr, bytes: u64) {
+        let now = self.clock.now();
+        self.total.record(now, bytes);
+        self.peers.entry(peer).or_default().record(now, bytes);
+    }
+
+    fn expire(&mut self) {
+        let now = self.clock.now();
+        self.total.expire(now);
+        for samples in self.peers.values_mut() {
+            samples.expire(now);
+        }
+        self.peers.retain(|_, samples| !samples.0.is_empty());
+    }
+
+    /// The bytes per second received recently.
+    pub fn rate(&mut self) -> f64 {
+        self.expire();
+        self.total.rate(self.clock.now(), self.start)
+    }
+
+    /// The bytes per second received recently from every peer that sent anything, fastest first.
+    pub fn peer_rates(&mut self) -> Vec<(SocketAddr, f64)> {
+        self.expire();
+        let now = self.clock.now();
+        let mut rates: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(&peer, samples)| (peer, samples.rate(now, self.start)))
+            .collect();
+        rates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        rates
+    }
+
+    /// A one-line summary of `progress`, e.g. `[######--------------]  31.2%  4/10 pieces
+    /// 91.55 KiB/292.97 KiB  45.00 KiB/s  ETA 5s  2 peers, fastest 10.0.0.1:6881`.
+    pub fn line(&mut self, progress: Progress) -> String {
+        const WIDTH: usize = 20;
+
+        let fraction = match progress.bytes_wanted {
+            0 => 1.0,
+            wanted => progress.bytes_done as f64 / wanted as f64,
+        };
+        let filled = ((fraction * WIDTH as f64) as usize).min(WIDTH);
+        let rate = self.rate();
+        let left = progress.bytes_wanted.saturating_sub(progress.bytes_done);
+        let eta = match left {
+            0 => Span(Duration::ZERO).to_string(),
+            _ if rate > 0.0 => {
+                Span(Duration::from_secs((left as f64 / rate).ceil() as u64)).to_string()
+            }
+            _ => "?".to_string(),
+        };
+        let mut line = format!(
+            "[{}{}] {:>5.1}%  {}/{} pieces  {}/{}  {}/s  ETA {eta}  {} peers",
+            "#".repeat(filled),
+            "-".repeat(WIDTH - filled),
+            fraction * 100.0,
+            progress.pieces_done,
+            progress.pieces_wanted,
+            ByteSize(progress.bytes_done),
+            ByteSize(progress.bytes_wanted),
+            ByteSize(rate as u64),
+            progress.peers
+        );
+        if let Some((peer, _)) = self.peer_rates().first() {
+            line.push_str(&format!(", fastest {peer}"));
+        }
+        line
+    }
+}
+
+/// Shows status lines on `out`: redrawn in place on a terminal, or one every [`LOG_INTERVAL`]
+/// otherwise, so that logs don't fill up and short transfers log nothing. The line drawn on a
+/// terminal is ended when dropped.
+#[derive(Debug)]
+pub struct StatusLine<C: Clock, W: Write> {
+    clock: C,
+    out: W,
+    terminal: bool,
+    /// When the last line was logged, or the status line created.
+    last: Instant,
+    /// Whether a line is drawn on the terminal and not ended yet.
+    drawn: bool,
+}
+
+/// How often [`StatusLine`] logs a line when not on a terminal.
+pub const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+impl<C: Clock, W: Write> StatusLine<C, W> {
+    pub fn new(clock: C, out: W, terminal: bool) -> Self {
+        Self {
+            last: clock.now(),
+            clock,
+            out,
+            terminal,
+            drawn: false,
+        }
+    }
+
+    pub fn draw(&mut self, line: &str) -> io::Result<()> {
+        if self.terminal {
+            // back to the start of the line, and clear what's left of a longer one
+            write!(self.out, "\r{line}\x1b[K")?;
+            self.drawn = true;
+            return self.out.flush();
+        }
+
+        let now = self.clock.now();
+        if now.saturating_duration_since(self.last) < LOG_INTERVAL {
+            return Ok(());
+        }
+        self.last = now;
+        writeln!(self.out, "{line}")
+    }
+}
+
+impl<C: Clock, W: Write> Drop for StatusLine<C, W> {
+    fn drop(&mut self) {
+        if self.drawn {
+            let _ = writeln!(self.out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn rates_and_status_lines() {
+        let clock = MockClock::new();
+        let mut meter = ProgressMeter::new(clock.clone());
+        let (a, b) = (
+            "10.0.0.1:6881".parse().unwrap(),
+            "10.0.0.2:6881".parse().unwrap(),
+        );
+
+        clock.advance(Duration::from_secs(2));
+        meter.record(a, 4096);
+        meter.record(b, 16384);
+        meter.record(b, 16384);
+        assert_eq!(meter.rate(), 18432.0, "over the 2s since the start");
+        assert_eq!(meter.peer_rates(), [(b, 16384.0), (a, 2048.0)]);
+
+        let progress = Progress {
+            pieces_done: 3,
+            pieces_wanted: 10,
+            bytes_done: 36864,
+            bytes_wanted: 122880,
+            peers: 2,
+        };
+        assert_eq!(
+            meter.line(progress),
+            "[######--------------]  30.0%  3/10 pieces  36.00 KiB/120.00 KiB  18.00 KiB/s  \
+             ETA 5s  2 peers, fastest 10.0.0.2:6881"
+        );
+
+        clock.advance(RATE_WINDOW + Duration::from_secs(1));
+        assert_eq!(meter.rate(), 0.0, "the samples expired");
+        assert!(meter.peer_rates().is_empty());
+        assert!(meter.line(progress).contains("ETA ?  2 peers"));
+
+        let mut out = Vec::new();
+        let mut log = StatusLine::new(clock.clone(), &mut out, false);
+        log.draw("too early").unwrap();
+        clock.advance(LOG_INTERVAL);
+        log.draw("one").unwrap();
+        log.draw("skipped").unwrap();
+        clock.advance(LOG_INTERVAL);
+        log.draw("two").unwrap();
+        drop(log);
+        assert_eq!(out, b"one\ntwo\n");
+
+        let mut out = Vec::new();
+        let mut terminal = StatusLine::new(clock, &mut out, true);
+        terminal.draw("one").unwrap();
+        terminal.draw("two").unwrap();
+        drop(terminal);
+        assert_eq!(out, b"\rone\x1b[K\rtwo\x1b[K\n", "ended when dropped");
+    }
+}
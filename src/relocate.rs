@@ -0,0 +1,176 @@
+//! Moving downloads around without ever losing data on the way.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    storage::layout,
+    torrent::{Content, Torrent},
+};
+
+/// Move the file at `from` to `to`, renaming it when both are on the same filesystem, and
+/// otherwise copying it next to `to`, checking the copy, renaming it to `to`, and only then
+/// deleting the original, so that `to` never holds part of the file.
+pub fn move_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if to.exists() {
+        bail!("{} already exists", to.display());
+    }
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            let part = part_path(to);
+            copy_verified(from, &part)?;
+            fs::rename(&part, to).context(format!("renaming {}", part.display()))?;
+            fs::remove_file(from).context(format!("removing {}", from.display()))
+        }
+        Err(err) => Err(err).context(format!("moving {} to {}", from.display(), to.display())),
+    }
+}
+
+/// Move the files of `torrent` downloaded to `from` to where they go when downloaded to `to`,
+/// removing the directories of a multi-file torrent left empty at `from`.
+pub fn move_download(torrent: &Torrent, from: &Path, to: &Path) -> anyhow::Result<()> {
+    let targets = layout(torrent, to)?;
+    if let Some(target) = targets.iter().find(|target| target.path.exists()) {
+        bail!("{} already exists", target.path.display());
+    }
+    for (file, target) in layout(torrent, from)?.iter().zip(&targets) {
+        if let Some(parent) = target.path.parent() {
+            fs::create_dir_all(parent)
+                .context(format!("creating directory {}", parent.display()))?;
+        }
+        move_file(&file.path, &target.path)?;
+    }
+    remove_empty_directories(torrent, from);
+    Ok(())
+}
+
+/// Delete the files of `torrent` downloaded to `output`, and the directories of a multi-file
+/// torrent they leave empty.
+pub fn remove_download(torrent: &Torrent, output: &Path) -> anyhow::Result<()> {
+    for file in layout(torrent, output)? {
+        match fs::remove_file(&file.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(err).context(format!("removing {}", file.path.display()))
+            }
+            _ => {}
+        }
+    }
+    remove_empty_directories(torrent, output);
+    Ok(())
+}
+
+/// Remove the directories of a multi-file `torrent` at `root`, and `root` itself, as far as
+/// they're empty.
+fn remove_empty_directories(torrent: &Torrent, root: &Path) {
+    if let Content::MultiFile { files } = &torrent.info.content {
+        let mut directories: Vec<PathBuf> = files
+            .iter()
+            .flat_map(|file| (1..file.path.len()).map(|depth| file.path[..depth].iter().collect()))
+            .collect();
+        // the deepest first, so that their parents are empty by the time they come up
+        directories.sort_by_key(|directory| std::cmp::Reverse(directory.components().count()));
+        directories.dedup();
+        for directory in directories {
+            let _ = fs::remove_dir(root.join(directory));
+        }
+        let _ = fs::remove_dir(root);
+    }
+}
+
+/// Where a file is copied to before it's renamed to `path`.
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = OsString::from(path.as_os_str());
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// Copy `from` to `to` and check that the copy reads back identical, removing it if it doesn't.
+pub fn copy_verified(from: &Path, to: &Path) -> anyhow::Result<()> {
+    fs::copy(from, to).context(format!("copying {} to {}", from.display(), to.display()))?;
+    let copy = File::open(to).and_then(|mut file| {
+        // make sure the copy is durable before the original may go away
+        file.sync_all()?;
+        sha1_of(&mut file)
+    });
+    let original = File::open(from).and_then(|mut file| sha1_of(&mut file));
+    match (original, copy) {
+        (Ok(original), Ok(copy)) if original == copy => Ok(()),
+        (original, copy) => {
+            let _ = fs::remove_file(to);
+            original.context(format!("reading {}", from.display()))?;
+            copy.context(format!("reading back {}", to.display()))?;
+            bail!(
+                "the copy of {} at {} doesn't match",
+                from.display(),
+                to.display()
+            )
+        }
+    }
+}
+
+fn sha1_of(reader: &mut impl Read) -> io::Result<[u8; 20]> {
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(hasher.finalize().into()),
+            n => hasher.update(&buf[..n]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::Storage, testing::torrent};
+
+    #[test]
+    fn move_and_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("data");
+        fs::write(&from, b"content").unwrap();
+
+        let copied = dir.path().join("copy");
+        copy_verified(&from, &copied).unwrap();
+        assert_eq!(fs::read(&copied).unwrap(), b"content");
+
+        let moved = dir.path().join("moved");
+        move_file(&from, &moved).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(&moved).unwrap(), b"content");
+        assert!(move_file(&moved, &copied).is_err(), "never overwrites");
+
+        let torrent = torrent(4, &[("a", &b"abc"[..]), ("sub/deep/b", b"d")]);
+        let incomplete = dir.path().join("incomplete/data");
+        let mut storage = Storage::create(&torrent, &incomplete).unwrap();
+        storage.write_at(0, b"abcd").unwrap();
+        drop(storage);
+        let output = dir.path().join("done/data");
+        move_download(&torrent, &incomplete, &output).unwrap();
+        assert_eq!(fs::read(output.join("a")).unwrap(), b"abc");
+        assert_eq!(fs::read(output.join("sub/deep/b")).unwrap(), b"d");
+        assert!(!incomplete.exists(), "no empty directory is left behind");
+        assert!(dir.path().join("incomplete").exists());
+
+        fs::write(output.join("sub/notes"), b"not the torrent's").unwrap();
+        remove_download(&torrent, &output).unwrap();
+        assert!(!output.join("a").exists());
+        assert!(!output.join("sub/deep").exists());
+        assert_eq!(
+            fs::read(output.join("sub/notes")).unwrap(),
+            b"not the torrent's",
+            "only the torrent's files are removed"
+        );
+        fs::remove_file(output.join("sub/notes")).unwrap();
+        remove_download(&torrent, &output).unwrap();
+        assert!(!output.exists());
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::cache_dir, resume::write_atomic};
+
+/// What a peer did in past sessions of a torrent.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PeerRecord {
+    /// The hex encoded peer id the peer last handshook with.
+    #[serde(rename = "peer id", default, skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<String>,
+    /// Pieces it delivered that passed their hash check.
+    pub pieces: u64,
+    /// Bytes of those pieces.
+    pub bytes: u64,
+    /// Milliseconds spent downloading those pieces.
+    pub millis: u64,
+    /// Pieces it delivered that failed their hash check.
+    pub corrupt: u64,
+    /// Connections or transfers with it that failed.
+    pub failures: u64,
+}
+
+impl PeerRecord {
+    /// Delivered pieces count for the peer, corrupt ones and failures count heavily against it.
+    pub fn score(&self) -> i64 {
+        self.pieces as i64 - 4 * self.corrupt as i64 - 2 * self.failures as i64
+    }
+
+    /// Bytes per second over every piece it delivered.
+    pub fn throughput(&self) -> u64 {
+        self.bytes * 1000 / self.millis.max(1)
+    }
+
+    pub fn delivered(&mut self, bytes: u64, elapsed: Duration) {
+        self.pieces += 1;
+        self.bytes += bytes;
+        self.millis += elapsed.as_millis() as u64;
+    }
+}
+
+/// Per-peer observations of one torrent, persisted across sessions so that future sessions dial
+/// peers that behaved well first.
+///
+/// Records are keyed by IP, since peers are ranked before their peer id is known. A different
+/// peer id at a known IP is another client, and starts with a clean record.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Reputation {
+    peers: BTreeMap<String, PeerRecord>,
+}
+
+impl Reputation {
+    /// Where the reputation of the torrent with `info_hash` is kept.
+    pub fn path_for(info_hash: [u8; 20]) -> PathBuf {
+        cache_dir().join("reputation").join(hex::encode(info_hash))
+    }
+
+    /// Load the reputation at `path`, starting afresh if it's missing or unreadable.
+    pub fn load(path: &Path) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|buf| serde_bencode::from_bytes(&buf).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context("creating reputation directory")?;
+        }
+        let bytes = serde_bencode::to_bytes(self).context("bencoding peer reputation")?;
+        write_atomic(path, &bytes).context("saving peer reputation")
+    }
+
+    pub fn get(&self, ip: IpAddr) -> Option<&PeerRecord> {
+        self.peers.get(&ip.to_string())
+    }
+
+    /// The record of the peer at `ip`.
+    pub fn peer(&mut self, ip: IpAddr) -> &mut PeerRecord {
+        self.peers.entry(ip.to_string()).or_default()
+    }
+
+    /// The record of the peer at `ip` that handshook with `peer_id`.
+    pub fn identified(&mut self, ip: IpAddr, peer_id: [u8; 20]) -> &mut PeerRecord {
+        let peer_id = hex::encode(peer_id);
+        let record = self.peer(ip);
+        if record
+            .peer_id
+            .as_ref()
+            .is_some_and(|known| *known != peer_id)
+        {
+            *record = PeerRecord::default();
+        }
+        record.peer_id = Some(peer_id);
+        record
+    }
+
+    /// Sort `peers` from worst to best, so the best can be popped first. Peers without a record
+    /// rank between the good and the bad ones, and ties keep their order.
+    pub fn rank(&self, peers: &mut [SocketAddr]) {
+        peers.sort_by_key(|peer| {
+            self.get(peer.ip())
+                .map_or((0, 0), |record| (record.score(), record.throughput()))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_and_persist() {
+        let good: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let bad: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let unknown: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+
+        let mut reputation = Reputation::default();
+        reputation
+            .identified(good.ip(), [1; 20])
+            .delivered(1 << 18, Duration::from_secs(1));
+        reputation.identified(bad.ip(), [2; 20]).corrupt += 1;
+
+        let mut peers = [good, unknown, bad];
+        reputation.rank(&mut peers);
+        assert_eq!(peers, [bad, unknown, good]);
+
+        reputation.identified(bad.ip(), [3; 20]);
+        assert_eq!(
+            reputation.get(bad.ip()).unwrap().corrupt,
+            0,
+            "another client"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reputation");
+        reputation.save(&path).unwrap();
+        assert_eq!(Reputation::load(&path), reputation);
+        assert_eq!(
+            Reputation::load(&dir.path().join("missing")),
+            Reputation::default()
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+
+/// The progress of a download, persisted next to its output so it can survive a crash.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ResumeState {
+    /// The info hash of the torrent this state belongs to.
+    #[serde(rename = "info hash", with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+
+    /// A bitfield of the pieces that are verified and written to disk, high bit first.
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+}
+
+impl ResumeState {
+    pub fn new(info_hash: [u8; 20], piece_count: usize) -> Self {
+        Self {
+            info_hash: info_hash.to_vec(),
+            pieces: vec![0; piece_count.div_ceil(8)],
+        }
+    }
+
+    /// The conventional resume file of a download written to `output`.
+    pub fn path_for(output: &Path) -> PathBuf {
+        let mut path = output.as_os_str().to_owned();
+        path.push(".resume");
+        PathBuf::from(path)
+    }
+
+    pub fn has_piece(&self, piece_index: usize) -> bool {
+        self.pieces
+            .get(piece_index / 8)
+            .is_some_and(|byte| byte & (0x80 >> (piece_index % 8)) != 0)
+    }
+
+    pub fn set_piece(&mut self, piece_index: usize) {
+        self.pieces[piece_index / 8] |= 0x80 >> (piece_index % 8);
+    }
+
+    pub fn clear_piece(&mut self, piece_index: usize) {
+        self.pieces[piece_index / 8] &= !(0x80 >> (piece_index % 8));
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let buf = fs::read(path).context("reading resume file")?;
+        serde_bencode::from_bytes(&buf).context("parsing resume file")
+    }
+
+    /// Write the state to `path` atomically, see [`write_atomic`].
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let bytes = serde_bencode::to_bytes(self).context("bencoding resume state")?;
+        write_atomic(path, &bytes).context("saving resume state")
+    }
+}
+
+/// A portable bundle of a download's metadata and progress, so it can be backed up or carried over
+/// to another machine along with its data.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The raw bytes of the .torrent file.
+    #[serde(with = "serde_bytes")]
+    pub torrent: Vec<u8>,
+
+    /// The file name of the download's output.
+    pub output: String,
+
+    /// The download's progress, absent if it never got to a checkpoint.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume: Option<ResumeState>,
+}
+
+impl Snapshot {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let buf = fs::read(path).context("reading snapshot")?;
+        serde_bencode::from_bytes(&buf).context("parsing snapshot")
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let bytes = serde_bencode::to_bytes(self).context("bencoding snapshot")?;
+        write_atomic(path, &bytes).context("saving snapshot")
+    }
+}
+
+/// Write `bytes` to `path` atomically: they go to a temporary sibling file which is synced and then
+/// renamed over `path`, so a crash leaves either the old or the new content, never a mix.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut tmp = File::create(&tmp_path).context("creating temporary file")?;
+    tmp.write_all(bytes).context("writing temporary file")?;
+    tmp.sync_all().context("syncing temporary file")?;
+    fs::rename(&tmp_path, path).context(format!("replacing {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Decides when the [`ResumeState`] of a running download is due to be written out.
+///
+/// A checkpoint is due every `every` verified pieces, or once `interval` has passed since the last
+/// one if any piece was verified in the meantime.
+#[derive(Debug)]
+pub struct Checkpointer<C: Clock> {
+    clock: C,
+    every: usize,
+    interval: Duration,
+    pending: usize,
+    last: Instant,
+}
+
+impl<C: Clock> Checkpointer<C> {
+    pub const DEFAULT_EVERY: usize = 16;
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+
+    pub fn new(clock: C) -> Self {
+        let last = clock.now();
+        Self {
+            clock,
+            every: Self::DEFAULT_EVERY,
+            interval: Self::DEFAULT_INTERVAL,
+            pending: 0,
+            last,
+        }
+    }
+
+    pub fn every(self, every: usize) -> Self {
+        Self { every, ..self }
+    }
+
+    pub fn interval(self, interval: Duration) -> Self {
+        Self { interval, ..self }
+    }
+
+    /// Record a verified piece, returning whether a checkpoint is now due.
+    pub fn piece_verified(&mut self) -> bool {
+        self.pending += 1;
+        self.is_due()
+    }
+
+    /// Whether pieces were verified since the last checkpoint, which must be saved before the
+    /// download stops.
+    pub fn has_unsaved(&self) -> bool {
+        self.pending > 0
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.pending > 0
+            && (self.pending >= self.every || self.clock.elapsed(self.last) >= self.interval)
+    }
+
+    /// Save `state` to `path` and restart the countdown to the next checkpoint.
+    pub fn save(&mut self, state: &ResumeState, path: &Path) -> anyhow::Result<()> {
+        state.save(path)?;
+        self.pending = 0;
+        self.last = self.clock.now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn checkpoint_due_by_count_or_interval() {
+        let clock = MockClock::new();
+        let mut checkpointer = Checkpointer::new(clock.clone())
+            .every(3)
+            .interval(Duration::from_secs(10));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.resume");
+        let mut state = ResumeState::new([1; 20], 10);
+
+        assert!(!checkpointer.piece_verified());
+        assert!(!checkpointer.piece_verified());
+        assert!(checkpointer.piece_verified());
+        state.set_piece(2);
+        checkpointer.save(&state, &path).unwrap();
+        assert!(!checkpointer.is_due());
+
+        clock.advance(Duration::from_secs(10));
+        assert!(!checkpointer.is_due(), "nothing new to save");
+        assert!(checkpointer.piece_verified());
+
+        let loaded = ResumeState::load(&path).unwrap();
+        assert_eq!(loaded, state);
+        assert!(loaded.has_piece(2));
+        assert!(!loaded.has_piece(3));
+    }
+}
@@ -0,0 +1,357 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    io,
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::time;
+
+use crate::{
+    choker::Choker,
+    clock::Clock,
+    endgame::Block,
+    peer::{PeerConnection, PeerMessage},
+    storage::Storage,
+    torrent::Torrent,
+};
+
+/// The largest block peers may request by default, as every mainstream client does.
+pub const MAX_REQUEST_LENGTH: u32 = 1 << 14;
+
+/// How often a connection checks whether the choker changed its mind about the peer.
+const CHOKE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A `Request` that must not be served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidRequest {
+    PieceIndexOutOfRange {
+        piece_index: u32,
+        piece_count: usize,
+    },
+    OutOfBounds {
+        block: Block,
+        piece_size: usize,
+    },
+    TooLong {
+        length: u32,
+        max: u32,
+    },
+    Empty,
+    /// The piece isn't complete locally (yet).
+    Missing {
+        piece_index: u32,
+    },
+}
+
+impl Display for InvalidRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use InvalidRequest::*;
+        match self {
+            PieceIndexOutOfRange {
+                piece_index,
+                piece_count,
+            } => write!(
+                f,
+                "requested piece {piece_index} is out of range for {piece_count} pieces"
+            ),
+            OutOfBounds { block, piece_size } => write!(
+                f,
+                "requested block at {} of length {} exceeds piece {} of size {piece_size}",
+                block.offset, block.length, block.piece_index
+            ),
+            TooLong { length, max } => {
+                write!(
+                    f,
+                    "requested block length {length} exceeds the {max} bytes limit"
+                )
+            }
+            Empty => write!(f, "requested an empty block"),
+            Missing { piece_index } => write!(f, "requested piece {piece_index} isn't available"),
+        }
+    }
+}
+
+impl Error for InvalidRequest {}
+
+/// Check that `block`, requested by a peer, lies within a piece of `torrent` that is complete
+/// locally according to `has_piece`, and isn't longer than `max_length`.
+///
+/// Nothing should be read from disk for a request failing these checks, the peer is either
+/// buggy or malicious.
+pub fn validate_request(
+    torrent: &Torrent,
+    block: Block,
+    max_length: u32,
+    has_piece: impl Fn(usize) -> bool,
+) -> Result<(), InvalidRequest> {
+    use InvalidRequest::*;
+
+    let piece_count = torrent.info.pieces.0.len();
+    let piece_index = block.piece_index as usize;
+    if piece_index >= piece_count {
+        return Err(PieceIndexOutOfRange {
+            piece_index: block.piece_index,
+            piece_count,
+        });
+    }
+    if block.length == 0 {
+        return Err(Empty);
+    }
+    if block.length > max_length {
+        return Err(TooLong {
+            length: block.length,
+            max: max_length,
+        });
+    }
+    let piece_size = torrent.piece_size(piece_index);
+    // u64 so that a huge offset can't overflow
+    if u64::from(block.offset) + u64::from(block.length) > piece_size as u64 {
+        return Err(OutOfBounds { block, piece_size });
+    }
+    if !has_piece(piece_index) {
+        return Err(Missing {
+            piece_index: block.piece_index,
+        });
+    }
+    Ok(())
+}
+
+/// Seed to the peer on `connection`, which already went through the handshake: advertise the
+/// pieces set in `bitfield`, choke and unchoke the peer as `choker` decides, and answer its
+/// requests from `storage` until it disconnects, returning the number of bytes uploaded.
+///
+/// Requests are answered as they come, so there is never anything queued for a `Cancel` to
+/// remove.
+pub async fn serve_peer<C: Clock>(
+    connection: &mut PeerConnection,
+    torrent: &Torrent,
+    storage: &Mutex<Storage>,
+    choker: &Mutex<Choker<C>>,
+    bitfield: &[u8],
+) -> anyhow::Result<u64> {
+    let addr = connection.addr();
+    choker.lock().expect("choker poisoned").join(addr);
+    let served = serve(connection, torrent, storage, choker, bitfield).await;
+    choker.lock().expect("choker poisoned").leave(&addr);
+    served
+}
+
+async fn serve<C: Clock>(
+    connection: &mut PeerConnection,
+    torrent: &Torrent,
+    storage: &Mutex<Storage>,
+    choker: &Mutex<Choker<C>>,
+    bitfield: &[u8],
+) -> anyhow::Result<u64> {
+    let addr = connection.addr();
+    let has_piece = |piece_index: usize| {
+        bitfield
+            .get(piece_index / 8)
+            .is_some_and(|byte| byte & (0x80 >> (piece_index % 8)) != 0)
+    };
+    connection
+        .send(PeerMessage::Bitfield {
+            fields: bitfield.to_vec(),
+        })
+        .await?;
+
+    let mut uploaded = 0;
+    let mut choked = true;
+    let mut poll = time::interval(CHOKE_POLL_INTERVAL);
+    loop {
+        // receiving is cancellation safe, so the choker can be checked while the peer is silent
+        let received = tokio::select! {
+            received = connection.recv() => Some(received),
+            _ = poll.tick() => None,
+        };
+        let message = match received {
+            Some(Ok(message)) => message,
+            Some(Err(err)) if is_disconnect(&err) => return Ok(uploaded),
+            Some(Err(err)) => return Err(err),
+            None => {
+                let unchoked = choker.lock().expect("choker poisoned").is_unchoked(&addr);
+                if unchoked == choked {
+                    choked = !unchoked;
+                    let message = match choked {
+                        true => PeerMessage::Choke,
+                        false => PeerMessage::UnChoke,
+                    };
+                    connection.send(message).await?;
+                }
+                continue;
+            }
+        };
+        message.validate(torrent.info.pieces.0.len())?;
+        match message {
+            PeerMessage::Interested | PeerMessage::NotInterested => {
+                let interested = message == PeerMessage::Interested;
+                let unchoked = {
+                    let mut choker = choker.lock().expect("choker poisoned");
+                    choker.interested(addr, interested);
+                    choker.is_unchoked(&addr)
+                };
+                // without waiting for the next poll, so that a free slot is used right away
+                if choked && unchoked {
+                    connection.send(PeerMessage::UnChoke).await?;
+                    choked = false;
+                }
+            }
+            PeerMessage::Request {
+                piece_index,
+                offset,
+                length,
+            } => {
+                if choked {
+                    // allowed to race with a choke, the peer asks again once unchoked
+                    continue;
+                }
+                let block = Block {
+                    piece_index,
+                    offset,
+                    length,
+                };
+                validate_request(torrent, block, MAX_REQUEST_LENGTH, has_piece)?;
+
+                let mut piece = vec![0u8; length as usize];
+                let start = piece_index as u64 * torrent.info.piece_length as u64 + offset as u64;
+                storage
+                    .lock()
+                    .expect("storage poisoned")
+                    .read_at(start, &mut piece)?;
+                connection
+                    .send(PeerMessage::Piece {
+                        piece_index,
+                        offset,
+                        piece,
+                    })
+                    .await?;
+                uploaded += u64::from(length);
+                choker
+                    .lock()
+                    .expect("choker poisoned")
+                    .uploaded(&addr, u64::from(length));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether `err` is the peer closing its connection.
+fn is_disconnect(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|err| {
+            matches!(
+                err.kind(),
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::SystemClock,
+        dial::Direct,
+        peer::HandShake,
+        testing::{runtime, single_file},
+    };
+
+    #[test]
+    fn requests_stay_in_bounds() {
+        let torrent = single_file(1 << 15, &[0; (1 << 15) + 100]);
+        let block = |piece_index, offset, length| Block {
+            piece_index,
+            offset,
+            length,
+        };
+        let validate = |block| validate_request(&torrent, block, MAX_REQUEST_LENGTH, |i| i == 0);
+
+        assert_eq!(validate(block(0, 1 << 14, 1 << 14)), Ok(()));
+        assert_eq!(
+            validate(block(2, 0, 1 << 14)),
+            Err(InvalidRequest::PieceIndexOutOfRange {
+                piece_index: 2,
+                piece_count: 2
+            })
+        );
+        assert_eq!(
+            validate(block(0, 0, 1 << 15)),
+            Err(InvalidRequest::TooLong {
+                length: 1 << 15,
+                max: 1 << 14
+            })
+        );
+        assert!(matches!(
+            validate(block(0, u32::MAX, 1 << 14)),
+            Err(InvalidRequest::OutOfBounds { .. })
+        ));
+        assert!(matches!(
+            validate(block(1, 0, 101)),
+            Err(InvalidRequest::OutOfBounds { .. })
+        ));
+        assert_eq!(
+            validate(block(1, 0, 100)),
+            Err(InvalidRequest::Missing { piece_index: 1 })
+        );
+        assert_eq!(validate(block(0, 0, 0)), Err(InvalidRequest::Empty));
+    }
+
+    #[test]
+    fn seed_a_piece() {
+        let content: Vec<u8> = (0..(1 << 15) + 100).map(|i| i as u8).collect();
+        let torrent = single_file(1 << 15, &content);
+        let info_hash = torrent.calculate_info_hash();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("data");
+        std::fs::write(&output, &content).unwrap();
+        let storage = Mutex::new(Storage::open(&torrent, &output).unwrap());
+        let choker = Mutex::new(Choker::new(SystemClock));
+
+        let runtime = runtime();
+        let (uploaded, piece) = runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let seeder = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut connection = PeerConnection::accept(stream).unwrap();
+                connection
+                    .accept_handshake(HandShake::new(info_hash))
+                    .await
+                    .unwrap();
+                serve_peer(&mut connection, &torrent, &storage, &choker, &[0b1000_0000]).await
+            };
+            let leecher = async {
+                let mut connection = PeerConnection::connect(&Direct, addr).await.unwrap();
+                connection
+                    .handshake(HandShake::new(info_hash))
+                    .await
+                    .unwrap();
+                let bitfield = connection.initiate_download(2).await.unwrap();
+                assert_eq!(bitfield.as_bytes(), [0b1000_0000]);
+                let piece = connection.download_piece(&torrent, 0, 1 << 14).await;
+                connection
+                    .send(PeerMessage::Request {
+                        piece_index: 1,
+                        offset: 0,
+                        length: 100,
+                    })
+                    .await
+                    .unwrap();
+                piece
+            };
+            let (uploaded, piece) = tokio::join!(seeder, leecher);
+            (uploaded, piece.unwrap())
+        });
+
+        assert_eq!(piece, content[..1 << 15]);
+        let err = uploaded.expect_err("piece 1 is missing");
+        assert_eq!(
+            err.downcast_ref::<InvalidRequest>(),
+            Some(&InvalidRequest::Missing { piece_index: 1 })
+        );
+    }
+}
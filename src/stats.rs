@@ -0,0 +1,156 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    time::{Duration, Instant},
+};
+
+use crate::display::ByteSize;
+
+/// Running totals of the bytes transferred by a download.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    /// Bytes of verified piece data received.
+    pub downloaded: u64,
+
+    /// Bytes of piece data sent to peers.
+    pub uploaded: u64,
+
+    /// Bytes discarded because their piece failed its hash check.
+    pub corrupt: u64,
+
+    /// Bytes received more than once, e.g. duplicate blocks during endgame.
+    pub redundant: u64,
+}
+
+/// Byte budgets for metered connections, `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub download: Option<u64>,
+}
+
+impl Quota {
+    /// Check that downloading `bytes` more keeps `stats` within the download budget.
+    pub fn check_download(&self, stats: &TransferStats, bytes: u64) -> Result<(), QuotaExhausted> {
+        match self.download {
+            Some(quota) if stats.downloaded + bytes > quota => Err(QuotaExhausted {
+                quota,
+                used: stats.downloaded,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The transfer would go over its byte quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaExhausted {
+    pub quota: u64,
+    pub used: u64,
+}
+
+impl Display for QuotaExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "download quota of {} exhausted after {}",
+            ByteSize(self.quota),
+            ByteSize(self.used)
+        )
+    }
+}
+
+impl Error for QuotaExhausted {}
+
+/// A step every piece goes through, timed separately to tell network, CPU and disk bottlenecks
+/// apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Connecting to and handshaking with the peer.
+    Connect,
+    /// Waiting for the peer's bitfield and unchoke.
+    Unchoke,
+    /// Requesting and receiving the piece's blocks.
+    Transfer,
+    /// Hashing the piece.
+    Verify,
+    /// Writing the piece to disk.
+    Write,
+}
+
+impl Stage {
+    pub const ALL: [Self; 5] = [
+        Self::Connect,
+        Self::Unchoke,
+        Self::Transfer,
+        Self::Verify,
+        Self::Write,
+    ];
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Stage::*;
+        match self {
+            Connect => "connect",
+            Unchoke => "unchoke",
+            Transfer => "transfer",
+            Verify => "verify",
+            Write => "write",
+        }
+        .fmt(f)
+    }
+}
+
+/// The time spent in every [`Stage`] over all pieces, displayed as a table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timings {
+    totals: [Duration; Stage::ALL.len()],
+    pieces: u32,
+}
+
+impl Timings {
+    pub fn record(&mut self, stage: Stage, elapsed: Duration) {
+        self.totals[stage as usize] += elapsed;
+    }
+
+    /// Run `f`, accounting the time it takes to `stage`.
+    pub fn time<T>(&mut self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.record(stage, started.elapsed());
+        result
+    }
+
+    pub fn piece_done(&mut self) {
+        self.pieces += 1;
+    }
+
+    pub fn total(&self, stage: Stage) -> Duration {
+        self.totals[stage as usize]
+    }
+}
+
+impl Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let overall: Duration = self.totals.iter().sum();
+        writeln!(f, "stage      total (s)  per piece (ms)  share")?;
+        for stage in Stage::ALL {
+            let total = self.total(stage);
+            writeln!(
+                f,
+                "{:<9} {:>10.3} {:>15.3} {:>5.1}%",
+                stage.to_string(),
+                total.as_secs_f64(),
+                total.as_secs_f64() * 1000.0 / f64::from(self.pieces.max(1)),
+                total.as_secs_f64() * 100.0 / overall.as_secs_f64().max(f64::EPSILON)
+            )?;
+        }
+        write!(
+            f,
+            "{:<9} {:>10.3} over {} pieces",
+            "total",
+            overall.as_secs_f64(),
+            self.pieces
+        )
+    }
+}
@@ -0,0 +1,145 @@
+//! A summary of a running download, periodically written to a JSON file so that dashboards and
+//! scripts can poll it.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{clock::Clock, resume::write_atomic};
+
+/// How far a download got, as counted by the download loop.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub pieces_done: usize,
+    pub pieces_wanted: usize,
+    pub bytes_done: u64,
+    pub bytes_wanted: u64,
+    /// Peer connections currently open or being opened.
+    pub peers: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Downloading,
+    Complete,
+    /// Interrupted by the user, resumable from where it stopped.
+    Stopped,
+}
+
+/// The content of the status file.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Status {
+    pub state: State,
+    pub pieces_done: usize,
+    pub pieces_wanted: usize,
+    pub bytes_done: u64,
+    pub bytes_wanted: u64,
+    /// The fraction of the wanted bytes that are done, from 0 to 1.
+    pub progress: f64,
+    /// The average download rate since the start, in bytes per second.
+    pub download_rate: f64,
+    pub peers: usize,
+    pub elapsed_secs: u64,
+    /// The estimated time left at the average rate, unknown until something was downloaded.
+    pub eta_secs: Option<u64>,
+}
+
+/// Writes the [`Status`] of a download to a file, atomically so that readers never see a partial
+/// one.
+#[derive(Debug)]
+pub struct StatusFile<C: Clock> {
+    clock: C,
+    path: PathBuf,
+    start: Instant,
+}
+
+impl<C: Clock> StatusFile<C> {
+    /// How often the status of a running download is written.
+    pub const INTERVAL: Duration = Duration::from_secs(2);
+
+    pub fn new(clock: C, path: &Path) -> Self {
+        let start = clock.now();
+        Self {
+            clock,
+            path: path.to_path_buf(),
+            start,
+        }
+    }
+
+    pub fn status(&self, state: State, progress: Progress) -> Status {
+        let elapsed = self.clock.elapsed(self.start);
+        let download_rate = match elapsed.is_zero() {
+            true => 0.0,
+            false => progress.bytes_done as f64 / elapsed.as_secs_f64(),
+        };
+        let left = progress.bytes_wanted.saturating_sub(progress.bytes_done);
+        Status {
+            state,
+            pieces_done: progress.pieces_done,
+            pieces_wanted: progress.pieces_wanted,
+            bytes_done: progress.bytes_done,
+            bytes_wanted: progress.bytes_wanted,
+            progress: match progress.bytes_wanted {
+                0 => 1.0,
+                wanted => progress.bytes_done as f64 / wanted as f64,
+            },
+            download_rate,
+            peers: progress.peers,
+            elapsed_secs: elapsed.as_secs(),
+            eta_secs: match left {
+                0 => Some(0),
+                _ if download_rate > 0.0 => Some((left as f64 / download_rate).ceil() as u64),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn write(&self, state: State, progress: Progress) -> anyhow::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.status(state, progress))
+            .context("serializing status")?;
+        write_atomic(&self.path, &json).context("writing status file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn rate_and_eta() {
+        let clock = MockClock::new();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("status.json");
+        let status_file = StatusFile::new(clock.clone(), &path);
+        let mut progress = Progress {
+            pieces_wanted: 4,
+            bytes_wanted: 400,
+            peers: 2,
+            ..Progress::default()
+        };
+
+        let status = status_file.status(State::Downloading, progress);
+        assert_eq!((status.download_rate, status.eta_secs), (0.0, None));
+
+        clock.advance(Duration::from_secs(10));
+        progress.pieces_done = 1;
+        progress.bytes_done = 100;
+        let status = status_file.status(State::Downloading, progress);
+        assert_eq!(status.progress, 0.25);
+        assert_eq!(status.download_rate, 10.0);
+        assert_eq!(status.eta_secs, Some(30));
+
+        status_file.write(State::Downloading, progress).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written["state"], "downloading");
+        assert_eq!(written["bytes_done"], 100);
+        assert_eq!(written["eta_secs"], 30);
+    }
+}
@@ -0,0 +1,604 @@
+//! Where the content of a torrent lives on disk.
+//!
+//! Pieces are addressed in the concatenation of every file of the torrent, [`Storage`] maps them
+//! back to the files, splitting the pieces that straddle file boundaries.
+
+use std::{
+    error::Error,
+    ffi::OsStr,
+    fmt::{self, Display},
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    clock::Clock,
+    torrent::{Content, Torrent},
+};
+
+/// A file of the torrent, and where its content starts in the concatenation of every file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSpan {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// `name` as a single path component, when it's a plain name that can't lead out of the directory
+/// it's joined to, unlike `..` or `/`.
+pub fn plain_name(name: &str) -> Option<&OsStr> {
+    match Path::new(name).components().collect::<Vec<_>>()[..] {
+        [Component::Normal(name)] => Some(name),
+        _ => None,
+    }
+}
+
+/// Where every file of `torrent` goes when it's downloaded to `output`: the file itself for single
+/// file torrents, and a directory holding the layout of [`crate::torrent::TorrentFile::path`]
+/// otherwise.
+pub fn layout(torrent: &Torrent, output: &Path) -> anyhow::Result<Vec<FileSpan>> {
+    let files = match &torrent.info.content {
+        Content::SingleFile { length } => {
+            return Ok(vec![FileSpan {
+                path: output.to_path_buf(),
+                offset: 0,
+                length: *length as u64,
+            }])
+        }
+        Content::MultiFile { files } => files,
+    };
+
+    let mut offset = 0;
+    let mut spans = Vec::with_capacity(files.len());
+    for file in files {
+        let mut path = output.to_path_buf();
+        for component in &file.path {
+            // a torrent must never write outside of its directory
+            match plain_name(component) {
+                Some(name) => path.push(name),
+                None => bail!("the torrent has an unsafe file path {:?}", file.path),
+            }
+        }
+        if file.path.is_empty() {
+            bail!("the torrent has a file without a path");
+        }
+        spans.push(FileSpan {
+            path,
+            offset,
+            length: file.length as u64,
+        });
+        offset += file.length as u64;
+    }
+    Ok(spans)
+}
+
+/// A contiguous part of one file of a layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// The index of the file in the layout.
+    pub file_index: usize,
+    /// Where the segment starts in the file.
+    pub file_offset: u64,
+    pub length: u64,
+}
+
+/// The parts of the files of `layout` holding the `length` bytes at `offset` of their
+/// concatenation, in order, e.g. the files a piece straddles. Empty files hold no part.
+pub fn segments(
+    layout: &[FileSpan],
+    offset: u64,
+    length: u64,
+) -> impl Iterator<Item = Segment> + '_ {
+    let end = offset + length;
+    layout
+        .iter()
+        .enumerate()
+        .filter(move |(_, span)| {
+            span.length > 0 && span.offset < end && offset < span.offset + span.length
+        })
+        .map(move |(file_index, span)| {
+            let start = offset.max(span.offset);
+            Segment {
+                file_index,
+                file_offset: start - span.offset,
+                length: end.min(span.offset + span.length) - start,
+            }
+        })
+}
+
+/// Where the verified pieces of a download go.
+pub trait StorageSink {
+    /// Write `data` at `offset` of the concatenated files.
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Make everything written so far durable.
+    fn sync_data(&mut self) -> anyhow::Result<()>;
+
+    /// Make what was written to the `length` bytes at `offset` durable, if not more.
+    fn sync_range(&mut self, _offset: u64, _length: u64) -> anyhow::Result<()> {
+        self.sync_data()
+    }
+
+    /// Complete the download once every piece was written. Syncing is up to [`Fsync`].
+    fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: StorageSink + ?Sized> StorageSink for Box<S> {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        (**self).write_at(offset, data)
+    }
+
+    fn sync_data(&mut self) -> anyhow::Result<()> {
+        (**self).sync_data()
+    }
+
+    fn sync_range(&mut self, offset: u64, length: u64) -> anyhow::Result<()> {
+        (**self).sync_range(offset, length)
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        (**self).finish()
+    }
+}
+
+/// The files a download is written to.
+#[derive(Debug)]
+pub struct Storage {
+    files: Vec<(FileSpan, File)>,
+    layout: Vec<FileSpan>,
+    piece_length: u64,
+}
+
+impl Storage {
+    /// Create, or truncate, every file of `torrent` under `output`, along with their directories,
+    /// at its full length.
+    pub fn create(torrent: &Torrent, output: &Path) -> anyhow::Result<Self> {
+        Self::open_with(torrent, output, true)
+    }
+
+    /// Open every file of `torrent` under `output` keeping their content, creating those missing
+    /// and extending those too short to their full length.
+    pub fn open(torrent: &Torrent, output: &Path) -> anyhow::Result<Self> {
+        Self::open_with(torrent, output, false)
+    }
+
+    /// Open the existing files of `torrent` under `output` for reading only, failing if any is
+    /// missing.
+    pub fn open_read_only(torrent: &Torrent, output: &Path) -> anyhow::Result<Self> {
+        let files = layout(torrent, output)?
+            .into_iter()
+            .map(|span| {
+                let file =
+                    File::open(&span.path).context(format!("opening {}", span.path.display()))?;
+                Ok((span, file))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self::new(torrent, files))
+    }
+
+    fn open_with(torrent: &Torrent, output: &Path, truncate: bool) -> anyhow::Result<Self> {
+        let files = layout(torrent, output)?
+            .into_iter()
+            .map(|span| {
+                if let Some(parent) = span.path.parent() {
+                    fs::create_dir_all(parent)
+                        .context(format!("creating directory {}", parent.display()))?;
+                }
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(truncate)
+                    .open(&span.path)
+                    .context(format!("opening {}", span.path.display()))?;
+                // allocated up front, sparsely where the filesystem can, so that every piece is
+                // written in place whatever order the pieces arrive in
+                let length = file
+                    .metadata()
+                    .context(format!("reading metadata of {}", span.path.display()))?
+                    .len();
+                if length < span.length {
+                    file.set_len(span.length)
+                        .context(format!("allocating {}", span.path.display()))?;
+                }
+                Ok((span, file))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self::new(torrent, files))
+    }
+
+    fn new(torrent: &Torrent, files: Vec<(FileSpan, File)>) -> Self {
+        Self {
+            layout: files.iter().map(|(span, _)| span.clone()).collect(),
+            files,
+            piece_length: torrent.info.piece_length as u64,
+        }
+    }
+
+    /// The length of the concatenated files.
+    fn content_length(&self) -> u64 {
+        self.layout
+            .last()
+            .map_or(0, |span| span.offset + span.length)
+    }
+
+    /// Fill `buf` from `offset` of the concatenated files, failing if any of them is too short.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        let end = offset + buf.len() as u64;
+        let length = self.content_length();
+        if end > length {
+            bail!("reading {offset}..{end} past the end of the content at {length}");
+        }
+        let mut read = 0;
+        for segment in segments(&self.layout, offset, buf.len() as u64) {
+            let (span, file) = &mut self.files[segment.file_index];
+            let chunk = &mut buf[read..read + segment.length as usize];
+            file.seek(SeekFrom::Start(segment.file_offset))
+                .and_then(|_| file.read_exact(chunk))
+                .context(format!("reading from {}", span.path.display()))?;
+            read += chunk.len();
+        }
+        Ok(())
+    }
+
+    /// Read the piece at `piece_index` into the start of `buf`, which must fit it, assembling it
+    /// from every file it straddles, and return it.
+    pub fn read_piece<'a>(
+        &mut self,
+        piece_index: usize,
+        buf: &'a mut [u8],
+    ) -> anyhow::Result<&'a mut [u8]> {
+        let offset = piece_index as u64 * self.piece_length;
+        let end = (offset + self.piece_length).min(self.content_length());
+        if end <= offset {
+            bail!("there is no piece {piece_index}");
+        }
+        let piece = &mut buf[..(end - offset) as usize];
+        self.read_at(offset, piece)
+            .context(format!("reading piece {piece_index}"))?;
+        Ok(piece)
+    }
+
+    /// Write `data` at `offset` of the concatenated files, across as many files as it spans.
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        let mut written = 0;
+        for segment in segments(&self.layout, offset, data.len() as u64) {
+            let (span, file) = &mut self.files[segment.file_index];
+            let chunk = &data[written..written + segment.length as usize];
+            file.seek(SeekFrom::Start(segment.file_offset))
+                .and_then(|_| file.write_all(chunk))
+                .context(format!("writing to {}", span.path.display()))?;
+            written += chunk.len();
+        }
+        Ok(())
+    }
+
+    /// Flush the data of every file to disk.
+    pub fn sync_data(&self) -> anyhow::Result<()> {
+        for (span, file) in &self.files {
+            file.sync_data()
+                .context(format!("syncing {}", span.path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl StorageSink for Storage {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        Storage::write_at(self, offset, data)
+    }
+
+    fn sync_data(&mut self) -> anyhow::Result<()> {
+        Storage::sync_data(self)
+    }
+
+    fn sync_range(&mut self, offset: u64, length: u64) -> anyhow::Result<()> {
+        for segment in segments(&self.layout, offset, length) {
+            let (span, file) = &self.files[segment.file_index];
+            file.sync_data()
+                .context(format!("syncing {}", span.path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// How often [`FsyncPolicy::Interval`] syncs by default.
+pub const FSYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// When [`Fsync`] makes the pieces written durable, trading how much a crash can lose for
+/// throughput.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Leave writing back to the operating system.
+    Never,
+    /// Sync the files of every piece as soon as it's written.
+    Piece,
+    /// Sync every file once it's written entirely, and the others when the download finishes.
+    #[default]
+    File,
+    /// Sync everything written at most every interval, and when the download finishes.
+    Interval,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = UnknownFsyncPolicy;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "never" => Ok(FsyncPolicy::Never),
+            "piece" => Ok(FsyncPolicy::Piece),
+            "file" => Ok(FsyncPolicy::File),
+            "interval" => Ok(FsyncPolicy::Interval),
+            _ => Err(UnknownFsyncPolicy(policy.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownFsyncPolicy(pub String);
+
+impl Display for UnknownFsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown fsync policy {:?}, expected never, piece, file or interval",
+            self.0
+        )
+    }
+}
+
+impl Error for UnknownFsyncPolicy {}
+
+/// Syncs the pieces written to `S` as its [`FsyncPolicy`] says. Explicit syncs, such as those
+/// before a resume checkpoint, always go through.
+#[derive(Debug)]
+pub struct Fsync<S: StorageSink, C: Clock> {
+    inner: S,
+    policy: FsyncPolicy,
+    interval: Duration,
+    clock: C,
+    last_sync: Instant,
+    layout: Vec<FileSpan>,
+    /// The bytes of every file not written yet.
+    left: Vec<u64>,
+    /// Whether every file was written to since it was last synced.
+    unsynced: Vec<bool>,
+}
+
+impl<S: StorageSink, C: Clock> Fsync<S, C> {
+    /// Sync the files of `layout` written to `inner` according to `policy`.
+    pub fn new(inner: S, layout: Vec<FileSpan>, policy: FsyncPolicy, clock: C) -> Self {
+        Self {
+            inner,
+            policy,
+            interval: FSYNC_INTERVAL,
+            last_sync: clock.now(),
+            clock,
+            left: layout.iter().map(|span| span.length).collect(),
+            unsynced: vec![false; layout.len()],
+            layout,
+        }
+    }
+
+    /// Sync at most every `interval` with [`FsyncPolicy::Interval`].
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Count the `length` bytes at `offset` as written already, by an earlier run, so that the
+    /// files holding them are synced once the rest is written.
+    pub fn already_written(&mut self, offset: u64, length: u64) {
+        for segment in segments(&self.layout, offset, length) {
+            let left = &mut self.left[segment.file_index];
+            *left = left.saturating_sub(segment.length);
+        }
+    }
+}
+
+impl<S: StorageSink, C: Clock> StorageSink for Fsync<S, C> {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        self.inner.write_at(offset, data)?;
+        let length = data.len() as u64;
+        let mut completed = Vec::new();
+        for segment in segments(&self.layout, offset, length) {
+            let left = &mut self.left[segment.file_index];
+            *left = left.saturating_sub(segment.length);
+            self.unsynced[segment.file_index] = true;
+            if *left == 0 {
+                completed.push(segment.file_index);
+            }
+        }
+
+        match self.policy {
+            FsyncPolicy::Never => Ok(()),
+            FsyncPolicy::Piece => self.sync_range(offset, length),
+            FsyncPolicy::File => {
+                for file_index in completed {
+                    let span = &self.layout[file_index];
+                    self.sync_range(span.offset, span.length)?;
+                }
+                Ok(())
+            }
+            FsyncPolicy::Interval => {
+                let now = self.clock.now();
+                match now.saturating_duration_since(self.last_sync) >= self.interval {
+                    true => self.sync_data(),
+                    false => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn sync_data(&mut self) -> anyhow::Result<()> {
+        self.inner.sync_data()?;
+        self.unsynced.fill(false);
+        self.last_sync = self.clock.now();
+        Ok(())
+    }
+
+    fn sync_range(&mut self, offset: u64, length: u64) -> anyhow::Result<()> {
+        self.inner.sync_range(offset, length)?;
+        for segment in segments(&self.layout, offset, length) {
+            self.unsynced[segment.file_index] = false;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if self.policy != FsyncPolicy::Never && self.unsynced.contains(&true) {
+            self.sync_data()?;
+        }
+        self.inner.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::MockClock, testing::torrent};
+
+    #[test]
+    fn pieces_are_split_across_files() {
+        // pieces of 4 bytes over files of 6, 0 and 3 bytes
+        let torrent = torrent(
+            4,
+            &[("a", &b"abcdef"[..]), ("sub/empty", b""), ("sub/b", b"ghi")],
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("data");
+        let mut storage = Storage::create(&torrent, &output).unwrap();
+        assert_eq!(
+            fs::read(output.join("a")).unwrap(),
+            [0; 6],
+            "allocated before any piece arrives"
+        );
+        storage.write_at(8, b"i").unwrap();
+        storage.write_at(4, b"efgh").unwrap();
+        storage.write_at(0, b"abcd").unwrap();
+        storage.sync_data().unwrap();
+
+        assert_eq!(fs::read(output.join("a")).unwrap(), b"abcdef");
+        assert_eq!(fs::read(output.join("sub/empty")).unwrap(), b"");
+        assert_eq!(fs::read(output.join("sub/b")).unwrap(), b"ghi");
+
+        let spans = layout(&torrent, &output).unwrap();
+        let segment = |file_index, file_offset, length| Segment {
+            file_index,
+            file_offset,
+            length,
+        };
+        assert_eq!(
+            segments(&spans, 4, 4).collect::<Vec<_>>(),
+            [segment(0, 4, 2), segment(2, 0, 2)],
+            "the empty file holds nothing"
+        );
+        assert_eq!(
+            segments(&spans, 8, 1).collect::<Vec<_>>(),
+            [segment(2, 2, 1)]
+        );
+
+        let mut storage = Storage::open(&torrent, &output).unwrap();
+        let mut piece = [0u8; 4];
+        storage.read_at(4, &mut piece).unwrap();
+        assert_eq!(&piece, b"efgh");
+        assert!(storage.read_at(8, &mut piece).is_err(), "past the end");
+        assert_eq!(storage.read_piece(1, &mut piece).unwrap(), b"efgh");
+        assert_eq!(
+            storage.read_piece(2, &mut piece).unwrap(),
+            b"i",
+            "the last piece is short"
+        );
+        assert!(storage.read_piece(3, &mut piece).is_err());
+        assert!(Storage::open_read_only(&torrent, &output).is_ok());
+        assert!(Storage::open_read_only(&torrent, &dir.path().join("missing")).is_err());
+
+        let mut unsafe_torrent = torrent.clone();
+        if let Content::MultiFile { files } = &mut unsafe_torrent.info.content {
+            files[0].path = vec!["..".to_string(), "escaped".to_string()];
+        }
+        assert!(layout(&unsafe_torrent, &output).is_err());
+        for name in ["..", "/", "a/b", "", "../../x"] {
+            assert_eq!(plain_name(name), None, "{name:?}");
+        }
+        assert_eq!(plain_name("data.bin"), Some(OsStr::new("data.bin")));
+    }
+
+    /// Records the syncs reaching it.
+    #[derive(Debug, Default)]
+    struct Syncs(Vec<Option<(u64, u64)>>);
+
+    impl StorageSink for Syncs {
+        fn write_at(&mut self, _offset: u64, _data: &[u8]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn sync_data(&mut self) -> anyhow::Result<()> {
+            self.0.push(None);
+            Ok(())
+        }
+
+        fn sync_range(&mut self, offset: u64, length: u64) -> anyhow::Result<()> {
+            self.0.push(Some((offset, length)));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn fsync_policies() {
+        // files of 6 and 3 bytes, in pieces of 4
+        let layout = vec![
+            FileSpan {
+                path: "a".into(),
+                offset: 0,
+                length: 6,
+            },
+            FileSpan {
+                path: "b".into(),
+                offset: 6,
+                length: 3,
+            },
+        ];
+        let clock = MockClock::new();
+        let run = |policy, clock: MockClock| {
+            let mut fsync = Fsync::new(Syncs::default(), layout.clone(), policy, clock.clone())
+                .interval(Duration::from_secs(10));
+            fsync.write_at(4, b"efgh").unwrap();
+            clock.advance(Duration::from_secs(10));
+            fsync.write_at(0, b"abcd").unwrap();
+            fsync.finish().unwrap();
+            fsync.inner.0
+        };
+
+        assert_eq!(run(FsyncPolicy::Never, clock.clone()), []);
+        assert_eq!(
+            run(FsyncPolicy::Piece, clock.clone()),
+            [Some((4, 4)), Some((0, 4))]
+        );
+        assert_eq!(
+            run(FsyncPolicy::File, clock.clone()),
+            [Some((0, 6)), None],
+            "b isn't complete until the end"
+        );
+        assert_eq!(
+            run(FsyncPolicy::Interval, clock.clone()),
+            [None],
+            "synced by the second write, nothing is left to the end"
+        );
+
+        let mut fsync = Fsync::new(Syncs::default(), layout, FsyncPolicy::File, clock);
+        fsync.already_written(8, 1);
+        fsync.write_at(4, b"efgh").unwrap();
+        fsync.write_at(0, b"abcd").unwrap();
+        fsync.finish().unwrap();
+        assert_eq!(fsync.inner.0, [Some((6, 3)), Some((0, 6))], "nothing left");
+    }
+}
@@ -0,0 +1,31 @@
+//! Fixtures shared by the tests of several modules.
+
+use tokio::runtime::{Builder, Runtime};
+
+use crate::torrent::{Torrent, TorrentBuilder};
+
+/// A torrent named `data` of `files`, each a `/`-separated path and its content, in pieces of
+/// `piece_length`.
+pub fn torrent(piece_length: usize, files: &[(&str, &[u8])]) -> Torrent {
+    files
+        .iter()
+        .fold(
+            TorrentBuilder::new("http://tracker.example.com/announce", "data")
+                .piece_length(piece_length),
+            |builder, (path, content)| {
+                builder.bytes(path.split('/').map(String::from).collect(), *content)
+            },
+        )
+        .build()
+        .unwrap()
+}
+
+/// A torrent of the single file `data`, holding `content` in pieces of `piece_length`.
+pub fn single_file(piece_length: usize, content: &[u8]) -> Torrent {
+    torrent(piece_length, &[("data", content)])
+}
+
+/// A runtime driving a test's connections on the test's own thread.
+pub fn runtime() -> Runtime {
+    Builder::new_current_thread().enable_all().build().unwrap()
+}
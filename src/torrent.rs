@@ -1,15 +1,42 @@
 use serde::{Deserialize, Serialize};
-use std::fmt::{self, Display};
+use std::{
+    error::Error,
+    fmt::{self, Display},
+};
 
+pub use builder::TorrentBuilder;
 pub use pieces::Pieces;
 use sha1::{Digest, Sha1};
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+use crate::{
+    bencode::{self, DecodeError},
+    display::Timestamp,
+};
+
+pub mod builder;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Torrent {
     // TODO: using a proper url
     /// The URL of the tracker.
     pub announce: String,
 
+    /// Tiers of backup trackers (BEP 12), tried in order when present instead of `announce`.
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+
+    /// The creation time of the torrent, in standard UNIX epoch format.
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+
     pub info: Info,
 }
 
@@ -22,6 +49,26 @@ impl Torrent {
         }
     }
 
+    /// The number of bytes in the piece at `piece_index`, only the last piece may be shorter than
+    /// [`Info::piece_length`].
+    pub fn piece_size(&self, piece_index: usize) -> usize {
+        let piece_length = self.info.piece_length;
+        piece_length.min(self.content_length() - piece_index * piece_length)
+    }
+
+    /// The trackers to announce to, grouped in tiers of decreasing preference.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        match &self.announce_list {
+            Some(tiers) if tiers.iter().any(|tier| !tier.is_empty()) => tiers.clone(),
+            _ => vec![vec![self.announce.clone()]],
+        }
+    }
+
+    /// The bencoded .torrent file of this torrent.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("guaranteed to be a valid bencode")
+    }
+
     /// The sha1 hash of ben-encoding the [`Torrent::info`] section of the torrent.
     pub fn calculate_info_hash(&self) -> [u8; 20] {
         let info_bytes =
@@ -32,6 +79,45 @@ impl Torrent {
     }
 }
 
+/// A v1 info hash, the sha1 hash of a bencoded info dictionary.
+pub type InfoHash = [u8; 20];
+
+/// The info hash of the .torrent file `bytes`, hashing its info dictionary exactly as it was
+/// encoded instead of deserializing the torrent first.
+///
+/// Unlike [`Torrent::calculate_info_hash`], which hashes the re-encoded [`Info`], this is the hash
+/// peers know the torrent by even when its info dictionary has keys [`Info`] doesn't keep.
+pub fn info_hash_from_bytes(bytes: &[u8]) -> Result<InfoHash, InvalidTorrent> {
+    let info = bencode::dict_entry(bytes, b"info")
+        .map_err(InvalidTorrent::Bencode)?
+        .ok_or(InvalidTorrent::MissingInfo)?;
+    if !info.starts_with(b"d") {
+        return Err(InvalidTorrent::MissingInfo);
+    }
+    Ok(Sha1::digest(info).into())
+}
+
+/// A .torrent file without an info dictionary to hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidTorrent {
+    Bencode(DecodeError),
+    MissingInfo,
+}
+
+impl Display for InvalidTorrent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidTorrent::Bencode(err) => write!(f, "invalid torrent file: {err}"),
+            InvalidTorrent::MissingInfo => {
+                write!(f, "torrent file doesn't have an info dictionary")
+            }
+        }
+    }
+}
+
+impl Error for InvalidTorrent {}
+
+/// The `info` output, field order and formats are fixed since scripts depend on them.
 impl Display for Torrent {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let info_hash = self.calculate_info_hash();
@@ -40,6 +126,9 @@ impl Display for Torrent {
         writeln!(f, "Length: {}", self.content_length())?;
         writeln!(f, "Info Hash: {}", hex::encode(info_hash))?;
         writeln!(f, "Piece Length: {}", self.info.piece_length)?;
+        if let Some(creation_date) = self.creation_date {
+            writeln!(f, "Creation Date: {}", Timestamp(creation_date))?;
+        }
         writeln!(f, "Piece Hashes:")?;
         for piece in self.info.pieces.0.iter() {
             writeln!(f, "{}", hex::encode(piece))?;
@@ -48,7 +137,7 @@ impl Display for Torrent {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Info {
     /// The suggested name to save the file (or directory) as. It is purely advisory.
     ///
@@ -73,7 +162,7 @@ pub struct Info {
     pub content: Content,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum Content {
     /// The `length` of the file in bytes
@@ -84,7 +173,7 @@ pub enum Content {
     MultiFile { files: Vec<TorrentFile> },
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct TorrentFile {
     ///  The length of the file, in bytes.
     pub length: usize,
@@ -101,7 +190,7 @@ mod pieces {
     };
     use std::fmt;
 
-    #[derive(Debug, PartialEq, Eq)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
     pub struct Pieces(pub Vec<[u8; 20]>);
     struct PiecesVisitor;
 
@@ -116,7 +205,7 @@ mod pieces {
         where
             E: de::Error,
         {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(E::custom(format!(
                     "length is {length}, {length} mod 20 = {remainder}",
                     length = v.len(),
@@ -152,3 +241,38 @@ mod pieces {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::single_file;
+
+    #[test]
+    fn info_hash_of_the_raw_info() {
+        let torrent = single_file(TorrentBuilder::DEFAULT_PIECE_LENGTH, &[1; 100]);
+        assert_eq!(
+            info_hash_from_bytes(&torrent.to_bytes()),
+            Ok(torrent.calculate_info_hash())
+        );
+
+        // a key `Info` doesn't know is lost when re-encoding, but still part of the info hash
+        let info =
+            b"d6:lengthi3e4:name1:a12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaa5:x-tag1:ze";
+        let bytes = [&b"d8:announce3:url4:info"[..], info, b"e"].concat();
+        let torrent: Torrent = serde_bencode::from_bytes(&bytes).unwrap();
+        assert_eq!(info_hash_from_bytes(&bytes), Ok(Sha1::digest(info).into()));
+        assert_ne!(
+            info_hash_from_bytes(&bytes),
+            Ok(torrent.calculate_info_hash())
+        );
+
+        assert_eq!(
+            info_hash_from_bytes(b"d8:announce3:urle"),
+            Err(InvalidTorrent::MissingInfo)
+        );
+        assert_eq!(
+            info_hash_from_bytes(b"d4:infoi1ee"),
+            Err(InvalidTorrent::MissingInfo)
+        );
+    }
+}
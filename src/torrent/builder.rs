@@ -0,0 +1,354 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use sha1::{Digest, Sha1};
+
+use super::{Content, Info, Pieces, Torrent, TorrentFile};
+
+/// A file to be included in a torrent, read from any byte source.
+struct Source {
+    path: Vec<String>,
+    reader: Box<dyn Read>,
+}
+
+/// Builds a [`Torrent`] out of in-memory content or any other [`Read`]ers, hashing the content
+/// into pieces on [`TorrentBuilder::build`].
+///
+/// A single source builds a single-file torrent named after the torrent itself, several sources
+/// build a multi-file torrent whose files live in a directory of that name.
+pub struct TorrentBuilder {
+    announce: String,
+    announce_list: Option<Vec<Vec<String>>>,
+    name: String,
+    piece_length: usize,
+    creation_date: Option<i64>,
+    sources: Vec<Source>,
+    /// Build a multi-file torrent even out of a single source, as a directory holding one file.
+    directory: bool,
+}
+
+impl TorrentBuilder {
+    /// $2^{18} = 256K$, the most common piece length.
+    pub const DEFAULT_PIECE_LENGTH: usize = 1 << 18;
+
+    pub fn new(announce: impl Into<String>, name: impl Into<String>) -> Self {
+        Self {
+            announce: announce.into(),
+            announce_list: None,
+            name: name.into(),
+            piece_length: Self::DEFAULT_PIECE_LENGTH,
+            creation_date: None,
+            sources: Vec::new(),
+            directory: false,
+        }
+    }
+
+    /// Start a torrent of the file or directory at `path`, named after it. Directories are walked
+    /// recursively, in name order, and always make a multi-file torrent.
+    pub fn from_path(announce: impl Into<String>, path: &Path) -> anyhow::Result<Self> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context(format!("{} has no usable name", path.display()))?;
+        let builder = Self::new(announce, name);
+        let metadata = fs::metadata(path).context(format!("reading {}", path.display()))?;
+        if !metadata.is_dir() {
+            return Ok(builder.file(vec![name.to_string()], LazyFile::new(path)));
+        }
+
+        let mut builder = Self {
+            directory: true,
+            ..builder
+        };
+        let mut files = Vec::new();
+        walk(path, &mut Vec::new(), &mut files)?;
+        for (components, file) in files {
+            builder = builder.file(components, LazyFile::new(&file));
+        }
+        Ok(builder)
+    }
+
+    pub fn piece_length(self, piece_length: usize) -> Self {
+        Self {
+            piece_length,
+            ..self
+        }
+    }
+
+    pub fn announce_list(self, announce_list: Vec<Vec<String>>) -> Self {
+        Self {
+            announce_list: Some(announce_list),
+            ..self
+        }
+    }
+
+    pub fn creation_date(self, creation_date: i64) -> Self {
+        Self {
+            creation_date: Some(creation_date),
+            ..self
+        }
+    }
+
+    /// Add a file at `path` (subdirectories then file name) whose content is read from `reader`.
+    pub fn file<R: Read + 'static>(mut self, path: Vec<String>, reader: R) -> Self {
+        self.sources.push(Source {
+            path,
+            reader: Box::new(reader),
+        });
+        self
+    }
+
+    /// Add a file at `path` with the given in-memory content.
+    pub fn bytes(self, path: Vec<String>, content: impl Into<Vec<u8>>) -> Self {
+        self.file(path, io::Cursor::new(content.into()))
+    }
+
+    pub fn build(self) -> anyhow::Result<Torrent> {
+        if self.piece_length == 0 {
+            bail!("piece length must not be zero")
+        }
+        if self.sources.is_empty() {
+            bail!("a torrent needs at least one file")
+        }
+        if let Some(source) = self.sources.iter().find(|source| {
+            source.path.is_empty()
+                || source.path.iter().any(|part| {
+                    part.is_empty() || part == "." || part == ".." || part.contains('/')
+                })
+        }) {
+            bail!("invalid file path {:?}", source.path)
+        }
+
+        let single_file = self.sources.len() == 1 && !self.directory;
+        let mut hasher = PieceHasher::new(self.piece_length);
+        let mut files = Vec::with_capacity(self.sources.len());
+        for mut source in self.sources {
+            let length = hasher
+                .consume(&mut source.reader)
+                .context(format!("reading {}", source.path.join("/")))?;
+            files.push(TorrentFile {
+                length,
+                path: source.path,
+            });
+        }
+
+        let content = match single_file {
+            true => Content::SingleFile {
+                length: files[0].length,
+            },
+            false => Content::MultiFile { files },
+        };
+
+        Ok(Torrent {
+            announce: self.announce,
+            announce_list: self.announce_list,
+            creation_date: self.creation_date,
+            info: Info {
+                name: self.name,
+                piece_length: self.piece_length,
+                pieces: hasher.finish(),
+                content,
+            },
+        })
+    }
+}
+
+/// Collect the files under `dir`, whose path in the torrent is `prefix`, sorted by name.
+fn walk(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<(Vec<String>, PathBuf)>,
+) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .context(format!("listing {}", dir.display()))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let Some(name) = entry.file_name().to_str().map(String::from) else {
+            bail!("{} isn't a valid UTF-8 name", path.display());
+        };
+        prefix.push(name);
+        // follows symbolic links, the torrent holds the content they point to
+        if fs::metadata(&path)
+            .context(format!("reading {}", path.display()))?
+            .is_dir()
+        {
+            walk(&path, prefix, files)?;
+        } else {
+            files.push((prefix.clone(), path));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// A file opened on its first read, so that building a torrent of a large directory doesn't hold
+/// every file open at once.
+struct LazyFile {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl LazyFile {
+    fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            file: None,
+        }
+    }
+}
+
+impl Read for LazyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            file => file.insert(File::open(&self.path)?),
+        };
+        let read = file.read(buf)?;
+        if read == 0 {
+            self.file = None;
+        }
+        Ok(read)
+    }
+}
+
+/// Hashes a stream of bytes, possibly made of several files, into fixed-size pieces.
+struct PieceHasher {
+    piece_length: usize,
+    filled: usize,
+    hasher: Sha1,
+    pieces: Vec<[u8; 20]>,
+}
+
+impl PieceHasher {
+    fn new(piece_length: usize) -> Self {
+        Self {
+            piece_length,
+            filled: 0,
+            hasher: Sha1::new(),
+            pieces: Vec::new(),
+        }
+    }
+
+    /// Feed all of `reader` into the pieces, returning the number of bytes read.
+    fn consume(&mut self, reader: &mut dyn Read) -> io::Result<usize> {
+        let mut buf = vec![0u8; self.piece_length.min(1 << 16)];
+        let mut total = 0;
+
+        loop {
+            let read = match reader.read(&mut buf) {
+                Ok(0) => return Ok(total),
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            total += read;
+
+            let mut chunk = &buf[..read];
+            while !chunk.is_empty() {
+                let take = chunk.len().min(self.piece_length - self.filled);
+                self.hasher.update(&chunk[..take]);
+                self.filled += take;
+                chunk = &chunk[take..];
+
+                if self.filled == self.piece_length {
+                    self.pieces.push(self.hasher.finalize_reset().into());
+                    self.filled = 0;
+                }
+            }
+        }
+    }
+
+    fn finish(mut self) -> Pieces {
+        if self.filled > 0 {
+            self.pieces.push(self.hasher.finalize().into());
+        }
+        Pieces(self.pieces)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha1(bytes: &[u8]) -> [u8; 20] {
+        Sha1::digest(bytes).into()
+    }
+
+    #[test]
+    fn pieces_span_file_boundaries() {
+        let torrent = TorrentBuilder::new("http://tracker.example.com/announce", "dir")
+            .piece_length(4)
+            .bytes(vec!["a.txt".into()], "hello")
+            .bytes(vec!["sub".into(), "b.txt".into()], "world!")
+            .build()
+            .unwrap();
+
+        assert_eq!(torrent.content_length(), 11);
+        assert_eq!(
+            torrent.info.pieces,
+            Pieces(vec![sha1(b"hell"), sha1(b"owor"), sha1(b"ld!")])
+        );
+
+        let decoded: Torrent = serde_bencode::from_bytes(&torrent.to_bytes()).unwrap();
+        assert_eq!(decoded, torrent);
+        assert_eq!(decoded.calculate_info_hash(), torrent.calculate_info_hash());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(TorrentBuilder::new("http://t", "x").build().is_err());
+        assert!(TorrentBuilder::new("http://t", "x")
+            .bytes(vec!["..".into()], "data")
+            .build()
+            .is_err());
+        assert!(TorrentBuilder::new("http://t", "x")
+            .piece_length(0)
+            .bytes(vec!["x".into()], "data")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn from_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("data");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/b.txt"), "world!").unwrap();
+        fs::write(root.join("a.txt"), "hello").unwrap();
+
+        let torrent = TorrentBuilder::from_path("http://t", &root)
+            .unwrap()
+            .piece_length(4)
+            .build()
+            .unwrap();
+        assert_eq!(torrent.info.name, "data");
+        assert_eq!(
+            torrent.info.pieces,
+            Pieces(vec![sha1(b"hell"), sha1(b"owor"), sha1(b"ld!")])
+        );
+        let Content::MultiFile { files } = &torrent.info.content else {
+            panic!("a directory makes a multi-file torrent");
+        };
+        assert_eq!(files[1].path, ["sub", "b.txt"]);
+
+        fs::remove_file(root.join("a.txt")).unwrap();
+        let torrent = TorrentBuilder::from_path("http://t", &root)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(matches!(torrent.info.content, Content::MultiFile { .. }));
+
+        let torrent = TorrentBuilder::from_path("http://t", &root.join("sub/b.txt"))
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(torrent.info.name, "b.txt");
+        assert_eq!(torrent.info.content, Content::SingleFile { length: 6 });
+    }
+}
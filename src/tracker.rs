@@ -1,5 +1,25 @@
-pub use peers::Peers;
+use anyhow::{bail, Context};
+pub use peers::{deserialize_peers6, Peers};
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value as BenValue;
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    io::Read,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cache::AnnounceCache,
+    clock::Clock,
+    peer::PeerId,
+    random::{self, Xorshift},
+    stats::TransferStats,
+    udp_tracker,
+};
 
 #[derive(Debug, Clone, Serialize)]
 pub struct TrackerRequest {
@@ -33,20 +53,106 @@ pub struct TrackerRequest {
     /// The compact representation is more commonly used in the wild, the non-compact
     /// representation is mostly supported for backward-compatibility.
     pub compact: u8,
+
+    /// What this announce is about, `None` for the regular ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+
+    /// The number of peers wanted, left to the tracker when unset (usually 50).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<usize>,
+
+    /// The number of bytes downloaded so far that failed their hash check (de facto extension).
+    #[serde(skip_serializing_if = "is_zero")]
+    pub corrupt: usize,
+
+    /// The number of bytes downloaded so far that were already received (de facto extension).
+    #[serde(skip_serializing_if = "is_zero")]
+    pub redundant: usize,
+
+    /// Whether this peer accepts encrypted (MSE) connections, asking the tracker for the
+    /// `crypto_flags` of the peers it returns (de facto extension).
+    #[serde(skip_serializing_if = "is_zero")]
+    pub supportcrypto: u8,
+
+    /// Whether this peer only accepts encrypted connections, asking the tracker to only return
+    /// peers that support them (de facto extension).
+    #[serde(skip_serializing_if = "is_zero")]
+    pub requirecrypto: u8,
+}
+
+/// The announces a tracker must be told apart from the regular ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    /// The first announce of a download.
+    Started,
+    /// The download stopped, e.g. after the user interrupted it.
+    Stopped,
+    /// Every piece was downloaded, only sent when the download finishes rather than when it
+    /// resumes a complete download.
+    Completed,
+}
+
+fn is_zero<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Which connections to peers may or must be encrypted with Message Stream Encryption.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CryptoPolicy {
+    /// Only plaintext connections, peers that require encryption are unusable.
+    #[default]
+    Plaintext,
+    /// Encrypted connections when the peer supports them, plaintext ones otherwise.
+    Prefer,
+    /// Only encrypted connections.
+    Require,
 }
 
 impl TrackerRequest {
     pub fn new(left: usize) -> Self {
         Self {
             left,
-            peer_id: "00112233445566778899".to_string(),
+            peer_id: PeerId::ours().to_string(),
             port: 6881,
             uploaded: 0,
             downloaded: 0,
             compact: 1,
+            event: None,
+            numwant: None,
+            corrupt: 0,
+            redundant: 0,
+            supportcrypto: 0,
+            requirecrypto: 0,
+        }
+    }
+
+    pub fn peer_id(self, peer_id: PeerId) -> Self {
+        Self {
+            peer_id: peer_id.to_string(),
+            ..self
+        }
+    }
+
+    pub fn event(self, event: Event) -> Self {
+        Self {
+            event: Some(event),
+            ..self
+        }
+    }
+
+    pub fn numwant(self, numwant: usize) -> Self {
+        Self {
+            numwant: Some(numwant),
+            ..self
         }
     }
 
+    pub fn port(self, port: u16) -> Self {
+        Self { port, ..self }
+    }
+
     pub fn left(self, left: usize) -> Self {
         Self { left, ..self }
     }
@@ -58,32 +164,533 @@ impl TrackerRequest {
     pub fn uploaded(self, uploaded: usize) -> Self {
         Self { uploaded, ..self }
     }
+
+    pub fn corrupt(self, corrupt: usize) -> Self {
+        Self { corrupt, ..self }
+    }
+
+    pub fn redundant(self, redundant: usize) -> Self {
+        Self { redundant, ..self }
+    }
+
+    /// Announce the encryption `policy` of this peer.
+    pub fn crypto(self, policy: CryptoPolicy) -> Self {
+        Self {
+            supportcrypto: (policy != CryptoPolicy::Plaintext).into(),
+            requirecrypto: (policy == CryptoPolicy::Require).into(),
+            ..self
+        }
+    }
+
+    /// The encryption policy announced by this request.
+    pub fn crypto_policy(&self) -> CryptoPolicy {
+        match (self.supportcrypto, self.requirecrypto) {
+            (_, 1..) => CryptoPolicy::Require,
+            (1.., 0) => CryptoPolicy::Prefer,
+            (0, 0) => CryptoPolicy::Plaintext,
+        }
+    }
+
+    /// Fill in the transfer counters from `stats`.
+    pub fn stats(self, stats: &TransferStats) -> Self {
+        self.downloaded(stats.downloaded as usize)
+            .uploaded(stats.uploaded as usize)
+            .corrupt(stats.corrupt as usize)
+            .redundant(stats.redundant as usize)
+    }
+}
+
+/// When to announce again: after the tracker's `interval`, and never before its `min interval`,
+/// even when more peers are wanted early.
+#[derive(Debug)]
+pub struct AnnounceSchedule<C: Clock> {
+    clock: C,
+    last: Instant,
+    interval: Duration,
+    min_interval: Duration,
+}
+
+impl<C: Clock> AnnounceSchedule<C> {
+    /// The interval until a tracker gives one.
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+    /// The shortest regular interval, whatever the tracker says.
+    pub const SHORTEST_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// The schedule after announcing just now.
+    pub fn new(clock: C, response: Option<&TrackerResponse>) -> Self {
+        let mut schedule = Self {
+            last: clock.now(),
+            clock,
+            interval: Self::DEFAULT_INTERVAL,
+            min_interval: Duration::ZERO,
+        };
+        schedule.announced(response);
+        schedule
+    }
+
+    /// Record an announce just now, along with the tracker's response if it gave one. A failed
+    /// announce is retried at the interval of the last response.
+    pub fn announced(&mut self, response: Option<&TrackerResponse>) {
+        self.last = self.clock.now();
+        if let Some(response) = response {
+            let seconds = |seconds: usize| Duration::from_secs(seconds as u64);
+            self.interval = seconds(response.interval).max(Self::SHORTEST_INTERVAL);
+            self.min_interval = response.min_interval.map_or(Duration::ZERO, seconds);
+        }
+    }
+
+    /// Whether the regular announce is due.
+    pub fn is_due(&self) -> bool {
+        self.clock.elapsed(self.last) >= self.interval.max(self.min_interval)
+    }
+
+    /// Whether an early announce is allowed.
+    pub fn may_announce(&self) -> bool {
+        self.clock.elapsed(self.last) >= self.min_interval
+    }
+}
+
+/// Restricts which trackers may be announced to.
+///
+/// Patterns match a tracker URL exactly, or its host and any of the host's subdomains.
+#[derive(Debug, Clone, Default)]
+pub struct TrackerPolicy {
+    /// When not empty, only trackers matching one of these patterns are used.
+    pub allow: Vec<String>,
+
+    /// Trackers matching any of these patterns are never used.
+    pub deny: Vec<String>,
+}
+
+impl TrackerPolicy {
+    pub fn permits(&self, url: &str) -> bool {
+        let matches = |pattern: &String| matches_tracker(pattern, url);
+        (self.allow.is_empty() || self.allow.iter().any(matches)) && !self.deny.iter().any(matches)
+    }
+
+    /// Drop the trackers this policy doesn't permit, along with any tier left empty.
+    pub fn apply(&self, tiers: Vec<Vec<String>>) -> Vec<Vec<String>> {
+        tiers
+            .into_iter()
+            .map(|tier| {
+                tier.into_iter()
+                    .filter(|url| self.permits(url))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect()
+    }
+}
+
+/// Whether `pattern` is the tracker `url` itself, or its host or a parent domain of it.
+fn matches_tracker(pattern: &str, url: &str) -> bool {
+    let host = tracker_host(url);
+    pattern == url
+        || pattern.eq_ignore_ascii_case(host)
+        || host
+            .to_ascii_lowercase()
+            .ends_with(&format!(".{}", pattern.to_ascii_lowercase()))
+}
+
+/// A query parameter added to the announces of the trackers matching `pattern`, as in
+/// [`TrackerPolicy`], or of every tracker without one, e.g. the static parameters some private
+/// trackers require.
+///
+/// Written `[PATTERN:]KEY=VALUE`, the key can't hold a `:`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtraParam {
+    pub pattern: Option<String>,
+    pub key: String,
+    pub value: String,
+}
+
+impl ExtraParam {
+    pub fn applies_to(&self, tracker: &str) -> bool {
+        self.pattern
+            .as_deref()
+            .is_none_or(|pattern| matches_tracker(pattern, tracker))
+    }
+}
+
+impl FromStr for ExtraParam {
+    type Err = InvalidExtraParam;
+
+    fn from_str(param: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidExtraParam(param.to_string());
+        let (name, value) = param.split_once('=').ok_or_else(invalid)?;
+        let (pattern, key) = match name.rsplit_once(':') {
+            Some((pattern, key)) => (Some(pattern.to_string()), key),
+            None => (None, name),
+        };
+        if key.is_empty() || pattern.as_deref() == Some("") {
+            return Err(invalid());
+        }
+        Ok(Self {
+            pattern,
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidExtraParam(pub String);
+
+impl Display for InvalidExtraParam {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid tracker parameter {:?}, expected [PATTERN:]KEY=VALUE",
+            self.0
+        )
+    }
+}
+
+impl Error for InvalidExtraParam {}
+
+/// The announce-list of a session, ordered as described by BEP 12.
+///
+/// Trackers are shuffled within their tier once, when the session starts, and a tracker that
+/// responds moves to the front of its tier, so the order stays stable for the rest of the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+    extra_params: Vec<ExtraParam>,
+}
+
+impl TrackerTiers {
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        Self::shuffled(tiers, &mut Xorshift::new(random::seed()))
+    }
+
+    pub fn shuffled(mut tiers: Vec<Vec<String>>, rng: &mut Xorshift) -> Self {
+        tiers.retain(|tier| !tier.is_empty());
+        for tier in &mut tiers {
+            rng.shuffle(tier);
+        }
+        Self {
+            tiers,
+            extra_params: Vec::new(),
+        }
+    }
+
+    /// Add `extra_params` to the announces of the trackers they apply to.
+    pub fn extra_params(mut self, extra_params: Vec<ExtraParam>) -> Self {
+        self.extra_params = extra_params;
+        self
+    }
+
+    /// Every tracker, in the order they should be tried.
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.tiers.iter().flatten().map(String::as_str)
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Move `tracker`, which just responded, to the front of its tier.
+    pub fn promote(&mut self, tracker: &str) {
+        for tier in &mut self.tiers {
+            if let Some(position) = tier.iter().position(|url| url == tracker) {
+                tier[..=position].rotate_right(1);
+                return;
+            }
+        }
+    }
+
+    /// Announce to the trackers in order, returning the response of the first tracker to
+    /// respond, which is promoted to the front of its tier.
+    pub fn announce(
+        &mut self,
+        request: &TrackerRequest,
+        info_hash: [u8; 20],
+        cache: Option<&AnnounceCache>,
+    ) -> anyhow::Result<TrackerResponse> {
+        if self.is_empty() {
+            bail!("there is no tracker to announce to, or the tracker policy doesn't permit any")
+        }
+
+        let mut last_err = None;
+        let order: Vec<String> = self.iter().map(String::from).collect();
+        for tracker in &order {
+            match announce(tracker, request, info_hash, &self.extra_params, cache) {
+                Ok(response) => {
+                    self.promote(tracker);
+                    return Ok(response);
+                }
+                Err(err) => last_err = Some(err.context(format!("announcing to {tracker}"))),
+            }
+        }
+
+        Err(last_err.expect("at least one tracker was tried"))
+    }
+}
+
+fn urlencode<B: AsRef<[u8]>>(bytes: B) -> String {
+    let mut result = String::with_capacity(3 * bytes.as_ref().len());
+    for &byte in bytes.as_ref() {
+        result.push('%');
+        result.push_str(&hex::encode([byte]));
+    }
+    result
+}
+
+/// Announce `request` for the torrent with `info_hash` to the HTTP or UDP `tracker`, or get the
+/// recent response of an HTTP tracker from the `cache` unless the request carries an event.
+///
+/// The `extra_params` only go to HTTP trackers, UDP announces have no query to add them to.
+pub fn announce(
+    tracker: &str,
+    request: &TrackerRequest,
+    info_hash: [u8; 20],
+    extra_params: &[ExtraParam],
+    cache: Option<&AnnounceCache>,
+) -> anyhow::Result<TrackerResponse> {
+    if tracker.starts_with("udp://") {
+        // UDP trackers answer in binary, not with a body the cache could keep
+        return udp_tracker::announce(tracker, request, info_hash);
+    }
+
+    // the tracker must hear about events
+    let cache = cache.filter(|_| request.event.is_none());
+    if let Some(response) = cache.and_then(|cache| cache.get(tracker, info_hash)) {
+        if let Ok(response) = TrackerResponse::from_bytes(&response) {
+            return Ok(response);
+        }
+    }
+
+    let tracker_url = announce_url(tracker, request, info_hash, extra_params)?;
+    let mut raw_response = Vec::new();
+    reqwest::blocking::get(tracker_url)
+        .context("tracker get request")?
+        .take(TrackerResponse::MAX_SIZE as u64 + 1)
+        .read_to_end(&mut raw_response)
+        .context("reading response bytes")?;
+    let response = TrackerResponse::from_bytes(&raw_response)?;
+
+    if let Some(cache) = cache {
+        let ttl = Duration::from_secs(response.interval as u64);
+        // failing to cache the response only costs the next run an announce
+        cache.put(tracker, info_hash, &raw_response, ttl).ok();
+    }
+
+    Ok(response)
+}
+
+/// The URL announcing `request` for `info_hash` to `tracker`, keeping the query the tracker URL
+/// may have, e.g. a passkey, and adding the `extra_params` that apply to it, but for those whose
+/// key is set already.
+pub fn announce_url(
+    tracker: &str,
+    request: &TrackerRequest,
+    info_hash: [u8; 20],
+    extra_params: &[ExtraParam],
+) -> anyhow::Result<Url> {
+    let mut url = Url::parse(tracker).context("parsing tracker URL")?;
+    let tracker_request = serde_urlencoded::to_string(request).context("url-encoding tracker")?;
+    let mut keys: Vec<String> = url.query_pairs().map(|(key, _)| key.into_owned()).collect();
+    keys.extend(
+        serde_urlencoded::from_str::<Vec<(String, String)>>(&tracker_request)
+            .context("reading back the tracker request")?
+            .into_iter()
+            .map(|(key, _)| key),
+    );
+    keys.push("info_hash".to_string());
+
+    let mut query: Vec<String> = url.query().map(String::from).into_iter().collect();
+    query.push(tracker_request);
+    query.push(format!("info_hash={}", urlencode(info_hash)));
+    for param in extra_params
+        .iter()
+        .filter(|param| param.applies_to(tracker))
+    {
+        if keys.contains(&param.key) {
+            continue;
+        }
+        keys.push(param.key.clone());
+        query.push(
+            serde_urlencoded::to_string([(&param.key, &param.value)])
+                .context("url-encoding tracker parameter")?,
+        );
+    }
+    url.set_query(Some(&query.join("&")));
+    Ok(url)
+}
+
+/// The host part of a tracker URL, without scheme, credentials, port or path.
+fn tracker_host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?']).next().unwrap_or_default();
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    }
 }
 
 /// Tracker responses are bencoded dictionaries.
-/// TODO: implement the case of response failure
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
 pub struct TrackerResponse {
     /// The number of seconds the downloader should wait between regular rerequests.
     pub interval: usize,
 
+    /// The number of seconds the downloader must wait between any two requests (de facto
+    /// extension).
+    #[serde(default, rename = "min interval")]
+    pub min_interval: Option<usize>,
+
     /// List of peers that your client can connect to.
+    #[serde(default)]
     pub peers: Peers,
+
+    /// List of IPv6 peers (BEP 7).
+    #[serde(default, deserialize_with = "deserialize_peers6")]
+    pub peers6: Peers,
+
+    /// One byte per peer, 1 when the peer requires encrypted connections (de facto extension).
+    #[serde(default, rename = "crypto_flags", with = "serde_bytes")]
+    pub crypto_flags: Option<Vec<u8>>,
+
+    /// Our address as the tracker sees it, 4 or 16 bytes (BEP 24).
+    #[serde(default, rename = "external ip", with = "serde_bytes")]
+    pub external_ip: Option<Vec<u8>>,
 }
 
+impl TrackerResponse {
+    /// The largest response body accepted from a tracker.
+    pub const MAX_SIZE: usize = 1 << 20;
+
+    /// Parse a raw response body, checking it's a reasonably sized bencoded dictionary first so
+    /// that e.g. an HTML error page gets reported as such instead of as a cryptic decoding error.
+    pub fn from_bytes(body: &[u8]) -> Result<Self, InvalidResponse> {
+        use InvalidResponse::*;
+
+        if body.len() > Self::MAX_SIZE {
+            return Err(TooLarge { size: body.len() });
+        }
+
+        let Ok(BenValue::Dict(dict)) = serde_bencode::from_bytes::<BenValue>(body) else {
+            return Err(NotADictionary {
+                preview: preview(body),
+            });
+        };
+
+        if let Some(BenValue::Bytes(reason)) = dict.get(b"failure reason".as_slice()) {
+            return Err(Failure(String::from_utf8_lossy(reason).into()));
+        }
+
+        serde_bencode::from_bytes(body).map_err(|err| Malformed(err.to_string()))
+    }
+
+    /// The peers that can be connected to under `policy`, IPv6 ones included. Without
+    /// `crypto_flags`, or with flags that don't line up with the IPv4 peers, every peer is kept.
+    pub fn usable_peers(&self, policy: CryptoPolicy) -> Peers {
+        let mut peers = match &self.crypto_flags {
+            Some(flags) if flags.len() == self.peers.0.len() => self
+                .peers
+                .0
+                .iter()
+                .zip(flags)
+                .filter(|(_, &flag)| policy != CryptoPolicy::Plaintext || flag == 0)
+                .map(|(peer, _)| *peer)
+                .collect(),
+            _ => self.peers.0.clone(),
+        };
+        peers.extend(&self.peers6.0);
+        Peers(peers)
+    }
+
+    /// Our address as the tracker sees it, if it says.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        let ip = self.external_ip.as_deref()?;
+        if let Ok(octets) = <[u8; 4]>::try_from(ip) {
+            return Some(Ipv4Addr::from(octets).into());
+        }
+        let octets = <[u8; 16]>::try_from(ip).ok()?;
+        Some(Ipv6Addr::from(octets).into())
+    }
+}
+
+/// The first bytes of `body`, printable and on a single line.
+fn preview(body: &[u8]) -> String {
+    const LENGTH: usize = 64;
+
+    let text = String::from_utf8_lossy(&body[..body.len().min(LENGTH)]);
+    let mut preview = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if body.len() > LENGTH {
+        preview.push('…');
+    }
+    preview
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidResponse {
+    TooLarge { size: usize },
+    NotADictionary { preview: String },
+    Failure(String),
+    Malformed(String),
+}
+
+impl Display for InvalidResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use InvalidResponse::*;
+        match self {
+            TooLarge { size } => write!(
+                f,
+                "tracker response of {size} bytes exceeds the {} bytes limit",
+                TrackerResponse::MAX_SIZE
+            ),
+            NotADictionary { preview } if preview.starts_with('<') => write!(
+                f,
+                "tracker returned HTML instead of a bencoded dictionary (misconfigured passkey?): {preview:?}"
+            ),
+            NotADictionary { preview } => write!(
+                f,
+                "tracker response isn't a bencoded dictionary: {preview:?}"
+            ),
+            Failure(reason) => write!(f, "tracker refused the announce: {reason}"),
+            Malformed(err) => write!(f, "malformed tracker response: {err}"),
+        }
+    }
+}
+
+impl Error for InvalidResponse {}
+
 mod peers {
     use serde::{
-        de::{self, Visitor},
+        de::{self, SeqAccess, Visitor},
         Deserialize, Deserializer, Serialize, Serializer,
     };
     use std::{
         fmt,
-        net::{Ipv4Addr, SocketAddrV4},
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     };
 
-    #[derive(Clone, Debug, PartialEq, Eq)]
-    pub struct Peers(pub Vec<SocketAddrV4>);
-    struct PeersVisitor;
+    /// Peers in their compact form, 4 bytes of IPv4 address (or 16 bytes of IPv6 address for
+    /// `peers6`, BEP 7) followed by 2 bytes of port each, or as a list of dictionaries from
+    /// trackers ignoring `compact=1`.
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct Peers(pub Vec<SocketAddr>);
+
+    /// A peer of the dictionary form, whose `peer id` isn't needed.
+    #[derive(Deserialize)]
+    struct PeerEntry {
+        /// An IPv4 or IPv6 address, or a DNS name in theory, which no tracker sends.
+        ip: String,
+        port: u16,
+    }
+
+    struct PeersVisitor {
+        /// The length of the address of every peer, 4 or 16.
+        address_length: usize,
+    }
 
     impl<'de> Visitor<'de> for PeersVisitor {
         type Value = Peers;
@@ -91,30 +698,50 @@ mod peers {
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
             write!(
                 formatter,
-                "6 bytes first 4 are a peer's IP address and the last 2 are their port"
-            )
+                "{} bytes per peer, the first {} are a peer's IP address and the last 2 are their port",
+                self.address_length + 2,
+                self.address_length
+            )?;
+            write!(formatter, ", or a list of dictionaries of `ip` and `port`")
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut peers = Vec::new();
+            while let Some(PeerEntry { ip, port }) = seq.next_element()? {
+                // no resolving names while parsing, the compact peers are plenty
+                if let Ok(ip) = ip.parse::<IpAddr>() {
+                    peers.push(SocketAddr::new(ip, port));
+                }
+            }
+            Ok(Peers(peers))
         }
 
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            if v.len() % 6 != 0 {
+            let entry_length = self.address_length + 2;
+            if !v.len().is_multiple_of(entry_length) {
                 return Err(E::custom(format!(
-                    "length is {length}, {length} mod 20 = {remainder}",
+                    "length is {length}, {length} mod {entry_length} = {remainder}",
                     length = v.len(),
-                    remainder = v.len() % 20
+                    remainder = v.len() % entry_length
                 )));
             }
 
             // TODO: use [`std::slice::array_chunks`] when stable
             Ok(Peers(
-                v.chunks_exact(6)
+                v.chunks_exact(entry_length)
                     .map(|slice| {
-                        SocketAddrV4::new(
-                            Ipv4Addr::from(u32::from_be_bytes(slice[0..4].try_into().unwrap())),
-                            u16::from_be_bytes(slice[4..].try_into().unwrap()),
-                        )
+                        let (ip, port) = slice.split_at(self.address_length);
+                        let ip: IpAddr = match ip.len() {
+                            4 => Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap()).into(),
+                            _ => Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()).into(),
+                        };
+                        SocketAddr::new(ip, u16::from_be_bytes(port.try_into().unwrap()))
                     })
                     .collect(),
             ))
@@ -126,10 +753,19 @@ mod peers {
         where
             D: Deserializer<'de>,
         {
-            deserializer.deserialize_bytes(PeersVisitor)
+            deserializer.deserialize_bytes(PeersVisitor { address_length: 4 })
         }
     }
 
+    /// Deserialize IPv6 peers in their compact form, e.g. `peers6` or `added6`.
+    pub fn deserialize_peers6<'de, D>(deserializer: D) -> Result<Peers, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(PeersVisitor { address_length: 16 })
+    }
+
+    /// Only the IPv4 peers have a place in the compact form, IPv6 ones are left out.
     impl Serialize for Peers {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
@@ -138,11 +774,227 @@ mod peers {
             let mut bytes = Vec::with_capacity(6 * self.0.len());
 
             for peer in self.0.iter() {
-                bytes.extend(peer.ip().octets());
-                bytes.extend(peer.port().to_be_bytes());
+                if let IpAddr::V4(ip) = peer.ip() {
+                    bytes.extend(ip.octets());
+                    bytes.extend(peer.port().to_be_bytes());
+                }
             }
 
             serializer.serialize_bytes(&bytes)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracker_policy() {
+        let policy = TrackerPolicy {
+            allow: vec![],
+            deny: vec!["dead.example.org".to_string()],
+        };
+        assert!(policy.permits("http://tracker.example.com:6969/announce"));
+        assert!(!policy.permits("udp://dead.example.org:80"));
+        assert!(!policy.permits("http://tracker.dead.example.org/announce"));
+
+        let policy = TrackerPolicy {
+            allow: vec!["example.com".to_string()],
+            deny: vec![],
+        };
+        assert_eq!(
+            policy.apply(vec![
+                vec!["http://other.net/announce".to_string()],
+                vec![
+                    "http://a.example.com/announce".to_string(),
+                    "http://[::1]:8080/announce".to_string(),
+                ],
+            ]),
+            vec![vec!["http://a.example.com/announce".to_string()]]
+        );
+    }
+
+    #[test]
+    fn tracker_tiers_promotion() {
+        let tiers = vec![
+            vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            vec![],
+            vec!["d".to_string()],
+        ];
+        let mut session = TrackerTiers::shuffled(tiers, &mut Xorshift::new(1));
+        assert_eq!(session.tiers().len(), 2, "empty tiers are dropped");
+        assert_eq!(session.iter().last(), Some("d"));
+
+        let mut first_tier = session.tiers()[0].clone();
+        first_tier.sort();
+        assert_eq!(first_tier, ["a", "b", "c"]);
+
+        let last = session.tiers()[0][2].clone();
+        session.promote(&last);
+        assert_eq!(session.tiers()[0][0], last);
+        let order = session.tiers().to_vec();
+        session.promote(&last);
+        session.promote("unknown");
+        assert_eq!(session.tiers(), order, "the order stays stable");
+    }
+
+    #[test]
+    fn tracker_response_validation() {
+        let response =
+            TrackerResponse::from_bytes(b"d8:intervali60e5:peers6:\x7f\0\0\x01\x1a\xe1e").unwrap();
+        assert_eq!(response.interval, 60);
+        assert_eq!(response.peers.0, vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(response.external_ip(), None);
+        let response =
+            TrackerResponse::from_bytes(b"d11:external ip4:\xc0\0\x02\x018:intervali60e5:peers0:e")
+                .unwrap();
+        assert_eq!(response.external_ip(), Some("192.0.2.1".parse().unwrap()));
+
+        let response = TrackerResponse::from_bytes(
+            b"d8:intervali60e6:peers618:\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe1e",
+        )
+        .unwrap();
+        assert!(response.peers.0.is_empty());
+        assert_eq!(
+            response.usable_peers(CryptoPolicy::Plaintext).0,
+            vec!["[2001:db8::1]:6881".parse().unwrap()]
+        );
+        assert!(
+            TrackerResponse::from_bytes(b"d8:intervali60e6:peers66:\x7f\0\0\x01\x1a\xe1e").is_err()
+        );
+
+        let response = TrackerResponse::from_bytes(
+            b"d8:intervali60e5:peersl\
+              d2:ip9:127.0.0.17:peer id20:-XX0001-0123456789ab4:porti6881ee\
+              d2:ip11:2001:db8::14:porti6882ee\
+              d2:ip16:peer.example.com4:porti6883ee\
+              ee",
+        )
+        .unwrap();
+        assert_eq!(
+            response.peers.0,
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "[2001:db8::1]:6882".parse().unwrap()
+            ]
+        );
+
+        let err = TrackerResponse::from_bytes(b"<html>\n  <body>Invalid passkey</body></html>")
+            .unwrap_err();
+        assert_eq!(
+            err,
+            InvalidResponse::NotADictionary {
+                preview: "<html> <body>Invalid passkey</body></html>".into()
+            }
+        );
+        assert!(err.to_string().contains("HTML"));
+
+        assert_eq!(
+            TrackerResponse::from_bytes(b"d14:failure reason12:unregisterede"),
+            Err(InvalidResponse::Failure("unregistered".into()))
+        );
+    }
+
+    #[test]
+    fn announce_schedule() {
+        use crate::clock::MockClock;
+
+        let clock = MockClock::new();
+        let minutes = |minutes: u64| Duration::from_secs(minutes * 60);
+        let response =
+            TrackerResponse::from_bytes(b"d8:intervali600e12:min intervali300e5:peers0:e").unwrap();
+        assert_eq!(response.min_interval, Some(300));
+
+        let mut schedule = AnnounceSchedule::new(clock.clone(), Some(&response));
+        assert!(!schedule.may_announce());
+        clock.advance(minutes(5));
+        assert!(schedule.may_announce());
+        assert!(!schedule.is_due());
+        clock.advance(minutes(5));
+        assert!(schedule.is_due());
+
+        // a failed announce keeps the intervals
+        schedule.announced(None);
+        assert!(!schedule.may_announce());
+        clock.advance(minutes(10));
+        assert!(schedule.is_due());
+
+        let response = TrackerResponse::from_bytes(b"d8:intervali0e5:peers0:e").unwrap();
+        schedule.announced(Some(&response));
+        assert!(schedule.may_announce(), "no min interval");
+        assert!(!schedule.is_due(), "the interval is at least a minute");
+        clock.advance(minutes(1));
+        assert!(schedule.is_due());
+    }
+
+    #[test]
+    fn crypto_parameters_and_flags() {
+        let query = |request: &TrackerRequest| serde_urlencoded::to_string(request).unwrap();
+        let request = TrackerRequest::new(10);
+        assert!(!query(&request).contains("crypto"));
+        assert!(!query(&request).contains("numwant"));
+        assert!(query(&request.clone().numwant(200)).ends_with("&compact=1&numwant=200"));
+        assert!(query(&request.clone().event(Event::Started)).ends_with("&compact=1&event=started"));
+        let request = request.crypto(CryptoPolicy::Require);
+        assert_eq!(request.crypto_policy(), CryptoPolicy::Require);
+        assert!(query(&request).ends_with("&supportcrypto=1&requirecrypto=1"));
+
+        let response = TrackerResponse::from_bytes(
+            b"d12:crypto_flags2:\x00\x018:intervali60e5:peers12:\x7f\0\0\x01\x1a\xe1\x7f\0\0\x02\x1a\xe1e",
+        )
+        .unwrap();
+        assert_eq!(
+            response.usable_peers(CryptoPolicy::Plaintext).0,
+            vec!["127.0.0.1:6881".parse().unwrap()],
+            "the peer requiring encryption is dropped"
+        );
+        assert_eq!(response.usable_peers(CryptoPolicy::Prefer).0.len(), 2);
+    }
+
+    #[test]
+    fn announce_urls_with_extra_params() {
+        let param = |param: &str| param.parse::<ExtraParam>().unwrap();
+        let params = [
+            param("tracker.example.com:uid=a b&c"),
+            param("http://other.example.org/announce:uid=other"),
+            param("key=global"),
+            param("key=again"),
+            param("passkey=ignored"),
+            param("port=1"),
+        ];
+        assert_eq!(
+            params[1].pattern.as_deref(),
+            Some("http://other.example.org/announce")
+        );
+        assert!("uid".parse::<ExtraParam>().is_err());
+        assert!(":uid=1".parse::<ExtraParam>().is_err());
+
+        let request = TrackerRequest::new(100).peer_id(PeerId(*b"-RS0100-012345678901"));
+        let url = announce_url(
+            "http://tracker.example.com/announce?passkey=secret",
+            &request,
+            [0xff; 20],
+            &params,
+        )
+        .unwrap();
+        let query = url.query().unwrap();
+        assert!(query.starts_with("passkey=secret&peer_id=-RS0100-012345678901&port=6881&"));
+        assert!(query.ends_with(&format!(
+            "&info_hash={}&uid=a+b%26c&key=global",
+            "%ff".repeat(20)
+        )));
+
+        let url = announce_url(
+            "http://other.example.org/announce",
+            &request,
+            [0; 20],
+            &params,
+        );
+        assert!(url
+            .unwrap()
+            .query()
+            .unwrap()
+            .contains("&uid=other&key=global"));
+    }
+}
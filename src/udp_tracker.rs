@@ -0,0 +1,341 @@
+//! Announcing to UDP trackers (BEP 15): a connect exchange for a connection id, then the announce
+//! itself, over IPv4 or IPv6 alike.
+//!
+//! A UDP tracker returns the peers of the address family the announce came over, 6 bytes each
+//! over IPv4 and 18 over IPv6, so a tracker reachable over both is asked over both, its answers
+//! merged into the `peers` and `peers6` of a single [`TrackerResponse`].
+
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use reqwest::Url;
+
+use crate::{
+    random::{self, Xorshift},
+    tracker::{Event, Peers, TrackerRequest, TrackerResponse},
+};
+
+/// The magic number opening a connect request.
+const PROTOCOL_ID: u64 = 0x0417_2710_1980;
+
+const CONNECT: u32 = 0;
+const ANNOUNCE: u32 = 1;
+const ERROR: u32 = 3;
+
+/// The largest datagram read from a tracker, room for well over a thousand IPv6 peers.
+const MAX_DATAGRAM: usize = 1 << 16;
+
+/// How long the first attempt waits for the tracker, doubling with every retry. BEP 15 starts
+/// at 15 seconds and retries 8 times, an hour all told, which nobody waits out on a command line.
+pub const UDP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How many times a request is sent before giving up on the tracker.
+pub const UDP_ATTEMPTS: u32 = 3;
+
+/// Why a UDP tracker didn't answer an announce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UdpTrackerError {
+    /// No answer came after [`UDP_ATTEMPTS`] attempts.
+    Timeout,
+    /// The tracker refused the request, saying why.
+    Failure(String),
+    /// The answer doesn't fit the request.
+    Malformed(&'static str),
+}
+
+impl Display for UdpTrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use UdpTrackerError::*;
+        match self {
+            Timeout => write!(f, "no answer after {UDP_ATTEMPTS} attempts"),
+            Failure(reason) => write!(f, "tracker refused the announce: {reason}"),
+            Malformed(what) => write!(f, "malformed tracker answer: {what}"),
+        }
+    }
+}
+
+impl Error for UdpTrackerError {}
+
+/// Announce `request` for the torrent with `info_hash` to the UDP `tracker`, over every address
+/// family it resolves to, succeeding when it answers over any of them.
+pub fn announce(
+    tracker: &str,
+    request: &TrackerRequest,
+    info_hash: [u8; 20],
+) -> anyhow::Result<TrackerResponse> {
+    let url = Url::parse(tracker).context("parsing tracker URL")?;
+    let addrs = url
+        .socket_addrs(|| None)
+        .context("resolving the tracker's address")?;
+    // the first address of each family, IPv4 first
+    let families = [
+        addrs.iter().find(|addr| addr.is_ipv4()),
+        addrs.iter().find(|addr| addr.is_ipv6()),
+    ];
+
+    let mut rng = Xorshift::new(random::seed());
+    let mut response: Option<TrackerResponse> = None;
+    let mut last_err = None;
+    for &addr in families.into_iter().flatten() {
+        let (interval, peers) = match announce_to(addr, request, info_hash, &mut rng) {
+            Ok(answer) => answer,
+            Err(err) => {
+                last_err = Some(err.context(format!("announcing over {addr}")));
+                continue;
+            }
+        };
+        let response = response.get_or_insert_with(|| TrackerResponse {
+            interval: interval as usize,
+            min_interval: None,
+            peers: Peers::default(),
+            peers6: Peers::default(),
+            crypto_flags: None,
+            external_ip: None,
+        });
+        response.interval = response.interval.min(interval as usize);
+        match addr {
+            SocketAddr::V4(_) => response.peers.0.extend(peers),
+            SocketAddr::V6(_) => response.peers6.0.extend(peers),
+        }
+    }
+    match (response, last_err) {
+        (Some(response), _) => Ok(response),
+        (None, Some(err)) => Err(err),
+        (None, None) => bail!("the tracker's host has no address"),
+    }
+}
+
+/// Connect to the tracker at `addr` and announce to it, returning its interval and peers.
+fn announce_to(
+    addr: SocketAddr,
+    request: &TrackerRequest,
+    info_hash: [u8; 20],
+    rng: &mut Xorshift,
+) -> anyhow::Result<(u32, Vec<SocketAddr>)> {
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).context("opening a UDP socket")?;
+    socket.connect(addr).context("connecting to the tracker")?;
+
+    let transaction_id = rng.next_u64() as u32;
+    let answer = exchange(&socket, &connect_request(transaction_id), transaction_id)?;
+    let connection_id = parse_connect(&answer)?;
+
+    let transaction_id = rng.next_u64() as u32;
+    let key = rng.next_u64() as u32;
+    let packet = announce_request(connection_id, transaction_id, key, request, info_hash);
+    let answer = exchange(&socket, &packet, transaction_id)?;
+    Ok(parse_announce(&answer, addr.is_ipv6())?)
+}
+
+/// Send `packet` until an answer to `transaction_id` comes back, returning it.
+fn exchange(socket: &UdpSocket, packet: &[u8], transaction_id: u32) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0; MAX_DATAGRAM];
+    for attempt in 0..UDP_ATTEMPTS {
+        socket.set_read_timeout(Some(UDP_TIMEOUT * 2u32.pow(attempt)))?;
+        socket.send(packet).context("sending to the tracker")?;
+        loop {
+            let length = match socket.recv(&mut buf) {
+                Ok(length) => length,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                Err(err) => return Err(err).context("receiving from the tracker"),
+            };
+            let answer = &buf[..length];
+            // a late answer to an earlier request, or garbage
+            if length < 8 || u32_at(answer, 4) != transaction_id {
+                continue;
+            }
+            if u32_at(answer, 0) == ERROR {
+                let reason = String::from_utf8_lossy(&answer[8..]).into_owned();
+                return Err(UdpTrackerError::Failure(reason).into());
+            }
+            return Ok(answer.to_vec());
+        }
+    }
+    Err(UdpTrackerError::Timeout.into())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn connect_request(transaction_id: u32) -> [u8; 16] {
+    let mut packet = [0; 16];
+    packet[..8].copy_from_slice(&PROTOCOL_ID.to_be_bytes());
+    packet[8..12].copy_from_slice(&CONNECT.to_be_bytes());
+    packet[12..].copy_from_slice(&transaction_id.to_be_bytes());
+    packet
+}
+
+/// The connection id of a connect answer.
+fn parse_connect(answer: &[u8]) -> Result<u64, UdpTrackerError> {
+    if answer.len() < 16 || u32_at(answer, 0) != CONNECT {
+        return Err(UdpTrackerError::Malformed("expected a connect answer"));
+    }
+    Ok(u64::from_be_bytes(answer[8..16].try_into().unwrap()))
+}
+
+fn announce_request(
+    connection_id: u64,
+    transaction_id: u32,
+    key: u32,
+    request: &TrackerRequest,
+    info_hash: [u8; 20],
+) -> Vec<u8> {
+    let mut peer_id = [0; 20];
+    let id = request.peer_id.as_bytes();
+    let length = id.len().min(20);
+    peer_id[..length].copy_from_slice(&id[..length]);
+    let event: u32 = match request.event {
+        None => 0,
+        Some(Event::Completed) => 1,
+        Some(Event::Started) => 2,
+        Some(Event::Stopped) => 3,
+    };
+    // -1 leaves the number of peers to the tracker
+    let numwant = request.numwant.map_or(-1, |numwant| numwant as i32);
+
+    let mut packet = Vec::with_capacity(98);
+    packet.extend(connection_id.to_be_bytes());
+    packet.extend(ANNOUNCE.to_be_bytes());
+    packet.extend(transaction_id.to_be_bytes());
+    packet.extend(info_hash);
+    packet.extend(peer_id);
+    packet.extend((request.downloaded as u64).to_be_bytes());
+    packet.extend((request.left as u64).to_be_bytes());
+    packet.extend((request.uploaded as u64).to_be_bytes());
+    packet.extend(event.to_be_bytes());
+    // the address to announce, 0 for the one the request comes from
+    packet.extend(0u32.to_be_bytes());
+    packet.extend(key.to_be_bytes());
+    packet.extend(numwant.to_be_bytes());
+    packet.extend(request.port.to_be_bytes());
+    packet
+}
+
+/// The interval and peers of an announce answer, whose peers have IPv6 addresses when the
+/// announce went over IPv6.
+fn parse_announce(answer: &[u8], ipv6: bool) -> Result<(u32, Vec<SocketAddr>), UdpTrackerError> {
+    if answer.len() < 20 || u32_at(answer, 0) != ANNOUNCE {
+        return Err(UdpTrackerError::Malformed("expected an announce answer"));
+    }
+    let interval = u32_at(answer, 8);
+    let address_length = if ipv6 { 16 } else { 4 };
+    let entries = &answer[20..];
+    if !entries.len().is_multiple_of(address_length + 2) {
+        return Err(UdpTrackerError::Malformed("peers of an uneven length"));
+    }
+    let peers = entries
+        .chunks_exact(address_length + 2)
+        .map(|entry| {
+            let (ip, port) = entry.split_at(address_length);
+            let ip: IpAddr = match ip.len() {
+                4 => Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap()).into(),
+                _ => Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()).into(),
+            };
+            SocketAddr::new(ip, u16::from_be_bytes(port.try_into().unwrap()))
+        })
+        .collect();
+    Ok((interval, peers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// A tracker on `addr` answering a connect and an announce with `peer`, returning its address.
+    fn fake_tracker(addr: &str, peer: SocketAddr) -> SocketAddr {
+        let socket = UdpSocket::bind(addr).unwrap();
+        let tracker = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            for _ in 0..2 {
+                let (length, from) = socket.recv_from(&mut buf).unwrap();
+                let (action, transaction) = match length {
+                    16 => (CONNECT, &buf[12..16]),
+                    _ => (ANNOUNCE, &buf[12..16]),
+                };
+                let mut answer = Vec::new();
+                answer.extend(action.to_be_bytes());
+                answer.extend(transaction);
+                match action {
+                    CONNECT => answer.extend(42u64.to_be_bytes()),
+                    _ => {
+                        assert_eq!(length, 98);
+                        assert_eq!(u64::from_be_bytes(buf[..8].try_into().unwrap()), 42);
+                        assert_eq!(&buf[16..36], [7; 20], "the info hash");
+                        // interval, leechers and seeders
+                        answer.extend([1800u32, 0, 1].iter().flat_map(|n| n.to_be_bytes()));
+                        match peer.ip() {
+                            IpAddr::V4(ip) => answer.extend(ip.octets()),
+                            IpAddr::V6(ip) => answer.extend(ip.octets()),
+                        }
+                        answer.extend(peer.port().to_be_bytes());
+                    }
+                }
+                socket.send_to(&answer, from).unwrap();
+            }
+        });
+        tracker
+    }
+
+    #[test]
+    fn announce_over_either_family() {
+        let request = TrackerRequest::new(1000).port(6881);
+
+        let peer: SocketAddr = "10.0.0.1:51413".parse().unwrap();
+        let tracker = fake_tracker("127.0.0.1:0", peer);
+        let response = announce(&format!("udp://{tracker}/announce"), &request, [7; 20]).unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.peers.0, [peer]);
+        assert!(response.peers6.0.is_empty());
+
+        let peer: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let tracker = fake_tracker("[::1]:0", peer);
+        let response = announce(&format!("udp://{tracker}"), &request, [7; 20]).unwrap();
+        assert!(response.peers.0.is_empty());
+        assert_eq!(response.peers6.0, [peer], "18 bytes per peer over IPv6");
+
+        let mut error = ERROR.to_be_bytes().to_vec();
+        error.extend(b"\0\0\0\0");
+        assert_eq!(
+            parse_connect(&error),
+            Err(UdpTrackerError::Malformed("expected a connect answer"))
+        );
+        let mut uneven = ANNOUNCE.to_be_bytes().to_vec();
+        uneven.extend([0; 16 + 6]);
+        assert!(parse_announce(&uneven, false).is_ok());
+        assert!(parse_announce(&uneven, true).is_err());
+    }
+
+    #[test]
+    fn errors_are_retryable_tracker_failures() {
+        use crate::events::ErrorKind;
+
+        for err in [
+            UdpTrackerError::Timeout,
+            UdpTrackerError::Failure("unregistered torrent".to_string()),
+            UdpTrackerError::Malformed("expected a connect answer"),
+        ] {
+            let err = anyhow::Error::from(err).context("announcing to udp://tracker.example.com");
+            assert_eq!(ErrorKind::classify(&err), ErrorKind::Tracker, "{err:#}");
+            assert!(ErrorKind::classify(&err).is_retryable());
+        }
+    }
+}
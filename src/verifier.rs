@@ -0,0 +1,218 @@
+//! Checking downloaded pieces against their hashes, and writing the good ones, on worker threads
+//! of their own, so that hashing a large piece never holds up the peer connections.
+
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{hasher::PieceHasher, peer::verify_piece, storage::StorageSink, torrent::Torrent};
+
+/// The most workers [`default_workers`] starts, more than enough to keep up with any connection.
+pub const MAX_WORKERS: usize = 4;
+
+/// A worker per core, up to [`MAX_WORKERS`].
+pub fn default_workers() -> usize {
+    thread::available_parallelism().map_or(1, |cores| cores.get().min(MAX_WORKERS))
+}
+
+/// What became of a piece handed to a [`Verifier`].
+#[derive(Debug)]
+pub enum Verdict {
+    /// It matched its hash and was written.
+    Written,
+    /// It didn't match its hash, and wasn't written.
+    Corrupt(anyhow::Error),
+    /// It matched its hash, but writing it failed.
+    WriteFailed(anyhow::Error),
+}
+
+/// A piece handed to a [`Verifier`], once it's done with it.
+#[derive(Debug)]
+pub struct Verified<T> {
+    pub piece_index: usize,
+    pub piece_size: usize,
+    pub verdict: Verdict,
+    pub verify: Duration,
+    /// Zero when the piece wasn't written.
+    pub write: Duration,
+    /// What the piece was submitted with.
+    pub tag: T,
+}
+
+struct Job<T> {
+    piece_index: usize,
+    piece: Vec<u8>,
+    tag: T,
+}
+
+/// A pool of threads verifying the pieces submitted to it, writing the good ones to a shared
+/// storage, and sending every [`Verified`] piece back in the order they're done.
+pub struct Verifier<T> {
+    jobs: Option<mpsc::Sender<Job<T>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> Verifier<T> {
+    /// Start `workers` threads checking pieces of `torrent` with `hasher` and writing them to
+    /// `storage`, sending the results to `results`.
+    pub fn new<S: StorageSink + Send + 'static>(
+        workers: usize,
+        torrent: Arc<Torrent>,
+        hasher: Arc<dyn PieceHasher>,
+        storage: Arc<Mutex<S>>,
+        results: UnboundedSender<Verified<T>>,
+    ) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job<T>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let (receiver, torrent, hasher, storage, results) = (
+                    receiver.clone(),
+                    torrent.clone(),
+                    hasher.clone(),
+                    storage.clone(),
+                    results.clone(),
+                );
+                thread::spawn(move || loop {
+                    // the lock is only held while waiting, never while hashing
+                    let job = receiver.lock().expect("verifier poisoned").recv();
+                    let Ok(Job {
+                        piece_index,
+                        piece,
+                        tag,
+                    }) = job
+                    else {
+                        break;
+                    };
+
+                    let started = Instant::now();
+                    let verified = verify_piece(&*hasher, &torrent, piece_index, &piece);
+                    let verify = started.elapsed();
+                    let started = Instant::now();
+                    let verdict = match verified {
+                        Err(err) => Verdict::Corrupt(err),
+                        Ok(()) => {
+                            let offset = (piece_index * torrent.info.piece_length) as u64;
+                            let written = storage
+                                .lock()
+                                .expect("storage poisoned")
+                                .write_at(offset, &piece)
+                                .context(format!("writing piece {piece_index}"));
+                            match written {
+                                Ok(()) => Verdict::Written,
+                                Err(err) => Verdict::WriteFailed(err),
+                            }
+                        }
+                    };
+                    let write = match verdict {
+                        Verdict::Corrupt(_) => Duration::ZERO,
+                        _ => started.elapsed(),
+                    };
+
+                    let verified = Verified {
+                        piece_index,
+                        piece_size: piece.len(),
+                        verdict,
+                        verify,
+                        write,
+                        tag,
+                    };
+                    if results.send(verified).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+        Self {
+            jobs: Some(jobs),
+            workers,
+        }
+    }
+
+    /// Queue `piece`, the one at `piece_index`, to be verified and written.
+    pub fn submit(&self, piece_index: usize, piece: Vec<u8>, tag: T) {
+        let job = Job {
+            piece_index,
+            piece,
+            tag,
+        };
+        self.jobs
+            .as_ref()
+            .expect("the verifier is running")
+            .send(job)
+            .expect("the workers outlive the verifier");
+    }
+}
+
+impl<T> Drop for Verifier<T> {
+    /// Let the workers finish the pieces queued already, then wait for them.
+    fn drop(&mut self) {
+        drop(self.jobs.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hasher::Sha1Hasher, testing::single_file};
+
+    /// The content, in memory.
+    struct Memory(Vec<u8>);
+
+    impl StorageSink for Memory {
+        fn write_at(&mut self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+            let offset = offset as usize;
+            self.0[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn sync_data(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn verify_and_write_off_thread() {
+        let content: Vec<u8> = (0..(1 << 15) + 100).map(|i| i as u8).collect();
+        let torrent = single_file(1 << 15, &content);
+        let storage = Arc::new(Mutex::new(Memory(vec![0; content.len()])));
+        let (results, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let verifier = Verifier::new(
+            2,
+            Arc::new(torrent),
+            Arc::new(Sha1Hasher),
+            storage.clone(),
+            results,
+        );
+
+        let mut corrupt = content[..1 << 15].to_vec();
+        corrupt[0] ^= 1;
+        verifier.submit(0, corrupt, "first try");
+        verifier.submit(1, content[1 << 15..].to_vec(), "second piece");
+        verifier.submit(0, content[..1 << 15].to_vec(), "second try");
+        drop(verifier);
+
+        let mut verified = Vec::new();
+        while let Some(piece) = receiver.blocking_recv() {
+            verified.push(piece);
+        }
+        verified.sort_by_key(|piece| piece.tag);
+        let verdicts: Vec<_> = verified
+            .iter()
+            .map(|piece| (piece.tag, piece.piece_index, &piece.verdict))
+            .collect();
+        assert!(matches!(verdicts[0], ("first try", 0, Verdict::Corrupt(_))));
+        assert!(matches!(verdicts[1], ("second piece", 1, Verdict::Written)));
+        assert!(matches!(verdicts[2], ("second try", 0, Verdict::Written)));
+        assert_eq!(verified[1].piece_size, 100);
+        assert_eq!(storage.lock().unwrap().0, content);
+    }
+}